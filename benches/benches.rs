@@ -1,6 +1,8 @@
 use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
-use kvs::thread_pool::{RayonThreadPool, SharedQueueThreadPool, ThreadPool};
-use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, SledKvsEngine};
+use kvs::thread_pool::{
+    RayonThreadPool, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool,
+};
+use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, LsmKvsEngine, SledKvsEngine};
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 use std::collections::HashMap;
@@ -89,6 +91,25 @@ pub fn write_bench(c: &mut Criterion) {
             BatchSize::SmallInput,
         )
     });
+
+    group.bench_function("lsm write", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (
+                    LsmKvsEngine::open(temp_dir.path()).unwrap(),
+                    data_map.clone(),
+                    temp_dir,
+                )
+            },
+            |(store, data_map, _temp_dir)| {
+                for (key, value) in data_map {
+                    assert!(store.set(key, value).is_ok(), "lsm store set error");
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
     group.finish();
 }
 
@@ -137,6 +158,25 @@ pub fn read_bench(c: &mut Criterion) {
             BatchSize::SmallInput,
         )
     });
+
+    group.bench_function("lsm read", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let store = LsmKvsEngine::open(temp_dir.path()).unwrap();
+                for (key, value) in data_map.clone() {
+                    store.set(key, value).unwrap();
+                }
+                (store, read_keys.clone(), temp_dir)
+            },
+            |(store, read_keys, _temp_dir)| {
+                for key in read_keys {
+                    assert!(store.get(key).is_ok(), "lsm store get error");
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
     group.finish();
 }
 
@@ -249,6 +289,115 @@ pub fn read_shared_queue_kvs(c: &mut Criterion) {
     group.finish();
 }
 
+pub fn write_stealing_kvs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_stealing_kvs");
+    group.measurement_time(Duration::from_secs(20));
+
+    let inputs: [u32; 6] = [1, 2, 4, 8, 16, 32];
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+
+    let data_map = gen_data_map();
+    for num in inputs.iter() {
+        let pool = WorkStealingThreadPool::new(*num).unwrap();
+        let mut server = KvsServer::new(engine.clone(), pool);
+        server.run(DEFAULT_ADDRESS).unwrap();
+
+        let client = Arc::new(Mutex::new(KvsClient::connect(DEFAULT_ADDRESS).unwrap()));
+        let (tx, rx) = crossbeam::crossbeam_channel::unbounded();
+        let barrier = Arc::new(Barrier::new(WORKLOAD_SIZE + 1));
+
+        for (key, value) in data_map.clone() {
+            let c = barrier.clone();
+            let rx1 = rx.clone();
+            let cli = client.clone();
+
+            thread::spawn(move || loop {
+                if let Err(_) = rx1.recv() {
+                    return;
+                }
+                {
+                    let mut cli = cli.lock().unwrap();
+                    assert!(
+                        cli.set(key.clone(), value.clone()).is_ok(),
+                        "client set error"
+                    );
+                }
+                c.wait();
+            });
+        }
+
+        group.bench_with_input(BenchmarkId::from_parameter(num), num, |b, &_num| {
+            let c = barrier.clone();
+            let tx1 = tx.clone();
+            b.iter(|| {
+                for _ in 0..WORKLOAD_SIZE {
+                    tx1.send(()).unwrap();
+                }
+                c.wait();
+            });
+        });
+
+        server.shutdown();
+    }
+    group.finish();
+}
+
+pub fn read_stealing_kvs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_stealing_kvs");
+    group.measurement_time(Duration::from_secs(20));
+
+    let inputs: [u32; 6] = [1, 2, 4, 8, 16, 32];
+    let data_map = gen_data_map();
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+
+    for (key, value) in data_map.clone() {
+        engine.set(key, value).unwrap();
+    }
+
+    for num in inputs.iter() {
+        let pool = WorkStealingThreadPool::new(*num).unwrap();
+        let mut server = KvsServer::new(engine.clone(), pool);
+        server.run(DEFAULT_ADDRESS).unwrap();
+
+        let client = Arc::new(Mutex::new(KvsClient::connect(DEFAULT_ADDRESS).unwrap()));
+        let (tx, rx) = crossbeam::crossbeam_channel::unbounded();
+        let barrier = Arc::new(Barrier::new(WORKLOAD_SIZE + 1));
+
+        for (key, _) in data_map.clone() {
+            let c = barrier.clone();
+            let rx1 = rx.clone();
+            let cli = client.clone();
+
+            thread::spawn(move || loop {
+                if let Err(_) = rx1.recv() {
+                    return;
+                }
+                {
+                    let mut cli = cli.lock().unwrap();
+                    assert!(cli.get(key.clone()).is_ok(), "client get error");
+                }
+                c.wait();
+            });
+        }
+
+        group.bench_with_input(BenchmarkId::from_parameter(num), num, |b, &_num| {
+            let c = barrier.clone();
+            let tx1 = tx.clone();
+            b.iter(|| {
+                for _ in 0..WORKLOAD_SIZE {
+                    tx1.send(()).unwrap();
+                }
+                c.wait();
+            });
+        });
+
+        server.shutdown();
+    }
+    group.finish();
+}
+
 pub fn write_rayon_kvs(c: &mut Criterion) {
     let mut group = c.benchmark_group("write_rayon_kvs");
     group.measurement_time(Duration::from_secs(20));
@@ -473,6 +622,8 @@ criterion_group!(
     read_bench,
     write_shared_queue_kvs,
     read_shared_queue_kvs,
+    write_stealing_kvs,
+    read_stealing_kvs,
     write_rayon_kvs,
     read_rayon_kvs,
     write_rayon_sled,
@@ -0,0 +1,218 @@
+use kvs::cdc::LogTailer;
+use kvs::replication::LogPosition;
+use kvs::sharding::HashRing;
+use kvs::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use structopt::StructOpt;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
+
+#[derive(StructOpt)]
+#[structopt(author, about)]
+struct Opt {
+    /// PEM file of the certificates trusted to verify the servers serving TLS
+    #[structopt(long, global = true, parse(from_os_str))]
+    tls_ca: Option<PathBuf>,
+
+    /// Name the server certificates are verified against, instead of their address
+    #[structopt(long, global = true, requires = "tls-ca")]
+    tls_server_name: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Print the server metrics in the Prometheus text format
+    Stats {
+        /// Server ip address
+        #[structopt(default_value = DEFAULT_ADDRESS, short, long)]
+        addr: String,
+    },
+
+    /// Promote a replica to a primary accepting writes
+    Promote {
+        /// Server ip address
+        #[structopt(default_value = DEFAULT_ADDRESS, short, long)]
+        addr: String,
+    },
+
+    /// Print the state of a cluster node
+    Cluster {
+        /// Server ip address
+        #[structopt(default_value = DEFAULT_ADDRESS, short, long)]
+        addr: String,
+    },
+
+    /// Add a running node with no members to the cluster
+    AddNode {
+        /// Id of the new node
+        id: u64,
+        /// Address of the new node
+        node_addr: String,
+
+        /// Server ip address
+        #[structopt(default_value = DEFAULT_ADDRESS, short, long)]
+        addr: String,
+    },
+
+    /// Remove a node from the cluster
+    RemoveNode {
+        /// Id of the node to remove
+        id: u64,
+
+        /// Server ip address
+        #[structopt(default_value = DEFAULT_ADDRESS, short, long)]
+        addr: String,
+    },
+
+    /// Add a server to a sharded deployment and move the keys it now owns to it
+    Reshard {
+        /// Address of the new server
+        new_addr: String,
+
+        /// Addresses of the current servers, in the order the clients use
+        #[structopt(long = "server", required = true, number_of_values = 1)]
+        servers: Vec<String>,
+
+        /// Number of virtual nodes per server on the hash ring
+        #[structopt(long, default_value = "160")]
+        virtual_nodes: usize,
+    },
+
+    /// Print the writes to a kvs data directory as JSON lines, following new writes
+    Tail {
+        /// Data directory of the store
+        #[structopt(long, parse(from_os_str), default_value = ".")]
+        data_dir: PathBuf,
+
+        /// Start from the oldest write still in the log instead of the next write
+        #[structopt(long)]
+        from_start: bool,
+
+        /// File saving the position after each printed write, resumed from if it exists
+        #[structopt(long, parse(from_os_str))]
+        position_file: Option<PathBuf>,
+    },
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", describe(&e));
+        exit(1);
+    }
+}
+
+/// Describes an error without the `String error` wrapping of the cluster errors.
+fn describe(e: &KvsError) -> String {
+    match e {
+        KvsError::StringError(msg) => msg.clone(),
+        e => e.to_string(),
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    let options = client_options(opt.tls_ca.as_deref(), opt.tls_server_name.as_deref())?;
+    match opt.command {
+        Command::Stats { addr } => {
+            let mut client = KvsClient::connect_with(addr, options)?;
+            print!("{}", client.stats()?);
+        }
+        Command::Promote { addr } => {
+            let mut client = KvsClient::connect_with(addr, options)?;
+            client.promote()?;
+        }
+        Command::Cluster { addr } => {
+            let mut client = KvsClient::connect_with(addr, options)?;
+            let status = client.cluster_status()?;
+            println!("id: {}", status.id);
+            println!("state: {}", status.state);
+            println!("term: {}", status.term);
+            match status.leader {
+                Some(leader) => println!("leader: {}", leader),
+                None => println!("leader: unknown"),
+            }
+            println!("commit index: {}", status.commit_index);
+            println!("applied index: {}", status.applied_index);
+            println!("snapshot index: {}", status.snapshot_index);
+            println!("members:");
+            for (id, addr) in status.members {
+                println!("  {} {}", id, addr);
+            }
+        }
+        Command::AddNode {
+            id,
+            node_addr,
+            addr,
+        } => {
+            let mut client = KvsClient::connect_with(addr, options)?;
+            client.add_node(id, node_addr)?;
+        }
+        Command::RemoveNode { id, addr } => {
+            let mut client = KvsClient::connect_with(addr, options)?;
+            client.remove_node(id)?;
+        }
+        Command::Reshard {
+            new_addr,
+            servers,
+            virtual_nodes,
+        } => {
+            let ring = HashRing::new(servers, virtual_nodes)?;
+            let mut client = ShardedKvsClient::with_options(ring, options)?;
+            let moved = client.add_server(new_addr)?;
+            println!("moved {} keys", moved);
+        }
+        Command::Tail {
+            data_dir,
+            from_start,
+            position_file,
+        } => tail(&data_dir, from_start, position_file.as_deref())?,
+    }
+    Ok(())
+}
+
+/// Returns the options of the connections to the servers, over TLS if a CA file is
+/// given.
+fn client_options(ca: Option<&Path>, server_name: Option<&str>) -> Result<KvsClientOptions> {
+    let tls = match ca {
+        Some(ca) => {
+            let connector = tls::TlsConnector::new(ca)?;
+            Some(match server_name {
+                Some(name) => connector.server_name(name)?,
+                None => connector,
+            })
+        }
+        None => None,
+    };
+    Ok(KvsClientOptions {
+        tls,
+        ..KvsClientOptions::default()
+    })
+}
+
+fn tail(data_dir: &Path, from_start: bool, position_file: Option<&Path>) -> Result<()> {
+    let saved = match position_file {
+        Some(path) if path.exists() => Some(serde_json::from_slice(&fs::read(path)?)?),
+        _ => None,
+    };
+    let from = match saved {
+        Some(pos) => Some(pos),
+        None if from_start => Some(LogPosition::default()),
+        None => None,
+    };
+
+    let mut tailer = LogTailer::open(data_dir, from)?;
+    while let Some(entry) = tailer.next() {
+        println!("{}", serde_json::to_string(&entry?)?);
+        if let Some(path) = position_file {
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, serde_json::to_vec(&tailer.position())?)?;
+            fs::rename(&tmp, path)?;
+        }
+    }
+    Ok(())
+}
@@ -1,12 +1,51 @@
+use kvs::bulk::{PairFormat, PairReader, PairWriter};
+use kvs::replication::LogOp;
 use kvs::*;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::thread;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
+/// Delay before a lost watch reconnects.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// How often `load` and `dump` report their progress.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// Shell history, in the home directory.
+const HISTORY_FILE: &str = ".kvs_history";
+/// Commands understood by the shell.
+const SHELL_COMMANDS: &[&str] = &[
+    "get", "set", "rm", "keys", "scan", "timing", "help", "exit", "quit",
+];
+const SHELL_HELP: &str = "\
+get <key>            print the value of a key
+set <key> <value>    set a key, the value is the rest of the line
+rm <key>             remove a key
+keys [prefix]        list the keys starting with a prefix, `scan` does the same
+timing on|off        print how long each command takes
+exit                 leave the shell";
 
 #[derive(StructOpt)]
 #[structopt(author, about)]
 struct Opt {
+    /// PEM file of the certificates trusted to verify a server serving TLS
+    #[structopt(long, global = true, parse(from_os_str))]
+    tls_ca: Option<PathBuf>,
+
+    /// Name the server certificate is verified against, instead of its address
+    #[structopt(long, global = true, requires = "tls-ca")]
+    tls_server_name: Option<String>,
+
     #[structopt(subcommand)]
     command: Command,
 }
@@ -40,20 +79,89 @@ enum Command {
         #[structopt(default_value = DEFAULT_ADDRESS, short, long)]
         addr: String,
     },
+
+    /// Print the writes to the keys starting with a prefix as they happen
+    Watch {
+        /// Prefix of the keys to watch, all keys by default
+        #[structopt(default_value = "")]
+        prefix: String,
+
+        /// Start with the writes following this sequence number
+        #[structopt(long)]
+        from: Option<u64>,
+
+        /// Server ip address
+        #[structopt(default_value = DEFAULT_ADDRESS, short, long)]
+        addr: String,
+    },
+
+    /// Load the key/value pairs of a CSV, TSV or JSON lines file
+    Load {
+        /// File to load, `-` for stdin
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// Format of the file, guessed from its extension and CSV otherwise
+        #[structopt(long, possible_values = PairFormat::VARIANTS)]
+        format: Option<PairFormat>,
+
+        /// Number of writes sent before waiting for their responses
+        #[structopt(long, default_value = "1000")]
+        batch_size: usize,
+
+        /// Server ip address
+        #[structopt(default_value = DEFAULT_ADDRESS, short, long)]
+        addr: String,
+    },
+
+    /// Write the keys and values of the server to a CSV, TSV or JSON lines file
+    Dump {
+        /// File to write, `-` for stdout
+        #[structopt(default_value = "-", parse(from_os_str))]
+        file: PathBuf,
+
+        /// Only write the keys starting with this prefix
+        #[structopt(long, default_value = "")]
+        prefix: String,
+
+        /// Format of the file, guessed from its extension and CSV otherwise
+        #[structopt(long, possible_values = PairFormat::VARIANTS)]
+        format: Option<PairFormat>,
+
+        /// Number of reads sent before waiting for their responses
+        #[structopt(long, default_value = "1000")]
+        batch_size: usize,
+
+        /// Server ip address
+        #[structopt(default_value = DEFAULT_ADDRESS, short, long)]
+        addr: String,
+    },
+
+    /// Run commands over a single connection, interactively or from a file
+    Shell {
+        /// Read the commands from this file, `-` for stdin, and stop at the first error
+        #[structopt(long, parse(from_os_str))]
+        file: Option<PathBuf>,
+
+        /// Server ip address
+        #[structopt(default_value = DEFAULT_ADDRESS, short, long)]
+        addr: String,
+    },
 }
 
 fn main() {
     let opt = Opt::from_args();
     if let Err(e) = run(opt) {
-        eprintln!("{}", e);
+        eprintln!("{}", describe(&e));
         exit(1);
     }
 }
 
 fn run(opt: Opt) -> Result<()> {
+    let options = client_options(opt.tls_ca.as_deref(), opt.tls_server_name.as_deref())?;
     match opt.command {
         Command::Get { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = KvsClient::connect_with(addr, options)?;
             if let Some(value) = client.get(key)? {
                 println!("{}", value);
             } else {
@@ -61,13 +169,414 @@ fn run(opt: Opt) -> Result<()> {
             }
         }
         Command::Set { key, value, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = KvsClient::connect_with(addr, options)?;
             client.set(key, value)?;
         }
         Command::Rm { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = KvsClient::connect_with(addr, options)?;
             client.remove(key)?;
         }
+        Command::Watch { prefix, from, addr } => watch(&prefix, from, &addr, &options)?,
+        Command::Load {
+            file,
+            format,
+            batch_size,
+            addr,
+        } => {
+            let format = format.unwrap_or_else(|| guess_format(&file));
+            load(&file, format, batch_size, &addr, &options)?
+        }
+        Command::Dump {
+            file,
+            prefix,
+            format,
+            batch_size,
+            addr,
+        } => {
+            let format = format.unwrap_or_else(|| guess_format(&file));
+            dump(&file, &prefix, format, batch_size, &addr, &options)?
+        }
+        Command::Shell { file, addr } => {
+            let mut shell = Shell {
+                client: KvsClient::connect_with(addr, options)?,
+                timing: file.is_none(),
+            };
+            match file {
+                Some(path) => shell.run_batch(&path)?,
+                None => shell.run_interactive()?,
+            }
+        }
     }
     Ok(())
 }
+
+struct Shell {
+    client: KvsClient,
+    timing: bool,
+}
+
+impl Shell {
+    fn run_interactive(&mut self) -> Result<()> {
+        let mut editor = Editor::<ShellHelper>::new();
+        editor.set_helper(Some(ShellHelper));
+        let history = env::var_os("HOME").map(|home| Path::new(&home).join(HISTORY_FILE));
+        if let Some(path) = &history {
+            // There is no history the first time
+            let _ = editor.load_history(path);
+        }
+
+        loop {
+            let line = match editor.readline("kvs> ") {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(KvsError::StringError(e.to_string())),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            editor.add_history_entry(line.as_str());
+            match self.execute(&line) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => println!("error: {}", describe(&e)),
+            }
+        }
+
+        if let Some(path) = &history {
+            if let Err(e) = editor.save_history(path) {
+                eprintln!("cannot save the history to {}: {}", path.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    fn run_batch(&mut self, path: &Path) -> Result<()> {
+        let reader: Box<dyn BufRead> = if path == Path::new("-") {
+            Box::new(BufReader::new(io::stdin()))
+        } else {
+            Box::new(BufReader::new(File::open(path)?))
+        };
+        for (number, line) in reader.lines().enumerate() {
+            match self.execute(&line?) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    let msg = format!("line {}: {}", number + 1, describe(&e));
+                    return Err(KvsError::StringError(msg));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs one command line, returns false when the shell must exit.
+    fn execute(&mut self, line: &str) -> Result<bool> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(true);
+        }
+        let (command, args) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim_start()),
+            None => (line, ""),
+        };
+
+        let start = Instant::now();
+        match (command, args) {
+            ("get", key) if is_word(key) => match self.client.get(key.to_owned())? {
+                Some(value) => println!("{}", value),
+                None => println!("Key not found"),
+            },
+            ("set", args) => match args.find(char::is_whitespace) {
+                Some(i) => {
+                    let value = args[i..].trim_start();
+                    self.client.set(args[..i].to_owned(), value.to_owned())?
+                }
+                None => return Err(usage("set <key> <value>")),
+            },
+            ("rm", key) if is_word(key) => self.client.remove(key.to_owned())?,
+            ("keys", prefix) | ("scan", prefix) if !prefix.contains(char::is_whitespace) => {
+                for key in self.client.keys(prefix)? {
+                    println!("{}", key);
+                }
+            }
+            ("timing", "on") => self.timing = true,
+            ("timing", "off") => self.timing = false,
+            ("help", "") => println!("{}", SHELL_HELP),
+            ("exit", "") | ("quit", "") => return Ok(false),
+            (command, _) if SHELL_COMMANDS.contains(&command) => {
+                return Err(usage(
+                    SHELL_HELP
+                        .lines()
+                        .find(|l| l.starts_with(command))
+                        .unwrap_or(command),
+                ))
+            }
+            (command, _) => {
+                return Err(KvsError::StringError(format!(
+                    "unknown command `{}`, try `help`",
+                    command
+                )))
+            }
+        }
+        if self.timing {
+            println!("({:.3} ms)", start.elapsed().as_secs_f64() * 1000.0);
+        }
+        Ok(true)
+    }
+}
+
+/// Describes an error without the `String error` wrapping of the shell errors.
+fn describe(e: &KvsError) -> String {
+    match e {
+        KvsError::StringError(msg) => msg.clone(),
+        e => e.to_string(),
+    }
+}
+
+fn is_word(s: &str) -> bool {
+    !s.is_empty() && !s.contains(char::is_whitespace)
+}
+
+fn usage(line: &str) -> KvsError {
+    let usage = line.split("  ").next().unwrap_or(line);
+    KvsError::StringError(format!("usage: {}", usage.trim_end()))
+}
+
+/// Completes the command names at the start of the line.
+struct ShellHelper;
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let word = &line[..pos];
+        if word.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let candidates = SHELL_COMMANDS
+            .iter()
+            .filter(|command| command.starts_with(word))
+            .map(|command| command.to_string())
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+/// Returns the options of the connections to the server, over TLS if a CA file is
+/// given.
+fn client_options(ca: Option<&Path>, server_name: Option<&str>) -> Result<KvsClientOptions> {
+    let tls = match ca {
+        Some(ca) => {
+            let connector = tls::TlsConnector::new(ca)?;
+            Some(match server_name {
+                Some(name) => connector.server_name(name)?,
+                None => connector,
+            })
+        }
+        None => None,
+    };
+    Ok(KvsClientOptions {
+        tls,
+        ..KvsClientOptions::default()
+    })
+}
+
+fn guess_format(path: &Path) -> PairFormat {
+    PairFormat::from_path(path).unwrap_or(PairFormat::Csv)
+}
+
+/// Writes the pairs of a file in batches, reporting the lines that fail and going on
+/// with the next ones.
+fn load(
+    path: &Path,
+    format: PairFormat,
+    batch_size: usize,
+    addr: &str,
+    options: &KvsClientOptions,
+) -> Result<()> {
+    check_batch_size(batch_size)?;
+    let mut client = KvsClient::connect_with(addr, options.clone())?;
+    let input: Box<dyn Read> = if path == Path::new("-") {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path)?)
+    };
+    let mut pairs = PairReader::new(input, format);
+    let mut progress = Progress::new("loaded");
+    let mut failed = 0;
+
+    loop {
+        let mut read = 0;
+        let mut lines = Vec::with_capacity(batch_size);
+        let mut batch = Vec::with_capacity(batch_size);
+        for pair in pairs.by_ref().take(batch_size) {
+            read += 1;
+            match pair {
+                Ok(pair) => {
+                    lines.push(pair.line);
+                    batch.push((pair.key, pair.value));
+                }
+                Err(e @ KvsError::Format { .. }) => {
+                    eprintln!("{}", e);
+                    failed += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if read == 0 {
+            break;
+        }
+
+        let mut written = 0;
+        for (line, res) in lines.into_iter().zip(client.set_batch(batch)?) {
+            match res {
+                Ok(()) => written += 1,
+                Err(e) => {
+                    eprintln!("line {}: {}", line, e);
+                    failed += 1;
+                }
+            }
+        }
+        progress.add(written);
+    }
+
+    progress.finish();
+    if failed > 0 {
+        let msg = format!("{} records failed", failed);
+        return Err(KvsError::StringError(msg));
+    }
+    Ok(())
+}
+
+/// Writes the keys starting with `prefix`, with their values read in batches.
+///
+/// Keys removed while the dump runs are left out.
+fn dump(
+    path: &Path,
+    prefix: &str,
+    format: PairFormat,
+    batch_size: usize,
+    addr: &str,
+    options: &KvsClientOptions,
+) -> Result<()> {
+    check_batch_size(batch_size)?;
+    let mut client = KvsClient::connect_with(addr, options.clone())?;
+    let output: Box<dyn Write> = if path == Path::new("-") {
+        Box::new(io::stdout())
+    } else {
+        Box::new(File::create(path)?)
+    };
+    let mut writer = PairWriter::new(BufWriter::new(output), format);
+    let mut progress = Progress::new("dumped");
+
+    let keys = client.keys(prefix)?;
+    for batch in keys.chunks(batch_size) {
+        let values = client.get_batch(batch.to_vec())?;
+        let mut written = 0;
+        for (key, value) in batch.iter().zip(values) {
+            if let Some(value) = value? {
+                writer.write(key, &value)?;
+                written += 1;
+            }
+        }
+        progress.add(written);
+    }
+
+    writer.flush()?;
+    progress.finish();
+    Ok(())
+}
+
+fn check_batch_size(batch_size: usize) -> Result<()> {
+    if batch_size == 0 {
+        return Err(KvsError::Config(
+            "the batch size must be at least 1".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Reports the number of keys processed to stderr, at most once per interval.
+struct Progress {
+    verb: &'static str,
+    count: u64,
+    start: Instant,
+    reported: Instant,
+}
+
+impl Progress {
+    fn new(verb: &'static str) -> Progress {
+        let now = Instant::now();
+        Progress {
+            verb,
+            count: 0,
+            start: now,
+            reported: now,
+        }
+    }
+
+    fn add(&mut self, count: u64) {
+        self.count += count;
+        if self.reported.elapsed() >= PROGRESS_INTERVAL {
+            self.reported = Instant::now();
+            eprintln!("{} {} keys", self.verb, self.count);
+        }
+    }
+
+    fn finish(&self) {
+        let secs = self.start.elapsed().as_secs_f64();
+        eprintln!(
+            "{} {} keys in {:.1} s ({:.0} keys/s)",
+            self.verb,
+            self.count,
+            secs,
+            self.count as f64 / secs.max(0.001)
+        );
+    }
+}
+
+/// Prints the changes forever, reconnecting after the last change seen if the
+/// connection is lost.
+fn watch(
+    prefix: &str,
+    mut from: Option<u64>,
+    addr: &str,
+    options: &KvsClientOptions,
+) -> Result<()> {
+    loop {
+        let res = KvsClient::connect_with(addr, options.clone()).and_then(|client| {
+            for event in client.watch(prefix, from)? {
+                let event = event?;
+                match event.op {
+                    LogOp::Set { key, value } => println!("{} set {} {}", event.seq, key, value),
+                    LogOp::Remove { key } => println!("{} rm {}", event.seq, key),
+                }
+                from = Some(event.seq);
+            }
+            Ok(())
+        });
+        match res {
+            Err(KvsError::Io(_)) | Err(KvsError::SerdeJson(_)) | Ok(_) => {
+                eprintln!("watch connection lost, reconnecting");
+                thread::sleep(RECONNECT_DELAY);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -1,81 +1,448 @@
-use clap::arg_enum;
-use kvs::{self, thread_pool::*, KvStore, KvsServer, Result, SledKvsEngine};
+use kvs::config::{CompactionPolicy, EngineKind, LogFormat, PoolKind, ServerConfig};
+use kvs::{
+    metrics, thread_pool::*, Compression, Durability, IndexKind, KvStore, KvsEngine, KvsError,
+    KvsServer, ReadMode, Result,
+};
+use kvs::{LsmKvsEngine, MemoryKvsEngine, SledKvsEngine};
 use log::{error, info, LevelFilter};
-use std::env::current_dir;
-use std::fmt::Debug;
+#[cfg(unix)]
+use nix::sys::signal::{SigSet, Signal};
 use std::fs::{self, File};
-use std::io::Read;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::thread;
+use std::time::Duration;
 use structopt::StructOpt;
 
-const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
-const DEFAULT_ENGINE: Engine = Engine::kvs;
+const DEFAULT_ENGINE: EngineKind = EngineKind::Kvs;
 
 #[derive(StructOpt)]
 #[structopt(author, about)]
 struct Opt {
+    /// TOML configuration file, overridden by the other options
+    #[structopt(short, long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
     /// Server ip address
-    #[structopt(default_value = DEFAULT_ADDRESS, short, long)]
-    addr: String,
+    #[structopt(short, long)]
+    addr: Option<String>,
 
     /// Engine name
-    #[structopt(long, possible_values = & Engine::variants())]
-    engine: Option<Engine>,
-}
+    #[structopt(long, possible_values = EngineKind::VARIANTS)]
+    engine: Option<EngineKind>,
 
-arg_enum! {
-    #[allow(non_camel_case_types)]
-    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-    enum Engine {
-        kvs,
-        sled
-    }
+    /// Directory holding the data files
+    #[structopt(long, parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+
+    /// Thread pool implementation
+    #[structopt(long, possible_values = PoolKind::VARIANTS)]
+    pool: Option<PoolKind>,
+
+    /// Number of threads in the thread pool
+    #[structopt(long)]
+    threads: Option<u32>,
+
+    /// Connections waiting for a thread of the shared pool, unbounded by default
+    #[structopt(long)]
+    queue_size: Option<usize>,
+
+    /// What happens to connections accepted while the queue is full
+    #[structopt(long, possible_values = QueuePolicy::VARIANTS)]
+    queue_policy: Option<QueuePolicy>,
+
+    /// Threads the elastic pool keeps when idle, `--threads` being the maximum
+    #[structopt(long)]
+    min_threads: Option<u32>,
+
+    /// Durability of writes
+    #[structopt(long, possible_values = &["flush", "sync"])]
+    durability: Option<Durability>,
+
+    /// In-memory index of the keys of the kvs engine
+    #[structopt(long, possible_values = IndexKind::VARIANTS)]
+    index: Option<IndexKind>,
+
+    /// Bytes of values the kvs engine caches in memory
+    #[structopt(long)]
+    cache_capacity: Option<usize>,
+
+    /// How the kvs engine reads values from its log files
+    #[structopt(long, possible_values = ReadMode::VARIANTS)]
+    read_mode: Option<ReadMode>,
+
+    /// False positive rate of the Bloom filters of the kvs and lsm engines
+    #[structopt(long)]
+    bloom_fp_rate: Option<f64>,
+
+    /// Compression of the values of the kvs engine
+    #[structopt(long, possible_values = Compression::VARIANTS)]
+    compression: Option<Compression>,
+
+    /// Bytes from which the kvs engine compresses values
+    #[structopt(long)]
+    compression_threshold: Option<usize>,
+
+    /// Compression the kvs engine compaction rewrites the values with
+    #[structopt(long, possible_values = Compression::VARIANTS)]
+    recompression: Option<Compression>,
+
+    /// File the memory engine loads its pairs from and saves them to on shutdown
+    #[structopt(long, parse(from_os_str))]
+    snapshot: Option<PathBuf>,
+
+    /// Compact the log once this many bytes are stale, 0 disables compaction
+    #[structopt(long)]
+    compaction_threshold: Option<u64>,
+
+    /// Maximum key size in bytes
+    #[structopt(long)]
+    max_key_size: Option<usize>,
+
+    /// Maximum value size in bytes
+    #[structopt(long)]
+    max_value_size: Option<usize>,
+
+    /// Address to serve Prometheus metrics on over HTTP
+    #[structopt(long)]
+    metrics_addr: Option<String>,
+
+    /// Run as a read-only replica of the primary at the given address
+    #[structopt(long)]
+    replica_of: Option<String>,
+
+    /// Id of this node in a Raft cluster
+    #[structopt(long)]
+    cluster_id: Option<u64>,
+
+    /// Initial cluster member as id@addr, repeated for every member
+    #[structopt(long = "cluster-member", number_of_values = 1)]
+    cluster_members: Vec<String>,
+
+    /// PEM file of the certificate chain to serve TLS with
+    #[structopt(long, parse(from_os_str))]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file of the private key of the TLS certificate
+    #[structopt(long, parse(from_os_str))]
+    tls_key: Option<PathBuf>,
+
+    /// PEM file of the certificates trusted to verify the primary and the cluster members
+    #[structopt(long, parse(from_os_str))]
+    tls_ca: Option<PathBuf>,
+
+    /// Log requests slower than the given milliseconds, with values redacted
+    #[structopt(long)]
+    slow_threshold: Option<u64>,
+
+    /// Log level
+    #[structopt(long)]
+    log_level: Option<String>,
+
+    /// Log output format
+    #[structopt(long, possible_values = LogFormat::VARIANTS)]
+    log_format: Option<LogFormat>,
 }
 
 fn main() {
+    // Before any thread starts, so they all leave the signals to `wait_for_shutdown`
+    #[cfg(unix)]
+    if let Err(e) = shutdown_signals().thread_block() {
+        eprintln!("cannot block the shutdown signals: {}", e);
+        exit(1);
+    }
     let opt = Opt::from_args();
 
-    env_logger::builder().filter_level(LevelFilter::Info).init();
+    let config = match load_config(opt) {
+        Ok(config) => config,
+        Err(e) => {
+            init_logger(LevelFilter::Info, LogFormat::Text);
+            error!("{}", e);
+            exit(1);
+        }
+    };
 
-    if let Err(e) = start(opt) {
+    init_logger(
+        config.log_level().unwrap_or(LevelFilter::Info),
+        config.log.format,
+    );
+
+    if let Err(e) = start(config) {
         error!("start failed: {}", e);
         exit(1);
     }
 }
 
-fn start(opt: Opt) -> Result<()> {
-    let engine = get_engine(opt.engine)?;
+fn load_config(opt: Opt) -> Result<ServerConfig> {
+    let mut config = match &opt.config {
+        Some(path) => ServerConfig::load(path)?,
+        None => ServerConfig::default(),
+    };
+
+    if let Some(addr) = opt.addr {
+        config.addr = addr;
+    }
+    if let Some(engine) = opt.engine {
+        config.engine = Some(engine);
+    }
+    if let Some(data_dir) = opt.data_dir {
+        config.data_dir = data_dir;
+    }
+    if let Some(pool) = opt.pool {
+        config.pool.kind = pool;
+    }
+    if let Some(threads) = opt.threads {
+        config.pool.threads = Some(threads);
+    }
+    if let Some(size) = opt.queue_size {
+        config.pool.queue_size = Some(size);
+    }
+    if let Some(policy) = opt.queue_policy {
+        config.pool.queue_policy = policy;
+    }
+    if let Some(min_threads) = opt.min_threads {
+        config.pool.min_threads = Some(min_threads);
+    }
+    if let Some(durability) = opt.durability {
+        config.durability = durability;
+    }
+    if let Some(index) = opt.index {
+        config.index = index;
+    }
+    if let Some(capacity) = opt.cache_capacity {
+        config.cache_capacity = Some(capacity);
+    }
+    if let Some(mode) = opt.read_mode {
+        config.read_mode = mode;
+    }
+    if let Some(rate) = opt.bloom_fp_rate {
+        config.bloom_fp_rate = Some(rate);
+    }
+    if let Some(compression) = opt.compression {
+        config.compression = Some(compression);
+    }
+    if let Some(threshold) = opt.compression_threshold {
+        config.compression_threshold = threshold;
+    }
+    if let Some(compression) = opt.recompression {
+        config.recompression = Some(compression);
+    }
+    if let Some(path) = opt.snapshot {
+        config.snapshot = Some(path);
+    }
+    match opt.compaction_threshold {
+        Some(0) => config.compaction.policy = CompactionPolicy::Disabled,
+        Some(threshold) => {
+            config.compaction.policy = CompactionPolicy::Threshold;
+            config.compaction.threshold = threshold;
+        }
+        None => {}
+    }
+    if let Some(limit) = opt.max_key_size {
+        config.limits.max_key_size = Some(limit);
+    }
+    if let Some(limit) = opt.max_value_size {
+        config.limits.max_value_size = Some(limit);
+    }
+    if let Some(addr) = opt.metrics_addr {
+        config.metrics_addr = Some(addr);
+    }
+    if let Some(primary) = opt.replica_of {
+        config.replica_of = Some(primary);
+    }
+    if let Some(id) = opt.cluster_id {
+        config.cluster.id = Some(id);
+    }
+    if !opt.cluster_members.is_empty() {
+        config.cluster.members = opt.cluster_members;
+    }
+    if let Some(path) = opt.tls_cert {
+        config.tls.cert = Some(path);
+    }
+    if let Some(path) = opt.tls_key {
+        config.tls.key = Some(path);
+    }
+    if let Some(path) = opt.tls_ca {
+        config.tls.ca = Some(path);
+    }
+    if let Some(ms) = opt.slow_threshold {
+        config.log.slow_threshold_ms = Some(ms);
+    }
+    if let Some(level) = opt.log_level {
+        config.log.level = level;
+    }
+    if let Some(format) = opt.log_format {
+        config.log.format = format;
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
+fn init_logger(level: LevelFilter, format: LogFormat) {
+    let mut builder = env_logger::builder();
+    builder.filter_level(level);
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "time": buf.timestamp().to_string(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+fn start(mut config: ServerConfig) -> Result<()> {
+    fs::create_dir_all(&config.data_dir)?;
+    let engine = get_engine(&config.data_dir, config.engine)?;
+    config.engine = Some(engine);
+    // The engine of an existing data directory was not known when loading
+    config.validate()?;
 
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("storage engines: {}", engine);
-    info!("listening on: {}", opt.addr);
+    info!("listening on: {}", config.addr);
+    info!("data directory: {:?}", config.data_dir);
+    info!(
+        "thread pool: {} with {} threads",
+        config.pool.kind,
+        config.threads()
+    );
+    if let Some(size) = config.pool.queue_size {
+        info!(
+            "thread pool queue: {} connections, {:?} when full",
+            size, config.pool.queue_policy
+        );
+    }
+
+    if let Some(cert) = &config.tls.cert {
+        info!("serving TLS with: {:?}", cert);
+    }
+    if let Some(primary) = &config.replica_of {
+        info!("replicating: {}", primary);
+    }
+    if let Some(id) = config.cluster.id {
+        info!("cluster node: {}", id);
+    }
+
+    if let Some(metrics_addr) = &config.metrics_addr {
+        info!("serving metrics on: {}", metrics_addr);
+        metrics::serve_http(metrics_addr)?;
+    }
 
     // write engines to engines file
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
+    fs::write(config.data_dir.join("engine"), format!("{}", engine))?;
 
-    let pool = RayonThreadPool::new(num_cpus::get() as u32)?;
+    let threads = config.threads();
+    match config.pool.kind {
+        PoolKind::Naive => run_with_pool(NaiveThreadPool::new(threads)?, config),
+        PoolKind::Shared => {
+            let pool = match config.pool.queue_size {
+                Some(size) => {
+                    SharedQueueThreadPool::with_queue(threads, size, config.pool.queue_policy)?
+                }
+                None => SharedQueueThreadPool::new(threads)?,
+            };
+            run_with_pool(pool, config)
+        }
+        PoolKind::Rayon => run_with_pool(RayonThreadPool::new(threads)?, config),
+        PoolKind::Stealing => run_with_pool(WorkStealingThreadPool::new(threads)?, config),
+        PoolKind::Elastic => {
+            let pool = ElasticThreadPool::with_options(ElasticOptions {
+                min_threads: config.pool.min_threads.unwrap_or(1),
+                max_threads: threads,
+                idle_timeout: Duration::from_millis(config.pool.idle_timeout_ms),
+            })?;
+            run_with_pool(pool, config)
+        }
+    }
+}
 
-    match engine {
-        Engine::kvs => {
-            let mut server = KvsServer::new(KvStore::open(current_dir()?)?, pool);
-            server.run(opt.addr)?;
-            loop {
-                thread::park()
-            }
+fn run_with_pool<P: ThreadPool>(pool: P, config: ServerConfig) -> Result<()> {
+    match config.engine.unwrap_or(DEFAULT_ENGINE) {
+        EngineKind::Kvs => {
+            let engine = KvStore::open_with(&config.data_dir, config.store_options())?;
+            run(engine, pool, config)
         }
-        Engine::sled => {
-            let mut server = KvsServer::new(SledKvsEngine::new(sled::open(current_dir()?)?), pool);
-            server.run(opt.addr)?;
-            loop {
-                thread::park()
-            }
+        EngineKind::Sled => {
+            let engine = SledKvsEngine::new(sled::open(&config.data_dir)?);
+            run(engine, pool, config)
+        }
+        EngineKind::Lsm => {
+            let engine = LsmKvsEngine::open_with(&config.data_dir, config.lsm_options())?;
+            run(engine, pool, config)
         }
+        EngineKind::Memory => match config.snapshot.clone() {
+            Some(path) => {
+                info!("memory snapshot: {:?}", path);
+                let engine = MemoryKvsEngine::with_snapshot(path)?;
+                run(engine.clone(), pool, config)?;
+                // Connections still open may hold clones of the engine
+                engine.save()
+            }
+            None => run(MemoryKvsEngine::new(), pool, config),
+        },
+    }
+}
+
+fn run<E: KvsEngine, P: ThreadPool>(engine: E, pool: P, config: ServerConfig) -> Result<()> {
+    let mut server = KvsServer::new(engine, pool);
+    if let Some(ms) = config.log.slow_threshold_ms {
+        server = server.slow_threshold(Duration::from_millis(ms));
+    }
+    if let Some(limit) = config.limits.max_key_size {
+        server = server.max_key_size(limit);
+    }
+    if let Some(limit) = config.limits.max_value_size {
+        server = server.max_value_size(limit);
+    }
+    if let Some(acceptor) = config.tls_acceptor()? {
+        server = server.tls(acceptor);
+    }
+    if let Some(connector) = config.tls_connector()? {
+        server = server.peer_tls(connector);
+    }
+    if let Some(raft) = config.raft_config()? {
+        server = server.cluster(raft);
+    }
+    if let Some(primary) = config.replica_of {
+        server = server.replica_of(primary, config.data_dir.join("replication"));
+    }
+    server.run(config.addr)?;
+    wait_for_shutdown()?;
+    server.shutdown();
+    Ok(())
+}
+
+#[cfg(unix)]
+fn shutdown_signals() -> SigSet {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals
+}
+
+/// Blocks until the server is asked to shut down.
+#[cfg(unix)]
+fn wait_for_shutdown() -> Result<()> {
+    let signal = shutdown_signals().wait().map_err(std::io::Error::from)?;
+    info!("shutting down on {}", signal);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn wait_for_shutdown() -> Result<()> {
+    loop {
+        std::thread::park()
     }
 }
 
-fn get_engine(arg: Option<Engine>) -> Result<Engine> {
-    let path = current_dir()?.join("engine");
+fn get_engine(data_dir: &Path, arg: Option<EngineKind>) -> Result<EngineKind> {
+    let path = data_dir.join("engine");
     let cur = if path.exists() {
         let mut f = File::open(path)?;
         let mut s = String::new();
@@ -91,8 +458,10 @@ fn get_engine(arg: Option<Engine>) -> Result<Engine> {
         (Some(ae), None) => Ok(ae),
         (Some(ae), Some(ce)) => {
             if ae != ce {
-                error!("engines not match");
-                exit(1);
+                return Err(KvsError::Config(format!(
+                    "engine `{}` does not match the existing data created by `{}`",
+                    ae, ce
+                )));
             }
             Ok(ae)
         }
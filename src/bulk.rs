@@ -0,0 +1,255 @@
+//! This module reads and writes the key/value files of `kvs-client load` and
+//! `kvs-client dump`.
+//!
+//! Every format holds one key/value pair per record. CSV quotes the fields as
+//! needed, so any key or value round-trips, while TSV has no quoting and cannot
+//! hold keys or values with tabs or newlines.
+
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{BufRead, BufReader, Lines, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Format of a key/value file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PairFormat {
+    /// Comma separated `key,value` records.
+    Csv,
+    /// Tab separated `key<TAB>value` records.
+    Tsv,
+    /// One `{"key": ..., "value": ...}` JSON object per line.
+    JsonLines,
+}
+
+impl PairFormat {
+    /// Names of all formats.
+    pub const VARIANTS: &'static [&'static str] = &["csv", "tsv", "jsonl"];
+
+    /// Guesses the format of a file from its extension.
+    pub fn from_path(path: &Path) -> Option<PairFormat> {
+        match path.extension()?.to_str()? {
+            "csv" => Some(PairFormat::Csv),
+            "tsv" | "tab" => Some(PairFormat::Tsv),
+            "jsonl" | "ndjson" => Some(PairFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for PairFormat {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(PairFormat::Csv),
+            "tsv" => Ok(PairFormat::Tsv),
+            "jsonl" => Ok(PairFormat::JsonLines),
+            _ => Err(KvsError::Config(format!(
+                "unknown PairFormat `{}`, expected one of {:?}",
+                s,
+                PairFormat::VARIANTS
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for PairFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            PairFormat::Csv => "csv",
+            PairFormat::Tsv => "tsv",
+            PairFormat::JsonLines => "jsonl",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Deserialize)]
+struct JsonPair {
+    key: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+struct JsonPairRef<'a> {
+    key: &'a str,
+    value: &'a str,
+}
+
+/// A key/value pair read from a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pair {
+    /// Line the record starts on, from 1.
+    pub line: u64,
+    /// The key.
+    pub key: String,
+    /// The value.
+    pub value: String,
+}
+
+/// Reads the key/value pairs of a file, in order.
+///
+/// A malformed record is returned as `KvsError::Format`, and reading can go on
+/// with the next one.
+pub struct PairReader<R: Read> {
+    records: Records<R>,
+}
+
+enum Records<R: Read> {
+    Csv(csv::StringRecordsIntoIter<R>),
+    JsonLines(Lines<BufReader<R>>, u64),
+}
+
+impl<R: Read> PairReader<R> {
+    /// Reads the pairs from `reader` in the given format.
+    pub fn new(reader: R, format: PairFormat) -> Self {
+        let mut builder = csv::ReaderBuilder::new();
+        builder.has_headers(false).flexible(true);
+        let records = match format {
+            PairFormat::Csv => Records::Csv(builder.from_reader(reader).into_records()),
+            PairFormat::Tsv => Records::Csv(
+                builder
+                    .delimiter(b'\t')
+                    .quoting(false)
+                    .from_reader(reader)
+                    .into_records(),
+            ),
+            PairFormat::JsonLines => Records::JsonLines(BufReader::new(reader).lines(), 0),
+        };
+        PairReader { records }
+    }
+}
+
+impl<R: Read> Iterator for PairReader<R> {
+    type Item = Result<Pair>;
+
+    fn next(&mut self) -> Option<Result<Pair>> {
+        match &mut self.records {
+            Records::Csv(records) => records.next().map(|res| {
+                let record = res.map_err(csv_error)?;
+                let line = record.position().map_or(0, |pos| pos.line());
+                if record.len() != 2 {
+                    return Err(KvsError::Format {
+                        line,
+                        message: format!(
+                            "expected a key and a value, found {} fields",
+                            record.len()
+                        ),
+                    });
+                }
+                Ok(Pair {
+                    line,
+                    key: record[0].to_owned(),
+                    value: record[1].to_owned(),
+                })
+            }),
+            Records::JsonLines(lines, line) => loop {
+                let text = match lines.next()? {
+                    Ok(text) => text,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                *line += 1;
+                if text.trim().is_empty() {
+                    continue;
+                }
+                let line = *line;
+                return Some(
+                    serde_json::from_str::<JsonPair>(&text)
+                        .map(|pair| Pair {
+                            line,
+                            key: pair.key,
+                            value: pair.value,
+                        })
+                        .map_err(|e| KvsError::Format {
+                            line,
+                            message: e.to_string(),
+                        }),
+                );
+            },
+        }
+    }
+}
+
+/// Writes key/value pairs to a file.
+///
+/// The output is buffered until `flush` is called or the writer is dropped.
+pub struct PairWriter<W: Write> {
+    sink: Sink<W>,
+}
+
+enum Sink<W: Write> {
+    Csv(csv::Writer<W>),
+    Tsv(csv::Writer<W>),
+    JsonLines(W),
+}
+
+impl<W: Write> PairWriter<W> {
+    /// Writes the pairs to `writer` in the given format.
+    pub fn new(writer: W, format: PairFormat) -> Self {
+        let sink = match format {
+            PairFormat::Csv => Sink::Csv(csv::Writer::from_writer(writer)),
+            PairFormat::Tsv => Sink::Tsv(
+                csv::WriterBuilder::new()
+                    .delimiter(b'\t')
+                    .quote_style(csv::QuoteStyle::Never)
+                    .from_writer(writer),
+            ),
+            PairFormat::JsonLines => Sink::JsonLines(writer),
+        };
+        PairWriter { sink }
+    }
+
+    /// Writes one pair.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` if the pair cannot be written as TSV.
+    pub fn write(&mut self, key: &str, value: &str) -> Result<()> {
+        match &mut self.sink {
+            Sink::Csv(writer) => writer.write_record(&[key, value]).map_err(csv_error),
+            Sink::Tsv(writer) => {
+                let special = |s: &str| s.contains(&['\t', '\n', '\r'][..]);
+                if special(key) || special(value) {
+                    return Err(KvsError::StringError(format!(
+                        "key `{}` holds a tab or a newline, which TSV cannot represent",
+                        key
+                    )));
+                }
+                writer.write_record(&[key, value]).map_err(csv_error)
+            }
+            Sink::JsonLines(writer) => {
+                serde_json::to_writer(&mut *writer, &JsonPairRef { key, value })?;
+                writer.write_all(b"\n")?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Flushes the pairs written so far to the underlying writer.
+    pub fn flush(&mut self) -> Result<()> {
+        match &mut self.sink {
+            Sink::Csv(writer) | Sink::Tsv(writer) => writer.flush()?,
+            Sink::JsonLines(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+fn csv_error(e: csv::Error) -> KvsError {
+    if e.is_io_error() {
+        return KvsError::Io(e.into());
+    }
+    let message = match e.kind() {
+        csv::ErrorKind::Utf8 { err, .. } => err.to_string(),
+        _ => e.to_string(),
+    };
+    match e.position() {
+        Some(pos) => KvsError::Format {
+            line: pos.line(),
+            message,
+        },
+        None => KvsError::StringError(message),
+    }
+}
@@ -0,0 +1,150 @@
+//! This module provides change data capture by tailing the log of a `KvStore`.
+//!
+//! The tailer only reads the log files, so it can follow a store opened by another
+//! process. It moves on to the next log file once the writer does, and goes on
+//! across a compaction if it had read the whole log when the compaction started.
+//! Otherwise the writes it had not read yet are gone and it fails with
+//! `KvsError::Compacted`.
+
+use crate::engines::kvs::{log_path, read_compaction_marker, read_entries, sorted_terms};
+use crate::replication::{LogEntry, LogPosition};
+use crate::{KvsError, Result};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+/// How often the tailer checks for new writes once it is caught up.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Follows the writes to the `KvStore` in a directory, in log order.
+///
+/// As an iterator, it blocks until the next write.
+pub struct LogTailer {
+    dir: PathBuf,
+    file: File,
+    pos: LogPosition,
+    pending: VecDeque<LogEntry>,
+}
+
+impl LogTailer {
+    /// Opens a tailer of the store in `dir`.
+    ///
+    /// It starts at `from`, or at the end of the log if `from` is `None`. The
+    /// default position starts at the beginning of the oldest log file, which holds
+    /// the values of all the keys as of the last compaction.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Compacted` if `from` has been compacted away.
+    pub fn open(dir: impl Into<PathBuf>, from: Option<LogPosition>) -> Result<LogTailer> {
+        let dir = dir.into();
+        let terms = sorted_terms(&dir)?;
+        let (first, last) = match (terms.first(), terms.last()) {
+            (Some(&first), Some(&last)) => (first, last),
+            _ => {
+                return Err(KvsError::Config(format!(
+                    "no KvStore log in {}",
+                    dir.display()
+                )))
+            }
+        };
+
+        let pos = match from {
+            Some(pos) if pos == LogPosition::default() => LogPosition {
+                term: first,
+                offset: 0,
+            },
+            Some(pos) if terms.contains(&pos.term) => pos,
+            Some(pos) => match read_compaction_marker(&dir)? {
+                Some(marker) if marker.from == pos => marker.to,
+                _ => return Err(KvsError::Compacted(pos)),
+            },
+            None => LogPosition {
+                term: last,
+                offset: 0,
+            },
+        };
+
+        let file = File::open(log_path(&dir, pos.term))?;
+        let mut tailer = LogTailer {
+            dir,
+            file,
+            pos,
+            pending: VecDeque::new(),
+        };
+        if from.is_none() {
+            tailer.pos = read_entries(&tailer.file, pos)?.1;
+        }
+        Ok(tailer)
+    }
+
+    /// Returns the position after the last write returned, to resume from.
+    pub fn position(&self) -> LogPosition {
+        self.pending.front().map_or(self.pos, |entry| entry.pos)
+    }
+
+    /// Returns the writes available now, without blocking.
+    pub fn poll(&mut self) -> Result<Vec<LogEntry>> {
+        let mut entries: Vec<LogEntry> = self.pending.drain(..).collect();
+        loop {
+            let (mut read, next) = read_entries(&self.file, self.pos)?;
+            self.pos = next;
+            if !read.is_empty() {
+                entries.append(&mut read);
+                return Ok(entries);
+            }
+            match self.next_term()? {
+                Some(pos) => {
+                    self.file = File::open(log_path(&self.dir, pos.term))?;
+                    self.pos = pos;
+                }
+                None => return Ok(entries),
+            }
+        }
+    }
+
+    /// Returns where the log goes on once the current file is read to its end, `None`
+    /// if the writer is still appending to it.
+    fn next_term(&self) -> Result<Option<LogPosition>> {
+        // The terms are listed before the marker is read, so a compacted term in the
+        // list always comes with its marker.
+        let terms = sorted_terms(&self.dir)?;
+        if let Some(marker) = read_compaction_marker(&self.dir)? {
+            if marker.from.term == self.pos.term {
+                // The compaction may not have created the new writer term yet
+                if self.pos.offset < marker.from.offset
+                    || !log_path(&self.dir, marker.to.term).exists()
+                {
+                    return Ok(None);
+                }
+                return Ok(Some(marker.to));
+            }
+            if marker.from.term > self.pos.term {
+                return Err(KvsError::Compacted(self.pos));
+            }
+        }
+        Ok(terms
+            .into_iter()
+            .find(|&term| term > self.pos.term)
+            .map(|term| LogPosition { term, offset: 0 }))
+    }
+}
+
+impl Iterator for LogTailer {
+    type Item = Result<LogEntry>;
+
+    fn next(&mut self) -> Option<Result<LogEntry>> {
+        loop {
+            if let Some(entry) = self.pending.pop_front() {
+                return Some(Ok(entry));
+            }
+            match self.poll() {
+                Ok(entries) if entries.is_empty() => thread::sleep(POLL_INTERVAL),
+                Ok(entries) => self.pending.extend(entries),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
@@ -1,57 +1,320 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
+use crate::common::{
+    ClusterStatusResponse, GetResponse, KeysResponse, MembershipResponse, PromoteResponse,
+    Redirect, RemoveResponse, Request, SetResponse, StatsResponse, WatchResponse,
+};
+use crate::raft::ClusterStatus;
+use crate::tls::{Stream, TlsConnector};
+use crate::watch::ChangeEvent;
 use crate::{KvsError, Result};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde_json::de::{Deserializer, IoRead};
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{self, BufReader, BufWriter, Write};
 use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+/// How many times a request follows a cluster node to another leader.
+const MAX_REDIRECTS: usize = 10;
+/// Delay before retrying a cluster which is electing its leader.
+const ELECTION_WAIT: Duration = Duration::from_millis(200);
+/// Requests of a batch sent ahead of their responses.
+const PIPELINE_WINDOW: usize = 64;
+
+/// Timeouts and TLS settings of a `KvsClient`, `None` timeouts wait forever.
+#[derive(Debug, Clone, Default)]
+pub struct KvsClientOptions {
+    /// Timeout of each connection attempt.
+    pub connect_timeout: Option<Duration>,
+    /// Timeout of each read from the server.
+    pub read_timeout: Option<Duration>,
+    /// Timeout of each write to the server.
+    pub write_timeout: Option<Duration>,
+    /// TLS settings, the connection is in the clear if absent.
+    pub tls: Option<TlsConnector>,
+}
 
 /// Key value store client
+///
+/// When connected to a cluster node which is not the leader, requests are
+/// transparently retried on the leader, and the client stays connected to it.
+///
+/// A request failing on I/O may leave a partial response on the connection, so
+/// the client must then be dropped and connected again.
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    reader: Deserializer<IoRead<BufReader<Stream>>>,
+    writer: BufWriter<Stream>,
+    options: KvsClientOptions,
+    broken: bool,
 }
 
 impl KvsClient {
     /// Connect to `addr` to access `KvsServer`.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
+        KvsClient::connect_with(addr, KvsClientOptions::default())
+    }
+
+    /// Connect to `addr` to access `KvsServer` with the given options.
+    pub fn connect_with<A: ToSocketAddrs>(addr: A, options: KvsClientOptions) -> Result<Self> {
+        let tcp = match options.connect_timeout {
+            Some(timeout) => connect_timeout(addr, timeout)?,
+            None => TcpStream::connect(addr)?,
+        };
+        tcp.set_read_timeout(options.read_timeout)?;
+        tcp.set_write_timeout(options.write_timeout)?;
+        let reader = Stream::connect(tcp, options.tls.as_ref())?;
+        let writer = reader.try_clone()?;
         Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-            writer: BufWriter::new(tcp_writer),
+            reader: Deserializer::from_reader(BufReader::new(reader)),
+            writer: BufWriter::new(writer),
+            options,
+            broken: false,
         })
     }
 
+    /// Returns false if a request failed on I/O or the server closed the connection.
+    ///
+    /// It does not block, and an idle connection with unread data from the server is
+    /// not healthy either.
+    pub fn is_healthy(&self) -> bool {
+        if self.broken {
+            return false;
+        }
+        self.writer.get_ref().is_idle()
+    }
+
     /// Get the value of a given key from the server.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
-        match resp {
+        match self.redirected(&Request::Get { key })? {
             GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            GetResponse::Err(e) => Err(e.into()),
+            GetResponse::NotLeader(leader) => Err(KvsError::NotLeader(leader)),
         }
     }
 
     /// Set the value of a string key in the server.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = SetResponse::deserialize(&mut self.reader)?;
-        match resp {
+        match self.redirected(&Request::Set { key, value })? {
             SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            SetResponse::Err(e) => Err(e.into()),
+            SetResponse::NotLeader(leader) => Err(KvsError::NotLeader(leader)),
         }
     }
 
     /// Remove a string key in the server.
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
-        let resp = RemoveResponse::deserialize(&mut self.reader)?;
-        match resp {
+        match self.redirected(&Request::Remove { key })? {
             RemoveResponse::Ok(_) => Ok(()),
-            RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            RemoveResponse::Err(e) => Err(e.into()),
+            RemoveResponse::NotLeader(leader) => Err(KvsError::NotLeader(leader)),
+        }
+    }
+
+    /// List the keys starting with `prefix` in the server, in ascending order.
+    pub fn keys(&mut self, prefix: &str) -> Result<Vec<String>> {
+        let prefix = prefix.to_owned();
+        match self.redirected(&Request::Keys { prefix })? {
+            KeysResponse::Ok(keys) => Ok(keys),
+            KeysResponse::Err(e) => Err(e.into()),
+            KeysResponse::NotLeader(leader) => Err(KvsError::NotLeader(leader)),
+        }
+    }
+
+    /// Get the values of many keys, sending the requests ahead of their responses.
+    ///
+    /// It returns the result of each read, in order. Reads rejected by a cluster
+    /// follower are not redirected.
+    pub fn get_batch(&mut self, keys: Vec<String>) -> Result<Vec<Result<Option<String>>>> {
+        let reqs: Vec<_> = keys.into_iter().map(|key| Request::Get { key }).collect();
+        let resps = self.pipeline(&reqs)?;
+        Ok(resps
+            .into_iter()
+            .map(|resp| match resp {
+                GetResponse::Ok(value) => Ok(value),
+                GetResponse::Err(e) => Err(e.into()),
+                GetResponse::NotLeader(leader) => Err(KvsError::NotLeader(leader)),
+            })
+            .collect())
+    }
+
+    /// Set many keys, sending the requests ahead of their responses.
+    ///
+    /// It returns the result of each write, in order. Writes rejected by a cluster
+    /// follower are not redirected.
+    pub fn set_batch(&mut self, pairs: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        let reqs: Vec<_> = pairs
+            .into_iter()
+            .map(|(key, value)| Request::Set { key, value })
+            .collect();
+        let resps = self.pipeline(&reqs)?;
+        Ok(resps
+            .into_iter()
+            .map(|resp| match resp {
+                SetResponse::Ok(_) => Ok(()),
+                SetResponse::Err(e) => Err(e.into()),
+                SetResponse::NotLeader(leader) => Err(KvsError::NotLeader(leader)),
+            })
+            .collect())
+    }
+
+    /// Watch the writes to the keys starting with `prefix`, turning the connection
+    /// into a stream of changes.
+    ///
+    /// With `from`, the stream starts with the writes following that sequence number,
+    /// which lets a client resume after a reconnect without missing any. Resuming
+    /// fails with `KvsError::SequenceExpired` once the server has restarted.
+    pub fn watch(mut self, prefix: &str, from: Option<u64>) -> Result<WatchStream> {
+        let prefix = prefix.to_owned();
+        match self.request(&Request::Watch { prefix, from })? {
+            WatchResponse::Heartbeat => Ok(WatchStream {
+                reader: self.reader,
+                done: false,
+            }),
+            WatchResponse::Err(e) => Err(e.into()),
+            WatchResponse::Event(_) => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Fetch the server metrics in the Prometheus text format.
+    pub fn stats(&mut self) -> Result<String> {
+        match self.request(&Request::Stats)? {
+            StatsResponse::Ok(text) => Ok(text),
+            StatsResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Promote a replica server to a primary accepting writes.
+    pub fn promote(&mut self) -> Result<()> {
+        match self.request(&Request::Promote)? {
+            PromoteResponse::Ok(_) => Ok(()),
+            PromoteResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Add node `id`, reachable at `addr`, to the cluster.
+    ///
+    /// The node must already be running with no members.
+    pub fn add_node(&mut self, id: u64, addr: String) -> Result<()> {
+        self.change_members(&Request::AddNode { id, addr })
+    }
+
+    /// Remove node `id` from the cluster.
+    pub fn remove_node(&mut self, id: u64) -> Result<()> {
+        self.change_members(&Request::RemoveNode { id })
+    }
+
+    /// Fetch the state of the cluster node the client is connected to.
+    pub fn cluster_status(&mut self) -> Result<ClusterStatus> {
+        match self.request(&Request::ClusterStatus)? {
+            ClusterStatusResponse::Ok(status) => Ok(status),
+            ClusterStatusResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    fn change_members(&mut self, req: &Request) -> Result<()> {
+        match self.redirected(req)? {
+            MembershipResponse::Ok(_) => Ok(()),
+            MembershipResponse::Err(e) => Err(e.into()),
+            MembershipResponse::NotLeader(leader) => Err(KvsError::NotLeader(leader)),
+        }
+    }
+
+    fn request<R: DeserializeOwned>(&mut self, req: &Request) -> Result<R> {
+        let res = (|| {
+            serde_json::to_writer(&mut self.writer, req)?;
+            self.writer.flush()?;
+            Ok(R::deserialize(&mut self.reader)?)
+        })();
+        if res.is_err() {
+            self.broken = true;
+        }
+        res
+    }
+
+    /// Sends the requests while reading their responses, at most `PIPELINE_WINDOW`
+    /// of them being unanswered.
+    ///
+    /// The server answers while the requests are still being sent. Either the
+    /// requests or the responses of a batch are small, so the unanswered ones fit in
+    /// the socket buffers whatever the size of the batch.
+    fn pipeline<R: DeserializeOwned>(&mut self, reqs: &[Request]) -> Result<Vec<R>> {
+        let res = (|| -> Result<Vec<R>> {
+            let mut resps = Vec::with_capacity(reqs.len());
+            for (sent, req) in reqs.iter().enumerate() {
+                if sent - resps.len() == PIPELINE_WINDOW {
+                    self.writer.flush()?;
+                    resps.push(R::deserialize(&mut self.reader)?);
+                }
+                serde_json::to_writer(&mut self.writer, req)?;
+            }
+            self.writer.flush()?;
+            while resps.len() < reqs.len() {
+                resps.push(R::deserialize(&mut self.reader)?);
+            }
+            Ok(resps)
+        })();
+        if res.is_err() {
+            self.broken = true;
+        }
+        res
+    }
+
+    /// Sends the request, following the cluster to its leader.
+    ///
+    /// The last `NotLeader` response is returned if no leader answers in time.
+    fn redirected<R: DeserializeOwned + Redirect>(&mut self, req: &Request) -> Result<R> {
+        let mut resp = self.request::<R>(req)?;
+        for _ in 0..MAX_REDIRECTS {
+            match resp.not_leader() {
+                None => break,
+                Some(Some(leader)) => {
+                    *self = KvsClient::connect_with(leader, self.options.clone())?
+                }
+                Some(None) => thread::sleep(ELECTION_WAIT),
+            }
+            resp = self.request(req)?;
+        }
+        Ok(resp)
+    }
+}
+
+/// Changes streamed by a server to a watching client.
+///
+/// It ends after the first error, the client can then reconnect and resume after
+/// the last change it received.
+pub struct WatchStream {
+    reader: Deserializer<IoRead<BufReader<Stream>>>,
+    done: bool,
+}
+
+impl Iterator for WatchStream {
+    type Item = Result<ChangeEvent>;
+
+    fn next(&mut self) -> Option<Result<ChangeEvent>> {
+        while !self.done {
+            let res = match WatchResponse::deserialize(&mut self.reader) {
+                Ok(WatchResponse::Event(event)) => Ok(event),
+                Ok(WatchResponse::Heartbeat) => continue,
+                Ok(WatchResponse::Err(e)) => Err(e.into()),
+                Err(e) => Err(e.into()),
+            };
+            self.done = res.is_err();
+            return Some(res);
+        }
+        None
+    }
+}
+
+/// Tries every address `addr` resolves to, each for at most `timeout`.
+fn connect_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => last_err = Some(e),
         }
     }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+    }))
 }
@@ -0,0 +1,226 @@
+use crate::client::KvsClientOptions;
+use crate::{KvsClient, KvsError, Result};
+use log::warn;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Options of a `KvsClientPool`.
+#[derive(Debug, Clone)]
+pub struct KvsClientPoolOptions {
+    /// Maximum number of open connections.
+    pub size: usize,
+    /// Timeouts and TLS settings of each connection.
+    pub client: KvsClientOptions,
+    /// How many times connecting, or an idempotent request, is retried after a
+    /// transient failure.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled after each one.
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for KvsClientPoolOptions {
+    fn default() -> Self {
+        KvsClientPoolOptions {
+            size: 8,
+            client: KvsClientOptions {
+                connect_timeout: Some(Duration::from_secs(1)),
+                read_timeout: Some(Duration::from_secs(10)),
+                write_timeout: Some(Duration::from_secs(10)),
+                tls: None,
+            },
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+/// A thread-safe pool of connections to one `KvsServer`.
+///
+/// Idle connections are checked before being handed out, and broken ones are
+/// replaced. Cloning the pool shares its connections.
+#[derive(Clone)]
+pub struct KvsClientPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    addr: String,
+    options: KvsClientPoolOptions,
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+struct State {
+    idle: Vec<KvsClient>,
+    open: usize,
+}
+
+impl KvsClientPool {
+    /// Creates a pool of connections to `addr`, opened on demand.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Config` if the pool size is zero.
+    pub fn new(addr: impl Into<String>, options: KvsClientPoolOptions) -> Result<Self> {
+        if options.size == 0 {
+            return Err(KvsError::Config(
+                "the client pool needs at least one connection".to_owned(),
+            ));
+        }
+        Ok(KvsClientPool {
+            inner: Arc::new(Inner {
+                addr: addr.into(),
+                options,
+                state: Mutex::new(State {
+                    idle: Vec::new(),
+                    open: 0,
+                }),
+                released: Condvar::new(),
+            }),
+        })
+    }
+
+    /// Takes a connection out of the pool, waiting for one if all are in use.
+    ///
+    /// The connection goes back to the pool when dropped, unless it broke.
+    /// Connecting is retried with backoff.
+    pub fn client(&self) -> Result<PooledClient> {
+        self.with_backoff(|| self.checkout())
+    }
+
+    fn checkout(&self) -> Result<PooledClient> {
+        let mut state = self.inner.state.lock().unwrap();
+        loop {
+            if let Some(client) = state.idle.pop() {
+                if client.is_healthy() {
+                    return Ok(self.pooled(client));
+                }
+                state.open -= 1;
+                continue;
+            }
+            if state.open < self.inner.options.size {
+                state.open += 1;
+                drop(state);
+                let options = self.inner.options.client.clone();
+                return match KvsClient::connect_with(&self.inner.addr, options) {
+                    Ok(client) => Ok(self.pooled(client)),
+                    Err(e) => {
+                        self.inner.state.lock().unwrap().open -= 1;
+                        self.inner.released.notify_one();
+                        Err(e)
+                    }
+                };
+            }
+            state = self.inner.released.wait(state).unwrap();
+        }
+    }
+
+    /// Get the value of a given key, retrying on transient failures.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.retry(|client| client.get(key.clone()))
+    }
+
+    /// Set the value of a string key.
+    ///
+    /// It is not retried, as the server may have applied it before failing.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.client()?.set(key, value)
+    }
+
+    /// Remove a string key.
+    ///
+    /// It is not retried, as the server may have applied it before failing.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.client()?.remove(key)
+    }
+
+    /// List the keys starting with `prefix`, retrying on transient failures.
+    pub fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        self.retry(|client| client.keys(prefix))
+    }
+
+    fn pooled(&self, client: KvsClient) -> PooledClient {
+        PooledClient {
+            pool: self.clone(),
+            client: Some(client),
+        }
+    }
+
+    fn retry<T, F>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&mut KvsClient) -> Result<T>,
+    {
+        self.with_backoff(|| f(&mut *self.checkout()?))
+    }
+
+    /// Runs `f` until it succeeds, fails for good, or runs out of retries.
+    fn with_backoff<T, F>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let options = &self.inner.options;
+        let mut backoff = options.initial_backoff;
+        let mut retries = 0;
+        loop {
+            match f() {
+                Err(ref e) if is_transient(e) && retries < options.max_retries => {
+                    warn!(
+                        "request to {} failed, retrying in {:?}: {}",
+                        self.inner.addr, backoff, e
+                    );
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(options.max_backoff);
+                    retries += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Errors after which the request may succeed on a new connection.
+fn is_transient(e: &KvsError) -> bool {
+    match e {
+        KvsError::Io(_) | KvsError::Busy => true,
+        KvsError::SerdeJson(e) => e.is_io() || e.is_eof(),
+        _ => false,
+    }
+}
+
+/// A connection taken out of a `KvsClientPool`.
+pub struct PooledClient {
+    pool: KvsClientPool,
+    client: Option<KvsClient>,
+}
+
+impl Deref for PooledClient {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        let client = self.client.take().unwrap();
+        let mut state = self.pool.inner.state.lock().unwrap();
+        if client.is_healthy() {
+            state.idle.push(client);
+        } else {
+            state.open -= 1;
+        }
+        self.pool.inner.released.notify_one();
+    }
+}
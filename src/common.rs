@@ -1,26 +1,204 @@
+use crate::raft::{ClusterStatus, Envelope};
+use crate::replication::LogPosition;
+use crate::watch::ChangeEvent;
+use crate::{ErrorCode, KvsError};
 use serde::{Deserialize, Serialize};
+use std::io;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
     Get { key: String },
     Set { key: String, value: String },
     Remove { key: String },
+    Keys { prefix: String },
+    Watch { prefix: String, from: Option<u64> },
+    Stats,
+    Replicate { from: LogPosition },
+    Promote,
+    AddNode { id: u64, addr: String },
+    RemoveNode { id: u64 },
+    ClusterStatus,
+    Raft(Envelope),
+}
+
+/// An error sent to the client, with the message of the server side error.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WireError {
+    code: ErrorCode,
+    message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    detail: Option<ErrorDetail>,
+}
+
+/// What an error needs besides its code to be rebuilt by the client.
+#[derive(Debug, Serialize, Deserialize)]
+enum ErrorDetail {
+    TooLarge {
+        what: String,
+        size: usize,
+        limit: usize,
+    },
+    SequenceExpired(u64),
+    Unsupported(String),
+    StringError(String),
+}
+
+impl From<&KvsError> for WireError {
+    fn from(e: &KvsError) -> Self {
+        let message = match std::error::Error::source(e) {
+            Some(source) => format!("{}: {}", e, source),
+            None => format!("{}", e),
+        };
+        let detail = match e {
+            KvsError::TooLarge(what, size, limit) => Some(ErrorDetail::TooLarge {
+                what: (*what).to_owned(),
+                size: *size,
+                limit: *limit,
+            }),
+            KvsError::SequenceExpired(seq) => Some(ErrorDetail::SequenceExpired(*seq)),
+            KvsError::Unsupported(op) => Some(ErrorDetail::Unsupported(op.to_string())),
+            KvsError::StringError(msg) => Some(ErrorDetail::StringError(msg.clone())),
+            _ => None,
+        };
+        WireError {
+            code: e.code(),
+            message,
+            detail,
+        }
+    }
+}
+
+impl From<WireError> for KvsError {
+    fn from(e: WireError) -> Self {
+        match (e.code, e.detail) {
+            (ErrorCode::KeyNotFound, _) => KvsError::KeyNotFound,
+            (ErrorCode::ReadOnly, _) => KvsError::ReadOnly,
+            (ErrorCode::NotLeader, _) => KvsError::NotLeader(None),
+            (ErrorCode::NotClustered, _) => KvsError::NotClustered,
+            (ErrorCode::Busy, _) => KvsError::Busy,
+            (ErrorCode::Internal, Some(ErrorDetail::StringError(msg))) => {
+                KvsError::StringError(msg)
+            }
+            (ErrorCode::Internal, _) => KvsError::Internal,
+            (ErrorCode::Io, _) => KvsError::Io(io::Error::other(e.message)),
+            (ErrorCode::TooLarge, Some(ErrorDetail::TooLarge { what, size, limit })) => {
+                // Only the keys and values are checked against a limit
+                let what = if what == "key" { "key" } else { "value" };
+                KvsError::TooLarge(what, size, limit)
+            }
+            (ErrorCode::SequenceExpired, Some(ErrorDetail::SequenceExpired(seq))) => {
+                KvsError::SequenceExpired(seq)
+            }
+            (ErrorCode::Unsupported, Some(ErrorDetail::Unsupported(op))) => {
+                KvsError::Unsupported(op.into())
+            }
+            // Sent by a server which does not send the details
+            (code, _) => KvsError::Remote {
+                code,
+                message: e.message,
+            },
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
     Ok(Option<String>),
-    Err(String),
+    Err(WireError),
+    NotLeader(Option<String>),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse {
     Ok(()),
-    Err(String),
+    Err(WireError),
+    NotLeader(Option<String>),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
-    Err(String),
+    Err(WireError),
+    NotLeader(Option<String>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum KeysResponse {
+    Ok(Vec<String>),
+    Err(WireError),
+    NotLeader(Option<String>),
+}
+
+/// Messages streamed to a watching client, the first one is a heartbeat once the
+/// watch is set up.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WatchResponse {
+    Event(ChangeEvent),
+    Heartbeat,
+    Err(WireError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatsResponse {
+    Ok(String),
+    Err(WireError),
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PromoteResponse {
+    Ok(()),
+    Err(WireError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MembershipResponse {
+    Ok(()),
+    Err(WireError),
+    NotLeader(Option<String>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClusterStatusResponse {
+    Ok(ClusterStatus),
+    Err(WireError),
+}
+
+/// Responses a cluster node answers with `NotLeader` when it cannot serve the request.
+pub trait Redirect: Sized {
+    /// Builds the error response, redirecting to the leader if `e` is `NotLeader`.
+    fn error(e: &KvsError) -> Self;
+
+    /// Returns `Some` with the leader address, if known, when the client must retry
+    /// elsewhere.
+    fn not_leader(&self) -> Option<Option<&str>>;
+}
+
+macro_rules! impl_redirect {
+    ($($name:ident),*) => {
+        $(
+            impl Redirect for $name {
+                fn error(e: &KvsError) -> Self {
+                    match e {
+                        KvsError::NotLeader(leader) => $name::NotLeader(leader.clone()),
+                        e => $name::Err(e.into()),
+                    }
+                }
+
+                fn not_leader(&self) -> Option<Option<&str>> {
+                    match self {
+                        $name::NotLeader(leader) => Some(leader.as_deref()),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_redirect!(
+    GetResponse,
+    SetResponse,
+    RemoveResponse,
+    KeysResponse,
+    MembershipResponse
+);
@@ -0,0 +1,724 @@
+//! This module provides the configuration of `kvs-server`.
+//!
+//! The configuration is read from a TOML file. Every field has a default, so an
+//! empty file, or no file at all, is a valid configuration:
+//!
+//! ```toml
+//! addr = "127.0.0.1:4000"
+//! engine = "kvs"
+//! data_dir = "/var/lib/kvs"
+//! metrics_addr = "127.0.0.1:9100"
+//! replica_of = "10.0.0.1:4000"
+//! durability = "flush"
+//! index = "skiplist"
+//! cache_capacity = 67108864
+//! read_mode = "mmap"
+//! bloom_fp_rate = 0.01
+//! compression = "lz4"
+//! compression_threshold = 256
+//! recompression = "zstd"
+//! snapshot = "/var/lib/kvs/memory.json"
+//!
+//! [pool]
+//! kind = "shared"
+//! threads = 8
+//! queue_size = 1024
+//! queue_policy = "reject"
+//! min_threads = 2
+//! idle_timeout_ms = 60000
+//!
+//! [compaction]
+//! policy = "threshold"
+//! threshold = 1048576
+//!
+//! [limits]
+//! max_key_size = 1024
+//! max_value_size = 1048576
+//!
+//! [log]
+//! level = "info"
+//! format = "json"
+//! slow_threshold_ms = 100
+//!
+//! [tls]
+//! cert = "/etc/kvs/server.pem"
+//! key = "/etc/kvs/server.key"
+//! ca = "/etc/kvs/ca.pem"
+//!
+//! [cluster]
+//! id = 1
+//! members = ["1@10.0.0.1:4000", "2@10.0.0.2:4000", "3@10.0.0.3:4000"]
+//! election_timeout_ms = 300
+//! heartbeat_interval_ms = 50
+//! snapshot_threshold = 10000
+//! ```
+
+use crate::raft::RaftConfig;
+use crate::thread_pool::QueuePolicy;
+use crate::tls::{TlsAcceptor, TlsConnector};
+use crate::{
+    Compression, Durability, IndexKind, KvStoreOptions, KvsError, LsmOptions, ReadMode, Result,
+};
+use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
+
+/// Configuration of `kvs-server`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address the server listens on.
+    pub addr: String,
+    /// Storage engine. If absent, the engine the data directory was created with is
+    /// used, or `kvs` for a new data directory.
+    pub engine: Option<EngineKind>,
+    /// Directory holding the data files.
+    pub data_dir: PathBuf,
+    /// Address to serve Prometheus metrics on over HTTP, disabled if absent.
+    pub metrics_addr: Option<String>,
+    /// Address of the primary to replicate, the server is a primary if absent.
+    pub replica_of: Option<String>,
+    /// Durability of writes, only used by the `kvs` and `lsm` engines.
+    pub durability: Durability,
+    /// In-memory index of the keys, only used by the `kvs` engine.
+    pub index: IndexKind,
+    /// Bytes of values cached in memory, no cache if absent. Only used by the `kvs`
+    /// engine.
+    pub cache_capacity: Option<usize>,
+    /// How values are read from the log files, only used by the `kvs` engine.
+    pub read_mode: ReadMode,
+    /// False positive rate of the Bloom filters of the keys. If absent, the `kvs`
+    /// engine has no filters and the `lsm` engine uses its default rate.
+    pub bloom_fp_rate: Option<f64>,
+    /// Compression of the values, none if absent. Only used by the `kvs` engine.
+    pub compression: Option<Compression>,
+    /// Bytes from which values are compressed, only used by the `kvs` engine.
+    pub compression_threshold: usize,
+    /// Compression the compaction rewrites the values with, the values are kept as
+    /// they were written if absent. Only used by the `kvs` engine.
+    pub recompression: Option<Compression>,
+    /// File the pairs are loaded from on start and saved to on shutdown, nothing
+    /// is kept across restarts if absent. Only used by the `memory` engine.
+    pub snapshot: Option<PathBuf>,
+    /// Thread pool serving the connections.
+    pub pool: PoolConfig,
+    /// Log compaction, only used by the `kvs` engine.
+    pub compaction: CompactionConfig,
+    /// Request size limits.
+    pub limits: LimitsConfig,
+    /// Logging.
+    pub log: LogConfig,
+    /// TLS.
+    pub tls: TlsConfig,
+    /// Raft cluster mode.
+    pub cluster: ClusterConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addr: DEFAULT_ADDRESS.to_owned(),
+            engine: None,
+            data_dir: PathBuf::from("."),
+            metrics_addr: None,
+            replica_of: None,
+            durability: Durability::Flush,
+            index: IndexKind::SkipList,
+            cache_capacity: None,
+            read_mode: ReadMode::Buffered,
+            bloom_fp_rate: None,
+            compression: None,
+            compression_threshold: KvStoreOptions::default().compression_threshold,
+            recompression: None,
+            snapshot: None,
+            pool: PoolConfig::default(),
+            compaction: CompactionConfig::default(),
+            limits: LimitsConfig::default(),
+            log: LogConfig::default(),
+            tls: TlsConfig::default(),
+            cluster: ClusterConfig::default(),
+        }
+    }
+}
+
+/// Thread pool settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    /// Thread pool implementation.
+    pub kind: PoolKind,
+    /// Number of threads, the number of CPUs if absent.
+    pub threads: Option<u32>,
+    /// Connections waiting for a thread, unbounded if absent. Only used by the
+    /// `shared` pool.
+    pub queue_size: Option<usize>,
+    /// What happens to connections accepted while the queue is full, any policy but
+    /// `caller-runs`. Only used by the `shared` pool.
+    pub queue_policy: QueuePolicy,
+    /// Threads kept when idle, the `threads` setting being the maximum. Only used
+    /// by the `elastic` pool.
+    pub min_threads: Option<u32>,
+    /// How long a thread above the minimum waits for a connection before it exits.
+    /// Only used by the `elastic` pool.
+    pub idle_timeout_ms: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            kind: PoolKind::Rayon,
+            threads: None,
+            queue_size: None,
+            queue_policy: QueuePolicy::Block,
+            min_threads: None,
+            idle_timeout_ms: 60_000,
+        }
+    }
+}
+
+/// Log compaction settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompactionConfig {
+    /// When to compact.
+    pub policy: CompactionPolicy,
+    /// Stale bytes that trigger a compaction under the `threshold` policy.
+    pub threshold: u64,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        CompactionConfig {
+            policy: CompactionPolicy::Threshold,
+            threshold: KvStoreOptions::default()
+                .compaction_threshold
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Request size limits, unlimited if absent.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Maximum key size in bytes.
+    pub max_key_size: Option<usize>,
+    /// Maximum value size in bytes.
+    pub max_value_size: Option<usize>,
+}
+
+/// Logging settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Level filter, one of `off`, `error`, `warn`, `info`, `debug` and `trace`.
+    pub level: String,
+    /// Output format.
+    pub format: LogFormat,
+    /// Requests slower than this many milliseconds are logged with values redacted.
+    pub slow_threshold_ms: Option<u64>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_owned(),
+            format: LogFormat::Text,
+            slow_threshold_ms: None,
+        }
+    }
+}
+
+/// TLS settings, the server accepts connections in the clear if `cert` is absent.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file of the certificate chain of the server.
+    pub cert: Option<PathBuf>,
+    /// PEM file of the private key of the certificate.
+    pub key: Option<PathBuf>,
+    /// PEM file of the certificates trusted to verify the primary of a replica and
+    /// the other members of a cluster, reached in the clear if absent.
+    pub ca: Option<PathBuf>,
+}
+
+/// Raft cluster settings, the server runs alone if `id` is absent.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Id of this node, unique in the cluster.
+    pub id: Option<u64>,
+    /// Initial members as `id@addr`, including this node. Empty to start a node
+    /// that joins an existing cluster.
+    pub members: Vec<String>,
+    /// Minimum time without a leader before a node starts an election.
+    pub election_timeout_ms: u64,
+    /// How often the leader contacts idle followers.
+    pub heartbeat_interval_ms: u64,
+    /// Number of log entries that triggers a log truncation.
+    pub snapshot_threshold: usize,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        let defaults = RaftConfig::new(0, PathBuf::new());
+        ClusterConfig {
+            id: None,
+            members: Vec::new(),
+            election_timeout_ms: defaults.election_timeout.as_millis() as u64,
+            heartbeat_interval_ms: defaults.heartbeat_interval.as_millis() as u64,
+            snapshot_threshold: defaults.snapshot_threshold,
+        }
+    }
+}
+
+macro_rules! name_enum {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident { $($(#[$vmeta:meta])* $variant:ident => $s:expr,)* }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+        #[serde(rename_all = "lowercase")]
+        pub enum $name {
+            $($(#[$vmeta])* $variant,)*
+        }
+
+        impl $name {
+            /// Names of all variants.
+            pub const VARIANTS: &'static [&'static str] = &[$($s),*];
+        }
+
+        impl FromStr for $name {
+            type Err = KvsError;
+
+            fn from_str(s: &str) -> Result<Self> {
+                match s {
+                    $($s => Ok($name::$variant),)*
+                    _ => Err(KvsError::Config(format!(
+                        "unknown {} `{}`, expected one of {:?}",
+                        stringify!($name),
+                        s,
+                        $name::VARIANTS
+                    ))),
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let s = match self {
+                    $($name::$variant => $s,)*
+                };
+                f.write_str(s)
+            }
+        }
+    };
+}
+
+name_enum! {
+    /// Storage engine of the server.
+    pub enum EngineKind {
+        /// `KvStore`
+        Kvs => "kvs",
+        /// `SledKvsEngine`
+        Sled => "sled",
+        /// `LsmKvsEngine`
+        Lsm => "lsm",
+        /// `MemoryKvsEngine`, keeping nothing across restarts without a snapshot
+        Memory => "memory",
+    }
+}
+
+name_enum! {
+    /// Thread pool implementation of the server.
+    pub enum PoolKind {
+        /// `NaiveThreadPool`
+        Naive => "naive",
+        /// `SharedQueueThreadPool`
+        Shared => "shared",
+        /// `RayonThreadPool`
+        Rayon => "rayon",
+        /// `WorkStealingThreadPool`
+        Stealing => "stealing",
+        /// `ElasticThreadPool`
+        Elastic => "elastic",
+    }
+}
+
+name_enum! {
+    /// Compaction policy of the `kvs` engine.
+    pub enum CompactionPolicy {
+        /// Compact once the stale bytes exceed the threshold.
+        Threshold => "threshold",
+        /// Never compact.
+        Disabled => "disabled",
+    }
+}
+
+name_enum! {
+    /// Log output format.
+    pub enum LogFormat {
+        /// Human readable lines.
+        Text => "text",
+        /// One JSON object per line.
+        Json => "json",
+    }
+}
+
+impl FromStr for Durability {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "flush" => Ok(Durability::Flush),
+            "sync" => Ok(Durability::Sync),
+            _ => Err(KvsError::Config(format!(
+                "unknown Durability `{}`, expected one of [\"flush\", \"sync\"]",
+                s
+            ))),
+        }
+    }
+}
+
+impl FromStr for IndexKind {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skiplist" => Ok(IndexKind::SkipList),
+            "compact" => Ok(IndexKind::Compact),
+            _ => Err(KvsError::Config(format!(
+                "unknown IndexKind `{}`, expected one of {:?}",
+                s,
+                IndexKind::VARIANTS
+            ))),
+        }
+    }
+}
+
+impl FromStr for ReadMode {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "buffered" => Ok(ReadMode::Buffered),
+            "mmap" => Ok(ReadMode::Mmap),
+            _ => Err(KvsError::Config(format!(
+                "unknown ReadMode `{}`, expected one of {:?}",
+                s,
+                ReadMode::VARIANTS
+            ))),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(KvsError::Config(format!(
+                "unknown Compression `{}`, expected one of {:?}",
+                s,
+                Compression::VARIANTS
+            ))),
+        }
+    }
+}
+
+impl FromStr for QueuePolicy {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "block" => Ok(QueuePolicy::Block),
+            "reject" => Ok(QueuePolicy::Reject),
+            "caller-runs" => Ok(QueuePolicy::CallerRuns),
+            "drop-oldest" => Ok(QueuePolicy::DropOldest),
+            _ => Err(KvsError::Config(format!(
+                "unknown QueuePolicy `{}`, expected one of {:?}",
+                s,
+                QueuePolicy::VARIANTS
+            ))),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads the configuration from a TOML file.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Config` if the file cannot be read or parsed.
+    pub fn load(path: &Path) -> Result<ServerConfig> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| KvsError::Config(format!("cannot read {:?}: {}", path, e)))?;
+        toml::from_str(&text).map_err(|e| KvsError::Config(format!("{:?}: {}", path, e)))
+    }
+
+    /// Checks that the configuration can be used to start a server.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Config` describing the first invalid field.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |msg: String| Err(KvsError::Config(msg));
+
+        if self.addr.parse::<SocketAddr>().is_err() {
+            return invalid(format!("addr `{}` is not a socket address", self.addr));
+        }
+        if let Some(addr) = &self.metrics_addr {
+            if addr.parse::<SocketAddr>().is_err() {
+                return invalid(format!("metrics_addr `{}` is not a socket address", addr));
+            }
+            if *addr == self.addr {
+                return invalid("metrics_addr must differ from addr".to_owned());
+            }
+        }
+        if let Some(primary) = &self.replica_of {
+            if primary.parse::<SocketAddr>().is_err() {
+                return invalid(format!("replica_of `{}` is not a socket address", primary));
+            }
+        }
+        if self.data_dir.is_file() {
+            return invalid(format!("data_dir {:?} is a file", self.data_dir));
+        }
+        if self.pool.threads == Some(0) {
+            return invalid("pool.threads must be positive".to_owned());
+        }
+        if self.pool.queue_size.is_some() && self.pool.kind != PoolKind::Shared {
+            return invalid("pool.queue_size is only supported by the shared pool".to_owned());
+        }
+        if self.pool.queue_policy != QueuePolicy::Block && self.pool.kind != PoolKind::Shared {
+            return invalid("pool.queue_policy is only supported by the shared pool".to_owned());
+        }
+        if self.pool.queue_policy == QueuePolicy::CallerRuns {
+            // A connection would be served on the accepting thread until it closes,
+            // holding up every other connection and the shutdown
+            return invalid(
+                "pool.queue_policy caller-runs is not supported by the server".to_owned(),
+            );
+        }
+        if self.pool.queue_size == Some(0) && self.pool.queue_policy == QueuePolicy::DropOldest {
+            return invalid("pool.queue_size must be positive with drop-oldest".to_owned());
+        }
+        if let Some(min_threads) = self.pool.min_threads {
+            if self.pool.kind != PoolKind::Elastic {
+                return invalid(
+                    "pool.min_threads is only supported by the elastic pool".to_owned(),
+                );
+            }
+            if min_threads > self.threads() {
+                return invalid("pool.min_threads must not exceed pool.threads".to_owned());
+            }
+        }
+        if self.pool.idle_timeout_ms == 0 {
+            return invalid("pool.idle_timeout_ms must be positive".to_owned());
+        }
+        if let Some(engine) = self.engine.filter(|engine| *engine != EngineKind::Kvs) {
+            let kvs_only = [
+                ("index", self.index != IndexKind::SkipList),
+                ("cache_capacity", self.cache_capacity.is_some()),
+                ("read_mode", self.read_mode != ReadMode::Buffered),
+                ("compression", self.compression.is_some()),
+                ("recompression", self.recompression.is_some()),
+            ];
+            if let Some((name, _)) = kvs_only.iter().find(|(_, set)| *set) {
+                return invalid(format!(
+                    "{} is not supported by the {} engine",
+                    name, engine
+                ));
+            }
+        }
+        if self.cache_capacity == Some(0) {
+            return invalid("cache_capacity must be positive".to_owned());
+        }
+        if matches!(self.bloom_fp_rate, Some(rate) if !(rate > 0.0 && rate < 1.0)) {
+            return invalid("bloom_fp_rate must be between 0 and 1".to_owned());
+        }
+        if self.compaction.policy == CompactionPolicy::Threshold && self.compaction.threshold == 0 {
+            return invalid("compaction.threshold must be positive".to_owned());
+        }
+        if self.limits.max_key_size == Some(0) {
+            return invalid("limits.max_key_size must be positive".to_owned());
+        }
+        if self.limits.max_value_size == Some(0) {
+            return invalid("limits.max_value_size must be positive".to_owned());
+        }
+        self.log_level()?;
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            return invalid("tls.cert and tls.key must be set together".to_owned());
+        }
+        // The members reach each other on the port serving the clients
+        if self.cluster.id.is_some() && self.tls.cert.is_some() != self.tls.ca.is_some() {
+            return invalid("a cluster node needs both tls.cert and tls.ca, or neither".to_owned());
+        }
+        self.tls_acceptor()?;
+        self.tls_connector()?;
+        if self.cluster.id.is_some() && self.replica_of.is_some() {
+            return invalid("a cluster node cannot be a replica".to_owned());
+        }
+        // The Raft log and the replication position would outlive the pairs they
+        // were applied to
+        let replicated = self.cluster.id.is_some() || self.replica_of.is_some();
+        if self.engine == Some(EngineKind::Memory) && replicated && self.snapshot.is_none() {
+            return invalid(
+                "the memory engine needs a snapshot in a cluster or as a replica".to_owned(),
+            );
+        }
+        if self.cluster.heartbeat_interval_ms == 0 {
+            return invalid("cluster.heartbeat_interval_ms must be positive".to_owned());
+        }
+        if self.cluster.election_timeout_ms <= self.cluster.heartbeat_interval_ms {
+            return invalid(
+                "cluster.election_timeout_ms must exceed cluster.heartbeat_interval_ms".to_owned(),
+            );
+        }
+        if self.cluster.snapshot_threshold == 0 {
+            return invalid("cluster.snapshot_threshold must be positive".to_owned());
+        }
+        self.raft_config()?;
+        Ok(())
+    }
+
+    /// Returns the number of threads of the thread pool.
+    pub fn threads(&self) -> u32 {
+        self.pool.threads.unwrap_or_else(|| num_cpus::get() as u32)
+    }
+
+    /// Returns the log level filter.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Config` if the level is unknown.
+    pub fn log_level(&self) -> Result<LevelFilter> {
+        self.log
+            .level
+            .parse()
+            .map_err(|_| KvsError::Config(format!("unknown log.level `{}`", self.log.level)))
+    }
+
+    /// Returns the TLS settings of the connections the server accepts, `None` if
+    /// they are in the clear.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Config` if the certificate or the key is invalid.
+    pub fn tls_acceptor(&self) -> Result<Option<TlsAcceptor>> {
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(cert), Some(key)) => Ok(Some(TlsAcceptor::new(cert, key)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the TLS settings of the connections to the primary and the other
+    /// cluster members, `None` if they are in the clear.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Config` if the CA file is invalid.
+    pub fn tls_connector(&self) -> Result<Option<TlsConnector>> {
+        self.tls.ca.as_deref().map(TlsConnector::new).transpose()
+    }
+
+    /// Returns the Raft configuration of the node, `None` outside cluster mode.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Config` if a member is invalid, or if the members do
+    /// not include this node.
+    pub fn raft_config(&self) -> Result<Option<RaftConfig>> {
+        let id = match self.cluster.id {
+            Some(id) => id,
+            None if self.cluster.members.is_empty() => return Ok(None),
+            None => {
+                return Err(KvsError::Config(
+                    "cluster.members requires cluster.id".to_owned(),
+                ))
+            }
+        };
+
+        let mut members = BTreeMap::new();
+        for member in &self.cluster.members {
+            let (member_id, addr) = parse_member(member)?;
+            if members.insert(member_id, addr).is_some() {
+                return Err(KvsError::Config(format!(
+                    "cluster member {} is listed twice",
+                    member_id
+                )));
+            }
+        }
+        if !members.is_empty() && !members.contains_key(&id) {
+            return Err(KvsError::Config(format!(
+                "cluster.members does not include cluster.id {}",
+                id
+            )));
+        }
+
+        let mut config = RaftConfig::new(id, self.data_dir.join("raft"));
+        config.members = members;
+        config.election_timeout = Duration::from_millis(self.cluster.election_timeout_ms);
+        config.heartbeat_interval = Duration::from_millis(self.cluster.heartbeat_interval_ms);
+        config.snapshot_threshold = self.cluster.snapshot_threshold;
+        Ok(Some(config))
+    }
+
+    /// Returns the options to open a `KvStore` with.
+    pub fn store_options(&self) -> KvStoreOptions {
+        KvStoreOptions {
+            compaction_threshold: match self.compaction.policy {
+                CompactionPolicy::Threshold => Some(self.compaction.threshold),
+                CompactionPolicy::Disabled => None,
+            },
+            durability: self.durability,
+            index: self.index,
+            cache_capacity: self.cache_capacity,
+            read_mode: self.read_mode,
+            bloom_fp_rate: self.bloom_fp_rate,
+            compression: self.compression,
+            compression_threshold: self.compression_threshold,
+            recompression: self.recompression,
+        }
+    }
+
+    /// Returns the options of the `lsm` engine.
+    pub fn lsm_options(&self) -> LsmOptions {
+        let defaults = LsmOptions::default();
+        LsmOptions {
+            durability: self.durability,
+            bloom_fp_rate: self.bloom_fp_rate.unwrap_or(defaults.bloom_fp_rate),
+            ..defaults
+        }
+    }
+}
+
+/// Parses a cluster member written as `id@addr`.
+fn parse_member(member: &str) -> Result<(u64, String)> {
+    let invalid = || {
+        KvsError::Config(format!(
+            "cluster member `{}` is not of the form id@addr",
+            member
+        ))
+    };
+    let mut parts = member.splitn(2, '@');
+    let id = parts
+        .next()
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(invalid)?;
+    let addr = parts.next().ok_or_else(invalid)?;
+    if addr.parse::<SocketAddr>().is_err() {
+        return Err(invalid());
+    }
+    Ok((id, addr.to_owned()))
+}
@@ -0,0 +1,119 @@
+use crate::{KvsError, Result};
+use std::f64::consts::LN_2;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+const MAX_HASHES: u32 = 16;
+
+/// A Bloom filter sized for a number of keys and a false positive rate.
+///
+/// Keys are added through a shared reference, so it can be read while written.
+/// Adding more keys than it is sized for only raises the false positive rate.
+pub(crate) struct BloomFilter {
+    bits: Vec<AtomicU64>,
+    hashes: u32,
+    capacity: usize,
+    len: AtomicUsize,
+}
+
+impl BloomFilter {
+    /// Creates a filter for `capacity` keys, of which about `fp_rate` of the absent
+    /// ones are reported as present.
+    pub(crate) fn new(capacity: usize, fp_rate: f64) -> Self {
+        let capacity = capacity.max(1);
+        let bits = (-(capacity as f64) * fp_rate.ln() / (LN_2 * LN_2)).ceil() as usize;
+        let words = bits / 64 + 1;
+        let hashes = ((words * 64) as f64 / capacity as f64 * LN_2).round() as u32;
+        BloomFilter {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            hashes: hashes.clamp(1, MAX_HASHES),
+            capacity,
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn insert(&self, key: &[u8]) {
+        for bit in self.bit_indexes(key) {
+            self.bits[bit / 64].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+        self.len.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns false if the key was surely never inserted.
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_indexes(key)
+            .all(|bit| self.bits[bit / 64].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+
+    /// Checks if the filter holds as many keys as it is sized for.
+    pub(crate) fn is_full(&self) -> bool {
+        self.len.load(Ordering::Relaxed) >= self.capacity
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Double hashing of a hash stable across runs, as filters are persisted.
+    fn bit_indexes(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let h1 = fnv1a(key);
+        let h2 = mix(h1) | 1;
+        let bits = (self.bits.len() * 64) as u64;
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+
+    /// Writes the filter as its number of hashes, capacity and number of words, then
+    /// the words, all little endian.
+    pub(crate) fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        writer.write_all(&self.hashes.to_le_bytes())?;
+        writer.write_all(&(self.capacity as u64).to_le_bytes())?;
+        writer.write_all(&(self.bits.len() as u64).to_le_bytes())?;
+        for word in &self.bits {
+            writer.write_all(&word.load(Ordering::Relaxed).to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Reads a filter written by `write_to`, counted as full.
+    pub(crate) fn read_from<R: Read>(mut reader: R) -> Result<Self> {
+        let mut hashes = [0; 4];
+        reader.read_exact(&mut hashes)?;
+        let hashes = u32::from_le_bytes(hashes);
+        let capacity = read_u64(&mut reader)? as usize;
+        let words = read_u64(&mut reader)? as usize;
+        if hashes == 0 || hashes > MAX_HASHES || words == 0 {
+            return Err(KvsError::StringError("corrupted Bloom filter".to_owned()));
+        }
+        let bits = (0..words)
+            .map(|_| read_u64(&mut reader).map(AtomicU64::new))
+            .collect::<Result<_>>()?;
+        Ok(BloomFilter {
+            bits,
+            hashes,
+            capacity,
+            len: AtomicUsize::new(capacity),
+        })
+    }
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn fnv1a(key: &[u8]) -> u64 {
+    key.iter().fold(FNV_OFFSET, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// The finalizer of SplitMix64.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
@@ -0,0 +1,216 @@
+use super::Pos;
+use crate::metrics;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Independently locked parts of the cache, picked by the hash of the key.
+const SHARDS: usize = 16;
+/// Estimated bytes an entry takes besides its key and value.
+const ENTRY_OVERHEAD: usize = mem::size_of::<Slot>() + 2 * mem::size_of::<usize>();
+
+/// Hit and miss counts and size of the value cache of a `KvStore`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads answered by the cache.
+    pub hits: u64,
+    /// Reads of a key the cache did not hold.
+    pub misses: u64,
+    /// Reads of a value too large to be cached, which are not counted as misses.
+    pub bypassed: u64,
+    /// Values in the cache.
+    pub entries: usize,
+    /// Estimated bytes taken by the cached keys and values.
+    pub bytes: usize,
+}
+
+/// A cache of the values last read, evicting with the CLOCK algorithm.
+///
+/// A value is cached together with its position in the log, and only returned for
+/// that position, so a read racing a write never caches a stale value for good.
+/// Writes still remove the value of their key, to free its room early.
+///
+/// Each shard holds an even share of the capacity, so a value taking more than
+/// a sixteenth of it is never cached.
+pub(super) struct ValueCache {
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    bypassed: AtomicU64,
+}
+
+struct Shard {
+    slots: Vec<Slot>,
+    // Index of every key in `slots`
+    keys: HashMap<String, usize>,
+    hand: usize,
+    bytes: usize,
+    capacity: usize,
+}
+
+struct Slot {
+    key: String,
+    pos: Pos,
+    value: String,
+    referenced: bool,
+}
+
+impl Slot {
+    fn charge(&self) -> usize {
+        charge(&self.key, &self.value)
+    }
+}
+
+fn charge(key: &str, value: &str) -> usize {
+    // The key is stored twice, in the slot and in the map
+    2 * key.len() + value.len() + ENTRY_OVERHEAD
+}
+
+impl ValueCache {
+    /// Creates a cache holding at most about `capacity` bytes.
+    pub(super) fn new(capacity: usize) -> Self {
+        ValueCache {
+            shards: (0..SHARDS)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        slots: Vec::new(),
+                        keys: HashMap::new(),
+                        hand: 0,
+                        bytes: 0,
+                        capacity: capacity / SHARDS,
+                    })
+                })
+                .collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            bypassed: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    /// Returns the value of `key` if it is cached for `pos`.
+    ///
+    /// The reads it does not answer are counted by `insert`.
+    pub(super) fn get(&self, key: &str, pos: Pos) -> Option<String> {
+        let value = self.shard(key).lock().unwrap().get(key, pos);
+        if value.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            metrics::global().cache_hits.inc();
+        }
+        value
+    }
+
+    /// Caches the value read after `get` did not return it, counting the read as
+    /// a miss, or as bypassed if the value is too large to be cached.
+    pub(super) fn insert(&self, key: String, pos: Pos, value: String) {
+        if self.shard(&key).lock().unwrap().insert(key, pos, value) {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            metrics::global().cache_misses.inc();
+        } else {
+            self.bypassed.fetch_add(1, Ordering::Relaxed);
+            metrics::global().cache_bypassed.inc();
+        }
+    }
+
+    pub(super) fn remove(&self, key: &str) {
+        self.shard(key).lock().unwrap().remove(key);
+    }
+
+    pub(super) fn clear(&self) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            shard.slots = Vec::new();
+            shard.keys = HashMap::new();
+            shard.hand = 0;
+            shard.bytes = 0;
+        }
+    }
+
+    pub(super) fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bypassed: self.bypassed.load(Ordering::Relaxed),
+            ..CacheStats::default()
+        };
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            stats.entries += shard.slots.len();
+            stats.bytes += shard.bytes;
+        }
+        stats
+    }
+}
+
+impl Shard {
+    fn get(&mut self, key: &str, pos: Pos) -> Option<String> {
+        let slot = &mut self.slots[*self.keys.get(key)?];
+        if slot.pos != pos {
+            return None;
+        }
+        slot.referenced = true;
+        Some(slot.value.clone())
+    }
+
+    /// Returns false if the value is larger than the shard.
+    fn insert(&mut self, key: String, pos: Pos, value: String) -> bool {
+        self.remove(&key);
+        let charge = charge(&key, &value);
+        if charge > self.capacity {
+            return false;
+        }
+        while self.bytes + charge > self.capacity {
+            self.evict_one();
+        }
+        self.bytes += charge;
+        self.keys.insert(key.clone(), self.slots.len());
+        self.slots.push(Slot {
+            key,
+            pos,
+            value,
+            referenced: false,
+        });
+        true
+    }
+
+    /// Sweeps the clock hand past the recently read values, clearing their mark,
+    /// and evicts the first value not read since the last sweep.
+    fn evict_one(&mut self) {
+        loop {
+            if self.hand >= self.slots.len() {
+                self.hand = 0;
+            }
+            let slot = &mut self.slots[self.hand];
+            if slot.referenced {
+                slot.referenced = false;
+                self.hand += 1;
+            } else {
+                self.remove_at(self.hand);
+                return;
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(&at) = self.keys.get(key) {
+            self.remove_at(at);
+        }
+    }
+
+    fn remove_at(&mut self, at: usize) {
+        let slot = self.slots.swap_remove(at);
+        self.keys.remove(&slot.key);
+        self.bytes -= slot.charge();
+        if let Some(moved) = self.slots.get(at) {
+            *self.keys.get_mut(&moved.key).unwrap() = at;
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Algorithm compressing the values of a `KvStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// LZ4, fast to compress and decompress.
+    Lz4,
+    /// Zstandard, slower but smaller.
+    Zstd,
+}
+
+impl Compression {
+    /// Names of all algorithms.
+    pub const VARIANTS: &'static [&'static str] = &["lz4", "zstd"];
+
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::Lz4 => Ok(lz4_flex::compress(data)),
+            Compression::Zstd => Ok(zstd::encode_all(data, 0)?),
+        }
+    }
+
+    fn decompress(self, data: &[u8], len: usize) -> Result<Vec<u8>> {
+        match self {
+            Compression::Lz4 => lz4_flex::decompress(data, len)
+                .map_err(|e| KvsError::StringError(format!("corrupted LZ4 value: {}", e))),
+            Compression::Zstd => Ok(zstd::decode_all(data)?),
+        }
+    }
+}
+
+/// How the value of a log record is compressed. Its compressed bytes are stored
+/// base64 encoded, so the log stays a stream of JSON records.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(super) struct Compressed {
+    codec: Compression,
+    // Bytes of the uncompressed value
+    len: u64,
+}
+
+impl Compressed {
+    pub(super) fn codec(&self) -> Compression {
+        self.codec
+    }
+}
+
+/// Compresses a value of at least `threshold` bytes. Returns `None` if it is
+/// shorter, or if compressing does not make it smaller.
+pub(super) fn compress(
+    value: &str,
+    codec: Compression,
+    threshold: usize,
+) -> Result<Option<(String, Compressed)>> {
+    if value.len() < threshold {
+        return Ok(None);
+    }
+    let encoded = base64::encode(codec.compress(value.as_bytes())?);
+    if encoded.len() >= value.len() {
+        return Ok(None);
+    }
+    let compressed = Compressed {
+        codec,
+        len: value.len() as u64,
+    };
+    Ok(Some((encoded, compressed)))
+}
+
+/// Returns the value of a log record as it was set.
+pub(super) fn decompress(value: String, compressed: Option<Compressed>) -> Result<String> {
+    let compressed = match compressed {
+        Some(compressed) => compressed,
+        None => return Ok(value),
+    };
+    let data = base64::decode(&value)
+        .map_err(|e| KvsError::StringError(format!("corrupted compressed value: {}", e)))?;
+    let data = compressed
+        .codec
+        .decompress(&data, compressed.len as usize)?;
+    Ok(String::from_utf8(data)?)
+}
+
+/// Sizes of the values in the log files of a `KvStore`, overwritten ones included
+/// until compacted away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionStats {
+    /// Bytes of the values as they were set.
+    pub raw_bytes: u64,
+    /// Bytes of the values as they are stored.
+    pub stored_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Returns how many times smaller the values are stored, 1 if there are none.
+    pub fn ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            return 1.0;
+        }
+        self.raw_bytes as f64 / self.stored_bytes as f64
+    }
+}
+
+/// Running `CompressionStats`, shared by the store and its writer.
+#[derive(Default)]
+pub(super) struct CompressionCounters {
+    raw_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+}
+
+impl CompressionCounters {
+    /// Counts a value written to the log.
+    pub(super) fn add(&self, value: &str, compressed: Option<Compressed>) {
+        let stored = value.len() as u64;
+        let raw = compressed.map_or(stored, |compressed| compressed.len);
+        self.raw_bytes.fetch_add(raw, Ordering::Relaxed);
+        self.stored_bytes.fetch_add(stored, Ordering::Relaxed);
+    }
+
+    /// Starts counting again from the values of a compacted log.
+    pub(super) fn reset(&self, stats: CompressionStats) {
+        self.raw_bytes.store(stats.raw_bytes, Ordering::Relaxed);
+        self.stored_bytes
+            .store(stats.stored_bytes, Ordering::Relaxed);
+    }
+
+    pub(super) fn stats(&self) -> CompressionStats {
+        CompressionStats {
+            raw_bytes: self.raw_bytes.load(Ordering::Relaxed),
+            stored_bytes: self.stored_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
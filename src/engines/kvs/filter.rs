@@ -0,0 +1,112 @@
+use crate::engines::bloom::BloomFilter;
+use crate::Result;
+use log::warn;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// Keys the filter of a term being written is first sized for.
+const FIRST_STAGE_CAPACITY: usize = 1024;
+
+/// Bloom filters of the keys set in every log file, telling that a key is absent
+/// without looking it up in the index.
+///
+/// The filter of a compacted term is sized for its keys and persisted next to its
+/// log file. The filter of a term being written, or replayed without a persisted
+/// filter, grows by stages of twice the capacity and half the false positive rate,
+/// so the overall rate stays under the configured one.
+pub(super) struct Filters {
+    fp_rate: f64,
+    terms: RwLock<BTreeMap<u64, Vec<BloomFilter>>>,
+}
+
+impl Filters {
+    pub(super) fn new(fp_rate: f64) -> Self {
+        Filters {
+            fp_rate,
+            terms: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Creates the filter of a compaction of `capacity` keys.
+    pub(super) fn compaction_filter(&self, capacity: usize) -> BloomFilter {
+        BloomFilter::new(capacity, self.fp_rate)
+    }
+
+    /// Reads the persisted filter of `term`. Returns false if there is none, so its
+    /// keys must be inserted.
+    pub(super) fn load(&self, dir: &Path, term: u64) -> Result<bool> {
+        let path = bloom_path(dir, term);
+        if !path.exists() {
+            return Ok(false);
+        }
+        match BloomFilter::read_from(BufReader::new(File::open(&path)?)) {
+            Ok(filter) => {
+                self.terms.write().unwrap().insert(term, vec![filter]);
+                Ok(true)
+            }
+            Err(e) => {
+                warn!("rebuilding the Bloom filter {:?}: {}", path, e);
+                Ok(false)
+            }
+        }
+    }
+
+    pub(super) fn insert(&self, term: u64, key: &str) {
+        {
+            let terms = self.terms.read().unwrap();
+            if let Some(stage) = terms.get(&term).and_then(|stages| stages.last()) {
+                if !stage.is_full() {
+                    stage.insert(key.as_bytes());
+                    return;
+                }
+            }
+        }
+
+        let mut terms = self.terms.write().unwrap();
+        let stages = terms.entry(term).or_default();
+        let capacity = match stages.last() {
+            Some(stage) if !stage.is_full() => None,
+            Some(stage) => Some(stage.capacity() * 2),
+            None => Some(FIRST_STAGE_CAPACITY),
+        };
+        if let Some(capacity) = capacity {
+            let fp_rate = self.fp_rate / 2f64.powi(stages.len() as i32 + 1);
+            stages.push(BloomFilter::new(capacity, fp_rate));
+        }
+        stages.last().unwrap().insert(key.as_bytes());
+    }
+
+    /// Returns false if the key is surely not set in any term.
+    pub(super) fn may_contain(&self, key: &str) -> bool {
+        self.terms
+            .read()
+            .unwrap()
+            .values()
+            .flatten()
+            .any(|stage| stage.may_contain(key.as_bytes()))
+    }
+
+    /// Persists the filter of the compacted `term`, which replaces the filters of
+    /// the terms before it.
+    pub(super) fn compacted(&self, dir: &Path, term: u64, filter: BloomFilter) -> Result<()> {
+        let path = bloom_path(dir, term);
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        filter.write_to(&mut writer)?;
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        fs::rename(&tmp, &path)?;
+
+        let mut terms = self.terms.write().unwrap();
+        *terms = terms.split_off(&term);
+        terms.insert(term, vec![filter]);
+        Ok(())
+    }
+}
+
+pub(super) fn bloom_path(dir: &Path, term: u64) -> PathBuf {
+    dir.join(format!("{}.bloom", term))
+}
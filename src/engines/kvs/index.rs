@@ -0,0 +1,480 @@
+use super::Pos;
+use crate::Result;
+use crossbeam_skiplist::SkipMap;
+use serde::Deserialize;
+use std::cmp::{self, Ordering};
+use std::collections::BTreeMap;
+use std::mem;
+use std::ops::Bound;
+use std::str;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::RwLock;
+
+/// Estimated bytes a skiplist node takes besides its key and value: the tower of
+/// links and the reference count.
+const SKIPLIST_NODE_OVERHEAD: usize = 32;
+/// Estimated bytes a `BTreeMap` entry takes besides its key and value.
+const BTREE_ENTRY_OVERHEAD: usize = 16;
+/// Keys of a segment between two full keys, the others only store the suffix they
+/// do not share with the previous key.
+const RESTART_INTERVAL: usize = 16;
+/// The overlay is merged into the segment once it holds this many keys, or a
+/// quarter of the keys of the segment if that is more.
+const MIN_OVERLAY_LEN: usize = 16 * 1024;
+
+/// In-memory index of a `KvStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexKind {
+    /// A concurrent skiplist holding every key. Fast, but it takes about 80 bytes
+    /// per key besides the key itself.
+    SkipList,
+    /// A sorted array of prefix compressed keys, with a small ordered map taking
+    /// the writes until it is merged into the array. Lookups are slower and writes
+    /// pause while merging, but it takes a few bytes per key besides the suffix of
+    /// the key.
+    Compact,
+}
+
+impl IndexKind {
+    /// Names of all kinds.
+    pub const VARIANTS: &'static [&'static str] = &["skiplist", "compact"];
+
+    pub(super) fn create(self) -> Box<dyn Index> {
+        match self {
+            IndexKind::SkipList => Box::new(SkipListIndex::default()),
+            IndexKind::Compact => Box::new(CompactIndex::default()),
+        }
+    }
+}
+
+/// Maps every live key of a `KvStore` to the position of its latest `set`.
+///
+/// Lookups run concurrently with each other and with mutations. Mutations are
+/// serialized by the caller, the writer of the store.
+pub(super) trait Index: Send + Sync {
+    fn get(&self, key: &str) -> Option<Pos>;
+
+    /// Returns the previous position of the key.
+    fn insert(&self, key: String, pos: Pos) -> Option<Pos>;
+
+    /// Returns the position of the removed key.
+    fn remove(&self, key: &str) -> Option<Pos>;
+
+    fn len(&self) -> usize;
+
+    /// Calls `f` on the keys starting with `prefix` in ascending order, until it
+    /// fails.
+    fn scan(&self, prefix: &str, f: &mut dyn FnMut(&str, Pos) -> Result<()>) -> Result<()>;
+
+    /// Moves every key to the position `f` returns for it, calling it in ascending
+    /// key order. The new positions are only visible once `commit` returns, after
+    /// `f` has been called for every key.
+    fn relocate(
+        &self,
+        f: &mut dyn FnMut(&str, Pos) -> Result<Pos>,
+        commit: &mut dyn FnMut() -> Result<()>,
+    ) -> Result<()>;
+
+    /// Returns an estimate of the heap memory used, in bytes.
+    fn memory_usage(&self) -> usize;
+}
+
+/// An index keeping every key in a `SkipMap`.
+#[derive(Default)]
+struct SkipListIndex {
+    map: SkipMap<String, Pos>,
+    key_bytes: AtomicUsize,
+}
+
+impl Index for SkipListIndex {
+    fn get(&self, key: &str) -> Option<Pos> {
+        self.map.get(key).map(|entry| *entry.value())
+    }
+
+    fn insert(&self, key: String, pos: Pos) -> Option<Pos> {
+        let old = self.get(&key);
+        if old.is_none() {
+            self.key_bytes
+                .fetch_add(key.capacity(), atomic::Ordering::Relaxed);
+        }
+        self.map.insert(key, pos);
+        old
+    }
+
+    fn remove(&self, key: &str) -> Option<Pos> {
+        let entry = self.map.remove(key)?;
+        self.key_bytes
+            .fetch_sub(entry.key().capacity(), atomic::Ordering::Relaxed);
+        Some(*entry.value())
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn scan(&self, prefix: &str, f: &mut dyn FnMut(&str, Pos) -> Result<()>) -> Result<()> {
+        for entry in self
+            .map
+            .range(prefix.to_owned()..)
+            .take_while(|entry| entry.key().starts_with(prefix))
+        {
+            f(entry.key(), *entry.value())?;
+        }
+        Ok(())
+    }
+
+    fn relocate(
+        &self,
+        f: &mut dyn FnMut(&str, Pos) -> Result<Pos>,
+        commit: &mut dyn FnMut() -> Result<()>,
+    ) -> Result<()> {
+        let mut relocated = Vec::with_capacity(self.map.len());
+        for entry in self.map.iter() {
+            relocated.push((entry.key().clone(), f(entry.key(), *entry.value())?));
+        }
+        commit()?;
+        for (key, pos) in relocated {
+            self.map.insert(key, pos);
+        }
+        Ok(())
+    }
+
+    fn memory_usage(&self) -> usize {
+        let entry = mem::size_of::<String>() + mem::size_of::<Pos>() + SKIPLIST_NODE_OVERHEAD;
+        self.map.len() * entry + self.key_bytes.load(atomic::Ordering::Relaxed)
+    }
+}
+
+/// An index keeping most keys in an immutable `Segment`, and the recent writes in
+/// an overlay.
+///
+/// The overlay holds `None` for the keys of the segment that are removed. It is
+/// merged into a new segment once it grows too large compared to the segment.
+#[derive(Default)]
+struct CompactIndex {
+    state: RwLock<CompactState>,
+}
+
+#[derive(Default)]
+struct CompactState {
+    segment: Segment,
+    overlay: BTreeMap<String, Option<Pos>>,
+    overlay_key_bytes: usize,
+    len: usize,
+}
+
+impl CompactState {
+    fn get(&self, key: &str) -> Option<Pos> {
+        match self.overlay.get(key) {
+            Some(slot) => *slot,
+            None => self.segment.get(key.as_bytes()),
+        }
+    }
+
+    fn overlay_insert(&mut self, key: String, slot: Option<Pos>) {
+        let key_bytes = key.capacity();
+        if self.overlay.insert(key, slot).is_none() {
+            self.overlay_key_bytes += key_bytes;
+        }
+    }
+
+    fn scan(&self, prefix: &str, f: &mut dyn FnMut(&str, Pos) -> Result<()>) -> Result<()> {
+        enum Step {
+            Segment,
+            Overlay,
+            Both,
+        }
+
+        let mut cursor = self.segment.seek(prefix.as_bytes());
+        let mut overlay = self
+            .overlay
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .peekable();
+        loop {
+            let in_segment = cursor
+                .current()
+                .filter(|(key, _)| key.starts_with(prefix.as_bytes()));
+            let step = match (in_segment, overlay.peek()) {
+                (None, None) => return Ok(()),
+                (Some(_), None) => Step::Segment,
+                (None, Some(_)) => Step::Overlay,
+                (Some((key, _)), Some((overlay_key, _))) => match key.cmp(overlay_key.as_bytes()) {
+                    Ordering::Less => Step::Segment,
+                    Ordering::Equal => Step::Both,
+                    Ordering::Greater => Step::Overlay,
+                },
+            };
+            match step {
+                Step::Segment => {
+                    let (key, pos) = cursor.current().unwrap();
+                    f(segment_key(key), pos)?;
+                    cursor.advance();
+                }
+                Step::Overlay | Step::Both => {
+                    let (key, slot) = overlay.next().unwrap();
+                    if let Some(pos) = slot {
+                        f(key, *pos)?;
+                    }
+                    if let Step::Both = step {
+                        cursor.advance();
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl CompactIndex {
+    /// Merges the overlay into a new segment if it is large enough.
+    ///
+    /// The new segment is built under the read lock, lookups only wait for it to
+    /// replace the old one.
+    fn maybe_merge(&self) {
+        let merged = {
+            let state = self.state.read().unwrap();
+            let limit = cmp::max(MIN_OVERLAY_LEN, state.segment.len / 4);
+            if state.overlay.len() <= limit {
+                return;
+            }
+            let mut builder = SegmentBuilder::default();
+            let res = state.scan("", &mut |key, pos| {
+                builder.push(key, pos);
+                Ok(())
+            });
+            debug_assert!(res.is_ok());
+            builder.finish()
+        };
+        self.replace(merged);
+    }
+
+    fn replace(&self, segment: Segment) {
+        let mut state = self.state.write().unwrap();
+        state.segment = segment;
+        state.overlay.clear();
+        state.overlay_key_bytes = 0;
+    }
+}
+
+impl Index for CompactIndex {
+    fn get(&self, key: &str) -> Option<Pos> {
+        self.state.read().unwrap().get(key)
+    }
+
+    fn insert(&self, key: String, pos: Pos) -> Option<Pos> {
+        let old = {
+            let mut state = self.state.write().unwrap();
+            let old = state.get(&key);
+            if old.is_none() {
+                state.len += 1;
+            }
+            state.overlay_insert(key, Some(pos));
+            old
+        };
+        self.maybe_merge();
+        old
+    }
+
+    fn remove(&self, key: &str) -> Option<Pos> {
+        let old = {
+            let mut state = self.state.write().unwrap();
+            let old = state.get(key)?;
+            state.len -= 1;
+            if state.segment.get(key.as_bytes()).is_some() {
+                state.overlay_insert(key.to_owned(), None);
+            } else if let Some((key, _)) = state.overlay.remove_entry(key) {
+                state.overlay_key_bytes -= key.capacity();
+            }
+            old
+        };
+        self.maybe_merge();
+        Some(old)
+    }
+
+    fn len(&self) -> usize {
+        self.state.read().unwrap().len
+    }
+
+    fn scan(&self, prefix: &str, f: &mut dyn FnMut(&str, Pos) -> Result<()>) -> Result<()> {
+        self.state.read().unwrap().scan(prefix, f)
+    }
+
+    /// Builds a segment of the new positions, so lookups see the old positions
+    /// until all keys are moved.
+    fn relocate(
+        &self,
+        f: &mut dyn FnMut(&str, Pos) -> Result<Pos>,
+        commit: &mut dyn FnMut() -> Result<()>,
+    ) -> Result<()> {
+        let relocated = {
+            let state = self.state.read().unwrap();
+            let mut builder = SegmentBuilder::default();
+            state.scan("", &mut |key, pos| {
+                builder.push(key, f(key, pos)?);
+                Ok(())
+            })?;
+            builder.finish()
+        };
+        commit()?;
+        self.replace(relocated);
+        Ok(())
+    }
+
+    fn memory_usage(&self) -> usize {
+        let state = self.state.read().unwrap();
+        let entry = mem::size_of::<String>() + mem::size_of::<Option<Pos>>() + BTREE_ENTRY_OVERHEAD;
+        state.segment.memory_usage() + state.overlay.len() * entry + state.overlay_key_bytes
+    }
+}
+
+/// Sorted keys and their positions, packed in a byte array.
+///
+/// Each key is stored as the length of the prefix it shares with the previous key,
+/// the length of the rest and the rest, followed by the position, all lengths and
+/// numbers as varints. Every `RESTART_INTERVAL` keys, a full key is stored, and
+/// `restarts` points to it.
+#[derive(Default)]
+struct Segment {
+    data: Vec<u8>,
+    restarts: Vec<usize>,
+    len: usize,
+}
+
+impl Segment {
+    fn get(&self, key: &[u8]) -> Option<Pos> {
+        match self.seek(key).current() {
+            Some((found, pos)) if found == key => Some(pos),
+            _ => None,
+        }
+    }
+
+    /// Returns a cursor at the first key not less than `key`.
+    fn seek(&self, key: &[u8]) -> Cursor<'_> {
+        let after = self
+            .restarts
+            .partition_point(|&at| self.restart_key(at) <= key);
+        let restart = after.saturating_sub(1);
+        let mut cursor = Cursor {
+            segment: self,
+            at: self.restarts.get(restart).copied().unwrap_or(0),
+            key: Vec::new(),
+            current: None,
+        };
+        cursor.advance();
+        while matches!(cursor.current(), Some((found, _)) if found < key) {
+            cursor.advance();
+        }
+        cursor
+    }
+
+    /// Returns the full key stored at a restart point.
+    fn restart_key(&self, mut at: usize) -> &[u8] {
+        let shared = read_varint(&self.data, &mut at);
+        debug_assert_eq!(shared, 0);
+        let len = read_varint(&self.data, &mut at) as usize;
+        &self.data[at..at + len]
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.data.capacity() + self.restarts.capacity() * mem::size_of::<usize>()
+    }
+}
+
+/// Reads the keys of a segment in ascending order.
+struct Cursor<'a> {
+    segment: &'a Segment,
+    // Offset of the next key in the data
+    at: usize,
+    key: Vec<u8>,
+    current: Option<Pos>,
+}
+
+impl Cursor<'_> {
+    fn current(&self) -> Option<(&[u8], Pos)> {
+        self.current.map(|pos| (&self.key[..], pos))
+    }
+
+    fn advance(&mut self) {
+        let data = &self.segment.data;
+        if self.at >= data.len() {
+            self.current = None;
+            return;
+        }
+        let shared = read_varint(data, &mut self.at) as usize;
+        let len = read_varint(data, &mut self.at) as usize;
+        self.key.truncate(shared);
+        self.key.extend_from_slice(&data[self.at..self.at + len]);
+        self.at += len;
+        self.current = Some(Pos {
+            term: read_varint(data, &mut self.at),
+            offset: read_varint(data, &mut self.at),
+            len: read_varint(data, &mut self.at),
+        });
+    }
+}
+
+/// Builds a segment from keys pushed in ascending order.
+#[derive(Default)]
+struct SegmentBuilder {
+    segment: Segment,
+    last_key: Vec<u8>,
+}
+
+impl SegmentBuilder {
+    fn push(&mut self, key: &str, pos: Pos) {
+        let key = key.as_bytes();
+        let segment = &mut self.segment;
+        let shared = if segment.len % RESTART_INTERVAL == 0 {
+            segment.restarts.push(segment.data.len());
+            0
+        } else {
+            key.iter()
+                .zip(&self.last_key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        write_varint(&mut segment.data, shared as u64);
+        write_varint(&mut segment.data, (key.len() - shared) as u64);
+        segment.data.extend_from_slice(&key[shared..]);
+        write_varint(&mut segment.data, pos.term);
+        write_varint(&mut segment.data, pos.offset);
+        write_varint(&mut segment.data, pos.len);
+        segment.len += 1;
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+    }
+
+    fn finish(mut self) -> Segment {
+        self.segment.data.shrink_to_fit();
+        self.segment.restarts.shrink_to_fit();
+        self.segment
+    }
+}
+
+/// Keys are only split between bytes sharing a prefix, and rebuilt in full.
+fn segment_key(key: &[u8]) -> &str {
+    str::from_utf8(key).expect("segment keys are UTF-8")
+}
+
+fn write_varint(data: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        data.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    data.push(n as u8);
+}
+
+fn read_varint(data: &[u8], at: &mut usize) -> u64 {
+    let mut n = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*at];
+        *at += 1;
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte < 0x80 {
+            return n;
+        }
+        shift += 7;
+    }
+}
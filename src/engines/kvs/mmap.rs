@@ -0,0 +1,64 @@
+use super::{log_path, Pos};
+use crate::{KvsError, Result};
+use memmap2::Mmap;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// Maps of the log files no longer written to, shared by all clones of a reader.
+///
+/// A term is mapped on its first read once sealed. Retiring it drops the map, which
+/// is unmapped when the last read of it finishes.
+#[derive(Default)]
+pub(super) struct Segments {
+    // Sealed terms, with their map once read
+    maps: RwLock<BTreeMap<u64, Option<Arc<Mmap>>>>,
+}
+
+impl Segments {
+    /// Marks the file of `term` as complete and never written again.
+    pub(super) fn seal(&self, term: u64) {
+        self.maps.write().unwrap().insert(term, None);
+    }
+
+    /// Drops the maps of the terms before `term`.
+    pub(super) fn retire(&self, term: u64) {
+        let mut maps = self.maps.write().unwrap();
+        *maps = maps.split_off(&term);
+    }
+
+    /// Returns the map of the file of `pos`, or `None` if it is not sealed.
+    pub(super) fn get(&self, dir: &Path, pos: Pos) -> Result<Option<Arc<Mmap>>> {
+        match self.maps.read().unwrap().get(&pos.term) {
+            None => return Ok(None),
+            Some(Some(map)) => return Ok(Some(Arc::clone(map))),
+            Some(None) => {}
+        }
+
+        let mut maps = self.maps.write().unwrap();
+        match maps.get_mut(&pos.term) {
+            None => Ok(None),
+            Some(Some(map)) => Ok(Some(Arc::clone(map))),
+            Some(slot) => {
+                let file = File::open(log_path(dir, pos.term))?;
+                // The file of a sealed term is neither written nor truncated again, it
+                // is only removed once retired, which keeps the pages of existing maps.
+                let map = Arc::new(unsafe { Mmap::map(&file)? });
+                *slot = Some(Arc::clone(&map));
+                Ok(Some(map))
+            }
+        }
+    }
+}
+
+/// Returns the bytes of the entry at `pos` in the map of its file.
+pub(super) fn entry(map: &Mmap, pos: Pos) -> Result<&[u8]> {
+    let start = pos.offset as usize;
+    map.get(start..start + pos.len as usize).ok_or_else(|| {
+        KvsError::StringError(format!(
+            "entry at {} of term {} is past the end of its file",
+            pos.offset, pos.term
+        ))
+    })
+}
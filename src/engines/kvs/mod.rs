@@ -0,0 +1,1009 @@
+use crate::error::{KvsError, Result};
+use crate::metrics;
+use crate::replication::{LogEntry, LogOp, LogPosition, LogRead};
+use crate::watch::{ChangeFeed, Watcher};
+use crate::KvsEngine;
+use log::error;
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+pub use self::cache::CacheStats;
+use self::cache::ValueCache;
+use self::compress::{Compressed, CompressionCounters};
+pub use self::compress::{Compression, CompressionStats};
+use self::filter::{bloom_path, Filters};
+use self::index::Index;
+pub use self::index::IndexKind;
+use self::mmap::Segments;
+
+mod cache;
+mod compress;
+mod filter;
+mod index;
+mod mmap;
+
+const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const COMPRESSION_THRESHOLD: usize = 256;
+const COMPACTION_MARKER: &str = "compaction";
+const SEQUENCE_FILE: &str = "sequence";
+
+/// How hard a `KvStore` tries to persist every write before acknowledging it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Hand every write to the OS. It survives a process crash, not a power loss.
+    Flush,
+    /// `fsync` the log after every write.
+    Sync,
+}
+
+/// How a `KvStore` reads the values from its log files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadMode {
+    /// Seek and read through file handles opened by every clone of the store.
+    Buffered,
+    /// Map the log files no longer written to in memory, shared by all clones of the
+    /// store. The file being written to is still read through file handles.
+    Mmap,
+}
+
+impl ReadMode {
+    /// Names of all modes.
+    pub const VARIANTS: &'static [&'static str] = &["buffered", "mmap"];
+}
+
+/// Options used to open a `KvStore`.
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    /// Compacts the log once this many bytes of it are stale, `None` never compacts.
+    pub compaction_threshold: Option<u64>,
+    /// Durability of writes.
+    pub durability: Durability,
+    /// In-memory index of the keys.
+    pub index: IndexKind,
+    /// Caches the values last read in at most about this many bytes, `None` reads
+    /// every value from the log. The values taking more than a sixteenth of it
+    /// are never cached.
+    pub cache_capacity: Option<usize>,
+    /// How values are read from the log files.
+    pub read_mode: ReadMode,
+    /// False positive rate of the Bloom filters answering the reads of absent keys
+    /// without the index, `None` for no filters.
+    pub bloom_fp_rate: Option<f64>,
+    /// Compresses the values of at least `compression_threshold` bytes, `None`
+    /// stores them as they are.
+    pub compression: Option<Compression>,
+    /// Bytes from which values are compressed.
+    pub compression_threshold: usize,
+    /// Compression the compaction rewrites the values with, `None` keeps them as
+    /// they were written.
+    pub recompression: Option<Compression>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compaction_threshold: Some(COMPACTION_THRESHOLD),
+            durability: Durability::Flush,
+            index: IndexKind::SkipList,
+            cache_capacity: None,
+            read_mode: ReadMode::Buffered,
+            bloom_fp_rate: None,
+            compression: None,
+            compression_threshold: COMPRESSION_THRESHOLD,
+            recompression: None,
+        }
+    }
+}
+
+/// The `KvStore` stores string key/value pairs.
+#[derive(Clone)]
+pub struct KvStore {
+    path: Arc<PathBuf>,
+    index: Arc<dyn Index>,
+    cache: Option<Arc<ValueCache>>,
+    filters: Option<Arc<Filters>>,
+    compression: Arc<CompressionCounters>,
+    reader: KvsReader,
+    writer: Arc<Mutex<KvsWriter>>,
+}
+
+impl KvStore {
+    /// Opens a `KvStore` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let path = Arc::new(path.into());
+        fs::create_dir_all(&*path)?;
+
+        let mut readers = BTreeMap::new();
+        let index: Arc<dyn Index> = Arc::from(options.index.create());
+        let cache = options
+            .cache_capacity
+            .map(|capacity| Arc::new(ValueCache::new(capacity)));
+        let filters = options
+            .bloom_fp_rate
+            .map(|fp_rate| Arc::new(Filters::new(fp_rate)));
+        let compression = Arc::new(CompressionCounters::default());
+
+        let terms = sorted_terms(&path)?;
+        let mut uncompacted = 0;
+        let mut disk_bytes = 0;
+
+        for &term in &terms {
+            let file = File::open(log_path(&path, term))?;
+            disk_bytes += file.metadata()?.len() as i64;
+            let mut reader = BufReader::new(file);
+            let filters = match &filters {
+                Some(filters) if !filters.load(&path, term)? => Some(&**filters),
+                _ => None,
+            };
+            uncompacted += load(term, &mut reader, &*index, filters, &compression)?;
+            readers.insert(term, reader);
+        }
+
+        let current_term = terms.last().unwrap_or(&0) + 1;
+        let writer = new_writer(&path, current_term)?;
+        let safe_point = Arc::new(AtomicU64::new(0));
+        let segments = match options.read_mode {
+            ReadMode::Buffered => None,
+            ReadMode::Mmap => {
+                let segments = Segments::default();
+                for &term in &terms {
+                    segments.seal(term);
+                }
+                Some(Arc::new(segments))
+            }
+        };
+
+        let reader = KvsReader {
+            path: Arc::clone(&path),
+            safe_point,
+            segments,
+            readers: RefCell::new(readers),
+        };
+
+        let mut writer = KvsWriter {
+            reader: reader.clone(),
+            writer,
+            current_term,
+            uncompacted,
+            options,
+            path: Arc::clone(&path),
+            index: Arc::clone(&index),
+            index_memory: 0,
+            disk_bytes,
+            cache: cache.clone(),
+            filters: filters.clone(),
+            compression: Arc::clone(&compression),
+            changes: ChangeFeed::open(path.join(SEQUENCE_FILE))?,
+        };
+        writer.report_index_memory();
+        writer.add_disk_bytes(0);
+
+        Ok(KvStore {
+            path,
+            reader,
+            index,
+            cache,
+            filters,
+            compression,
+            writer: Arc::new(Mutex::new(writer)),
+        })
+    }
+
+    /// Returns an estimate of the memory used by the index of the keys, in bytes.
+    pub fn index_memory(&self) -> usize {
+        self.index.memory_usage()
+    }
+
+    /// Returns the statistics of the value cache, if the store has one.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats())
+    }
+
+    /// Returns the sizes of the values in the log files, before and after
+    /// compression.
+    ///
+    /// Without `compression` nor `recompression`, compactions copy the records as
+    /// they are without counting them again, so the sizes keep including the values
+    /// compacted away.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.compression.stats()
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.writer.lock().unwrap().set(key, value)
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if let Some(filters) = &self.filters {
+            if !filters.may_contain(&key) {
+                metrics::global().bloom_avoided_reads.inc();
+                return Ok(None);
+            }
+        }
+        if let Some(pos) = self.index.get(&key) {
+            if let Some(value) = self.cache.as_ref().and_then(|cache| cache.get(&key, pos)) {
+                return Ok(Some(value));
+            }
+            if let Command::Set {
+                value, compressed, ..
+            } = self.reader.read_cmd(pos)?
+            {
+                let value = compress::decompress(value, compressed)?;
+                if let Some(cache) = &self.cache {
+                    cache.insert(key, pos, value.clone());
+                }
+                Ok(Some(value))
+            } else {
+                Err(KvsError::UnexpectedCommandType)
+            }
+        } else {
+            if self.filters.is_some() {
+                metrics::global().bloom_false_positives.inc();
+            }
+            Ok(None)
+        }
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Error
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn remove(&self, key: String) -> Result<()> {
+        self.writer.lock().unwrap().remove(key)
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        self.index.scan(prefix, &mut |key, _| {
+            keys.push(key.to_owned());
+            Ok(())
+        })?;
+        Ok(keys)
+    }
+
+    /// Reads at most `limit` entries of the write log, starting at `from`.
+    ///
+    /// It returns `LogRead::Compacted` if the log file of `from` has been removed by
+    /// a compaction, if `from` is past the end of its log file, or if `from` is the
+    /// default position.
+    ///
+    /// A position at the end of the log when it was last compacted stays valid.
+    fn read_log(&self, from: LogPosition, limit: usize) -> Result<LogRead> {
+        // Only the files are opened under the lock, and read once it is released
+        let files = self.writer.lock().unwrap().log_files(from)?;
+        match files {
+            Some(files) => files.read(limit),
+            None => Ok(LogRead::Compacted),
+        }
+    }
+
+    fn snapshot(&self) -> Result<(LogPosition, Vec<(String, String)>)> {
+        // Only the positions are taken under the lock. The files they point to stay
+        // readable once open, even if a compaction deletes them.
+        let (pos, positions, mut files) = {
+            let mut writer = self.writer.lock().unwrap();
+            let pos = writer.position()?;
+            let mut positions = Vec::with_capacity(self.index.len());
+            self.index.scan("", &mut |_, pos| {
+                positions.push(pos);
+                Ok(())
+            })?;
+            let mut files = BTreeMap::new();
+            for pos in &positions {
+                if !files.contains_key(&pos.term) {
+                    let file = File::open(log_path(&self.path, pos.term))?;
+                    files.insert(pos.term, BufReader::new(file));
+                }
+            }
+            (pos, positions, files)
+        };
+
+        let mut pairs = Vec::with_capacity(positions.len());
+        for pos in positions {
+            let reader = files.get_mut(&pos.term).unwrap();
+            reader.seek(SeekFrom::Start(pos.offset))?;
+            if let Command::Set {
+                key,
+                value,
+                compressed,
+            } = serde_json::from_reader(reader.take(pos.len))?
+            {
+                pairs.push((key, compress::decompress(value, compressed)?));
+            } else {
+                return Err(KvsError::UnexpectedCommandType);
+            }
+        }
+        Ok((pos, pairs))
+    }
+
+    fn watch(&self, prefix: &str, from: Option<u64>) -> Result<Watcher> {
+        self.writer.lock().unwrap().changes.watch(prefix, from)
+    }
+}
+
+/// Replays the log file of `term` into the index, and into the filters if the term
+/// has no persisted filter.
+fn load(
+    term: u64,
+    reader: &mut BufReader<File>,
+    index: &dyn Index,
+    filters: Option<&Filters>,
+    compression: &CompressionCounters,
+) -> Result<u64> {
+    let mut offset: u64 = reader.seek(SeekFrom::Start(0))?;
+    let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
+    let mut uncompacted: u64 = 0;
+    while let Some(res) = stream.next() {
+        let new_offset = stream.byte_offset() as u64;
+        let pos = Pos {
+            term,
+            offset,
+            len: new_offset - offset,
+        };
+        match res? {
+            Command::Set {
+                key,
+                value,
+                compressed,
+            } => {
+                compression.add(&value, compressed);
+                if let Some(filters) = filters {
+                    filters.insert(term, &key);
+                }
+                if let Some(old) = index.insert(key, pos) {
+                    uncompacted += old.len;
+                }
+            }
+            Command::Remove { key } => {
+                if let Some(old) = index.remove(&key) {
+                    uncompacted += old.len;
+                }
+                uncompacted += new_offset - offset;
+            }
+        };
+        offset = new_offset;
+    }
+
+    Ok(uncompacted)
+}
+
+fn new_writer(dir: &Path, term: u64) -> Result<BufWriter<File>> {
+    let path = log_path(dir, term);
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(&path)?;
+
+    Ok(BufWriter::new(file))
+}
+
+/// Persists the creation of the files in `dir`.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+pub(crate) fn sorted_terms(path: &Path) -> Result<Vec<u64>> {
+    let mut terms = fs::read_dir(path)?
+        .map(|res| res.expect("log file error").path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .flat_map(|path| path.file_stem().map(|s| s.to_owned()))
+        .flat_map(|o| o.to_str().map(|s| s.to_owned()))
+        .flat_map(|s| s.parse::<u64>())
+        .collect::<Vec<u64>>();
+
+    terms.sort();
+
+    Ok(terms)
+}
+
+pub(crate) fn log_path(dir: &Path, term: u64) -> PathBuf {
+    dir.join(format!("{}.log", term))
+}
+
+/// Reads the complete entries of a log file from `from`, returns them together with
+/// the position after the last one.
+///
+/// A partly written entry at the end of the file is left for a later read.
+pub(crate) fn read_entries(file: &File, from: LogPosition) -> Result<(Vec<LogEntry>, LogPosition)> {
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(from.offset))?;
+    let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
+    let mut entries = Vec::new();
+    let mut next = from;
+    loop {
+        match stream.next() {
+            Some(Ok(cmd)) => {
+                entries.push(LogEntry {
+                    pos: next,
+                    op: cmd.into_op()?,
+                });
+                next.offset = from.offset + stream.byte_offset() as u64;
+            }
+            Some(Err(e)) if !e.is_eof() => return Err(e.into()),
+            _ => return Ok((entries, next)),
+        }
+    }
+}
+
+/// Where the log goes on after the last compaction: a reader at the end of the log
+/// when it was compacted continues at the start of the new writer term.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CompactionMarker {
+    pub from: LogPosition,
+    pub to: LogPosition,
+}
+
+pub(crate) fn read_compaction_marker(dir: &Path) -> Result<Option<CompactionMarker>> {
+    let path = dir.join(COMPACTION_MARKER);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+}
+
+fn write_compaction_marker(dir: &Path, marker: &CompactionMarker) -> Result<()> {
+    let path = dir.join(COMPACTION_MARKER);
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    serde_json::to_writer(&mut file, marker)?;
+    file.sync_data()?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Command {
+    Set {
+        key: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compressed: Option<Compressed>,
+    },
+    Remove {
+        key: String,
+    },
+}
+
+/// A `Command::Set` borrowing its key and value, written as the same record.
+#[derive(Serialize)]
+enum SetRecord<'a> {
+    Set {
+        key: &'a str,
+        value: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        compressed: Option<Compressed>,
+    },
+}
+
+impl Command {
+    fn into_op(self) -> Result<LogOp> {
+        Ok(match self {
+            Command::Set {
+                key,
+                value,
+                compressed,
+            } => LogOp::Set {
+                key,
+                value: compress::decompress(value, compressed)?,
+            },
+            Command::Remove { key } => LogOp::Remove { key },
+        })
+    }
+}
+
+/// Returns the record setting the key, with the value compressed if it is at least
+/// `threshold` bytes.
+fn set_command(
+    key: String,
+    value: String,
+    compression: Option<Compression>,
+    threshold: usize,
+) -> Result<Command> {
+    let compressed = match compression {
+        Some(codec) => compress::compress(&value, codec, threshold)?,
+        None => None,
+    };
+    Ok(match compressed {
+        Some((stored, compressed)) => Command::Set {
+            key,
+            value: stored,
+            compressed: Some(compressed),
+        },
+        None => Command::Set {
+            key,
+            value,
+            compressed: None,
+        },
+    })
+}
+
+/// Rewrites the value of a set record with `codec`, unless it already is.
+fn recompress(cmd: Command, codec: Compression, threshold: usize) -> Result<Command> {
+    match cmd {
+        Command::Set {
+            key,
+            value,
+            compressed,
+        } if compressed.map(|compressed| compressed.codec()) != Some(codec) => {
+            let value = compress::decompress(value, compressed)?;
+            set_command(key, value, Some(codec), threshold)
+        }
+        cmd => Ok(cmd),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Pos {
+    term: u64,
+    offset: u64,
+    len: u64,
+}
+
+struct KvsReader {
+    path: Arc<PathBuf>,
+    safe_point: Arc<AtomicU64>,
+    // Only with `ReadMode::Mmap`
+    segments: Option<Arc<Segments>>,
+    readers: RefCell<BTreeMap<u64, BufReader<File>>>,
+}
+
+impl Clone for KvsReader {
+    fn clone(&self) -> KvsReader {
+        KvsReader {
+            path: Arc::clone(&self.path),
+            safe_point: Arc::clone(&self.safe_point),
+            segments: self.segments.clone(),
+            readers: RefCell::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl KvsReader {
+    fn close_stale_handles(&self) {
+        let mut readers = self.readers.borrow_mut();
+        while !readers.is_empty() {
+            let term = *readers.keys().next().unwrap();
+            if self.safe_point.load(Ordering::SeqCst) <= term {
+                break;
+            }
+            readers.remove(&term);
+        }
+    }
+
+    /// Returns the map of the file of `pos`, if it is read from a map.
+    fn mapped(&self, pos: Pos) -> Result<Option<Arc<Mmap>>> {
+        match &self.segments {
+            Some(segments) => segments.get(&self.path, pos),
+            None => Ok(None),
+        }
+    }
+
+    fn read_and<F, R>(&self, pos: Pos, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut dyn Read) -> Result<R>,
+    {
+        if let Some(map) = self.mapped(pos)? {
+            return f(&mut mmap::entry(&map, pos)?);
+        }
+        self.close_stale_handles();
+
+        let mut readers = self.readers.borrow_mut();
+        if !readers.contains_key(&pos.term) {
+            let file = File::open(log_path(&self.path, pos.term))?;
+            let reader = BufReader::new(file);
+            readers.insert(pos.term, reader);
+        }
+
+        let reader = readers.get_mut(&pos.term).unwrap();
+        reader.seek(SeekFrom::Start(pos.offset))?;
+        f(&mut reader.take(pos.len))
+    }
+
+    fn read_cmd(&self, pos: Pos) -> Result<Command> {
+        if let Some(map) = self.mapped(pos)? {
+            return Ok(serde_json::from_slice(mmap::entry(&map, pos)?)?);
+        }
+        self.read_and(pos, |cmd_reader| Ok(serde_json::from_reader(cmd_reader)?))
+    }
+}
+
+/// Log files opened under the writer lock, read once it is released.
+///
+/// Open files stay readable even if a compaction deletes them.
+struct LogFiles {
+    from: LogPosition,
+    // Term, file and length to read of every log file from the term of `from`
+    files: Vec<(u64, File, u64)>,
+}
+
+impl LogFiles {
+    /// Reads at most `limit` entries from `from`.
+    fn read(&self, limit: usize) -> Result<LogRead> {
+        let mut entries = Vec::new();
+        let mut next = self.from;
+        for &(term, ref file, len) in &self.files {
+            if entries.len() >= limit {
+                break;
+            }
+            if term != next.term {
+                next = LogPosition { term, offset: 0 };
+            }
+
+            let mut reader = BufReader::new(file);
+            let start = reader.seek(SeekFrom::Start(next.offset))?;
+            let mut stream = serde_json::Deserializer::from_reader(reader.take(len - start))
+                .into_iter::<Command>();
+            while entries.len() < limit {
+                let cmd = match stream.next() {
+                    Some(cmd) => cmd?,
+                    None => break,
+                };
+                entries.push(LogEntry {
+                    pos: next,
+                    op: cmd.into_op()?,
+                });
+                next.offset = start + stream.byte_offset() as u64;
+            }
+        }
+
+        let mut behind = 0;
+        for &(term, _, len) in &self.files {
+            if term >= next.term {
+                behind += len;
+            }
+        }
+        behind -= next.offset;
+
+        Ok(LogRead::Entries {
+            entries,
+            next,
+            behind,
+        })
+    }
+}
+
+struct KvsWriter {
+    path: Arc<PathBuf>,
+    current_term: u64,
+    uncompacted: u64,
+    options: KvStoreOptions,
+    reader: KvsReader,
+    writer: BufWriter<File>,
+    index: Arc<dyn Index>,
+    // Index memory last added to the metrics
+    index_memory: usize,
+    // Size of the log files, reported under the data directory
+    disk_bytes: i64,
+    cache: Option<Arc<ValueCache>>,
+    filters: Option<Arc<Filters>>,
+    compression: Arc<CompressionCounters>,
+    changes: ChangeFeed,
+}
+
+impl KvsWriter {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let encoded = match self.options.compression {
+            Some(codec) => compress::compress(&value, codec, self.options.compression_threshold)?,
+            None => None,
+        };
+        // The record borrows the value, which is then moved to the watchers
+        let (stored, compressed) = match &encoded {
+            Some((stored, compressed)) => (stored.as_str(), Some(*compressed)),
+            None => (value.as_str(), None),
+        };
+        let offset = self.writer.seek(SeekFrom::Current(0))?;
+        let record = SetRecord::Set {
+            key: &key,
+            value: stored,
+            compressed,
+        };
+        serde_json::to_writer(&mut self.writer, &record)?;
+        self.persist()?;
+        let new_offset = self.writer.seek(SeekFrom::Current(0))?;
+        let pos = Pos {
+            term: self.current_term,
+            offset,
+            len: new_offset - offset,
+        };
+        self.add_disk_bytes(pos.len as i64);
+        self.compression.add(stored, compressed);
+
+        if let Some(cache) = &self.cache {
+            cache.remove(&key);
+        }
+        // Before the index, so a read never finds the key missing from the filters
+        if let Some(filters) = &self.filters {
+            filters.insert(self.current_term, &key);
+        }
+        if let Some(old) = self.index.insert(key.clone(), pos) {
+            self.uncompacted += old.len;
+        }
+        self.report_index_memory();
+        self.changes.publish(LogOp::Set { key, value })?;
+
+        self.maybe_compact()?;
+
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.index.get(&key).is_some() {
+            let cmd = Command::Remove { key: key.clone() };
+            let offset = self.writer.seek(SeekFrom::Current(0))?;
+            serde_json::to_writer(&mut self.writer, &cmd)?;
+            self.persist()?;
+            let new_offset = self.writer.seek(SeekFrom::Current(0))?;
+            self.add_disk_bytes((new_offset - offset) as i64);
+            if let Some(old) = self.index.remove(&key) {
+                self.uncompacted += old.len;
+            }
+            if let Some(cache) = &self.cache {
+                cache.remove(&key);
+            }
+            self.report_index_memory();
+            self.changes.publish(LogOp::Remove { key })?;
+            return Ok(());
+        }
+
+        self.maybe_compact()?;
+
+        Err(KvsError::KeyNotFound)
+    }
+
+    fn report_index_memory(&mut self) {
+        let memory = self.index.memory_usage();
+        metrics::global()
+            .index_bytes
+            .add(memory as i64 - self.index_memory as i64);
+        self.index_memory = memory;
+    }
+
+    fn add_disk_bytes(&mut self, n: i64) {
+        self.disk_bytes += n;
+        metrics::global()
+            .disk_bytes
+            .set(&self.path, self.disk_bytes);
+    }
+
+    fn position(&mut self) -> Result<LogPosition> {
+        Ok(LogPosition {
+            term: self.current_term,
+            offset: self.writer.seek(SeekFrom::Current(0))?,
+        })
+    }
+
+    /// Opens the log files to read from `from`, `None` if it has been compacted away.
+    ///
+    /// The file being written to is only read up to its current end, so no write
+    /// in progress is read.
+    fn log_files(&mut self, from: LogPosition) -> Result<Option<LogFiles>> {
+        let terms = sorted_terms(&self.path)?;
+        let from = if terms.contains(&from.term) {
+            from
+        } else {
+            match read_compaction_marker(&self.path)? {
+                Some(marker) if marker.from == from => marker.to,
+                _ => return Ok(None),
+            }
+        };
+
+        let end = self.position()?;
+        let mut files = Vec::new();
+        for &term in terms.iter().filter(|&&term| term >= from.term) {
+            let file = File::open(log_path(&self.path, term))?;
+            let len = if term == end.term {
+                end.offset
+            } else {
+                file.metadata()?.len()
+            };
+            files.push((term, file, len));
+        }
+        // Past the end of its log file, `from` is not a position this log has been at
+        match files.first() {
+            Some(&(_, _, len)) if from.offset <= len => Ok(Some(LogFiles { from, files })),
+            _ => Ok(None),
+        }
+    }
+
+    fn persist(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if self.options.durability == Durability::Sync {
+            self.writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn maybe_compact(&mut self) -> Result<()> {
+        match self.options.compaction_threshold {
+            Some(threshold) if self.uncompacted > threshold => self.compact(),
+            _ => Ok(()),
+        }
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        let start = Instant::now();
+        let compact_term = self.current_term + 1;
+        let marker = CompactionMarker {
+            from: self.position()?,
+            to: LogPosition {
+                term: self.current_term + 2,
+                offset: 0,
+            },
+        };
+        // Readers at the end of the log must know where it goes on before they can
+        // see the compacted term.
+        write_compaction_marker(&self.path, &marker)?;
+        self.current_term += 2;
+
+        let compact_writer = RefCell::new(new_writer(&self.path, compact_term)?);
+        self.writer = new_writer(&self.path, self.current_term)?;
+
+        let mut offset: u64 = 0;
+        let reader = &self.reader;
+        let filter = self
+            .filters
+            .as_ref()
+            .map(|filters| filters.compaction_filter(self.index.len()));
+        let recompression = self.options.recompression;
+        let threshold = self.options.compression_threshold;
+        // Without compression the records are copied as they are, and not counted again
+        let count = recompression.is_some() || self.options.compression.is_some();
+        let compression = CompressionCounters::default();
+        let mut record = Vec::new();
+        self.index.relocate(
+            &mut |key, pos| {
+                if let Some(filter) = &filter {
+                    filter.insert(key.as_bytes());
+                }
+                record.clear();
+                let cmd = match recompression {
+                    Some(codec) => {
+                        let cmd = recompress(reader.read_cmd(pos)?, codec, threshold)?;
+                        serde_json::to_writer(&mut record, &cmd)?;
+                        Some(cmd)
+                    }
+                    None => {
+                        reader.read_and(pos, |entry_reader| {
+                            Ok(entry_reader.read_to_end(&mut record)?)
+                        })?;
+                        if count {
+                            Some(serde_json::from_slice(&record)?)
+                        } else {
+                            None
+                        }
+                    }
+                };
+                if let Some(Command::Set {
+                    value, compressed, ..
+                }) = &cmd
+                {
+                    compression.add(value, *compressed);
+                }
+                compact_writer.borrow_mut().write_all(&record)?;
+                let len = record.len() as u64;
+
+                let new_pos = Pos {
+                    term: compact_term,
+                    offset,
+                    len,
+                };
+                offset += len;
+                Ok(new_pos)
+            },
+            // Readers must find the records at their new positions
+            &mut || Ok(compact_writer.borrow_mut().flush()?),
+        )?;
+        // The stale logs are the only other copy of the acknowledged writes
+        if self.options.durability == Durability::Sync {
+            compact_writer.borrow().get_ref().sync_data()?;
+            sync_dir(&self.path)?;
+        }
+        if count {
+            self.compression.reset(compression.stats());
+        }
+        self.report_index_memory();
+        // Every value moved, the cached ones would never be read again
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+        self.add_disk_bytes(offset as i64);
+        if let (Some(filters), Some(filter)) = (&self.filters, filter) {
+            filters.compacted(&self.path, compact_term, filter)?;
+        }
+
+        self.reader.safe_point.store(compact_term, Ordering::SeqCst);
+        self.reader.close_stale_handles();
+        if let Some(segments) = &self.reader.segments {
+            segments.seal(compact_term);
+            segments.retire(compact_term);
+        }
+
+        let stale_terms = sorted_terms(&self.path)?
+            .into_iter()
+            .filter(|&gen| gen < compact_term);
+
+        for term in stale_terms {
+            let path = log_path(&self.path, term);
+            let len = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            if let Err(e) = fs::remove_file(&path) {
+                error!("{:?} cannot be deleted: {}", path, e);
+            } else {
+                self.add_disk_bytes(-(len as i64));
+            }
+            let path = bloom_path(&self.path, term);
+            if let Err(e) = fs::remove_file(&path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    error!("{:?} cannot be deleted: {}", path, e);
+                }
+            }
+        }
+
+        let m = metrics::global();
+        m.compactions.inc();
+        m.compaction_reclaimed_bytes.inc_by(self.uncompacted);
+        m.compaction_duration.observe(start.elapsed());
+        self.uncompacted = 0;
+        Ok(())
+    }
+}
+
+impl Drop for KvsWriter {
+    fn drop(&mut self) {
+        // The store is closed, its files are no longer reported
+        metrics::global().disk_bytes.remove(&self.path);
+        metrics::global()
+            .index_bytes
+            .add(-(self.index_memory as i64));
+    }
+}
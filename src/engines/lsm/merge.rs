@@ -0,0 +1,61 @@
+use crate::Result;
+
+type Entry = (String, Option<String>);
+
+/// Sorted entries of a memtable or an SSTable, `None` values being tombstones.
+pub(super) type Source<'a> = Box<dyn Iterator<Item = Result<Entry>> + 'a>;
+
+/// Merges sorted sources into one, the entry of the first source having a key
+/// hiding the entries of the same key in the following ones.
+pub(super) struct Merge<'a> {
+    sources: Vec<Source<'a>>,
+    // Next item of every source
+    heads: Vec<Option<Result<Entry>>>,
+}
+
+impl<'a> Merge<'a> {
+    /// Merges the sources, from the newest to the oldest.
+    pub(super) fn new(mut sources: Vec<Source<'a>>) -> Self {
+        let heads = sources.iter_mut().map(Iterator::next).collect();
+        Merge { sources, heads }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(i) = self
+            .heads
+            .iter()
+            .position(|head| matches!(head, Some(Err(_))))
+        {
+            return self.heads[i].take();
+        }
+        let i = self
+            .heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| match head {
+                Some(Ok((key, _))) => Some((i, key)),
+                _ => None,
+            })
+            .min_by(|(_, a), (_, b)| a.cmp(b))?
+            .0;
+        let entry = match self.heads[i].take() {
+            Some(Ok(entry)) => entry,
+            _ => unreachable!("the smallest head is an entry"),
+        };
+        self.heads[i] = self.sources[i].next();
+        // Older entries of the same key are hidden
+        for (head, source) in self.heads.iter_mut().zip(&mut self.sources) {
+            while let Some(Ok((key, _))) = head {
+                if *key != entry.0 {
+                    break;
+                }
+                *head = source.next();
+            }
+        }
+        Some(Ok(entry))
+    }
+}
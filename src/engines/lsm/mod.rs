@@ -0,0 +1,664 @@
+use crate::error::{KvsError, Result};
+use crate::{Durability, KvsEngine};
+use crossbeam::channel::{self, Receiver, Sender};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+
+use self::merge::{Merge, Source};
+use self::table::{Table, TableBuilder};
+use self::wal::{wal_path, Wal};
+
+mod merge;
+mod table;
+mod wal;
+
+const MANIFEST: &str = "MANIFEST";
+const MEMTABLE_SIZE: usize = 4 * 1024 * 1024;
+const BLOCK_SIZE: usize = 4 * 1024;
+const BLOOM_FP_RATE: f64 = 0.01;
+const TIER_SIZE: usize = 4;
+/// Estimated bytes a memtable entry takes besides its key and value.
+const MEMTABLE_ENTRY_OVERHEAD: usize = 16;
+
+/// Sorted writes not yet in an SSTable, `None` values being removals.
+type Memtable = BTreeMap<String, Option<String>>;
+
+/// Options used to open a `LsmKvsEngine`.
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// Flushes the memtable to an SSTable once it holds about this many bytes.
+    pub memtable_size: usize,
+    /// Durability of the writes to the write-ahead log.
+    pub durability: Durability,
+    /// Bytes of entries in every block of an SSTable.
+    pub block_size: usize,
+    /// False positive rate of the Bloom filter of every SSTable.
+    pub bloom_fp_rate: f64,
+    /// Merges the SSTables of a tier into one of the next tier once it has this many.
+    pub tier_size: usize,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: MEMTABLE_SIZE,
+            durability: Durability::Flush,
+            block_size: BLOCK_SIZE,
+            bloom_fp_rate: BLOOM_FP_RATE,
+            tier_size: TIER_SIZE,
+        }
+    }
+}
+
+/// The `LsmKvsEngine` stores string key/value pairs in a log-structured merge tree.
+///
+/// Writes go to a write-ahead log and a sorted memtable, which is flushed to an
+/// immutable SSTable once full. SSTables are grouped in tiers: once a tier has
+/// `tier_size` of them, they are merged into one SSTable of the next tier, dropping
+/// the overwritten values, and the removed keys when no older tier is left.
+///
+/// Flushes and merges run on background threads, a write only waits for the flush of
+/// the previous memtable once the current one is full.
+#[derive(Clone)]
+pub struct LsmKvsEngine {
+    state: Arc<RwLock<State>>,
+    writer: Arc<Mutex<LsmWriter>>,
+}
+
+struct State {
+    memtable: Memtable,
+    // Memtable being flushed
+    immutable: Option<Arc<Memtable>>,
+    // SSTables of every tier, the newest first
+    tiers: Vec<Vec<Arc<Table>>>,
+}
+
+/// SSTable ids of every tier, the newest first.
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    tiers: Vec<Vec<u64>>,
+    // Last write-ahead log whose writes are in the SSTables, left behind if it
+    // could not be removed
+    #[serde(default)]
+    flushed_wal: u64,
+}
+
+impl LsmKvsEngine {
+    /// Opens a `LsmKvsEngine` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the recovery.
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmKvsEngine> {
+        LsmKvsEngine::open_with(path, LsmOptions::default())
+    }
+
+    /// Opens a `LsmKvsEngine` with the given path and options.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the recovery.
+    pub fn open_with(path: impl Into<PathBuf>, options: LsmOptions) -> Result<LsmKvsEngine> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+
+        let manifest = read_manifest(&path)?;
+        let mut tiers = Vec::new();
+        for ids in &manifest.tiers {
+            let tier = ids
+                .iter()
+                .map(|&id| Ok(Arc::new(Table::open(&table_path(&path, id), id)?)))
+                .collect::<Result<Vec<_>>>()?;
+            tiers.push(tier);
+        }
+
+        let live: HashSet<u64> = manifest.tiers.iter().flatten().copied().collect();
+        let mut wals = Vec::new();
+        let mut next_id = 1;
+        for (id, extension) in data_files(&path)? {
+            next_id = next_id.max(id + 1);
+            match extension.as_str() {
+                // Replaying it would bring back the values overwritten since
+                "wal" if id <= manifest.flushed_wal => remove_file(&wal_path(&path, id)),
+                "wal" => wals.push(id),
+                // Left by a flush or a merge interrupted before the manifest lists it
+                "sst" if !live.contains(&id) => fs::remove_file(table_path(&path, id))?,
+                _ => {}
+            }
+        }
+        wals.sort_unstable();
+
+        let mut memtable = Memtable::new();
+        let mut memtable_bytes = 0;
+        for &id in &wals {
+            memtable_bytes += wal::replay(&path, id, &mut memtable)?;
+        }
+        let wal = Wal::create(&path, next_id, options.durability)?;
+        wals.push(next_id);
+
+        let state = Arc::new(RwLock::new(State {
+            memtable,
+            immutable: None,
+            tiers,
+        }));
+        let tables = Arc::new(Tables {
+            path,
+            options,
+            state: Arc::clone(&state),
+            next_id: AtomicU64::new(next_id + 1),
+            manifest: Mutex::new(manifest.flushed_wal),
+            flush: Mutex::new(FlushStatus::Idle),
+            flushed: Condvar::new(),
+        });
+        let (flushes, jobs) = channel::unbounded();
+        let flusher = {
+            let tables = Arc::clone(&tables);
+            thread::spawn(move || run_flushes(tables, jobs))
+        };
+        let writer = LsmWriter {
+            tables,
+            wal,
+            wals,
+            memtable_bytes,
+            flushes: Some(flushes),
+            flusher: Some(flusher),
+        };
+
+        Ok(LsmKvsEngine {
+            state,
+            writer: Arc::new(Mutex::new(writer)),
+        })
+    }
+
+    /// Returns the key/value pairs whose key is in `range`, in ascending key order.
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let start = match range.start_bound() {
+            Bound::Included(start) | Bound::Excluded(start) => start.as_str(),
+            Bound::Unbounded => "",
+        };
+        let mut pairs = Vec::new();
+        self.scan_from(start, &mut |key, value| {
+            if let Bound::Excluded(start) = range.start_bound() {
+                if key == start {
+                    return true;
+                }
+            }
+            let in_range = match range.end_bound() {
+                Bound::Included(end) => key <= end.as_str(),
+                Bound::Excluded(end) => key < end.as_str(),
+                Bound::Unbounded => true,
+            };
+            if in_range {
+                pairs.push((key.to_owned(), value.to_owned()));
+            }
+            in_range
+        })?;
+        Ok(pairs)
+    }
+
+    /// Returns the number of SSTables of every tier, the newest tier first.
+    pub fn tables(&self) -> Vec<usize> {
+        let state = self.state.read().unwrap();
+        state.tiers.iter().map(Vec::len).collect()
+    }
+
+    /// Calls `f` with the live key/value pairs from the first key not before `start`,
+    /// in ascending key order, until it returns false.
+    fn scan_from(&self, start: &str, f: &mut dyn FnMut(&str, &str) -> bool) -> Result<()> {
+        let (memtable, immutable, tables) = {
+            let state = self.state.read().unwrap();
+            let memtable = state
+                .memtable
+                .range::<str, _>((Bound::Included(start), Bound::Unbounded))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect::<Vec<_>>();
+            let tables = state.tiers.iter().flatten().cloned().collect::<Vec<_>>();
+            (memtable, state.immutable.clone(), tables)
+        };
+
+        let mut sources: Vec<Source<'_>> = vec![Box::new(memtable.into_iter().map(Ok))];
+        if let Some(immutable) = &immutable {
+            sources.push(Box::new(
+                immutable
+                    .range::<str, _>((Bound::Included(start), Bound::Unbounded))
+                    .map(|(key, value)| Ok((key.clone(), value.clone()))),
+            ));
+        }
+        for table in &tables {
+            sources.push(Box::new(table.iter_from(start)));
+        }
+
+        for entry in Merge::new(sources) {
+            if let (key, Some(value)) = entry? {
+                if !f(&key, &value) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl KvsEngine for LsmKvsEngine {
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during writing the log, and the error of a failed
+    /// flush of the memtable.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.writer.lock().unwrap().set(key, value)
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        lookup(&self.state, &key)
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Error
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O errors during writing the log, and the error of a failed
+    /// flush of the memtable.
+    fn remove(&self, key: String) -> Result<()> {
+        self.writer.lock().unwrap().remove(key)
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        self.scan_from(prefix, &mut |key, _| {
+            if !key.starts_with(prefix) {
+                return false;
+            }
+            keys.push(key.to_owned());
+            true
+        })?;
+        Ok(keys)
+    }
+}
+
+/// Looks a key up in the memtables, then in the SSTables from the newest.
+fn lookup(state: &RwLock<State>, key: &str) -> Result<Option<String>> {
+    let tables = {
+        let state = state.read().unwrap();
+        if let Some(value) = state.memtable.get(key) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = state.immutable.as_ref().and_then(|m| m.get(key)) {
+            return Ok(value.clone());
+        }
+        state.tiers.iter().flatten().cloned().collect::<Vec<_>>()
+    };
+    for table in tables {
+        if let Some(value) = table.get(key)? {
+            return Ok(value);
+        }
+    }
+    Ok(None)
+}
+
+struct LsmWriter {
+    tables: Arc<Tables>,
+    wal: Wal,
+    // Write-ahead logs of the writes in the memtable
+    wals: Vec<u64>,
+    memtable_bytes: usize,
+    // Full memtables handed to the flushing thread, `None` once dropped
+    flushes: Option<Sender<FlushJob>>,
+    flusher: Option<JoinHandle<()>>,
+}
+
+impl LsmWriter {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.wal.set(key.clone(), value.clone())?;
+        self.memtable_bytes += entry_size(&key, Some(&value));
+        self.tables
+            .state
+            .write()
+            .unwrap()
+            .memtable
+            .insert(key, Some(value));
+        self.maybe_flush()
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if lookup(&self.tables.state, &key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.wal.remove(key.clone())?;
+        self.memtable_bytes += entry_size(&key, None);
+        self.tables
+            .state
+            .write()
+            .unwrap()
+            .memtable
+            .insert(key, None);
+        self.maybe_flush()
+    }
+
+    /// Hands the memtable to the flushing thread once full.
+    ///
+    /// It only waits for the flush of the previous memtable, never for a merge.
+    fn maybe_flush(&mut self) -> Result<()> {
+        if self.memtable_bytes < self.tables.options.memtable_size {
+            return Ok(());
+        }
+        self.tables.wait_for_flush()?;
+
+        let id = self.tables.next_id();
+        let wal = Wal::create(&self.tables.path, id, self.tables.options.durability)?;
+        let memtable = {
+            let mut state = self.tables.state.write().unwrap();
+            let memtable = Arc::new(mem::take(&mut state.memtable));
+            state.immutable = Some(Arc::clone(&memtable));
+            memtable
+        };
+        self.wal = wal;
+        let wals = mem::replace(&mut self.wals, vec![id]);
+        self.memtable_bytes = 0;
+
+        *self.tables.flush.lock().unwrap() = FlushStatus::Running;
+        let job = FlushJob { memtable, wals };
+        if self.flushes.as_ref().unwrap().send(job).is_err() {
+            let e = "the flushing thread exited".to_owned();
+            *self.tables.flush.lock().unwrap() = FlushStatus::Failed(e.clone());
+            return Err(flush_failed(&e));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LsmWriter {
+    fn drop(&mut self) {
+        // The memtables handed over are flushed, and the full tiers merged, before
+        // another engine may open the directory
+        self.flushes.take();
+        if let Some(handle) = self.flusher.take() {
+            if handle.join().is_err() {
+                error!("the flushing thread panicked");
+            }
+        }
+    }
+}
+
+/// The SSTables, shared by the writer with the threads flushing the memtables and
+/// merging the tiers.
+struct Tables {
+    path: PathBuf,
+    options: LsmOptions,
+    state: Arc<RwLock<State>>,
+    next_id: AtomicU64,
+    // Last flushed write-ahead log, held while the tiers and their manifest change
+    manifest: Mutex<u64>,
+    flush: Mutex<FlushStatus>,
+    flushed: Condvar,
+}
+
+/// State of the flush of the immutable memtable.
+enum FlushStatus {
+    Idle,
+    Running,
+    // The memtable stays immutable and its write-ahead logs are kept, so the writes
+    // are recovered once the engine is opened again
+    Failed(String),
+}
+
+/// A full memtable, and the write-ahead logs of its writes.
+struct FlushJob {
+    memtable: Arc<Memtable>,
+    wals: Vec<u64>,
+}
+
+impl Tables {
+    /// Waits for the immutable memtable to be flushed.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Io` if a flush failed, after which no write succeeds.
+    fn wait_for_flush(&self) -> Result<()> {
+        let mut status = self.flush.lock().unwrap();
+        loop {
+            match &*status {
+                FlushStatus::Idle => return Ok(()),
+                FlushStatus::Running => status = self.flushed.wait(status).unwrap(),
+                FlushStatus::Failed(e) => return Err(flush_failed(e)),
+            }
+        }
+    }
+
+    /// Writes the memtable to a new SSTable of the first tier.
+    ///
+    /// The memtable stays readable while written, and its write-ahead logs are
+    /// removed once the manifest lists the SSTable and the last of them.
+    fn flush(&self, job: FlushJob) -> Result<()> {
+        let entries = job
+            .memtable
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), value.clone())));
+        let table = self.write_table(entries, job.memtable.len())?;
+        {
+            let mut flushed_wal = self.manifest.lock().unwrap();
+            let mut tiers = self.state.read().unwrap().tiers.clone();
+            if tiers.is_empty() {
+                tiers.push(Vec::new());
+            }
+            if let Some(table) = table {
+                tiers[0].insert(0, table);
+            }
+            let last_wal = job.wals.iter().copied().max().unwrap_or(*flushed_wal);
+            self.write_manifest(&tiers, last_wal)?;
+            *flushed_wal = last_wal;
+            let mut state = self.state.write().unwrap();
+            state.tiers = tiers;
+            state.immutable = None;
+        }
+
+        for id in job.wals {
+            remove_file(&wal_path(&self.path, id));
+        }
+        Ok(())
+    }
+
+    /// Merges every full tier into one SSTable of the next tier.
+    ///
+    /// Flushes go on meanwhile, adding newer SSTables to the first tier.
+    fn merge_tiers(&self) -> Result<()> {
+        let mut level = 0;
+        loop {
+            let (merged, last) = {
+                let state = self.state.read().unwrap();
+                if level >= state.tiers.len() {
+                    return Ok(());
+                }
+                if state.tiers[level].len() < self.options.tier_size {
+                    level += 1;
+                    continue;
+                }
+                // Removals only hide older values, none are left below the last tier
+                let last = state.tiers[level + 1..].iter().all(Vec::is_empty);
+                (state.tiers[level].clone(), last)
+            };
+
+            let capacity = merged.iter().map(|table| table.entries() as usize).sum();
+            let sources = merged
+                .iter()
+                .map(|table| Box::new(table.iter_from("")) as Source<'_>)
+                .collect();
+            let entries =
+                Merge::new(sources).filter(|entry| !last || !matches!(entry, Ok((_, None))));
+            let table = self.write_table(entries, capacity)?;
+            {
+                let flushed_wal = self.manifest.lock().unwrap();
+                let mut tiers = self.state.read().unwrap().tiers.clone();
+                tiers[level].retain(|table| !merged.iter().any(|m| m.id() == table.id()));
+                if tiers.len() == level + 1 {
+                    tiers.push(Vec::new());
+                }
+                if let Some(table) = table {
+                    tiers[level + 1].insert(0, table);
+                }
+                self.write_manifest(&tiers, *flushed_wal)?;
+                self.state.write().unwrap().tiers = tiers;
+            }
+
+            for table in merged {
+                remove_file(&table_path(&self.path, table.id()));
+            }
+            level += 1;
+        }
+    }
+
+    /// Writes the sorted entries to a new SSTable, returns `None` if there are none.
+    fn write_table<I>(&self, entries: I, capacity: usize) -> Result<Option<Arc<Table>>>
+    where
+        I: Iterator<Item = Result<(String, Option<String>)>>,
+    {
+        let id = self.next_id();
+        let path = table_path(&self.path, id);
+        let mut builder = TableBuilder::create(
+            &path,
+            capacity,
+            self.options.block_size,
+            self.options.bloom_fp_rate,
+        )?;
+        for entry in entries {
+            let (key, value) = entry?;
+            builder.add(&key, value.as_deref())?;
+        }
+        if builder.is_empty() {
+            drop(builder);
+            fs::remove_file(&path)?;
+            return Ok(None);
+        }
+        builder.finish()?;
+        Ok(Some(Arc::new(Table::open(&path, id)?)))
+    }
+
+    fn write_manifest(&self, tiers: &[Vec<Arc<Table>>], flushed_wal: u64) -> Result<()> {
+        let manifest = Manifest {
+            tiers: tiers
+                .iter()
+                .map(|tier| tier.iter().map(|table| table.id()).collect())
+                .collect(),
+            flushed_wal,
+        };
+        let path = self.path.join(MANIFEST);
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer(&mut file, &manifest)?;
+        file.sync_data()?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+/// Flushes the memtables sent by the writer, and has the full tiers merged by
+/// another thread, until the writer is dropped.
+fn run_flushes(tables: Arc<Tables>, jobs: Receiver<FlushJob>) {
+    // A single pending notification stands for every flush since the last merge
+    let (flushed, merges) = channel::bounded(1);
+    let merger = {
+        let tables = Arc::clone(&tables);
+        thread::spawn(move || {
+            for () in merges {
+                if let Err(e) = tables.merge_tiers() {
+                    // The tiers are left as they were, and merged after the next flush
+                    error!("SSTables cannot be merged: {:?}", e);
+                }
+            }
+        })
+    };
+
+    for job in jobs {
+        let status = match tables.flush(job) {
+            Ok(()) => {
+                let _ = flushed.try_send(());
+                FlushStatus::Idle
+            }
+            Err(e) => {
+                error!("the memtable cannot be flushed: {:?}", e);
+                FlushStatus::Failed(format!("{:?}", e))
+            }
+        };
+        *tables.flush.lock().unwrap() = status;
+        tables.flushed.notify_all();
+    }
+
+    drop(flushed);
+    if merger.join().is_err() {
+        error!("the merging thread panicked");
+    }
+}
+
+fn flush_failed(e: &str) -> KvsError {
+    KvsError::Io(io::Error::other(format!(
+        "the memtable cannot be flushed: {}",
+        e
+    )))
+}
+
+fn read_manifest(dir: &Path) -> Result<Manifest> {
+    let path = dir.join(MANIFEST);
+    if !path.exists() {
+        return Ok(Manifest::default());
+    }
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Returns the id and extension of the SSTables and write-ahead logs in `dir`.
+fn data_files(dir: &Path) -> Result<Vec<(u64, String)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let extension = match path.extension().and_then(|s| s.to_str()) {
+            Some(extension @ "sst") | Some(extension @ "wal") => extension.to_owned(),
+            _ => continue,
+        };
+        if let Some(id) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse().ok())
+        {
+            files.push((id, extension));
+        }
+    }
+    Ok(files)
+}
+
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
+
+fn remove_file(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        error!("{:?} cannot be deleted: {}", path, e);
+    }
+}
+
+fn entry_size(key: &str, value: Option<&str>) -> usize {
+    key.len() + value.map_or(0, str::len) + MEMTABLE_ENTRY_OVERHEAD
+}
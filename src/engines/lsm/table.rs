@@ -0,0 +1,329 @@
+use crate::engines::bloom::BloomFilter;
+use crate::{KvsError, Result};
+use memmap2::Mmap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str;
+use std::sync::Arc;
+
+/// Last 8 bytes of every SSTable, "kvs_sst1".
+const MAGIC: u64 = 0x6b76_735f_7373_7431;
+/// Offsets of the index and the filter, the number of entries and the magic number.
+const FOOTER_LEN: usize = 32;
+const TOMBSTONE: u8 = 0;
+const VALUE: u8 = 1;
+
+/// Writes the sorted entries of a new SSTable.
+///
+/// The file holds the entries in blocks of about `block_size` bytes, then the
+/// index of the blocks by their first key, the Bloom filter of the keys and the
+/// footer. An entry is its key, a tag telling a value from a tombstone, and the
+/// value, strings being prefixed by their length as a little endian `u32`.
+pub(super) struct TableBuilder {
+    writer: BufWriter<File>,
+    block_size: usize,
+    block: Vec<u8>,
+    // First key of the block being written
+    block_key: String,
+    index: Vec<u8>,
+    filter: BloomFilter,
+    offset: u64,
+    entries: u64,
+}
+
+impl TableBuilder {
+    /// Creates the file of an SSTable of at most `capacity` entries.
+    pub(super) fn create(
+        path: &Path,
+        capacity: usize,
+        block_size: usize,
+        fp_rate: f64,
+    ) -> Result<TableBuilder> {
+        Ok(TableBuilder {
+            writer: BufWriter::new(File::create(path)?),
+            block_size,
+            block: Vec::with_capacity(block_size),
+            block_key: String::new(),
+            index: Vec::new(),
+            filter: BloomFilter::new(capacity, fp_rate),
+            offset: 0,
+            entries: 0,
+        })
+    }
+
+    /// Adds an entry, after all the entries of smaller keys. `None` is a tombstone.
+    pub(super) fn add(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        if self.block.is_empty() {
+            self.block_key = key.to_owned();
+        }
+        put_str(&mut self.block, key);
+        match value {
+            Some(value) => {
+                self.block.push(VALUE);
+                put_str(&mut self.block, value);
+            }
+            None => self.block.push(TOMBSTONE),
+        }
+        self.filter.insert(key.as_bytes());
+        self.entries += 1;
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block)?;
+        put_str(&mut self.index, &self.block_key);
+        self.index.extend_from_slice(&self.offset.to_le_bytes());
+        self.index
+            .extend_from_slice(&(self.block.len() as u32).to_le_bytes());
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes the index, the filter and the footer, and syncs the file.
+    pub(super) fn finish(mut self) -> Result<()> {
+        self.finish_block()?;
+        let index_offset = self.offset;
+        self.writer.write_all(&self.index)?;
+        let filter_offset = index_offset + self.index.len() as u64;
+        self.filter.write_to(&mut self.writer)?;
+        for n in &[index_offset, filter_offset, self.entries, MAGIC] {
+            self.writer.write_all(&n.to_le_bytes())?;
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+/// An immutable SSTable, mapped in memory.
+pub(super) struct Table {
+    id: u64,
+    map: Mmap,
+    blocks: Vec<Block>,
+    filter: BloomFilter,
+    // End of the entries, where the index starts
+    data_end: usize,
+    entries: u64,
+}
+
+struct Block {
+    first_key: String,
+    offset: usize,
+    len: usize,
+}
+
+impl Table {
+    pub(super) fn open(path: &Path, id: u64) -> Result<Table> {
+        let file = File::open(path)?;
+        // An SSTable is never written again once finished, it is only removed once
+        // no longer listed in the manifest, which keeps the pages of existing maps.
+        let map = unsafe { Mmap::map(&file)? };
+        if map.len() < FOOTER_LEN {
+            return Err(corrupted(id));
+        }
+        let mut footer = Decoder::new(&map[map.len() - FOOTER_LEN..], id);
+        let index_offset = footer.u64()? as usize;
+        let filter_offset = footer.u64()? as usize;
+        let entries = footer.u64()?;
+        if footer.u64()? != MAGIC
+            || index_offset > filter_offset
+            || filter_offset > map.len() - FOOTER_LEN
+        {
+            return Err(corrupted(id));
+        }
+
+        let mut blocks = Vec::new();
+        let mut index = Decoder::new(&map[index_offset..filter_offset], id);
+        while !index.is_empty() {
+            let block = Block {
+                first_key: index.str()?.to_owned(),
+                offset: index.u64()? as usize,
+                len: index.u32()? as usize,
+            };
+            if block.offset + block.len > index_offset {
+                return Err(corrupted(id));
+            }
+            blocks.push(block);
+        }
+        let filter = BloomFilter::read_from(&map[filter_offset..map.len() - FOOTER_LEN])?;
+
+        Ok(Table {
+            id,
+            blocks,
+            filter,
+            data_end: index_offset,
+            entries,
+            map,
+        })
+    }
+
+    pub(super) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the number of entries, tombstones included.
+    pub(super) fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    /// Returns the entry of `key`, `Some(None)` being a tombstone, or `None` if the
+    /// table has no entry for it.
+    pub(super) fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        if !self.filter.may_contain(key.as_bytes()) {
+            return Ok(None);
+        }
+        let block = match self.block_of(key) {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        let mut decoder = Decoder::new(&self.map[block.offset..block.offset + block.len], self.id);
+        while !decoder.is_empty() {
+            let (entry_key, value) = decoder.entry()?;
+            if entry_key == key {
+                return Ok(Some(value.map(str::to_owned)));
+            }
+            if entry_key > key {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the last block starting at or before `key`.
+    fn block_of(&self, key: &str) -> Option<&Block> {
+        let i = self
+            .blocks
+            .partition_point(|block| block.first_key.as_str() <= key);
+        i.checked_sub(1).map(|i| &self.blocks[i])
+    }
+
+    /// Iterates over the entries from the first key not before `start`.
+    pub(super) fn iter_from(self: &Arc<Self>, start: &str) -> TableIter {
+        let at = self.block_of(start).map_or(0, |block| block.offset);
+        let mut iter = TableIter {
+            table: Arc::clone(self),
+            at,
+        };
+        // Skip the entries of the block before `start`
+        while let Some(key) = iter.peek_key() {
+            if key >= start {
+                break;
+            }
+            iter.next();
+        }
+        iter
+    }
+}
+
+/// Iterator over the entries of a table, in ascending key order.
+pub(super) struct TableIter {
+    table: Arc<Table>,
+    at: usize,
+}
+
+impl TableIter {
+    fn decoder(&self) -> Decoder<'_> {
+        let mut decoder = Decoder::new(&self.table.map[..self.table.data_end], self.table.id);
+        decoder.at = self.at;
+        decoder
+    }
+
+    fn peek_key(&self) -> Option<&str> {
+        let mut decoder = self.decoder();
+        if decoder.is_empty() {
+            return None;
+        }
+        decoder.str().ok()
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Result<(String, Option<String>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut decoder = self.decoder();
+        if decoder.is_empty() {
+            return None;
+        }
+        let entry = decoder
+            .entry()
+            .map(|(key, value)| (key.to_owned(), value.map(str::to_owned)));
+        // A corrupted entry ends the iteration after its error
+        self.at = if entry.is_ok() {
+            decoder.at
+        } else {
+            self.table.data_end
+        };
+        Some(entry)
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    at: usize,
+    id: u64,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8], id: u64) -> Self {
+        Decoder { data, at: 0, id }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.at >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.at..self.at + len)
+            .ok_or_else(|| corrupted(self.id))?;
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'a str> {
+        let len = self.u32()? as usize;
+        let bytes = self.bytes(len)?;
+        str::from_utf8(bytes).map_err(|_| corrupted(self.id))
+    }
+
+    fn entry(&mut self) -> Result<(&'a str, Option<&'a str>)> {
+        let key = self.str()?;
+        match self.bytes(1)?[0] {
+            VALUE => Ok((key, Some(self.str()?))),
+            TOMBSTONE => Ok((key, None)),
+            _ => Err(corrupted(self.id)),
+        }
+    }
+}
+
+fn put_str(data: &mut Vec<u8>, s: &str) {
+    data.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    data.extend_from_slice(s.as_bytes());
+}
+
+fn corrupted(id: u64) -> KvsError {
+    KvsError::StringError(format!("SSTable {} is corrupted", id))
+}
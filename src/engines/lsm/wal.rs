@@ -0,0 +1,71 @@
+use super::Memtable;
+use crate::{Durability, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Record {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+/// The write-ahead log of the memtable, holding the writes not yet in an SSTable.
+pub(super) struct Wal {
+    writer: BufWriter<File>,
+    durability: Durability,
+}
+
+impl Wal {
+    pub(super) fn create(dir: &Path, id: u64, durability: Durability) -> Result<Wal> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(wal_path(dir, id))?;
+        Ok(Wal {
+            writer: BufWriter::new(file),
+            durability,
+        })
+    }
+
+    pub(super) fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.append(&Record::Set { key, value })
+    }
+
+    pub(super) fn remove(&mut self, key: String) -> Result<()> {
+        self.append(&Record::Remove { key })
+    }
+
+    fn append(&mut self, record: &Record) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, record)?;
+        self.writer.flush()?;
+        if self.durability == Durability::Sync {
+            self.writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// Replays the log of `id` into the memtable, returns the bytes it added.
+///
+/// A partly written record at the end of the log is ignored.
+pub(super) fn replay(dir: &Path, id: u64, memtable: &mut Memtable) -> Result<usize> {
+    let reader = BufReader::new(File::open(wal_path(dir, id))?);
+    let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Record>();
+    let mut bytes = 0;
+    loop {
+        let (key, value) = match stream.next() {
+            Some(Ok(Record::Set { key, value })) => (key, Some(value)),
+            Some(Ok(Record::Remove { key })) => (key, None),
+            Some(Err(e)) if !e.is_eof() => return Err(e.into()),
+            _ => return Ok(bytes),
+        };
+        bytes += super::entry_size(&key, value.as_deref());
+        memtable.insert(key, value);
+    }
+}
+
+pub(super) fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
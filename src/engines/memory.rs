@@ -0,0 +1,151 @@
+use super::KvsEngine;
+use crate::replication::LogOp;
+use crate::watch::{ChangeFeed, Watcher};
+use crate::{KvsError, Result};
+use log::error;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// The `MemoryKvsEngine` keeps string key/value pairs in memory only.
+///
+/// Opened with a snapshot file, it loads the pairs from it and writes them back
+/// when its last clone is dropped. Otherwise nothing outlives the process.
+#[derive(Clone)]
+pub struct MemoryKvsEngine {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    pairs: RwLock<BTreeMap<String, String>>,
+    // Locked before `pairs` by writes, so they are published in order
+    changes: Mutex<ChangeFeed>,
+    snapshot: Option<PathBuf>,
+}
+
+impl MemoryKvsEngine {
+    /// Creates an empty `MemoryKvsEngine`.
+    pub fn new() -> Self {
+        MemoryKvsEngine::with_pairs(BTreeMap::new(), ChangeFeed::new(), None)
+    }
+
+    /// Creates a `MemoryKvsEngine` loading the pairs of the given snapshot file, if it
+    /// exists, and saving them to it when dropped.
+    ///
+    /// The sequence numbers of its writes are persisted next to it, with the `seq`
+    /// extension.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during reading the snapshot.
+    pub fn with_snapshot(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let pairs = if path.exists() {
+            serde_json::from_slice(&fs::read(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        let changes = ChangeFeed::open(path.with_extension("seq"))?;
+        Ok(MemoryKvsEngine::with_pairs(pairs, changes, Some(path)))
+    }
+
+    fn with_pairs(
+        pairs: BTreeMap<String, String>,
+        changes: ChangeFeed,
+        snapshot: Option<PathBuf>,
+    ) -> Self {
+        MemoryKvsEngine {
+            inner: Arc::new(Inner {
+                pairs: RwLock::new(pairs),
+                changes: Mutex::new(changes),
+                snapshot,
+            }),
+        }
+    }
+
+    /// Writes the pairs to the snapshot file now. Does nothing without one.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the snapshot.
+    pub fn save(&self) -> Result<()> {
+        self.inner.save()
+    }
+}
+
+impl Default for MemoryKvsEngine {
+    fn default() -> Self {
+        MemoryKvsEngine::new()
+    }
+}
+
+impl Inner {
+    fn save(&self) -> Result<()> {
+        let path = match &self.snapshot {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, &*self.pairs.read().unwrap())?;
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            error!("snapshot {:?} cannot be saved: {}", self.snapshot, e);
+        }
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let mut changes = self.inner.changes.lock().unwrap();
+        self.inner
+            .pairs
+            .write()
+            .unwrap()
+            .insert(key.clone(), value.clone());
+        changes.publish(LogOp::Set { key, value })
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.inner.pairs.read().unwrap().get(&key).cloned())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut changes = self.inner.changes.lock().unwrap();
+        self.inner
+            .pairs
+            .write()
+            .unwrap()
+            .remove(&key)
+            .ok_or(KvsError::KeyNotFound)?;
+        changes.publish(LogOp::Remove { key })
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        Ok(self
+            .inner
+            .pairs
+            .read()
+            .unwrap()
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    fn watch(&self, prefix: &str, from: Option<u64>) -> Result<Watcher> {
+        self.inner.changes.lock().unwrap().watch(prefix, from)
+    }
+}
@@ -1,6 +1,8 @@
 //! This module provides various key value storage engines.
 
-use crate::Result;
+use crate::replication::{LogPosition, LogRead};
+use crate::watch::Watcher;
+use crate::{KvsError, Result};
 
 /// Trait for a key value storage engines.
 pub trait KvsEngine: Clone + Send + 'static {
@@ -20,10 +22,62 @@ pub trait KvsEngine: Clone + Send + 'static {
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Returns all keys starting with `prefix`, in ascending order.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if the engine cannot list its keys.
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let _ = prefix;
+        Err(KvsError::Unsupported("Listing keys".into()))
+    }
+
+    /// Reads at most `limit` entries of the write log, starting at `from`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if the engine has no write log.
+    fn read_log(&self, from: LogPosition, limit: usize) -> Result<LogRead> {
+        let _ = (from, limit);
+        Err(KvsError::Unsupported("Reading the write log".into()))
+    }
+
+    /// Returns every key/value pair together with the log position right after the
+    /// last write they include.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if the engine has no write log.
+    fn snapshot(&self) -> Result<(LogPosition, Vec<(String, String)>)> {
+        Err(KvsError::Unsupported("Snapshotting the write log".into()))
+    }
+
+    /// Watches the writes to the keys starting with `prefix`.
+    ///
+    /// With `from`, the watcher first receives the writes following that sequence
+    /// number, otherwise only the writes made from now on.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::SequenceExpired` if the writes after `from` are no
+    /// longer kept, as after a restart, and `KvsError::Unsupported` if the engine cannot be watched.
+    fn watch(&self, prefix: &str, from: Option<u64>) -> Result<Watcher> {
+        let _ = (prefix, from);
+        Err(KvsError::Unsupported("Watching".into()))
+    }
 }
 
-mod kvs;
+mod bloom;
+pub(crate) mod kvs;
+mod lsm;
+mod memory;
 mod sled;
 
-pub use self::kvs::KvStore;
+pub use self::kvs::{
+    CacheStats, Compression, CompressionStats, Durability, IndexKind, KvStore, KvStoreOptions,
+    ReadMode,
+};
+pub use self::lsm::{LsmKvsEngine, LsmOptions};
+pub use self::memory::MemoryKvsEngine;
 pub use self::sled::SledKvsEngine;
@@ -36,4 +36,12 @@ impl KvsEngine for SledKvsEngine {
         tree.flush()?;
         Ok(())
     }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let tree: &Tree = &self.0;
+        tree.scan_prefix(prefix)
+            .keys()
+            .map(|key| Ok(String::from_utf8(AsRef::<[u8]>::as_ref(&key?).to_vec())?))
+            .collect()
+    }
 }
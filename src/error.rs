@@ -1,4 +1,8 @@
+use crate::replication::LogPosition;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::io;
+use std::time::Duration;
 use thiserror::Error;
 
 /// Custom defined `Error` type.
@@ -28,9 +32,122 @@ pub enum KvsError {
     #[error("From utf8 error")]
     FromUtf8(#[from] std::string::FromUtf8Error),
 
+    /// Key or value larger than the configured limit
+    #[error("{0} size {1} exceeds the limit of {2} bytes")]
+    TooLarge(&'static str, usize, usize),
+
+    /// Write sent to a read-only replica
+    #[error("Server is a read-only replica")]
+    ReadOnly,
+
+    /// Request sent to a cluster node that is not the leader, with the leader
+    /// address if known
+    #[error("Server is not the cluster leader")]
+    NotLeader(Option<String>),
+
+    /// Cluster request sent to a server outside cluster mode
+    #[error("Server is not running in cluster mode")]
+    NotClustered,
+
+    /// Operation not supported by the engine
+    #[error("{0} is not supported by this engine")]
+    Unsupported(Cow<'static, str>),
+
+    /// Watch resumed from a sequence number whose following changes are gone
+    #[error("Changes after sequence number {0} are no longer available")]
+    SequenceExpired(u64),
+
+    /// Job rejected by a thread pool whose queue is full, or request rejected by
+    /// an overloaded server
+    #[error("Server is busy")]
+    Busy,
+
+    /// Job spawned into a thread pool that is shut down
+    #[error("Thread pool is shut down")]
+    PoolShutDown,
+
+    /// Thread pool whose jobs were still running when its shutdown timed out
+    #[error("Thread pool jobs still running after {0:?}")]
+    ShutdownTimeout(Duration),
+
+    /// Request whose handling panicked on the server
+    #[error("Internal server error")]
+    Internal,
+
+    /// Log position removed by a compaction
+    #[error("Log position {0:?} has been compacted away")]
+    Compacted(LogPosition),
+
+    /// Malformed record in a key/value file
+    #[error("line {line}: {message}")]
+    Format {
+        /// Line the record starts on
+        line: u64,
+        /// What is wrong with the record
+        message: String,
+    },
+
+    /// Invalid configuration error
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
     /// String error
     #[error("String error `{0}`")]
     StringError(String),
+
+    /// Error returned by a server which sent too little to rebuild it, the message
+    /// is the one of the server side error
+    #[error("{message}")]
+    Remote {
+        /// Kind of the error
+        code: ErrorCode,
+        /// Description of the error by the server
+        message: String,
+    },
+}
+
+/// Kind of an error sent by the server to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// Key not found
+    KeyNotFound,
+    /// Key or value larger than the limit of the server
+    TooLarge,
+    /// Write sent to a read-only replica
+    ReadOnly,
+    /// Request sent to a cluster node that is not the leader
+    NotLeader,
+    /// Cluster request sent to a server outside cluster mode
+    NotClustered,
+    /// Operation not supported by the engine of the server
+    Unsupported,
+    /// Watch resumed from a sequence number whose following changes are gone
+    SequenceExpired,
+    /// Server too busy to serve the request
+    Busy,
+    /// I/O failure on the server
+    Io,
+    /// Any other failure on the server
+    Internal,
+}
+
+impl KvsError {
+    /// Returns the code of the error when sent over the wire.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::TooLarge(..) => ErrorCode::TooLarge,
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            KvsError::NotLeader(_) => ErrorCode::NotLeader,
+            KvsError::NotClustered => ErrorCode::NotClustered,
+            KvsError::Unsupported(_) => ErrorCode::Unsupported,
+            KvsError::SequenceExpired(_) => ErrorCode::SequenceExpired,
+            KvsError::Busy => ErrorCode::Busy,
+            KvsError::Io(_) | KvsError::Sled(_) => ErrorCode::Io,
+            KvsError::Remote { code, .. } => *code,
+            _ => ErrorCode::Internal,
+        }
+    }
 }
 
 /// Custom defined `Result` type.
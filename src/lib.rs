@@ -1,14 +1,29 @@
 #![deny(missing_docs)]
 //! A simple key/value store.
 
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
-pub use error::{KvsError, Result};
+pub use client::{KvsClient, KvsClientOptions, WatchStream};
+pub use client_pool::{KvsClientPool, KvsClientPoolOptions, PooledClient};
+pub use engines::{
+    CacheStats, Compression, CompressionStats, Durability, IndexKind, KvStore, KvStoreOptions,
+    KvsEngine, LsmKvsEngine, LsmOptions, MemoryKvsEngine, ReadMode, SledKvsEngine,
+};
+pub use error::{ErrorCode, KvsError, Result};
 pub use server::KvsServer;
+pub use sharding::ShardedKvsClient;
 
+pub mod bulk;
+pub mod cdc;
 mod client;
+mod client_pool;
 mod common;
+pub mod config;
 mod engines;
 mod error;
+pub mod metrics;
+pub mod raft;
+pub mod replication;
 mod server;
+pub mod sharding;
 pub mod thread_pool;
+pub mod tls;
+pub mod watch;
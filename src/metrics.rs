@@ -0,0 +1,666 @@
+//! This module provides process-wide metrics exported in the Prometheus text format.
+
+use crate::Result;
+use lazy_static::lazy_static;
+use log::{debug, error};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 10] = [
+    0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0,
+];
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Returns the metrics registry shared by the whole process.
+pub fn global() -> &'static Metrics {
+    &METRICS
+}
+
+/// A monotonically increasing counter.
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    /// Increments the counter by one.
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    /// Increments the counter by `n`.
+    pub fn inc_by(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Returns the current value.
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that can go up and down.
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    /// Increments the gauge by one.
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Decrements the gauge by one.
+    pub fn dec(&self) {
+        self.add(-1);
+    }
+
+    /// Adds `n` to the gauge, `n` may be negative.
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Sets the gauge to `n`.
+    pub fn set(&self, n: i64) {
+        self.0.store(n, Ordering::Relaxed);
+    }
+
+    /// Returns the current value.
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Gauges labeled by the data directory of the store they measure.
+#[derive(Default)]
+pub struct DirGauges(Mutex<BTreeMap<String, i64>>);
+
+impl DirGauges {
+    /// Sets the gauge of `dir` to `n`.
+    pub fn set(&self, dir: &Path, n: i64) {
+        self.0.lock().unwrap().insert(dir.display().to_string(), n);
+    }
+
+    /// Removes the gauge of `dir`, once its store is closed.
+    pub fn remove(&self, dir: &Path) {
+        self.0.lock().unwrap().remove(&dir.display().to_string());
+    }
+
+    /// Returns the current value of the gauge of `dir`, `None` if it has none.
+    pub fn get(&self, dir: &Path) -> Option<i64> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(&dir.display().to_string())
+            .copied()
+    }
+}
+
+/// A latency histogram with fixed buckets.
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    /// Records one observation.
+    pub fn observe(&self, d: Duration) {
+        let secs = d.as_secs_f64();
+        for (bucket, &bound) in self.buckets.iter().zip(BUCKETS.iter()) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(d.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the number of observations.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+/// Kind of a client request, used as the `op` label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// `get` request
+    Get,
+    /// `set` request
+    Set,
+    /// `remove` request
+    Remove,
+}
+
+impl Op {
+    const ALL: [Op; 3] = [Op::Get, Op::Set, Op::Remove];
+
+    /// Returns the label value of the op.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Op::Get => "get",
+            Op::Set => "set",
+            Op::Remove => "remove",
+        }
+    }
+}
+
+/// Metrics of a single kind of request.
+#[derive(Default)]
+pub struct OpMetrics {
+    /// Requests received.
+    pub requests: Counter,
+    /// Requests answered with an error.
+    pub errors: Counter,
+    /// Time spent serving the request, including the response write.
+    pub duration: Histogram,
+    /// Time spent inside the storage engine.
+    pub engine_duration: Histogram,
+}
+
+/// All metrics collected by the key value store.
+#[derive(Default)]
+pub struct Metrics {
+    get: OpMetrics,
+    set: OpMetrics,
+    remove: OpMetrics,
+    /// Client connections currently being served.
+    pub connections: Gauge,
+    /// Connections accepted but not yet picked up by a thread pool worker.
+    pub queued_jobs: Gauge,
+    /// Connections turned away because the thread pool was full.
+    pub rejected_connections: Counter,
+    /// Connections whose thread pool job panicked.
+    pub panicked_connections: Counter,
+    /// Compactions finished by `KvStore`.
+    pub compactions: Counter,
+    /// Time spent compacting.
+    pub compaction_duration: Histogram,
+    /// Stale log bytes reclaimed by compaction.
+    pub compaction_reclaimed_bytes: Counter,
+    /// Size of the log files on disk of each open `KvStore`.
+    pub disk_bytes: DirGauges,
+    /// Estimated memory used by the `KvStore` index of the keys.
+    pub index_bytes: Gauge,
+    /// `KvStore` reads answered by the value cache.
+    pub cache_hits: Counter,
+    /// `KvStore` reads missing the value cache.
+    pub cache_misses: Counter,
+    /// `KvStore` reads of values too large for the value cache.
+    pub cache_bypassed: Counter,
+    /// `KvStore` reads of absent keys answered by the Bloom filters.
+    pub bloom_avoided_reads: Counter,
+    /// `KvStore` reads of absent keys the Bloom filters did not rule out.
+    pub bloom_false_positives: Counter,
+    /// 1 while the server is a replica following a primary.
+    pub replication_following: Gauge,
+    /// 1 while a replica is connected to its primary.
+    pub replication_connected: Gauge,
+    /// Log entries applied by a replica.
+    pub replication_applied: Counter,
+    /// Snapshots applied by a replica.
+    pub replication_snapshots: Counter,
+    /// Bytes of the primary log a replica has yet to apply.
+    pub replication_lag_bytes: Gauge,
+    /// Current Raft term of a cluster node.
+    pub raft_term: Gauge,
+    /// 1 while the cluster node is the leader.
+    pub raft_leader: Gauge,
+    /// Index of the last committed Raft log entry.
+    pub raft_commit_index: Gauge,
+    /// Index of the last Raft log entry applied to the engine.
+    pub raft_applied_index: Gauge,
+    /// Snapshots installed from the leader.
+    pub raft_snapshots_installed: Counter,
+}
+
+impl Metrics {
+    /// Returns the metrics of the given op.
+    pub fn op(&self, op: Op) -> &OpMetrics {
+        match op {
+            Op::Get => &self.get,
+            Op::Set => &self.set,
+            Op::Remove => &self.remove,
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "kvs_requests_total",
+            "counter",
+            "Requests received.",
+        );
+        for &op in Op::ALL.iter() {
+            let value = self.op(op).requests.get();
+            sample(&mut out, "kvs_requests_total", &op_label(op), value);
+        }
+
+        header(
+            &mut out,
+            "kvs_request_errors_total",
+            "counter",
+            "Requests answered with an error.",
+        );
+        for &op in Op::ALL.iter() {
+            let value = self.op(op).errors.get();
+            sample(&mut out, "kvs_request_errors_total", &op_label(op), value);
+        }
+
+        header(
+            &mut out,
+            "kvs_request_duration_seconds",
+            "histogram",
+            "Time spent serving requests.",
+        );
+        for &op in Op::ALL.iter() {
+            histogram(
+                &mut out,
+                "kvs_request_duration_seconds",
+                &op_label(op),
+                &self.op(op).duration,
+            );
+        }
+
+        header(
+            &mut out,
+            "kvs_engine_duration_seconds",
+            "histogram",
+            "Time spent inside the storage engine.",
+        );
+        for &op in Op::ALL.iter() {
+            histogram(
+                &mut out,
+                "kvs_engine_duration_seconds",
+                &op_label(op),
+                &self.op(op).engine_duration,
+            );
+        }
+
+        header(
+            &mut out,
+            "kvs_connections",
+            "gauge",
+            "Client connections currently being served.",
+        );
+        sample(&mut out, "kvs_connections", "", self.connections.get());
+
+        header(
+            &mut out,
+            "kvs_pool_queued_jobs",
+            "gauge",
+            "Connections waiting for a thread pool worker.",
+        );
+        sample(&mut out, "kvs_pool_queued_jobs", "", self.queued_jobs.get());
+
+        header(
+            &mut out,
+            "kvs_pool_rejected_connections_total",
+            "counter",
+            "Connections turned away because the thread pool was full.",
+        );
+        sample(
+            &mut out,
+            "kvs_pool_rejected_connections_total",
+            "",
+            self.rejected_connections.get(),
+        );
+
+        header(
+            &mut out,
+            "kvs_pool_panicked_connections_total",
+            "counter",
+            "Connections whose thread pool job panicked.",
+        );
+        sample(
+            &mut out,
+            "kvs_pool_panicked_connections_total",
+            "",
+            self.panicked_connections.get(),
+        );
+
+        header(
+            &mut out,
+            "kvs_compactions_total",
+            "counter",
+            "Compactions finished.",
+        );
+        sample(
+            &mut out,
+            "kvs_compactions_total",
+            "",
+            self.compactions.get(),
+        );
+
+        header(
+            &mut out,
+            "kvs_compaction_duration_seconds",
+            "histogram",
+            "Time spent compacting.",
+        );
+        histogram(
+            &mut out,
+            "kvs_compaction_duration_seconds",
+            "",
+            &self.compaction_duration,
+        );
+
+        header(
+            &mut out,
+            "kvs_compaction_reclaimed_bytes_total",
+            "counter",
+            "Stale log bytes reclaimed by compaction.",
+        );
+        sample(
+            &mut out,
+            "kvs_compaction_reclaimed_bytes_total",
+            "",
+            self.compaction_reclaimed_bytes.get(),
+        );
+
+        header(
+            &mut out,
+            "kvs_disk_bytes",
+            "gauge",
+            "Size of the log files on disk, by data directory.",
+        );
+        for (dir, bytes) in self.disk_bytes.0.lock().unwrap().iter() {
+            let label = format!("dir=\"{}\"", escape_label(dir));
+            sample(&mut out, "kvs_disk_bytes", &label, bytes);
+        }
+
+        header(
+            &mut out,
+            "kvs_index_memory_bytes",
+            "gauge",
+            "Estimated memory used by the index of the keys.",
+        );
+        sample(
+            &mut out,
+            "kvs_index_memory_bytes",
+            "",
+            self.index_bytes.get(),
+        );
+
+        header(
+            &mut out,
+            "kvs_cache_hits_total",
+            "counter",
+            "Reads answered by the value cache.",
+        );
+        sample(&mut out, "kvs_cache_hits_total", "", self.cache_hits.get());
+
+        header(
+            &mut out,
+            "kvs_cache_misses_total",
+            "counter",
+            "Reads missing the value cache.",
+        );
+        sample(
+            &mut out,
+            "kvs_cache_misses_total",
+            "",
+            self.cache_misses.get(),
+        );
+
+        header(
+            &mut out,
+            "kvs_cache_bypassed_total",
+            "counter",
+            "Reads of values too large for the value cache.",
+        );
+        sample(
+            &mut out,
+            "kvs_cache_bypassed_total",
+            "",
+            self.cache_bypassed.get(),
+        );
+
+        header(
+            &mut out,
+            "kvs_bloom_avoided_reads_total",
+            "counter",
+            "Reads of absent keys answered by the Bloom filters.",
+        );
+        sample(
+            &mut out,
+            "kvs_bloom_avoided_reads_total",
+            "",
+            self.bloom_avoided_reads.get(),
+        );
+
+        header(
+            &mut out,
+            "kvs_bloom_false_positives_total",
+            "counter",
+            "Reads of absent keys the Bloom filters did not rule out.",
+        );
+        sample(
+            &mut out,
+            "kvs_bloom_false_positives_total",
+            "",
+            self.bloom_false_positives.get(),
+        );
+
+        header(
+            &mut out,
+            "kvs_replication_following",
+            "gauge",
+            "1 while the server is a replica following a primary.",
+        );
+        sample(
+            &mut out,
+            "kvs_replication_following",
+            "",
+            self.replication_following.get(),
+        );
+
+        header(
+            &mut out,
+            "kvs_replication_connected",
+            "gauge",
+            "1 while the replica is connected to its primary.",
+        );
+        sample(
+            &mut out,
+            "kvs_replication_connected",
+            "",
+            self.replication_connected.get(),
+        );
+
+        header(
+            &mut out,
+            "kvs_replication_applied_total",
+            "counter",
+            "Log entries applied by the replica.",
+        );
+        sample(
+            &mut out,
+            "kvs_replication_applied_total",
+            "",
+            self.replication_applied.get(),
+        );
+
+        header(
+            &mut out,
+            "kvs_replication_snapshots_total",
+            "counter",
+            "Snapshots applied by the replica.",
+        );
+        sample(
+            &mut out,
+            "kvs_replication_snapshots_total",
+            "",
+            self.replication_snapshots.get(),
+        );
+
+        header(
+            &mut out,
+            "kvs_replication_lag_bytes",
+            "gauge",
+            "Bytes of the primary log the replica has yet to apply.",
+        );
+        sample(
+            &mut out,
+            "kvs_replication_lag_bytes",
+            "",
+            self.replication_lag_bytes.get(),
+        );
+
+        header(
+            &mut out,
+            "kvs_raft_term",
+            "gauge",
+            "Current Raft term of the cluster node.",
+        );
+        sample(&mut out, "kvs_raft_term", "", self.raft_term.get());
+
+        header(
+            &mut out,
+            "kvs_raft_leader",
+            "gauge",
+            "1 while the cluster node is the leader.",
+        );
+        sample(&mut out, "kvs_raft_leader", "", self.raft_leader.get());
+
+        header(
+            &mut out,
+            "kvs_raft_commit_index",
+            "gauge",
+            "Index of the last committed Raft log entry.",
+        );
+        sample(
+            &mut out,
+            "kvs_raft_commit_index",
+            "",
+            self.raft_commit_index.get(),
+        );
+
+        header(
+            &mut out,
+            "kvs_raft_applied_index",
+            "gauge",
+            "Index of the last Raft log entry applied to the engine.",
+        );
+        sample(
+            &mut out,
+            "kvs_raft_applied_index",
+            "",
+            self.raft_applied_index.get(),
+        );
+
+        header(
+            &mut out,
+            "kvs_raft_snapshots_installed_total",
+            "counter",
+            "Snapshots installed from the leader.",
+        );
+        sample(
+            &mut out,
+            "kvs_raft_snapshots_installed_total",
+            "",
+            self.raft_snapshots_installed.get(),
+        );
+
+        out
+    }
+}
+
+fn op_label(op: Op) -> String {
+    format!("op=\"{}\"", op.as_str())
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample<V: std::fmt::Display>(out: &mut String, name: &str, labels: &str, value: V) {
+    if labels.is_empty() {
+        let _ = writeln!(out, "{} {}", name, value);
+    } else {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+fn histogram(out: &mut String, name: &str, labels: &str, h: &Histogram) {
+    let sep = if labels.is_empty() { "" } else { "," };
+    let bucket_name = format!("{}_bucket", name);
+    for (bucket, bound) in h.buckets.iter().zip(BUCKETS.iter()) {
+        let le = format!("{}{}le=\"{}\"", labels, sep, bound);
+        sample(out, &bucket_name, &le, bucket.load(Ordering::Relaxed));
+    }
+    let le = format!("{}{}le=\"+Inf\"", labels, sep);
+    sample(out, &bucket_name, &le, h.count());
+    let sum = h.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+    sample(out, &format!("{}_sum", name), labels, sum);
+    sample(out, &format!("{}_count", name), labels, h.count());
+}
+
+/// Serves the global metrics over HTTP on the given address.
+///
+/// Every `GET /metrics` request is answered with the Prometheus text format, any other
+/// path gets a `404`. The listener runs on its own thread until the process exits.
+pub fn serve_http<A: ToSocketAddrs>(addr: A) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)?;
+    let handle = thread::spawn(move || {
+        for stream in listener.incoming() {
+            let res = stream.map_err(Into::into).and_then(answer_http);
+            if let Err(e) = res {
+                error!("error on serving metrics: {}", e);
+            }
+        }
+    });
+    Ok(handle)
+}
+
+fn answer_http(stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    debug!("metrics request: {}", request_line.trim_end());
+
+    // Drain the headers, the body of a GET request is empty.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", global().render()),
+        _ => ("404 Not Found", String::from("not found\n")),
+    };
+
+    let mut writer = &stream;
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    writer.flush()?;
+    Ok(())
+}
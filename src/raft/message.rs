@@ -0,0 +1,95 @@
+//! Log entries and messages exchanged by the nodes of a cluster.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A write replicated through the Raft log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    /// Written by a new leader to commit the entries of earlier terms.
+    Noop,
+    /// Sets the key to the value.
+    Set { key: String, value: String },
+    /// Removes the key.
+    Remove { key: String },
+    /// Replaces the cluster members, ids mapped to addresses.
+    Config(BTreeMap<u64, String>),
+}
+
+/// An entry of the Raft log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub index: u64,
+    pub term: u64,
+    pub command: Command,
+}
+
+/// Describes the log prefix replaced by the engine contents.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SnapshotMeta {
+    /// Index of the last entry included.
+    pub index: u64,
+    /// Term of the last entry included.
+    pub term: u64,
+    /// Cluster members as of the last entry included.
+    pub members: BTreeMap<u64, String>,
+}
+
+/// A message together with its sender, so that nodes which do not know the sender
+/// yet can still answer.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    pub from: u64,
+    pub addr: String,
+    pub to: u64,
+    pub msg: Message,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Message {
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+        seq: u64,
+    },
+    InstallSnapshot {
+        term: u64,
+        meta: SnapshotMeta,
+        data: Vec<(String, String)>,
+        seq: u64,
+    },
+    /// Answers both `AppendEntries` and `InstallSnapshot`.
+    ///
+    /// `match_index` is the last index known to match the leader on success, and a
+    /// hint of where to retry from on failure.
+    AppendResponse {
+        term: u64,
+        success: bool,
+        match_index: u64,
+        seq: u64,
+    },
+}
+
+impl Message {
+    pub fn term(&self) -> u64 {
+        match *self {
+            Message::RequestVote { term, .. }
+            | Message::Vote { term, .. }
+            | Message::AppendEntries { term, .. }
+            | Message::InstallSnapshot { term, .. }
+            | Message::AppendResponse { term, .. } => term,
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! This module provides a strongly consistent cluster mode based on Raft.
+//!
+//! Every write goes through the replicated Raft log and is applied to the
+//! `KvsEngine` of each node once a majority of the members has stored it. Reads are
+//! answered by the leader after it has confirmed with a majority that it still
+//! leads. The other nodes redirect clients to the leader.
+//!
+//! Nodes talk to each other over the client port of `KvsServer`. The log is
+//! truncated once it grows past `RaftConfig::snapshot_threshold` entries, and
+//! followers too far behind receive the engine contents instead.
+//!
+//! Members are added and removed one at a time through the leader. A new node is
+//! started with no members and waits for the leader to contact it.
+
+use crate::tls::TlsConnector;
+use crate::{KvsEngine, KvsError, Result};
+use crossbeam::channel::{self, RecvTimeoutError, Sender};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+pub(crate) use self::message::{Command, Envelope};
+use self::node::{Event, MemberChange, Node};
+
+mod message;
+mod node;
+mod storage;
+mod transport;
+
+/// How long a client request waits for the cluster to commit it.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Configuration of a cluster node.
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    /// Id of this node, unique in the cluster.
+    pub id: u64,
+    /// Initial members of the cluster, ids mapped to addresses, including this node.
+    ///
+    /// Only used when the node starts with an empty log. A node joining an existing
+    /// cluster starts with no members.
+    pub members: BTreeMap<u64, String>,
+    /// Directory holding the Raft log and state.
+    pub dir: PathBuf,
+    /// A follower starts an election after hearing nothing from the leader for a
+    /// random time between one and two election timeouts.
+    pub election_timeout: Duration,
+    /// How often the leader contacts idle followers.
+    pub heartbeat_interval: Duration,
+    /// Number of log entries that triggers a truncation of the applied entries.
+    pub snapshot_threshold: usize,
+    /// Network faults to simulate, for testing.
+    pub network: Network,
+}
+
+impl RaftConfig {
+    /// Creates the configuration of node `id` storing its state in `dir`.
+    pub fn new(id: u64, dir: PathBuf) -> Self {
+        RaftConfig {
+            id,
+            members: BTreeMap::new(),
+            dir,
+            election_timeout: Duration::from_millis(300),
+            heartbeat_interval: Duration::from_millis(50),
+            snapshot_threshold: 10_000,
+            network: Network::default(),
+        }
+    }
+}
+
+/// Simulated network faults between the nodes of a cluster.
+///
+/// All the nodes of an in-process cluster share one `Network`. Messages between
+/// nodes it separates are dropped, as a real network partition would. Client
+/// connections are not affected.
+#[derive(Debug, Clone, Default)]
+pub struct Network {
+    faults: Arc<Mutex<Faults>>,
+}
+
+#[derive(Debug, Default)]
+struct Faults {
+    isolated: HashSet<u64>,
+    cut: HashSet<(u64, u64)>,
+}
+
+impl Network {
+    /// Creates a network without faults.
+    pub fn new() -> Self {
+        Network::default()
+    }
+
+    /// Drops every message from and to node `id`.
+    pub fn isolate(&self, id: u64) {
+        self.faults.lock().unwrap().isolated.insert(id);
+    }
+
+    /// Drops every message between a node of `a` and a node of `b`.
+    pub fn partition(&self, a: &[u64], b: &[u64]) {
+        let mut faults = self.faults.lock().unwrap();
+        for &x in a {
+            for &y in b {
+                faults.cut.insert((x, y));
+                faults.cut.insert((y, x));
+            }
+        }
+    }
+
+    /// Removes all faults.
+    pub fn heal(&self) {
+        let mut faults = self.faults.lock().unwrap();
+        faults.isolated.clear();
+        faults.cut.clear();
+    }
+
+    /// Returns whether a message from `from` to `to` gets through.
+    pub fn delivers(&self, from: u64, to: u64) -> bool {
+        let faults = self.faults.lock().unwrap();
+        !faults.isolated.contains(&from)
+            && !faults.isolated.contains(&to)
+            && !faults.cut.contains(&(from, to))
+    }
+}
+
+/// Role of a cluster node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NodeState {
+    /// Replicates the log of the leader.
+    Follower,
+    /// Asks the other members to elect it.
+    Candidate,
+    /// Accepts the writes and replicates them.
+    Leader,
+}
+
+impl fmt::Display for NodeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            NodeState::Follower => "follower",
+            NodeState::Candidate => "candidate",
+            NodeState::Leader => "leader",
+        };
+        f.write_str(s)
+    }
+}
+
+/// State of a cluster node as seen by itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterStatus {
+    /// Id of the node.
+    pub id: u64,
+    /// Role of the node.
+    pub state: NodeState,
+    /// Current term.
+    pub term: u64,
+    /// Id of the leader, if known.
+    pub leader: Option<u64>,
+    /// Members, ids mapped to addresses.
+    pub members: BTreeMap<u64, String>,
+    /// Index of the last committed entry.
+    pub commit_index: u64,
+    /// Index of the last entry applied to the engine.
+    pub applied_index: u64,
+    /// Index of the last entry replaced by the engine contents.
+    pub snapshot_index: u64,
+}
+
+/// Handle to the Raft node of a server.
+#[derive(Clone)]
+pub(crate) struct Raft {
+    inbox: Sender<Event>,
+    handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl Raft {
+    /// Starts the node, `addr` is where the other nodes reach this one, over TLS
+    /// with `tls`.
+    pub(crate) fn start<E: KvsEngine>(
+        config: RaftConfig,
+        addr: String,
+        engine: E,
+        tls: Option<TlsConnector>,
+    ) -> Result<Raft> {
+        let (inbox, receiver) = channel::unbounded();
+        let node = Node::new(config, addr, engine, receiver, tls)?;
+        let handle = thread::spawn(move || node.run());
+        Ok(Raft {
+            inbox,
+            handle: Arc::new(Mutex::new(Some(handle))),
+        })
+    }
+
+    /// Hands a message of another node to this one.
+    pub(crate) fn step(&self, envelope: Envelope) -> Result<()> {
+        self.inbox
+            .send(Event::Message(envelope))
+            .map_err(|_| stopped())
+    }
+
+    /// Replicates the command and waits until it is applied.
+    pub(crate) fn propose(&self, command: Command) -> Result<()> {
+        self.call(|reply| Event::Propose(command, reply))?
+    }
+
+    /// Waits until the engine may be read without missing a committed write.
+    pub(crate) fn read_barrier(&self) -> Result<()> {
+        self.call(Event::Read)?
+    }
+
+    pub(crate) fn add_node(&self, id: u64, addr: String) -> Result<()> {
+        self.call(|reply| Event::ChangeMembers(MemberChange::Add { id, addr }, reply))?
+    }
+
+    pub(crate) fn remove_node(&self, id: u64) -> Result<()> {
+        self.call(|reply| Event::ChangeMembers(MemberChange::Remove { id }, reply))?
+    }
+
+    pub(crate) fn status(&self) -> Result<ClusterStatus> {
+        self.call(Event::Status)
+    }
+
+    /// Stops the node and waits for its thread to exit.
+    pub(crate) fn stop(&self) {
+        let _ = self.inbox.send(Event::Stop);
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            handle.join().unwrap();
+        }
+    }
+
+    fn call<T, F: FnOnce(Sender<T>) -> Event>(&self, event: F) -> Result<T> {
+        let (reply, receiver) = channel::bounded(1);
+        self.inbox.send(event(reply)).map_err(|_| stopped())?;
+        receiver.recv_timeout(REQUEST_TIMEOUT).map_err(|e| match e {
+            RecvTimeoutError::Timeout => {
+                KvsError::StringError("timed out waiting for the cluster".to_owned())
+            }
+            RecvTimeoutError::Disconnected => stopped(),
+        })
+    }
+}
+
+fn stopped() -> KvsError {
+    KvsError::StringError("raft node stopped".to_owned())
+}
@@ -0,0 +1,1053 @@
+//! The Raft state machine of a single node.
+//!
+//! A node runs on its own thread and owns all of its state. Client requests and
+//! messages from other nodes reach it as `Event`s over a channel.
+
+use super::message::{Command, Entry, Envelope, Message, SnapshotMeta};
+use super::storage::{HardState, Storage};
+use super::transport::Transport;
+use super::{ClusterStatus, NodeState, RaftConfig};
+use crate::metrics;
+use crate::tls::TlsConnector;
+use crate::{KvsEngine, KvsError, Result};
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender};
+use log::{debug, error, info};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Maximum number of entries sent in one `AppendEntries` message.
+const MAX_ENTRIES: usize = 256;
+/// A snapshot is sent again if the follower has not answered within this time.
+const SNAPSHOT_RETRY: Duration = Duration::from_secs(1);
+
+pub(super) enum Event {
+    Message(Envelope),
+    Propose(Command, Sender<Result<()>>),
+    Read(Sender<Result<()>>),
+    ChangeMembers(MemberChange, Sender<Result<()>>),
+    Status(Sender<ClusterStatus>),
+    Stop,
+}
+
+pub(super) enum MemberChange {
+    Add { id: u64, addr: String },
+    Remove { id: u64 },
+}
+
+/// What the leader knows about the log of a follower.
+struct Progress {
+    next: u64,
+    matched: u64,
+    acked_seq: u64,
+    last_ack: Instant,
+    snapshot_sent: Option<Instant>,
+}
+
+impl Progress {
+    fn new(next: u64) -> Self {
+        Progress {
+            next,
+            matched: 0,
+            acked_seq: 0,
+            last_ack: Instant::now(),
+            snapshot_sent: None,
+        }
+    }
+}
+
+enum State {
+    Follower,
+    Candidate {
+        votes: HashSet<u64>,
+    },
+    Leader {
+        progress: HashMap<u64, Progress>,
+        /// Bumped for every read, followers echo it back.
+        seq: u64,
+        /// Index of the entry the leader wrote when elected.
+        term_start: u64,
+        next_heartbeat: Instant,
+    },
+}
+
+/// A read waiting for the leader to confirm it still leads.
+struct PendingRead {
+    index: u64,
+    seq: u64,
+    reply: Sender<Result<()>>,
+}
+
+pub(super) struct Node<E: KvsEngine> {
+    id: u64,
+    config: RaftConfig,
+    engine: E,
+    storage: Storage,
+    transport: Transport,
+    inbox: Receiver<Event>,
+
+    term: u64,
+    voted_for: Option<u64>,
+    snapshot: SnapshotMeta,
+    /// Entries following the snapshot.
+    log: Vec<Entry>,
+    /// Members as of the last entry of the log, committed or not.
+    members: BTreeMap<u64, String>,
+    commit_index: u64,
+    last_applied: u64,
+
+    state: State,
+    leader: Option<u64>,
+    leader_seen: Instant,
+    election_deadline: Instant,
+    waiters: HashMap<u64, (u64, Sender<Result<()>>)>,
+    reads: Vec<PendingRead>,
+    rng: u64,
+}
+
+impl<E: KvsEngine> Node<E> {
+    pub(super) fn new(
+        config: RaftConfig,
+        addr: String,
+        engine: E,
+        inbox: Receiver<Event>,
+        tls: Option<TlsConnector>,
+    ) -> Result<Self> {
+        let (storage, hard_state, snapshot, log) = Storage::open(&config.dir)?;
+        let transport = Transport::new(config.id, addr, config.network.clone(), tls);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+
+        let mut node = Node {
+            id: config.id,
+            engine,
+            storage,
+            transport,
+            inbox,
+            term: hard_state.term,
+            voted_for: hard_state.voted_for,
+            commit_index: snapshot.index,
+            last_applied: snapshot.index,
+            snapshot,
+            log,
+            members: BTreeMap::new(),
+            state: State::Follower,
+            leader: None,
+            leader_seen: Instant::now(),
+            election_deadline: Instant::now(),
+            waiters: HashMap::new(),
+            reads: Vec::new(),
+            rng: (nanos ^ config.id.wrapping_mul(0x9E37_79B9_7F4A_7C15)) | 1,
+            config,
+        };
+
+        if node.last_index() == 0 && !node.config.members.is_empty() {
+            // Every initial member writes the same first entry, so their logs agree.
+            let entry = Entry {
+                index: 1,
+                term: 0,
+                command: Command::Config(node.config.members.clone()),
+            };
+            node.storage.append(std::slice::from_ref(&entry))?;
+            node.log.push(entry);
+        }
+        node.update_members();
+        node.reset_election_timer();
+        Ok(node)
+    }
+
+    pub(super) fn run(mut self) {
+        info!(
+            "raft node {} starting at term {} with members {:?}",
+            self.id, self.term, self.members
+        );
+        loop {
+            let timeout = self
+                .next_deadline()
+                .saturating_duration_since(Instant::now());
+            let res = match self.inbox.recv_timeout(timeout) {
+                Ok(Event::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+                Ok(event) => self.handle(event),
+                Err(RecvTimeoutError::Timeout) => Ok(()),
+            };
+            if let Err(e) = res.and_then(|_| self.tick()).and_then(|_| self.apply()) {
+                error!("raft node {} failed: {}", self.id, e);
+            }
+            self.update_metrics();
+        }
+        self.fail_pending();
+        info!("raft node {} stopped", self.id);
+    }
+
+    fn handle(&mut self, event: Event) -> Result<()> {
+        match event {
+            Event::Message(envelope) => {
+                if envelope.to != self.id {
+                    debug!("dropping message for node {}", envelope.to);
+                    return Ok(());
+                }
+                self.transport.learn(envelope.from, &envelope.addr);
+                self.step(envelope.from, envelope.msg)?;
+            }
+            Event::Propose(command, reply) => match self.check_leader() {
+                Ok(()) => {
+                    let index = self.append(command)?;
+                    self.waiters.insert(index, (self.term, reply));
+                    self.broadcast();
+                }
+                Err(e) => {
+                    let _ = reply.send(Err(e));
+                }
+            },
+            Event::Read(reply) => match self.check_leader() {
+                Ok(()) => self.read(reply),
+                Err(e) => {
+                    let _ = reply.send(Err(e));
+                }
+            },
+            Event::ChangeMembers(change, reply) => match self.change_members(change) {
+                Ok(index) => {
+                    self.waiters.insert(index, (self.term, reply));
+                    self.broadcast();
+                }
+                Err(e) => {
+                    let _ = reply.send(Err(e));
+                }
+            },
+            Event::Status(reply) => {
+                let _ = reply.send(self.status());
+            }
+            Event::Stop => {}
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, from: u64, msg: Message) -> Result<()> {
+        // A node that has been removed, or cut off, must not depose a live leader.
+        if let Message::RequestVote { term, .. } = msg {
+            if term > self.term && self.leader_is_alive() {
+                debug!("ignoring vote request of node {} for term {}", from, term);
+                return Ok(());
+            }
+        }
+        if msg.term() > self.term {
+            self.become_follower(msg.term(), None)?;
+        }
+
+        match msg {
+            Message::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let granted = term == self.term
+                    && up_to_date
+                    && (self.voted_for.is_none() || self.voted_for == Some(from));
+                if granted {
+                    self.voted_for = Some(from);
+                    self.save_hard_state()?;
+                    self.reset_election_timer();
+                }
+                let term = self.term;
+                self.transport.send(from, Message::Vote { term, granted });
+            }
+            Message::Vote { term, granted } => {
+                if let State::Candidate { votes } = &mut self.state {
+                    if term == self.term && granted {
+                        votes.insert(from);
+                    }
+                }
+                if self.has_quorum_of_votes() {
+                    self.become_leader()?;
+                }
+            }
+            Message::AppendEntries {
+                term,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+                seq,
+            } => {
+                let (success, match_index) = if term < self.term {
+                    (false, 0)
+                } else {
+                    self.follow(from);
+                    self.accept_entries(prev_index, prev_term, entries, commit)?
+                };
+                self.respond(from, success, match_index, seq);
+            }
+            Message::InstallSnapshot {
+                term,
+                meta,
+                data,
+                seq,
+            } => {
+                let (success, match_index) = if term < self.term {
+                    (false, 0)
+                } else {
+                    self.follow(from);
+                    (true, self.install_snapshot(meta, data)?)
+                };
+                self.respond(from, success, match_index, seq);
+            }
+            Message::AppendResponse {
+                term,
+                success,
+                match_index,
+                seq,
+            } => {
+                if term == self.term {
+                    self.on_append_response(from, success, match_index, seq);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn respond(&mut self, to: u64, success: bool, match_index: u64, seq: u64) {
+        let term = self.term;
+        self.transport.send(
+            to,
+            Message::AppendResponse {
+                term,
+                success,
+                match_index,
+                seq,
+            },
+        );
+    }
+
+    /// Recognizes `leader` as the leader of the current term.
+    fn follow(&mut self, leader: u64) {
+        if !matches!(self.state, State::Follower) {
+            self.state = State::Follower;
+        }
+        if self.leader != Some(leader) {
+            info!(
+                "raft node {} follows node {} in term {}",
+                self.id, leader, self.term
+            );
+            self.leader = Some(leader);
+        }
+        self.leader_seen = Instant::now();
+        self.reset_election_timer();
+    }
+
+    /// Appends the entries of the leader following `prev_index`.
+    ///
+    /// Returns whether the log matched at `prev_index`, and the last index known to
+    /// match the leader, or a hint of where to retry from.
+    fn accept_entries(
+        &mut self,
+        prev_index: u64,
+        prev_term: u64,
+        mut entries: Vec<Entry>,
+        commit: u64,
+    ) -> Result<(bool, u64)> {
+        if prev_index > self.last_index() {
+            return Ok((false, self.last_index()));
+        }
+        if prev_index < self.snapshot.index {
+            // Entries up to the snapshot are committed, so they match the leader.
+            let snapshot_index = self.snapshot.index;
+            entries.retain(|entry| entry.index > snapshot_index);
+        } else if self.term_at(prev_index) != Some(prev_term) {
+            // Skip back over the whole conflicting term at once.
+            let conflict_term = self.term_at(prev_index);
+            let mut index = prev_index;
+            while index > self.snapshot.index + 1 && self.term_at(index - 1) == conflict_term {
+                index -= 1;
+            }
+            return Ok((false, index - 1));
+        }
+
+        let last_new = entries
+            .last()
+            .map_or(prev_index.max(self.snapshot.index), |entry| entry.index);
+        let mut truncated = false;
+        let mut new = Vec::new();
+        for entry in entries {
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term && new.is_empty() => continue,
+                Some(_) if new.is_empty() => {
+                    let keep = (entry.index - self.snapshot.index - 1) as usize;
+                    self.log.truncate(keep);
+                    truncated = true;
+                    new.push(entry);
+                }
+                _ => new.push(entry),
+            }
+        }
+
+        if truncated {
+            self.log.extend(new);
+            self.storage.rewrite(&self.snapshot, &self.log)?;
+            self.update_members();
+        } else if !new.is_empty() {
+            self.storage.append(&new)?;
+            let config_changed = new
+                .iter()
+                .any(|entry| matches!(entry.command, Command::Config(_)));
+            self.log.extend(new);
+            if config_changed {
+                self.update_members();
+            }
+        }
+
+        if commit > self.commit_index {
+            self.commit_index = commit.min(last_new);
+        }
+        Ok((true, last_new))
+    }
+
+    /// Replaces the engine contents and the log prefix with a snapshot of the leader.
+    ///
+    /// Returns the last index known to match the leader.
+    fn install_snapshot(&mut self, meta: SnapshotMeta, data: Vec<(String, String)>) -> Result<u64> {
+        if meta.index <= self.commit_index {
+            return Ok(meta.index);
+        }
+        info!(
+            "raft node {} installing a snapshot of {} keys at index {}",
+            self.id,
+            data.len(),
+            meta.index
+        );
+
+        let keys: HashSet<String> = data.iter().map(|(key, _)| key.clone()).collect();
+        for (key, value) in data {
+            self.engine.set(key, value)?;
+        }
+        for key in self.engine.keys("")? {
+            if !keys.contains(&key) {
+                remove_if_present(&self.engine, key)?;
+            }
+        }
+
+        if self.term_at(meta.index) == Some(meta.term) {
+            self.log
+                .drain(..(meta.index - self.snapshot.index) as usize);
+        } else {
+            self.log.clear();
+        }
+        self.commit_index = meta.index;
+        self.last_applied = meta.index;
+        self.snapshot = meta;
+        self.storage.rewrite(&self.snapshot, &self.log)?;
+        self.update_members();
+        metrics::global().raft_snapshots_installed.inc();
+        Ok(self.snapshot.index)
+    }
+
+    fn on_append_response(&mut self, from: u64, success: bool, match_index: u64, seq: u64) {
+        let last_index = self.last_index();
+        let progress = match &mut self.state {
+            State::Leader { progress, .. } => match progress.get_mut(&from) {
+                Some(progress) => progress,
+                None => return,
+            },
+            _ => return,
+        };
+
+        progress.acked_seq = progress.acked_seq.max(seq);
+        progress.last_ack = Instant::now();
+        if success {
+            progress.matched = progress.matched.max(match_index);
+            progress.next = progress.matched + 1;
+            progress.snapshot_sent = None;
+        } else {
+            progress.next = (match_index + 1)
+                .max(progress.matched + 1)
+                .min(progress.next);
+        }
+        let more = !success || progress.next <= last_index;
+
+        self.advance_commit();
+        if more {
+            self.send_append(from);
+        }
+        self.check_reads();
+    }
+
+    fn tick(&mut self) -> Result<()> {
+        let now = Instant::now();
+        match &self.state {
+            State::Leader { next_heartbeat, .. } => {
+                if now >= *next_heartbeat {
+                    if self.quorum_is_alive() {
+                        self.broadcast();
+                    } else {
+                        info!(
+                            "raft node {} lost contact with the quorum, stepping down",
+                            self.id
+                        );
+                        self.become_follower(self.term, None)?;
+                    }
+                }
+            }
+            _ => {
+                if now >= self.election_deadline {
+                    self.campaign()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn campaign(&mut self) -> Result<()> {
+        self.reset_election_timer();
+        if !self.members.contains_key(&self.id) {
+            return Ok(());
+        }
+
+        self.term += 1;
+        self.voted_for = Some(self.id);
+        self.save_hard_state()?;
+        self.fail_pending();
+        self.leader = None;
+        let mut votes = HashSet::new();
+        votes.insert(self.id);
+        self.state = State::Candidate { votes };
+        info!("raft node {} campaigning in term {}", self.id, self.term);
+
+        if self.has_quorum_of_votes() {
+            return self.become_leader();
+        }
+        let msg_term = self.term;
+        let (last_log_index, last_log_term) = (self.last_index(), self.last_term());
+        let peers: Vec<u64> = self.peers().collect();
+        for peer in peers {
+            self.transport.send(
+                peer,
+                Message::RequestVote {
+                    term: msg_term,
+                    last_log_index,
+                    last_log_term,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        info!("raft node {} is the leader of term {}", self.id, self.term);
+        let next = self.last_index() + 1;
+        let progress = self
+            .peers()
+            .map(|peer| (peer, Progress::new(next)))
+            .collect();
+        self.state = State::Leader {
+            progress,
+            seq: 0,
+            term_start: next,
+            next_heartbeat: Instant::now(),
+        };
+        self.leader = Some(self.id);
+        self.append(Command::Noop)?;
+        self.broadcast();
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<u64>) -> Result<()> {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.save_hard_state()?;
+        }
+        if !matches!(self.state, State::Follower) {
+            self.state = State::Follower;
+            self.fail_pending();
+        }
+        self.leader = leader;
+        self.reset_election_timer();
+        Ok(())
+    }
+
+    /// Appends a new entry to the log of the leader.
+    fn append(&mut self, command: Command) -> Result<u64> {
+        let entry = Entry {
+            index: self.last_index() + 1,
+            term: self.term,
+            command,
+        };
+        self.storage.append(std::slice::from_ref(&entry))?;
+        let index = entry.index;
+        let config_changed = matches!(entry.command, Command::Config(_));
+        self.log.push(entry);
+        if config_changed {
+            self.update_members();
+        }
+        self.advance_commit();
+        Ok(index)
+    }
+
+    fn change_members(&mut self, change: MemberChange) -> Result<u64> {
+        self.check_leader()?;
+        let term_start = match &self.state {
+            State::Leader { term_start, .. } => *term_start,
+            _ => unreachable!(),
+        };
+        // One change at a time keeps any two consecutive configurations overlapping.
+        let pending = self.log.iter().any(|entry| {
+            entry.index > self.commit_index && matches!(entry.command, Command::Config(_))
+        });
+        if pending || self.commit_index < term_start {
+            return Err(KvsError::StringError(
+                "another membership change is in progress".to_owned(),
+            ));
+        }
+
+        let mut members = self.members.clone();
+        match change {
+            MemberChange::Add { id, addr } => {
+                if members.contains_key(&id) {
+                    return Err(KvsError::StringError(format!(
+                        "node {} is already a member",
+                        id
+                    )));
+                }
+                members.insert(id, addr);
+            }
+            MemberChange::Remove { id } => {
+                if members.remove(&id).is_none() {
+                    return Err(KvsError::StringError(format!(
+                        "node {} is not a member",
+                        id
+                    )));
+                }
+                if members.is_empty() {
+                    return Err(KvsError::StringError(
+                        "cannot remove the last member".to_owned(),
+                    ));
+                }
+            }
+        }
+        info!("raft node {} changing members to {:?}", self.id, members);
+        self.append(Command::Config(members))
+    }
+
+    fn read(&mut self, reply: Sender<Result<()>>) {
+        let commit_index = self.commit_index;
+        if let State::Leader {
+            seq, term_start, ..
+        } = &mut self.state
+        {
+            *seq += 1;
+            self.reads.push(PendingRead {
+                index: commit_index.max(*term_start),
+                seq: *seq,
+                reply,
+            });
+        }
+        self.broadcast();
+        self.check_reads();
+    }
+
+    /// Answers the reads confirmed by a quorum once their index is applied.
+    fn check_reads(&mut self) {
+        let progress = match &self.state {
+            State::Leader { progress, .. } => progress,
+            _ => return,
+        };
+        let members = &self.members;
+        let id = self.id;
+        let quorum = self.members.len() / 2 + 1;
+        let applied = self.last_applied;
+
+        self.reads.retain(|read| {
+            let acks = members
+                .keys()
+                .filter(|member| {
+                    **member == id
+                        || matches!(progress.get(member), Some(p) if p.acked_seq >= read.seq)
+                })
+                .count();
+            if acks >= quorum && applied >= read.index {
+                let _ = read.reply.send(Ok(()));
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn advance_commit(&mut self) {
+        let progress = match &self.state {
+            State::Leader { progress, .. } => progress,
+            _ => return,
+        };
+        let mut matched: Vec<u64> = self
+            .members
+            .keys()
+            .map(|member| {
+                if *member == self.id {
+                    self.last_index()
+                } else {
+                    progress.get(member).map_or(0, |p| p.matched)
+                }
+            })
+            .collect();
+        if matched.is_empty() {
+            return;
+        }
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.quorum() - 1];
+        // Only entries of the current term are committed by counting replicas.
+        if index > self.commit_index && self.term_at(index) == Some(self.term) {
+            self.commit_index = index;
+        }
+    }
+
+    /// Applies the committed entries to the engine.
+    fn apply(&mut self) -> Result<()> {
+        while self.last_applied < self.commit_index {
+            let entry = self.entry(self.last_applied + 1).clone();
+            let mut config = None;
+            let res = match entry.command {
+                Command::Noop => Ok(()),
+                Command::Set { key, value } => self.engine.set(key, value),
+                Command::Remove { key } => self.engine.remove(key),
+                Command::Config(members) => {
+                    config = Some(members);
+                    Ok(())
+                }
+            };
+            self.last_applied = entry.index;
+
+            match self.waiters.remove(&entry.index) {
+                Some((term, reply)) if term == entry.term => {
+                    let _ = reply.send(res);
+                }
+                Some((_, reply)) => {
+                    let _ = reply.send(Err(KvsError::NotLeader(self.leader_addr())));
+                }
+                None => match res {
+                    Ok(()) | Err(KvsError::KeyNotFound) => {}
+                    Err(e) => error!("raft node {} failed to apply an entry: {}", self.id, e),
+                },
+            }
+            // After answering, a removed leader fails whatever is still pending.
+            if let Some(members) = config {
+                self.apply_config(&members);
+            }
+        }
+        self.check_reads();
+        self.maybe_compact()
+    }
+
+    fn apply_config(&mut self, members: &BTreeMap<u64, String>) {
+        if !members.contains_key(&self.id) {
+            info!("raft node {} has been removed from the cluster", self.id);
+            if let State::Leader { .. } = self.state {
+                self.state = State::Follower;
+                self.leader = None;
+                self.fail_pending();
+            }
+        }
+        // Removed nodes have seen the change by now.
+        let latest = &self.members;
+        if let State::Leader { progress, .. } = &mut self.state {
+            progress.retain(|peer, _| latest.contains_key(peer));
+        }
+    }
+
+    /// Replaces the applied log prefix with the engine contents.
+    ///
+    /// The engine may be ahead of the snapshot index: every command is a blind
+    /// write, so replaying the entries after the index onto it converges to the
+    /// same state anyway.
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.log.len() <= self.config.snapshot_threshold
+            || self.last_applied <= self.snapshot.index
+        {
+            return Ok(());
+        }
+        let index = self.last_applied;
+        let meta = SnapshotMeta {
+            index,
+            term: self.term_at(index).unwrap(),
+            members: self.members_at(index),
+        };
+        self.log.drain(..(index - self.snapshot.index) as usize);
+        self.snapshot = meta;
+        self.storage.rewrite(&self.snapshot, &self.log)?;
+        debug!("raft node {} compacted its log up to {}", self.id, index);
+        Ok(())
+    }
+
+    fn broadcast(&mut self) {
+        let peers: Vec<u64> = match &mut self.state {
+            State::Leader {
+                progress,
+                next_heartbeat,
+                ..
+            } => {
+                *next_heartbeat = Instant::now() + self.config.heartbeat_interval;
+                progress.keys().cloned().collect()
+            }
+            _ => return,
+        };
+        for peer in peers {
+            self.send_append(peer);
+        }
+    }
+
+    fn send_append(&mut self, to: u64) {
+        let (next, seq, snapshot_sent) = match &self.state {
+            State::Leader { progress, seq, .. } => match progress.get(&to) {
+                Some(p) => (p.next, *seq, p.snapshot_sent),
+                None => return,
+            },
+            _ => return,
+        };
+
+        let msg = if next <= self.snapshot.index {
+            if matches!(snapshot_sent, Some(sent) if sent.elapsed() < SNAPSHOT_RETRY) {
+                return;
+            }
+            let data = match self.snapshot_data() {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("raft node {} cannot read a snapshot: {}", self.id, e);
+                    return;
+                }
+            };
+            if let State::Leader { progress, .. } = &mut self.state {
+                if let Some(p) = progress.get_mut(&to) {
+                    p.snapshot_sent = Some(Instant::now());
+                }
+            }
+            info!(
+                "raft node {} sending a snapshot at index {} to node {}",
+                self.id, self.snapshot.index, to
+            );
+            Message::InstallSnapshot {
+                term: self.term,
+                meta: self.snapshot.clone(),
+                data,
+                seq,
+            }
+        } else {
+            let start = (next - self.snapshot.index - 1) as usize;
+            Message::AppendEntries {
+                term: self.term,
+                prev_index: next - 1,
+                prev_term: self.term_at(next - 1).unwrap(),
+                entries: self.log[start..]
+                    .iter()
+                    .take(MAX_ENTRIES)
+                    .cloned()
+                    .collect(),
+                commit: self.commit_index,
+                seq,
+            }
+        };
+        self.transport.send(to, msg);
+    }
+
+    fn snapshot_data(&self) -> Result<Vec<(String, String)>> {
+        let mut data = Vec::new();
+        for key in self.engine.keys("")? {
+            if let Some(value) = self.engine.get(key.clone())? {
+                data.push((key, value));
+            }
+        }
+        Ok(data)
+    }
+
+    fn check_leader(&self) -> Result<()> {
+        match self.state {
+            State::Leader { .. } => Ok(()),
+            _ => Err(KvsError::NotLeader(self.leader_addr())),
+        }
+    }
+
+    fn leader_addr(&self) -> Option<String> {
+        self.leader
+            .filter(|leader| *leader != self.id)
+            .and_then(|leader| self.transport.addr_of(leader))
+            .map(str::to_owned)
+    }
+
+    fn leader_is_alive(&self) -> bool {
+        match self.state {
+            State::Leader { .. } => true,
+            _ => self.leader.is_some() && self.leader_seen.elapsed() < self.config.election_timeout,
+        }
+    }
+
+    /// Whether a quorum has answered the leader within an election timeout.
+    fn quorum_is_alive(&self) -> bool {
+        let progress = match &self.state {
+            State::Leader { progress, .. } => progress,
+            _ => return false,
+        };
+        let alive = self
+            .members
+            .keys()
+            .filter(|member| {
+                **member == self.id
+                    || matches!(progress.get(member),
+                        Some(p) if p.last_ack.elapsed() < self.config.election_timeout)
+            })
+            .count();
+        alive >= self.quorum()
+    }
+
+    fn has_quorum_of_votes(&self) -> bool {
+        match &self.state {
+            State::Candidate { votes } => {
+                let granted = votes
+                    .iter()
+                    .filter(|id| self.members.contains_key(id))
+                    .count();
+                granted >= self.quorum()
+            }
+            _ => false,
+        }
+    }
+
+    /// Answers every waiting client with `KvsError::NotLeader`.
+    fn fail_pending(&mut self) {
+        let leader = self.leader_addr();
+        for (_, (_, reply)) in self.waiters.drain() {
+            let _ = reply.send(Err(KvsError::NotLeader(leader.clone())));
+        }
+        for read in self.reads.drain(..) {
+            let _ = read.reply.send(Err(KvsError::NotLeader(leader.clone())));
+        }
+    }
+
+    /// Recomputes the members from the log, and the followers the leader tracks.
+    fn update_members(&mut self) {
+        self.members = self.members_at(self.last_index());
+        for (id, addr) in &self.members {
+            self.transport.learn(*id, addr);
+        }
+        let next = self.last_index() + 1;
+        let members = &self.members;
+        let id = self.id;
+        if let State::Leader { progress, .. } = &mut self.state {
+            for member in members.keys() {
+                if *member != id {
+                    progress
+                        .entry(*member)
+                        .or_insert_with(|| Progress::new(next));
+                }
+            }
+        }
+    }
+
+    fn members_at(&self, index: u64) -> BTreeMap<u64, String> {
+        self.log
+            .iter()
+            .take_while(|entry| entry.index <= index)
+            .filter_map(|entry| match &entry.command {
+                Command::Config(members) => Some(members),
+                _ => None,
+            })
+            .last()
+            .unwrap_or(&self.snapshot.members)
+            .clone()
+    }
+
+    fn status(&self) -> ClusterStatus {
+        ClusterStatus {
+            id: self.id,
+            state: match self.state {
+                State::Follower => NodeState::Follower,
+                State::Candidate { .. } => NodeState::Candidate,
+                State::Leader { .. } => NodeState::Leader,
+            },
+            term: self.term,
+            leader: self.leader,
+            members: self.members.clone(),
+            commit_index: self.commit_index,
+            applied_index: self.last_applied,
+            snapshot_index: self.snapshot.index,
+        }
+    }
+
+    fn update_metrics(&self) {
+        let m = metrics::global();
+        m.raft_term.set(self.term as i64);
+        m.raft_leader
+            .set(matches!(self.state, State::Leader { .. }) as i64);
+        m.raft_commit_index.set(self.commit_index as i64);
+        m.raft_applied_index.set(self.last_applied as i64);
+    }
+
+    fn save_hard_state(&self) -> Result<()> {
+        self.storage.save_state(&HardState {
+            term: self.term,
+            voted_for: self.voted_for,
+        })
+    }
+
+    fn peers(&self) -> impl Iterator<Item = u64> + '_ {
+        let id = self.id;
+        self.members
+            .keys()
+            .cloned()
+            .filter(move |member| *member != id)
+    }
+
+    fn quorum(&self) -> usize {
+        self.members.len() / 2 + 1
+    }
+
+    fn next_deadline(&self) -> Instant {
+        match &self.state {
+            State::Leader { next_heartbeat, .. } => *next_heartbeat,
+            _ => self.election_deadline,
+        }
+    }
+
+    /// Picks a deadline between one and two election timeouts from now.
+    fn reset_election_timer(&mut self) {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let timeout = self.config.election_timeout.as_millis() as u64;
+        let jitter = self.rng % (timeout + 1);
+        self.election_deadline = Instant::now() + Duration::from_millis(timeout + jitter);
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot.index + self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log
+            .last()
+            .map_or(self.snapshot.term, |entry| entry.term)
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            return Some(self.snapshot.term);
+        }
+        if index < self.snapshot.index {
+            return None;
+        }
+        self.log
+            .get((index - self.snapshot.index - 1) as usize)
+            .map(|entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> &Entry {
+        &self.log[(index - self.snapshot.index - 1) as usize]
+    }
+}
+
+fn remove_if_present<E: KvsEngine>(engine: &E, key: String) -> Result<()> {
+    match engine.remove(key) {
+        Err(KvsError::KeyNotFound) => Ok(()),
+        res => res,
+    }
+}
@@ -0,0 +1,122 @@
+//! Durable state of a Raft node: its vote, its log and the snapshot metadata.
+//!
+//! Every file is replaced atomically by writing a temporary file and renaming it,
+//! except the log which is appended to and only rewritten when it is truncated.
+
+use super::message::{Entry, SnapshotMeta};
+use crate::Result;
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const STATE_FILE: &str = "state";
+const SNAPSHOT_FILE: &str = "snapshot";
+const LOG_FILE: &str = "log";
+
+/// State that must survive a restart before a node answers a vote or an append.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(super) struct HardState {
+    pub term: u64,
+    pub voted_for: Option<u64>,
+}
+
+pub(super) struct Storage {
+    dir: PathBuf,
+    log: BufWriter<File>,
+}
+
+impl Storage {
+    /// Opens the storage in `dir`, returning what was persisted so far.
+    pub(super) fn open(dir: &Path) -> Result<(Storage, HardState, SnapshotMeta, Vec<Entry>)> {
+        fs::create_dir_all(dir)?;
+        let state = read_json(&dir.join(STATE_FILE))?.unwrap_or_default();
+        let snapshot: SnapshotMeta = read_json(&dir.join(SNAPSHOT_FILE))?.unwrap_or_default();
+
+        let log_path = dir.join(LOG_FILE);
+        let mut entries = Vec::new();
+        let mut dirty = false;
+        if log_path.exists() {
+            let reader = BufReader::new(File::open(&log_path)?);
+            for entry in Deserializer::from_reader(reader).into_iter::<Entry>() {
+                match entry {
+                    Ok(entry) if entry.index > snapshot.index => entries.push(entry),
+                    // A crash between writing the snapshot and the log leaves these.
+                    Ok(_) => dirty = true,
+                    Err(e) => {
+                        warn!("dropping the torn tail of the raft log: {}", e);
+                        dirty = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        let mut storage = Storage {
+            dir: dir.to_owned(),
+            log: open_log(&log_path)?,
+        };
+        if dirty {
+            storage.rewrite(&snapshot, &entries)?;
+        }
+        Ok((storage, state, snapshot, entries))
+    }
+
+    pub(super) fn save_state(&self, state: &HardState) -> Result<()> {
+        write_atomic(&self.dir.join(STATE_FILE), &serde_json::to_vec(state)?)
+    }
+
+    /// Appends entries to the log and syncs them to disk.
+    pub(super) fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        for entry in entries {
+            serde_json::to_writer(&mut self.log, entry)?;
+        }
+        self.log.flush()?;
+        self.log.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Replaces the snapshot metadata and the whole log.
+    pub(super) fn rewrite(&mut self, snapshot: &SnapshotMeta, entries: &[Entry]) -> Result<()> {
+        write_atomic(
+            &self.dir.join(SNAPSHOT_FILE),
+            &serde_json::to_vec(snapshot)?,
+        )?;
+
+        let log_path = self.dir.join(LOG_FILE);
+        let tmp = log_path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for entry in entries {
+            serde_json::to_writer(&mut writer, entry)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        fs::rename(&tmp, &log_path)?;
+        self.log = open_log(&log_path)?;
+        Ok(())
+    }
+}
+
+fn open_log(path: &Path) -> Result<BufWriter<File>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(BufWriter::new(file))
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+}
+
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_data()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
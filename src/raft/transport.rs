@@ -0,0 +1,149 @@
+//! Connections to the other nodes of a cluster.
+//!
+//! Messages are fire and forget: each peer has a thread writing them to a single
+//! connection, and answers come back over the connection the peer opens to us.
+//! A message that cannot be delivered is dropped, Raft sends it again.
+
+use super::message::{Envelope, Message};
+use super::Network;
+use crate::common::Request;
+use crate::tls::{Stream, TlsConnector};
+use crate::Result;
+use crossbeam::channel::{self, Receiver, Sender};
+use log::debug;
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(100);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Messages to a peer that refused a connection are dropped for this long.
+const RECONNECT_DELAY: Duration = Duration::from_millis(100);
+
+pub(super) struct Transport {
+    id: u64,
+    addr: String,
+    network: Network,
+    addrs: HashMap<u64, String>,
+    peers: HashMap<u64, Peer>,
+    tls: Option<TlsConnector>,
+}
+
+struct Peer {
+    addr: String,
+    sender: Sender<Envelope>,
+}
+
+impl Transport {
+    pub(super) fn new(id: u64, addr: String, network: Network, tls: Option<TlsConnector>) -> Self {
+        Transport {
+            id,
+            addr,
+            network,
+            addrs: HashMap::new(),
+            peers: HashMap::new(),
+            tls,
+        }
+    }
+
+    /// Remembers the address of node `id`.
+    pub(super) fn learn(&mut self, id: u64, addr: &str) {
+        if id != self.id && self.addrs.get(&id).map(String::as_str) != Some(addr) {
+            self.addrs.insert(id, addr.to_owned());
+        }
+    }
+
+    pub(super) fn addr_of(&self, id: u64) -> Option<&str> {
+        if id == self.id {
+            return Some(&self.addr);
+        }
+        self.addrs.get(&id).map(String::as_str)
+    }
+
+    pub(super) fn send(&mut self, to: u64, msg: Message) {
+        if !self.network.delivers(self.id, to) {
+            return;
+        }
+        let addr = match self.addrs.get(&to) {
+            Some(addr) => addr,
+            None => {
+                debug!("dropping message to node {} with an unknown address", to);
+                return;
+            }
+        };
+        if self.peers.get(&to).map(|peer| &peer.addr) != Some(addr) {
+            // The thread of a replaced peer exits once its sender is dropped.
+            self.peers
+                .insert(to, Peer::start(addr.clone(), self.tls.clone()));
+        }
+
+        let envelope = Envelope {
+            from: self.id,
+            addr: self.addr.clone(),
+            to,
+            msg,
+        };
+        let _ = self.peers[&to].sender.send(envelope);
+    }
+}
+
+impl Peer {
+    fn start(addr: String, tls: Option<TlsConnector>) -> Peer {
+        let (sender, receiver) = channel::unbounded();
+        let thread_addr = addr.clone();
+        thread::spawn(move || run_peer(&thread_addr, tls.as_ref(), receiver));
+        Peer { addr, sender }
+    }
+}
+
+fn run_peer(addr: &str, tls: Option<&TlsConnector>, messages: Receiver<Envelope>) {
+    let mut conn: Option<BufWriter<Stream>> = None;
+    let mut retry_at = Instant::now();
+
+    for envelope in messages.iter() {
+        if conn.is_none() {
+            if Instant::now() < retry_at {
+                continue;
+            }
+            match connect(addr, tls) {
+                Ok(stream) => conn = Some(BufWriter::new(stream)),
+                Err(e) => {
+                    debug!("cannot connect to peer {}: {}", addr, e);
+                    retry_at = Instant::now() + RECONNECT_DELAY;
+                    continue;
+                }
+            }
+        }
+
+        let writer = conn.as_mut().unwrap();
+        let res = serde_json::to_writer(&mut *writer, &Request::Raft(envelope))
+            .map_err(io::Error::from)
+            .and_then(|_| {
+                // Batch the messages queued meanwhile into one write.
+                if messages.is_empty() {
+                    writer.flush()
+                } else {
+                    Ok(())
+                }
+            });
+        if let Err(e) = res {
+            debug!("lost connection to peer {}: {}", addr, e);
+            conn = None;
+        }
+    }
+}
+
+fn connect(addr: &str, tls: Option<&TlsConnector>) -> Result<Stream> {
+    let sock_addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))?;
+    let stream = TcpStream::connect_timeout(&sock_addr, CONNECT_TIMEOUT)?;
+    stream.set_nodelay(true)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    // Only a TLS handshake reads from the connection
+    stream.set_read_timeout(Some(WRITE_TIMEOUT))?;
+    Stream::connect(stream, tls)
+}
@@ -0,0 +1,393 @@
+//! This module provides leader-follower replication by shipping the write log.
+//!
+//! A follower connects to its primary and asks for the log from the last position it
+//! applied. The primary streams the log entries as they are written and sends a
+//! snapshot instead when the requested position has already been compacted away.
+
+use crate::common::Request;
+use crate::metrics;
+use crate::tls::{Stream, TlsConnector};
+use crate::{KvsEngine, KvsError, Result};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Maximum number of entries shipped in a single batch.
+const BATCH_SIZE: usize = 1000;
+/// Number of key/value pairs sent in a single snapshot chunk.
+const SNAPSHOT_CHUNK_SIZE: usize = 1000;
+/// How often the primary checks for new entries when the follower is caught up.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How often the primary tells an idle follower it is still alive.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// The follower gives up on a primary that stays silent for this long.
+const PRIMARY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delay before a follower reconnects to its primary.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Position in the write log of an engine.
+///
+/// The default position is before the beginning of any log.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LogPosition {
+    /// Log file the position is in.
+    pub term: u64,
+    /// Byte offset in the log file.
+    pub offset: u64,
+}
+
+/// A write read back from the log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogOp {
+    /// The key was set to the value.
+    Set {
+        /// Key
+        key: String,
+        /// Value
+        value: String,
+    },
+    /// The key was removed.
+    Remove {
+        /// Key
+        key: String,
+    },
+}
+
+/// A write together with its position in the log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// Position the entry starts at.
+    pub pos: LogPosition,
+    /// The write.
+    pub op: LogOp,
+}
+
+/// Result of reading the log from a given position.
+#[derive(Debug)]
+pub enum LogRead {
+    /// Entries following the position, empty if there is nothing new.
+    Entries {
+        /// Entries in log order.
+        entries: Vec<LogEntry>,
+        /// Position to continue reading from.
+        next: LogPosition,
+        /// Bytes of log after `next`.
+        behind: u64,
+    },
+    /// The position is no longer in the log, it has been compacted.
+    Compacted,
+}
+
+/// Messages streamed from the primary to a follower.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum ReplicationEvent {
+    Entries {
+        entries: Vec<LogEntry>,
+        next: LogPosition,
+        behind: u64,
+    },
+    SnapshotBegin,
+    SnapshotChunk(Vec<(String, String)>),
+    SnapshotEnd {
+        next: LogPosition,
+    },
+    Heartbeat,
+    Err(String),
+}
+
+/// Streams the log of `engine` from `from` until the follower goes away.
+pub(crate) fn ship<E: KvsEngine, W: Write>(
+    engine: &E,
+    from: LogPosition,
+    mut writer: W,
+) -> Result<()> {
+    let mut pos = from;
+    let mut last_sent = Instant::now();
+
+    loop {
+        let read = match engine.read_log(pos, BATCH_SIZE) {
+            Ok(read) => read,
+            Err(e) => {
+                send(&mut writer, &ReplicationEvent::Err(format!("{}", e)))?;
+                return Err(e);
+            }
+        };
+
+        match read {
+            LogRead::Compacted => {
+                let (next, pairs) = engine.snapshot()?;
+                info!("sending snapshot of {} keys at {:?}", pairs.len(), next);
+                send(&mut writer, &ReplicationEvent::SnapshotBegin)?;
+                for chunk in pairs.chunks(SNAPSHOT_CHUNK_SIZE) {
+                    send(
+                        &mut writer,
+                        &ReplicationEvent::SnapshotChunk(chunk.to_vec()),
+                    )?;
+                }
+                send(&mut writer, &ReplicationEvent::SnapshotEnd { next })?;
+                pos = next;
+            }
+            LogRead::Entries {
+                entries,
+                next,
+                behind,
+            } => {
+                if entries.is_empty() && next == pos {
+                    if last_sent.elapsed() >= HEARTBEAT_INTERVAL {
+                        send(&mut writer, &ReplicationEvent::Heartbeat)?;
+                        last_sent = Instant::now();
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                let event = ReplicationEvent::Entries {
+                    entries,
+                    next,
+                    behind,
+                };
+                send(&mut writer, &event)?;
+                pos = next;
+            }
+        }
+        last_sent = Instant::now();
+    }
+}
+
+fn send<W: Write>(writer: &mut W, event: &ReplicationEvent) -> Result<()> {
+    serde_json::to_writer(&mut *writer, event)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Replication role of a server.
+pub(crate) struct Role {
+    replica: AtomicBool,
+    follower: Mutex<Option<Follower>>,
+}
+
+impl Role {
+    pub(crate) fn primary() -> Role {
+        Role {
+            replica: AtomicBool::new(false),
+            follower: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn follow(&self, follower: Follower) {
+        self.replica.store(true, Ordering::SeqCst);
+        self.follower.lock().unwrap().replace(follower);
+    }
+
+    /// Fails with `KvsError::ReadOnly` while following a primary.
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if self.replica.load(Ordering::SeqCst) {
+            Err(KvsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Stops following the primary and starts accepting writes.
+    pub(crate) fn promote(&self) -> Result<()> {
+        let follower = self.follower.lock().unwrap().take();
+        match follower {
+            Some(mut follower) => {
+                follower.stop();
+                self.replica.store(false, Ordering::SeqCst);
+                info!("promoted to primary");
+                Ok(())
+            }
+            None => Err(KvsError::StringError("server is not a replica".to_owned())),
+        }
+    }
+
+    pub(crate) fn stop(&self) {
+        if let Some(mut follower) = self.follower.lock().unwrap().take() {
+            follower.stop();
+        }
+    }
+}
+
+/// Background thread replicating a primary into a local engine.
+pub(crate) struct Follower {
+    state: Arc<FollowerState>,
+    handle: Option<JoinHandle<()>>,
+}
+
+struct FollowerState {
+    stop: AtomicBool,
+    conn: Mutex<Option<TcpStream>>,
+}
+
+impl Follower {
+    /// Starts following `primary`, over TLS with `tls`, persisting the applied
+    /// position in `position_file`.
+    pub(crate) fn start<E: KvsEngine>(
+        primary: String,
+        engine: E,
+        position_file: PathBuf,
+        tls: Option<TlsConnector>,
+    ) -> Follower {
+        let state = Arc::new(FollowerState {
+            stop: AtomicBool::new(false),
+            conn: Mutex::new(None),
+        });
+        let thread_state = Arc::clone(&state);
+        let handle = thread::spawn(move || {
+            metrics::global().replication_following.set(1);
+            while !thread_state.stop.load(Ordering::SeqCst) {
+                let res = follow(
+                    &primary,
+                    tls.as_ref(),
+                    &engine,
+                    &position_file,
+                    &thread_state,
+                );
+                if let Err(e) = res {
+                    if !thread_state.stop.load(Ordering::SeqCst) {
+                        error!("replication from {} failed: {}", primary, e);
+                    }
+                }
+                metrics::global().replication_connected.set(0);
+                sleep_unless_stopped(&thread_state, RECONNECT_DELAY);
+            }
+            metrics::global().replication_following.set(0);
+        });
+        Follower {
+            state,
+            handle: Some(handle),
+        }
+    }
+
+    /// Stops the replication and waits for the thread to exit.
+    pub(crate) fn stop(&mut self) {
+        self.state.stop.store(true, Ordering::SeqCst);
+        if let Some(conn) = self.state.conn.lock().unwrap().as_ref() {
+            let _ = conn.shutdown(Shutdown::Both);
+        }
+        if let Some(handle) = self.handle.take() {
+            handle.join().unwrap();
+        }
+    }
+}
+
+fn sleep_unless_stopped(state: &FollowerState, d: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < d && !state.stop.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn follow<E: KvsEngine>(
+    primary: &str,
+    tls: Option<&TlsConnector>,
+    engine: &E,
+    position_file: &Path,
+    state: &FollowerState,
+) -> Result<()> {
+    let mut pos = load_position(position_file)?;
+    let tcp = TcpStream::connect(primary)?;
+    tcp.set_read_timeout(Some(PRIMARY_TIMEOUT))?;
+    state.conn.lock().unwrap().replace(tcp.try_clone()?);
+    if state.stop.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let stream = Stream::connect(tcp, tls)?;
+    let mut writer = BufWriter::new(&stream);
+    serde_json::to_writer(&mut writer, &Request::Replicate { from: pos })?;
+    writer.flush()?;
+    info!("replicating from {} at {:?}", primary, pos);
+    metrics::global().replication_connected.set(1);
+
+    let events = Deserializer::from_reader(BufReader::new(&stream)).into_iter::<ReplicationEvent>();
+    let mut snapshot_keys: Option<HashSet<String>> = None;
+
+    for event in events {
+        if state.stop.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        match event? {
+            ReplicationEvent::Entries {
+                entries,
+                next,
+                behind,
+            } => {
+                for entry in entries {
+                    apply(engine, entry.op)?;
+                    metrics::global().replication_applied.inc();
+                }
+                pos = next;
+                save_position(position_file, pos)?;
+                metrics::global().replication_lag_bytes.set(behind as i64);
+            }
+            ReplicationEvent::SnapshotBegin => {
+                snapshot_keys = Some(HashSet::new());
+            }
+            ReplicationEvent::SnapshotChunk(pairs) => {
+                let keys = snapshot_keys
+                    .as_mut()
+                    .ok_or(KvsError::UnexpectedCommandType)?;
+                for (key, value) in pairs {
+                    keys.insert(key.clone());
+                    engine.set(key, value)?;
+                }
+            }
+            ReplicationEvent::SnapshotEnd { next } => {
+                let keys = snapshot_keys
+                    .take()
+                    .ok_or(KvsError::UnexpectedCommandType)?;
+                for key in engine.keys("")? {
+                    if !keys.contains(&key) {
+                        apply(engine, LogOp::Remove { key })?;
+                    }
+                }
+                pos = next;
+                save_position(position_file, pos)?;
+                metrics::global().replication_snapshots.inc();
+                info!("applied snapshot of {} keys at {:?}", keys.len(), pos);
+            }
+            ReplicationEvent::Heartbeat => {
+                metrics::global().replication_lag_bytes.set(0);
+            }
+            ReplicationEvent::Err(msg) => return Err(KvsError::StringError(msg)),
+        }
+    }
+
+    warn!("primary {} closed the replication stream", primary);
+    Ok(())
+}
+
+fn apply<E: KvsEngine>(engine: &E, op: LogOp) -> Result<()> {
+    match op {
+        LogOp::Set { key, value } => engine.set(key, value),
+        // A replayed entry may remove a key that is already gone.
+        LogOp::Remove { key } => match engine.remove(key) {
+            Err(KvsError::KeyNotFound) => Ok(()),
+            res => res,
+        },
+    }
+}
+
+fn load_position(path: &Path) -> Result<LogPosition> {
+    if !path.exists() {
+        return Ok(LogPosition::default());
+    }
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+fn save_position(path: &Path, pos: LogPosition) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(&pos)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -1,15 +1,22 @@
 use crate::common::*;
 use crate::engines::*;
 use crate::error::*;
-use crate::thread_pool::ThreadPool;
-use log::{debug, error};
+use crate::metrics::{self, Op};
+use crate::raft::{Command, Envelope, Raft, RaftConfig};
+use crate::replication::{self, Follower, LogPosition, Role};
+use crate::thread_pool::{JoinHandle as PoolJoinHandle, ThreadPool};
+use crate::tls::{Stream, TlsAcceptor, TlsConnector};
+use crate::watch::{RecvTimeoutError, Watcher};
+use log::{debug, error, warn};
 use serde_json::Deserializer;
-use std::io::{self, BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 /// The server of a key value store.
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
@@ -17,6 +24,38 @@ pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     pool: P,
     handle: Option<JoinHandle<()>>,
     shutdown: Arc<AtomicBool>,
+    settings: Settings,
+    primary: Option<(String, PathBuf)>,
+    role: Arc<Role>,
+    cluster: Option<RaftConfig>,
+    raft: Option<Raft>,
+    tls: Option<TlsAcceptor>,
+    peer_tls: Option<TlsConnector>,
+}
+
+/// Per-connection settings of a `KvsServer`.
+#[derive(Clone, Copy, Default)]
+struct Settings {
+    slow_threshold: Option<Duration>,
+    max_key_size: Option<usize>,
+    max_value_size: Option<usize>,
+}
+
+impl Settings {
+    fn check(&self, key: &str, value: Option<&str>) -> Result<()> {
+        check_size("key", key.len(), self.max_key_size)?;
+        if let Some(value) = value {
+            check_size("value", value.len(), self.max_value_size)?;
+        }
+        Ok(())
+    }
+}
+
+fn check_size(what: &'static str, size: usize, limit: Option<usize>) -> Result<()> {
+    match limit {
+        Some(limit) if size > limit => Err(KvsError::TooLarge(what, size, limit)),
+        _ => Ok(()),
+    }
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
@@ -27,28 +66,139 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
             pool,
             handle: None,
             shutdown: Arc::new(AtomicBool::new(false)),
+            settings: Settings::default(),
+            primary: None,
+            role: Arc::new(Role::primary()),
+            cluster: None,
+            raft: None,
+            tls: None,
+            peer_tls: None,
         }
     }
 
+    /// Runs the server as a read-only replica of the server at `primary`.
+    ///
+    /// The position of the primary log applied so far is kept in `position_file`, so
+    /// the replica resumes where it stopped after a restart. Writes are rejected
+    /// until the replica is promoted.
+    pub fn replica_of(mut self, primary: String, position_file: PathBuf) -> Self {
+        self.primary = Some((primary, position_file));
+        self
+    }
+
+    /// Runs the server as a node of a Raft cluster.
+    ///
+    /// Writes are replicated to a majority of the members before they are
+    /// acknowledged, and only the leader serves requests. The other nodes redirect
+    /// clients to it. The members reach this node at the address the server listens
+    /// on.
+    pub fn cluster(mut self, config: RaftConfig) -> Self {
+        self.cluster = Some(config);
+        self
+    }
+
+    /// Accepts connections over TLS only.
+    pub fn tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// Connects over TLS to the primary of a replica, or to the other members of a
+    /// cluster.
+    pub fn peer_tls(mut self, connector: TlsConnector) -> Self {
+        self.peer_tls = Some(connector);
+        self
+    }
+
+    /// Logs every request that takes at least `threshold` to answer as a slow request.
+    ///
+    /// Values are redacted in the slow request log, only keys are printed.
+    pub fn slow_threshold(mut self, threshold: Duration) -> Self {
+        self.settings.slow_threshold = Some(threshold);
+        self
+    }
+
+    /// Rejects requests whose key is longer than `limit` bytes.
+    pub fn max_key_size(mut self, limit: usize) -> Self {
+        self.settings.max_key_size = Some(limit);
+        self
+    }
+
+    /// Rejects `set` requests whose value is longer than `limit` bytes.
+    pub fn max_value_size(mut self, limit: usize) -> Self {
+        self.settings.max_value_size = Some(limit);
+        self
+    }
+
     /// Run the server listening on the given address
     pub fn run<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
+        if self.primary.is_some() && self.cluster.is_some() {
+            return Err(KvsError::Config(
+                "a cluster node cannot be a replica".to_owned(),
+            ));
+        }
+
         let listener = TcpListener::bind(addr)?;
         listener.set_nonblocking(true)?;
 
+        if let Some(config) = self.cluster.take() {
+            let addr = listener.local_addr()?.to_string();
+            let tls = self.peer_tls.clone();
+            self.raft = Some(Raft::start(config, addr, self.engine.clone(), tls)?);
+        }
+
         let shutdown = self.shutdown.clone();
         let engine = self.engine.clone();
         let pool = self.pool.clone();
+        let settings = self.settings;
+        let role = self.role.clone();
+        let raft = self.raft.clone();
+        let tls = self.tls.clone();
+
+        if let Some((primary, position_file)) = self.primary.clone() {
+            let tls = self.peer_tls.clone();
+            let follower = Follower::start(primary, self.engine.clone(), position_file, tls);
+            self.role.follow(follower);
+        }
 
         let handle = thread::spawn(move || {
+            let mut served = Vec::new();
             for stream in listener.incoming() {
+                reap(&mut served);
                 match stream {
-                    Ok(stream) => {
+                    Ok(tcp) => {
                         let eng = engine.clone();
-                        pool.spawn(|| {
-                            if let Err(e) = serve(stream, eng) {
+                        let role = role.clone();
+                        let raft = raft.clone();
+                        let stream = match Stream::accept(tcp, tls.as_ref()) {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                error!("encountered IO error: {}", e);
+                                continue;
+                            }
+                        };
+                        let reply = match stream.try_clone() {
+                            Ok(reply) => reply,
+                            Err(e) => {
+                                error!("encountered IO error: {}", e);
+                                continue;
+                            }
+                        };
+                        let queued = QueuedJob::new();
+                        let res = pool.spawn_with_handle(move || {
+                            drop(queued);
+                            let _connection = ServedConnection::new();
+                            if let Err(e) = serve(stream, eng, settings, role, raft) {
                                 error!("error on serving client: {}", e);
                             }
                         });
+                        match res {
+                            Ok(handle) => served.push(Connection::new(reply, handle)),
+                            Err(e) => {
+                                metrics::global().rejected_connections.inc();
+                                close_with_error(reply, &e);
+                            }
+                        }
                     }
                     Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                         if shutdown.load(Ordering::Relaxed) {
@@ -68,54 +218,476 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
 
     /// Shutdown the server
     pub fn shutdown(&mut self) {
+        self.role.stop();
+        if let Some(raft) = self.raft.take() {
+            raft.stop();
+        }
         self.shutdown.store(true, Ordering::Relaxed);
         let handle = self.handle.take().unwrap();
         handle.join().unwrap();
     }
 }
 
-fn serve<E: KvsEngine>(tcp: TcpStream, engine: E) -> Result<()> {
-    let peer_addr = tcp.peer_addr()?;
-    let reader = BufReader::new(&tcp);
-    let mut writer = BufWriter::new(&tcp);
-    let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+/// Counts a connection as queued until its job runs or is dropped by the pool.
+struct QueuedJob;
+
+impl QueuedJob {
+    fn new() -> QueuedJob {
+        metrics::global().queued_jobs.inc();
+        QueuedJob
+    }
+}
+
+impl Drop for QueuedJob {
+    fn drop(&mut self) {
+        metrics::global().queued_jobs.dec();
+    }
+}
+
+/// Counts a connection as served while its job runs, even if it panics.
+struct ServedConnection;
 
-    for req in req_reader {
+impl ServedConnection {
+    fn new() -> ServedConnection {
+        metrics::global().connections.inc();
+        ServedConnection
+    }
+}
+
+impl Drop for ServedConnection {
+    fn drop(&mut self) {
+        metrics::global().connections.dec();
+    }
+}
+
+/// A connection handed to the pool, kept until its job finishes to report a panic
+/// to the client.
+struct Connection {
+    stream: Stream,
+    peer: String,
+    accepted: Instant,
+    handle: PoolJoinHandle<()>,
+}
+
+impl Connection {
+    fn new(stream: Stream, handle: PoolJoinHandle<()>) -> Self {
+        let peer = match stream.tcp().peer_addr() {
+            Ok(addr) => addr.to_string(),
+            Err(_) => "unknown peer".to_owned(),
+        };
+        Connection {
+            stream,
+            peer,
+            accepted: Instant::now(),
+            handle,
+        }
+    }
+
+    /// Logs the panic of the job if any, and answers the client with an internal
+    /// error.
+    fn finish(self) {
+        if let Err(panic) = self.handle.join() {
+            metrics::global().panicked_connections.inc();
+            error!(
+                "panic while serving {} after {:?}: {}",
+                self.peer,
+                self.accepted.elapsed(),
+                panic.message()
+            );
+            close_with_error(self.stream, &KvsError::Internal);
+        }
+    }
+}
+
+/// Finishes the connections whose job has returned or panicked.
+fn reap(served: &mut Vec<Connection>) {
+    let mut i = 0;
+    while i < served.len() {
+        if served[i].handle.is_finished() {
+            served.swap_remove(i).finish();
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// Answers a connection with `e` and closes it, either before its request is read
+/// because the pool has no room for it, or after its job panicked.
+///
+/// Every response has an `Err` variant, so the client reads it whatever its request.
+/// It blocks the accepting thread for at most `CLOSE_TIMEOUT` per read or write,
+/// which only a TLS handshake could take.
+fn close_with_error(stream: Stream, e: &KvsError) {
+    debug!("closing connection: {}", e);
+    let resp = GetResponse::Err(e.into());
+    let res = (|| -> Result<()> {
+        let tcp = stream.tcp();
+        tcp.set_read_timeout(Some(CLOSE_TIMEOUT))?;
+        tcp.set_write_timeout(Some(CLOSE_TIMEOUT))?;
+        serde_json::to_writer(&stream, &resp)?;
+        (&stream).flush()?;
+        tcp.set_nonblocking(true)?;
+        // Read what the client sent already, so closing does not reset the
+        // connection before it gets the response
+        let mut buf = [0; 4096];
+        while (&stream).read(&mut buf)? > 0 {}
+        Ok(())
+    })();
+    match res {
+        Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {}
+        Err(e) => debug!("failed to close connection: {}", e),
+        Ok(()) => {}
+    }
+}
+
+/// How long a connection closed with an error may take to send or receive data.
+const CLOSE_TIMEOUT: Duration = Duration::from_millis(100);
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// How often an idle watch connection is checked by sending a heartbeat.
+const WATCH_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+fn serve<E: KvsEngine>(
+    stream: Stream,
+    engine: E,
+    settings: Settings,
+    role: Arc<Role>,
+    raft: Option<Raft>,
+) -> Result<()> {
+    let peer_addr = stream.tcp().peer_addr()?;
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(&stream);
+    let mut req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+
+    while let Some(req) = req_reader.next() {
         let req = req?;
-        debug!("receive request from {}: {:?}", peer_addr, req);
+        if let Request::Raft(envelope) = req {
+            return forward_raft(raft, envelope, req_reader);
+        }
+        let span = RequestSpan::new(peer_addr, &req);
 
         macro_rules! send_resp {
             ($resp:ident) => {
-                debug!("send response to {}: {:?}", peer_addr, $resp);
                 serde_json::to_writer(&mut writer, &$resp)?;
                 writer.flush()?;
             };
         }
 
-        match req {
+        let (op, res) = match req {
             Request::Get { key } => {
-                let resp = match engine.get(key) {
-                    Ok(value) => GetResponse::Ok(value),
-                    Err(e) => GetResponse::Err(format!("{}", e)),
+                let res = timed(Op::Get, || {
+                    settings.check(&key, None)?;
+                    if let Some(raft) = &raft {
+                        raft.read_barrier()?;
+                    }
+                    engine.get(key)
+                });
+                let (resp, res) = match res {
+                    Ok(value) => (GetResponse::Ok(value), Ok(())),
+                    Err(e) => (GetResponse::error(&e), Err(e)),
                 };
                 send_resp!(resp);
+                (Some(Op::Get), res)
             }
             Request::Set { key, value } => {
-                let resp = match engine.set(key, value) {
-                    Ok(_) => SetResponse::Ok(()),
-                    Err(e) => SetResponse::Err(format!("{}", e)),
+                let res = timed(Op::Set, || {
+                    settings.check(&key, Some(&value))?;
+                    role.check_writable()?;
+                    match &raft {
+                        Some(raft) => raft.propose(Command::Set { key, value }),
+                        None => engine.set(key, value),
+                    }
+                });
+                let (resp, res) = match res {
+                    Ok(_) => (SetResponse::Ok(()), Ok(())),
+                    Err(e) => (SetResponse::error(&e), Err(e)),
                 };
                 send_resp!(resp);
+                (Some(Op::Set), res)
             }
             Request::Remove { key } => {
-                let resp = match engine.remove(key) {
-                    Ok(_) => RemoveResponse::Ok(()),
-                    Err(e) => RemoveResponse::Err(format!("{}", e)),
+                let res = timed(Op::Remove, || {
+                    settings.check(&key, None)?;
+                    role.check_writable()?;
+                    match &raft {
+                        Some(raft) => raft.propose(Command::Remove { key }),
+                        None => engine.remove(key),
+                    }
+                });
+                let (resp, res) = match res {
+                    Ok(_) => (RemoveResponse::Ok(()), Ok(())),
+                    Err(e) => (RemoveResponse::error(&e), Err(e)),
+                };
+                send_resp!(resp);
+                (Some(Op::Remove), res)
+            }
+            Request::Keys { prefix } => {
+                let res = (|| {
+                    settings.check(&prefix, None)?;
+                    if let Some(raft) = &raft {
+                        raft.read_barrier()?;
+                    }
+                    engine.keys(&prefix)
+                })();
+                let (resp, res) = match res {
+                    Ok(keys) => (KeysResponse::Ok(keys), Ok(())),
+                    Err(e) => (KeysResponse::error(&e), Err(e)),
+                };
+                send_resp!(resp);
+                (None, res)
+            }
+            Request::Watch { prefix, from } => {
+                let res = settings
+                    .check(&prefix, None)
+                    .and_then(|_| engine.watch(&prefix, from));
+                match res {
+                    Ok(watcher) => {
+                        span.finish(settings.slow_threshold, None);
+                        return stream_changes(watcher, stream.try_clone()?);
+                    }
+                    Err(e) => {
+                        let resp = WatchResponse::Err((&e).into());
+                        send_resp!(resp);
+                        (None, Err(e))
+                    }
+                }
+            }
+            Request::Stats => {
+                let resp = StatsResponse::Ok(metrics::global().render());
+                send_resp!(resp);
+                (None, Ok(()))
+            }
+            Request::Replicate { from } => {
+                span.finish(settings.slow_threshold, None);
+                debug!("replicating to {} from {:?}", peer_addr, from);
+                return ship_log(engine, from, stream.try_clone()?);
+            }
+            Request::Promote => {
+                let (resp, res) = match role.promote() {
+                    Ok(_) => (PromoteResponse::Ok(()), Ok(())),
+                    Err(e) => (PromoteResponse::Err((&e).into()), Err(e)),
+                };
+                send_resp!(resp);
+                (None, res)
+            }
+            Request::AddNode { id, addr } => {
+                let res = match &raft {
+                    Some(raft) => raft.add_node(id, addr),
+                    None => Err(KvsError::NotClustered),
+                };
+                let (resp, res) = match res {
+                    Ok(_) => (MembershipResponse::Ok(()), Ok(())),
+                    Err(e) => (MembershipResponse::error(&e), Err(e)),
                 };
                 send_resp!(resp);
+                (None, res)
             }
+            Request::RemoveNode { id } => {
+                let res = match &raft {
+                    Some(raft) => raft.remove_node(id),
+                    None => Err(KvsError::NotClustered),
+                };
+                let (resp, res) = match res {
+                    Ok(_) => (MembershipResponse::Ok(()), Ok(())),
+                    Err(e) => (MembershipResponse::error(&e), Err(e)),
+                };
+                send_resp!(resp);
+                (None, res)
+            }
+            Request::ClusterStatus => {
+                let res = match &raft {
+                    Some(raft) => raft.status(),
+                    None => Err(KvsError::NotClustered),
+                };
+                let (resp, res) = match res {
+                    Ok(status) => (ClusterStatusResponse::Ok(status), Ok(())),
+                    Err(e) => (ClusterStatusResponse::Err((&e).into()), Err(e)),
+                };
+                send_resp!(resp);
+                (None, res)
+            }
+            Request::Raft(_) => unreachable!(),
         };
+
+        let elapsed = span.finish(settings.slow_threshold, res.err());
+        if let Some(op) = op {
+            metrics::global().op(op).duration.observe(elapsed);
+        }
     }
 
     Ok(())
 }
+
+/// Hands the messages of a cluster peer to the Raft node.
+///
+/// A peer keeps its connection open for as long as it runs, so it is read on a
+/// thread of its own rather than holding on to a thread pool worker.
+fn forward_raft<I>(raft: Option<Raft>, first: Envelope, requests: I) -> Result<()>
+where
+    I: Iterator<Item = serde_json::Result<Request>> + Send + 'static,
+{
+    let raft = raft.ok_or(KvsError::NotClustered)?;
+    raft.step(first)?;
+    thread::spawn(move || {
+        for req in requests {
+            match req {
+                Ok(Request::Raft(envelope)) => {
+                    if raft.step(envelope).is_err() {
+                        break;
+                    }
+                }
+                Ok(req) => {
+                    error!("unexpected request on a cluster connection: {:?}", req);
+                    break;
+                }
+                Err(e) => {
+                    debug!("cluster connection closed: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Ships the write log to a follower on a dedicated thread, so the follower does not
+/// hold a worker of the pool for as long as it stays connected.
+fn ship_log<E: KvsEngine>(engine: E, from: LogPosition, stream: Stream) -> Result<()> {
+    let peer_addr = stream.tcp().peer_addr()?;
+    thread::spawn(move || {
+        if let Err(e) = replication::ship(&engine, from, BufWriter::new(stream)) {
+            debug!("replication to {} stopped: {}", peer_addr, e);
+        }
+    });
+    Ok(())
+}
+
+/// Streams the changes on a dedicated thread, so the watch does not hold a worker
+/// of the pool, until the client goes away or falls too far behind.
+fn stream_changes(watcher: Watcher, stream: Stream) -> Result<()> {
+    let mut writer = BufWriter::new(stream);
+    send(&mut writer, &WatchResponse::Heartbeat)?;
+    thread::spawn(move || loop {
+        let resp = match watcher.recv_timeout(WATCH_HEARTBEAT_INTERVAL) {
+            Ok(event) => WatchResponse::Event(event),
+            Err(RecvTimeoutError::Timeout) => WatchResponse::Heartbeat,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if let Err(e) = send(&mut writer, &resp) {
+            debug!("watch connection closed: {}", e);
+            break;
+        }
+    });
+    Ok(())
+}
+
+fn send<W: Write>(writer: &mut W, resp: &WatchResponse) -> Result<()> {
+    serde_json::to_writer(&mut *writer, resp)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Context of a single request, logged once the response is sent.
+///
+/// The value itself is never kept, only its size. The key is printed by the slow
+/// request log alone.
+struct RequestSpan {
+    id: u64,
+    peer: SocketAddr,
+    op: &'static str,
+    key: String,
+    value_len: Option<usize>,
+    start: Instant,
+}
+
+impl RequestSpan {
+    fn new(peer: SocketAddr, req: &Request) -> Self {
+        let (op, key, value_len) = match req {
+            Request::Get { key } => ("get", key.as_str(), None),
+            Request::Set { key, value } => ("set", key.as_str(), Some(value.len())),
+            Request::Remove { key } => ("remove", key.as_str(), None),
+            Request::Keys { prefix } => ("keys", prefix.as_str(), None),
+            Request::Stats => ("stats", "", None),
+            Request::Watch { prefix, .. } => ("watch", prefix.as_str(), None),
+            Request::Replicate { .. } => ("replicate", "", None),
+            Request::Promote => ("promote", "", None),
+            Request::AddNode { .. } => ("add_node", "", None),
+            Request::RemoveNode { .. } => ("remove_node", "", None),
+            Request::ClusterStatus => ("cluster_status", "", None),
+            Request::Raft(_) => ("raft", "", None),
+        };
+        RequestSpan {
+            id: NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+            peer,
+            op,
+            key: key.to_owned(),
+            value_len,
+            start: Instant::now(),
+        }
+    }
+
+    fn finish(self, slow_threshold: Option<Duration>, err: Option<KvsError>) -> Duration {
+        let elapsed = self.start.elapsed();
+        let result = match err {
+            None => "ok".to_owned(),
+            Some(e) => format!("{:?}", e.to_string()),
+        };
+
+        debug!(
+            target: "kvs::request",
+            "{} latency_us={} result={}",
+            self,
+            elapsed.as_micros(),
+            result
+        );
+
+        if matches!(slow_threshold, Some(threshold) if elapsed >= threshold) {
+            let value = match self.value_len {
+                Some(len) => format!(" value=<redacted {} bytes>", len),
+                None => String::new(),
+            };
+            warn!(
+                target: "kvs::slow",
+                "slow request {} key={:?}{} latency_us={} result={}",
+                self,
+                self.key,
+                value,
+                elapsed.as_micros(),
+                result
+            );
+        }
+
+        elapsed
+    }
+}
+
+impl fmt::Display for RequestSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "id={} peer={} op={} key_size={}",
+            self.id,
+            self.peer,
+            self.op,
+            self.key.len()
+        )?;
+        if let Some(len) = self.value_len {
+            write!(f, " value_size={}", len)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs an engine call, recording its latency and outcome under `op`.
+fn timed<T, F: FnOnce() -> Result<T>>(op: Op, f: F) -> Result<T> {
+    let m = metrics::global().op(op);
+    m.requests.inc();
+    let start = Instant::now();
+    let res = f();
+    m.engine_duration.observe(start.elapsed());
+    if res.is_err() {
+        m.errors.inc();
+    }
+    res
+}
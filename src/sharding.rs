@@ -0,0 +1,295 @@
+//! This module provides client-side sharding of keys across several servers.
+//!
+//! Keys are placed with consistent hashing: every server owns many points, its
+//! virtual nodes, on a hash ring, and a key belongs to the server of the first point
+//! following the hash of the key. Adding a server then only moves the keys which
+//! now fall on its points, roughly `1 / n` of them.
+
+use crate::{KvsClient, KvsClientOptions, KvsError, Result};
+use std::collections::BTreeMap;
+
+/// Default number of virtual nodes per server.
+pub const DEFAULT_VIRTUAL_NODES: usize = 160;
+
+/// A consistent hash ring mapping keys to servers.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    nodes: Vec<String>,
+    ring: BTreeMap<u64, usize>,
+    virtual_nodes: usize,
+}
+
+impl HashRing {
+    /// Creates a ring of the given server addresses, each with `virtual_nodes` points.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Config` if there is no server or no virtual node, as
+    /// the ring would then have no point to place the keys on.
+    pub fn new(nodes: Vec<String>, virtual_nodes: usize) -> Result<Self> {
+        if nodes.is_empty() {
+            return Err(KvsError::Config("no server to shard over".to_owned()));
+        }
+        if virtual_nodes == 0 {
+            return Err(KvsError::Config(
+                "the number of virtual nodes must be positive".to_owned(),
+            ));
+        }
+        let mut ring = HashRing {
+            nodes: Vec::new(),
+            ring: BTreeMap::new(),
+            virtual_nodes,
+        };
+        for node in nodes {
+            ring.add(node);
+        }
+        Ok(ring)
+    }
+
+    /// Adds a server to the ring.
+    pub fn add(&mut self, node: String) {
+        let index = self.nodes.len();
+        for i in 0..self.virtual_nodes {
+            self.ring.insert(hash(&format!("{}#{}", node, i)), index);
+        }
+        self.nodes.push(node);
+    }
+
+    /// Returns the servers of the ring, in the order they were added.
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    /// Returns the address of the server owning `key`.
+    pub fn node_for(&self, key: &str) -> &str {
+        &self.nodes[self.index_for(key)]
+    }
+
+    fn index_for(&self, key: &str) -> usize {
+        let h = hash(key);
+        let (_, &index) = self
+            .ring
+            .range(h..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .expect("a ring has at least one point");
+        index
+    }
+}
+
+/// 64-bit FNV-1a, stable across processes and Rust versions unlike `DefaultHasher`.
+///
+/// FNV barely mixes the last bytes into the high bits, and keys and virtual nodes
+/// often differ only there, so the result goes through the MurmurHash3 finalizer.
+fn hash(s: &str) -> u64 {
+    let mut h = s.bytes().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+/// Key value store client spreading the keys over several servers.
+///
+/// All clients of a dataset must use the same servers, in the same order, and the
+/// same number of virtual nodes.
+pub struct ShardedKvsClient {
+    ring: HashRing,
+    clients: Vec<KvsClient>,
+    options: KvsClientOptions,
+}
+
+impl ShardedKvsClient {
+    /// Connects to every server in `addrs`, with the default number of virtual nodes.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Config` if `addrs` is empty.
+    pub fn connect(addrs: Vec<String>) -> Result<Self> {
+        Self::with_ring(HashRing::new(addrs, DEFAULT_VIRTUAL_NODES)?)
+    }
+
+    /// Connects to every server of `ring`.
+    pub fn with_ring(ring: HashRing) -> Result<Self> {
+        Self::with_options(ring, KvsClientOptions::default())
+    }
+
+    /// Connects to every server of `ring` with the given options.
+    pub fn with_options(ring: HashRing, options: KvsClientOptions) -> Result<Self> {
+        let clients = ring
+            .nodes()
+            .iter()
+            .map(|addr| KvsClient::connect_with(addr, options.clone()))
+            .collect::<Result<_>>()?;
+        Ok(ShardedKvsClient {
+            ring,
+            clients,
+            options,
+        })
+    }
+
+    /// Returns the hash ring routing the keys.
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    /// Get the value of a given key from its server.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.client_for(&key).get(key)
+    }
+
+    /// Set the value of a string key in its server.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.client_for(&key).set(key, value)
+    }
+
+    /// Remove a string key in its server.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.client_for(&key).remove(key)
+    }
+
+    /// Gets the values of several keys, in the order of `keys`.
+    ///
+    /// The servers are queried in parallel.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let shards = self.split(keys.into_iter().enumerate(), |(_, key)| key);
+        let results = self.fan_out(shards, |client, batch| {
+            batch
+                .into_iter()
+                .map(|(pos, key)| Ok((pos, client.get(key)?)))
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        let mut values = Vec::new();
+        for (pos, value) in results.into_iter().flatten() {
+            if values.len() <= pos {
+                values.resize(pos + 1, None);
+            }
+            values[pos] = value;
+        }
+        Ok(values)
+    }
+
+    /// Sets several keys, the servers are written in parallel.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let shards = self.split(pairs.into_iter(), |(key, _)| key);
+        self.fan_out(shards, |client, batch| {
+            batch
+                .into_iter()
+                .try_for_each(|(key, value)| client.set(key, value))
+        })?;
+        Ok(())
+    }
+
+    /// Removes several keys, the servers are written in parallel.
+    ///
+    /// # Errors
+    ///
+    /// It fails with `KeyNotFound` if any of the keys is not found, after removing
+    /// the other keys. Other errors stop the removal on the server they come from.
+    pub fn remove_many(&mut self, keys: Vec<String>) -> Result<()> {
+        let shards = self.split(keys.into_iter(), |key| key);
+        self.fan_out(shards, |client, batch| {
+            let mut missing = Ok(());
+            for key in batch {
+                match client.remove(key) {
+                    Err(KvsError::KeyNotFound) => missing = Err(KvsError::KeyNotFound),
+                    res => res?,
+                }
+            }
+            missing
+        })?;
+        Ok(())
+    }
+
+    /// Returns the keys starting with `prefix` across all servers, in ascending order.
+    pub fn keys(&mut self, prefix: &str) -> Result<Vec<String>> {
+        let shards = vec![(); self.clients.len()];
+        let results = self.fan_out(shards, |client, _| client.keys(prefix))?;
+        let mut keys: Vec<String> = results.into_iter().flatten().collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Adds a server and moves the keys it now owns from the other servers.
+    ///
+    /// Returns the number of keys moved. Each key is copied before it is removed
+    /// from its old server, so an interrupted migration can be run again. Other
+    /// clients must not write while the keys move, and must then be created with
+    /// the new server list.
+    pub fn add_server(&mut self, addr: String) -> Result<usize> {
+        if self.ring.nodes().contains(&addr) {
+            return Err(KvsError::Config(format!(
+                "server {} is already in the ring",
+                addr
+            )));
+        }
+        let mut ring = self.ring.clone();
+        ring.add(addr.clone());
+        let new_index = ring.nodes().len() - 1;
+        let mut new_client = KvsClient::connect_with(&addr, self.options.clone())?;
+
+        let mut moved = 0;
+        for client in &mut self.clients {
+            for key in client.keys("")? {
+                if ring.index_for(&key) != new_index {
+                    continue;
+                }
+                if let Some(value) = client.get(key.clone())? {
+                    new_client.set(key.clone(), value)?;
+                    client.remove(key)?;
+                    moved += 1;
+                }
+            }
+        }
+
+        self.ring = ring;
+        self.clients.push(new_client);
+        Ok(moved)
+    }
+
+    fn client_for(&mut self, key: &str) -> &mut KvsClient {
+        let index = self.ring.index_for(key);
+        &mut self.clients[index]
+    }
+
+    /// Groups `items` by the server owning their key.
+    fn split<T, I, F>(&self, items: I, key: F) -> Vec<Vec<T>>
+    where
+        I: Iterator<Item = T>,
+        F: Fn(&T) -> &String,
+    {
+        let mut shards: Vec<Vec<T>> = self.clients.iter().map(|_| Vec::new()).collect();
+        for item in items {
+            let index = self.ring.index_for(key(&item));
+            shards[index].push(item);
+        }
+        shards
+    }
+
+    /// Runs `f` on every server with its share of the work, each on its own thread.
+    fn fan_out<B, R, F>(&mut self, shards: Vec<B>, f: F) -> Result<Vec<R>>
+    where
+        B: Send,
+        R: Send,
+        F: Fn(&mut KvsClient, B) -> Result<R> + Sync,
+    {
+        let f = &f;
+        crossbeam::scope(|scope| {
+            let handles: Vec<_> = self
+                .clients
+                .iter_mut()
+                .zip(shards)
+                .map(|(client, batch)| scope.spawn(move |_| f(client, batch)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        })
+        .unwrap()
+    }
+}
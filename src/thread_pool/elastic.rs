@@ -0,0 +1,252 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::threads::Threads;
+use super::ThreadPool;
+use crate::{KvsError, Result};
+use log::{debug, error};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Sizing of an `ElasticThreadPool`.
+#[derive(Debug, Clone, Copy)]
+pub struct ElasticOptions {
+    /// Threads kept even when idle.
+    pub min_threads: u32,
+    /// Upper bound of the number of threads.
+    pub max_threads: u32,
+    /// How long a thread above the minimum waits for a job before it exits.
+    pub idle_timeout: Duration,
+}
+
+/// A snapshot of the load of an `ElasticThreadPool`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Threads running a job.
+    pub active: usize,
+    /// Threads waiting for a job.
+    pub idle: usize,
+    /// Jobs waiting for a thread.
+    pub queued: usize,
+}
+
+/// A thread pool growing and shrinking with its load.
+///
+/// A thread is started whenever the queued jobs outnumber the idle threads, up to
+/// the maximum, and threads above the minimum exit once idle for a while. A thread
+/// whose job panics is replaced if the pool still needs it.
+#[derive(Clone)]
+pub struct ElasticThreadPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    options: ElasticOptions,
+    state: Mutex<State>,
+    job_queued: Condvar,
+    threads: Arc<Threads>,
+}
+
+struct State {
+    queue: VecDeque<Job>,
+    threads: usize,
+    idle: usize,
+    closed: bool,
+}
+
+impl ElasticThreadPool {
+    /// Creates a thread pool sized by `options`, immediately spawning the minimum
+    /// number of threads.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Config` if the maximum is zero or below the minimum.
+    pub fn with_options(options: ElasticOptions) -> Result<Self> {
+        if options.max_threads == 0 || options.max_threads < options.min_threads {
+            return Err(KvsError::Config(format!(
+                "invalid thread pool size, from {} to {} threads",
+                options.min_threads, options.max_threads
+            )));
+        }
+        let pool = ElasticThreadPool {
+            shared: Arc::new(Shared {
+                options,
+                state: Mutex::new(State {
+                    queue: VecDeque::new(),
+                    threads: 0,
+                    idle: 0,
+                    closed: false,
+                }),
+                job_queued: Condvar::new(),
+                threads: Arc::new(Threads::default()),
+            }),
+        };
+        for _ in 0..options.min_threads {
+            let state = pool.shared.state.lock().unwrap();
+            if let Err(e) = pool.shared.add_thread(state) {
+                pool.join();
+                return Err(e);
+            }
+        }
+        Ok(pool)
+    }
+
+    /// Returns the current load of the pool.
+    pub fn stats(&self) -> PoolStats {
+        let state = self.shared.state.lock().unwrap();
+        PoolStats {
+            active: state.threads - state.idle,
+            idle: state.idle,
+            queued: state.queue.len(),
+        }
+    }
+
+    fn stop(&self, deadline: Option<Instant>) -> bool {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.job_queued.notify_all();
+        self.shared.threads.wait(deadline)
+    }
+}
+
+impl Shared {
+    /// Starts a thread, counted in `state` before it runs.
+    fn add_thread(self: &Arc<Self>, mut state: MutexGuard<State>) -> Result<()> {
+        state.threads += 1;
+        drop(state);
+        let runner = TaskRunner {
+            shared: Arc::clone(self),
+            reaped: false,
+        };
+        if let Err(e) = self.threads.spawn(move || run_tasks(runner)) {
+            self.state.lock().unwrap().threads -= 1;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+impl ThreadPool for ElasticThreadPool {
+    /// Creates a thread pool of one to `threads` threads, whose threads exit after a
+    /// minute without a job.
+    fn new(threads: u32) -> Result<Self> {
+        ElasticThreadPool::with_options(ElasticOptions {
+            min_threads: 1,
+            max_threads: threads,
+            idle_timeout: Duration::from_secs(60),
+        })
+    }
+
+    /// Spawns a function into the thread pool.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the thread pool is shut down.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(KvsError::PoolShutDown) = self.try_spawn(job) {
+            panic!("The thread pool is shut down.");
+        }
+    }
+
+    /// Spawns a function into the thread pool, starting a thread for it if every
+    /// thread is busy and the pool is below its maximum.
+    ///
+    /// The job stays queued if the thread cannot be started, and the error is
+    /// only logged.
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(KvsError::PoolShutDown);
+        }
+        state.queue.push_back(Box::new(job));
+        let max_threads = self.shared.options.max_threads as usize;
+        if state.queue.len() > state.idle && state.threads < max_threads {
+            if let Err(e) = self.shared.add_thread(state) {
+                error!("Failed to spawn a thread: {}", e);
+            }
+        } else {
+            drop(state);
+            self.shared.job_queued.notify_one();
+        }
+        Ok(())
+    }
+
+    fn shutdown(&self, timeout: Duration) -> Result<()> {
+        if self.stop(Some(Instant::now() + timeout)) {
+            Ok(())
+        } else {
+            Err(KvsError::ShutdownTimeout(timeout))
+        }
+    }
+
+    fn join(&self) {
+        self.stop(None);
+    }
+}
+
+/// Counts its thread out of the pool when it exits, and replaces it if its job
+/// panicked while the pool still needs it.
+struct TaskRunner {
+    shared: Arc<Shared>,
+    // Already counted out when it exits idle
+    reaped: bool,
+}
+
+impl Drop for TaskRunner {
+    fn drop(&mut self) {
+        if self.reaped {
+            return;
+        }
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        state.threads -= 1;
+        let needed =
+            state.threads < shared.options.min_threads as usize || state.queue.len() > state.idle;
+        if thread::panicking() && !state.closed && needed {
+            if let Err(e) = shared.add_thread(state) {
+                error!("Failed to spawn a thread: {}", e);
+            }
+        }
+    }
+}
+
+fn run_tasks(mut runner: TaskRunner) {
+    let shared = Arc::clone(&runner.shared);
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        if let Some(job) = state.queue.pop_front() {
+            drop(state);
+            job();
+            state = shared.state.lock().unwrap();
+            continue;
+        }
+        if state.closed {
+            debug!("Thread exits because the thread pool is shut down.");
+            return;
+        }
+
+        state.idle += 1;
+        let (guard, res) = shared
+            .job_queued
+            .wait_timeout(state, shared.options.idle_timeout)
+            .unwrap();
+        state = guard;
+        state.idle -= 1;
+        let min_threads = shared.options.min_threads as usize;
+        if res.timed_out() && state.queue.is_empty() && state.threads > min_threads {
+            // Counted out under the lock, so threads timing out together cannot all
+            // see themselves above the minimum
+            state.threads -= 1;
+            runner.reaped = true;
+            debug!("Thread exits after {:?} idle.", shared.options.idle_timeout);
+            return;
+        }
+    }
+}
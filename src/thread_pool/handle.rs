@@ -0,0 +1,116 @@
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// The panic of a job spawned by `ThreadPool::spawn_with_handle`.
+#[derive(Debug, Clone)]
+pub struct PanicInfo {
+    message: String,
+}
+
+impl PanicInfo {
+    fn new(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(message) => (*message).to_owned(),
+                Err(_) => "Box<dyn Any>".to_owned(),
+            },
+        };
+        PanicInfo { message }
+    }
+
+    /// Returns the message the job panicked with, if it is a string.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for PanicInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "job panicked: {}", self.message)
+    }
+}
+
+impl Error for PanicInfo {}
+
+/// An owned permission to wait for a job spawned by `ThreadPool::spawn_with_handle`.
+///
+/// Dropping the handle detaches the job, which still runs.
+pub struct JoinHandle<T> {
+    packet: Arc<Packet<T>>,
+}
+
+struct Packet<T> {
+    result: Mutex<Option<Result<T, PanicInfo>>>,
+    done: Condvar,
+}
+
+impl<T> JoinHandle<T> {
+    /// Waits for the job to finish and returns its result, or its panic.
+    ///
+    /// A job dropped by the pool without running, as `QueuePolicy::DropOldest` does,
+    /// is reported as a panic.
+    pub fn join(self) -> Result<T, PanicInfo> {
+        let mut result = self.packet.result.lock().unwrap();
+        loop {
+            match result.take() {
+                Some(result) => return result,
+                None => result = self.packet.done.wait(result).unwrap(),
+            }
+        }
+    }
+
+    /// Checks if the job has finished, without blocking.
+    pub fn is_finished(&self) -> bool {
+        self.packet.result.lock().unwrap().is_some()
+    }
+}
+
+/// Fills the packet when the job returns, panics, or is dropped without running.
+struct Completion<T> {
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> Completion<T> {
+    fn complete(&self, res: Result<T, PanicInfo>) {
+        let mut result = self.packet.result.lock().unwrap();
+        if result.is_none() {
+            *result = Some(res);
+            self.packet.done.notify_all();
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        self.complete(Err(PanicInfo {
+            message: "job dropped without running".to_owned(),
+        }));
+    }
+}
+
+/// Wraps `job` into a job catching its panic, and the handle to its result.
+///
+/// The panic does not reach the thread of the pool, which keeps running.
+pub(super) fn wrap<F, T>(job: F) -> (impl FnOnce() + Send + 'static, JoinHandle<T>)
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Packet {
+        result: Mutex::new(None),
+        done: Condvar::new(),
+    });
+    let completion = Completion {
+        packet: Arc::clone(&packet),
+    };
+    let wrapped = move || {
+        // The job is not used again once it panicked
+        let res = panic::catch_unwind(AssertUnwindSafe(job)).map_err(PanicInfo::new);
+        completion.complete(res);
+    };
+    (wrapped, JoinHandle { packet })
+}
@@ -2,14 +2,44 @@
 //! the `ThreadPool` trait.
 
 use crate::Result;
+use serde::Deserialize;
+use std::time::Duration;
 
+mod elastic;
+mod handle;
 mod naive;
 mod rayon;
 mod shared_queue;
+mod threads;
+mod work_stealing;
 
+pub use self::elastic::{ElasticOptions, ElasticThreadPool, PoolStats};
+pub use self::handle::{JoinHandle, PanicInfo};
 pub use self::naive::NaiveThreadPool;
 pub use self::rayon::RayonThreadPool;
 pub use self::shared_queue::SharedQueueThreadPool;
+pub use self::work_stealing::WorkStealingThreadPool;
+
+/// What a thread pool with a bounded queue does with a job spawned while the queue
+/// is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueuePolicy {
+    /// Wait for room in the queue.
+    Block,
+    /// Fail with `KvsError::Busy`.
+    Reject,
+    /// Run the job on the spawning thread.
+    CallerRuns,
+    /// Drop the job that has waited longest to make room.
+    DropOldest,
+}
+
+impl QueuePolicy {
+    /// Names of all policies.
+    pub const VARIANTS: &'static [&'static str] =
+        &["block", "reject", "caller-runs", "drop-oldest"];
+}
 
 /// The trait that all thread pools should implement.
 pub trait ThreadPool: Clone + Send + 'static {
@@ -27,7 +57,58 @@ pub trait ThreadPool: Clone + Send + 'static {
     /// Spawning always succeeds, but if the function panics the threadpool continues
     /// to operate with the same number of threads &mdash; the thread count is not
     /// reduced nor is the thread pool destroyed, corrupted or invalidated.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the thread pool is shut down.
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Spawns a function into the thread pool, unless the pool is overloaded.
+    ///
+    /// The job is dropped without running when it fails. Pools without a bounded
+    /// queue always accept it.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Busy` if the pool rejects the job, and
+    /// `KvsError::PoolShutDown` if the pool is shut down.
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static;
+
+    /// Spawns a function into the thread pool, unless the pool is overloaded, and
+    /// returns a handle to wait for its result.
+    ///
+    /// A panic of the function is caught and returned by `JoinHandle::join` instead
+    /// of replacing the thread.
+    ///
+    /// # Errors
+    ///
+    /// It fails as `try_spawn` does.
+    fn spawn_with_handle<F, T>(&self, job: F) -> Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = handle::wrap(job);
+        self.try_spawn(job)?;
+        Ok(handle)
+    }
+
+    /// Stops accepting jobs, then waits for the jobs already spawned to finish and
+    /// for the threads to exit, for at most `timeout`.
+    ///
+    /// The pool is shut down for all its clones.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ShutdownTimeout` if jobs are still running after
+    /// `timeout`. Their threads exit once they finish.
+    fn shutdown(&self, timeout: Duration) -> Result<()>;
+
+    /// Stops accepting jobs, then waits for the jobs already spawned to finish and
+    /// for the threads to exit.
+    fn join(&self);
 }
@@ -1,22 +1,64 @@
-use std::thread;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
+use super::threads::Threads;
 use super::ThreadPool;
-use crate::Result;
+use crate::{KvsError, Result};
 
 /// It is actually not a thread pool. It spawns a new thread every time
 /// the `spawn` method is called.
 #[derive(Clone)]
-pub struct NaiveThreadPool;
+pub struct NaiveThreadPool {
+    // Spawning holds the read lock, so no thread starts once shut down
+    closed: Arc<RwLock<bool>>,
+    threads: Arc<Threads>,
+}
+
+impl NaiveThreadPool {
+    fn stop(&self, deadline: Option<Instant>) -> bool {
+        *self.closed.write().unwrap() = true;
+        self.threads.wait(deadline)
+    }
+}
 
 impl ThreadPool for NaiveThreadPool {
     fn new(_threads: u32) -> Result<Self> {
-        Ok(NaiveThreadPool)
+        Ok(NaiveThreadPool {
+            closed: Arc::new(RwLock::new(false)),
+            threads: Arc::new(Threads::default()),
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        match self.try_spawn(job) {
+            Err(KvsError::PoolShutDown) => panic!("The thread pool is shut down."),
+            res => res.expect("failed to spawn thread"),
+        }
+    }
+
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let closed = self.closed.read().unwrap();
+        if *closed {
+            return Err(KvsError::PoolShutDown);
+        }
+        Ok(self.threads.spawn(job)?)
+    }
+
+    fn shutdown(&self, timeout: Duration) -> Result<()> {
+        if self.stop(Some(Instant::now() + timeout)) {
+            Ok(())
+        } else {
+            Err(KvsError::ShutdownTimeout(timeout))
+        }
+    }
+
+    fn join(&self) {
+        self.stop(None);
     }
 }
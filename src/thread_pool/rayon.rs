@@ -1,24 +1,73 @@
+use super::threads::Threads;
 use super::ThreadPool;
 use crate::{KvsError, Result};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 /// Wrapper of rayon::ThreadPool
+///
+/// Shutting down drops the rayon pool, whose threads exit once they have run all
+/// the spawned jobs.
 #[derive(Clone)]
-pub struct RayonThreadPool(Arc<rayon::ThreadPool>);
+pub struct RayonThreadPool {
+    pool: Arc<RwLock<Option<rayon::ThreadPool>>>,
+    threads: Arc<Threads>,
+}
+
+impl RayonThreadPool {
+    fn stop(&self, deadline: Option<Instant>) -> bool {
+        self.pool.write().unwrap().take();
+        self.threads.wait(deadline)
+    }
+}
 
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self> {
+        let counted = Arc::new(Threads::default());
+        let exited = Arc::clone(&counted);
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(threads as usize)
+            .exit_handler(move |_| exited.exited())
             .build()
             .map_err(|e| KvsError::StringError(format!("{}", e)))?;
-        Ok(RayonThreadPool(Arc::new(pool)))
+        counted.started(pool.current_num_threads());
+        Ok(RayonThreadPool {
+            pool: Arc::new(RwLock::new(Some(pool))),
+            threads: counted,
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.0.spawn(job)
+        if self.try_spawn(job).is_err() {
+            panic!("The thread pool is shut down.");
+        }
+    }
+
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match &*self.pool.read().unwrap() {
+            Some(pool) => {
+                pool.spawn(job);
+                Ok(())
+            }
+            None => Err(KvsError::PoolShutDown),
+        }
+    }
+
+    fn shutdown(&self, timeout: Duration) -> Result<()> {
+        if self.stop(Some(Instant::now() + timeout)) {
+            Ok(())
+        } else {
+            Err(KvsError::ShutdownTimeout(timeout))
+        }
+    }
+
+    fn join(&self) {
+        self.stop(None);
     }
 }
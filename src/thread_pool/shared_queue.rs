@@ -1,58 +1,204 @@
+use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use super::ThreadPool;
-use crate::Result;
+use super::threads::Threads;
+use super::{QueuePolicy, ThreadPool};
+use crate::{KvsError, Result};
 use log::{debug, error};
 
-use crossbeam::channel::{self, Receiver, Sender};
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
 
 // Note for Rust training course: the thread pool is not implemented using
 // `catch_unwind` because it would require the task to be `UnwindSafe`.
 
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
 /// A thread pool using a shared queue inside.
 ///
 /// If a spawned task panics, the old thread will be destroyed and a new one will be
 /// created. It fails silently when any failure to create the thread at the OS level
 /// is captured after the thread pool is created. So, the thread number in the pool
 /// can decrease to zero, then spawning a task to the thread pool will panic.
+///
+/// The queue is unbounded, unless the pool is created by `with_queue`.
 #[derive(Clone)]
 pub struct SharedQueueThreadPool {
-    tx: Sender<Box<dyn FnOnce() + Send + 'static>>,
+    // Taken on shutdown, so the threads see the queue disconnected once it is empty.
+    // Spawning holds the read lock, but not while it waits for room.
+    tx: Arc<RwLock<Option<Sender<Job>>>>,
+    // Only kept to drop the oldest jobs, as it keeps the queue open when every
+    // thread is gone
+    rx: Option<Receiver<Job>>,
+    policy: QueuePolicy,
+    threads: Arc<Threads>,
 }
 
-impl ThreadPool for SharedQueueThreadPool {
-    fn new(threads: u32) -> Result<Self> {
-        let (tx, rx) = channel::unbounded::<Box<dyn FnOnce() + Send + 'static>>();
+impl SharedQueueThreadPool {
+    /// Creates a thread pool whose queue holds at most `capacity` jobs waiting for a
+    /// thread, with `policy` deciding what happens to the jobs spawned while it is
+    /// full.
+    ///
+    /// A capacity of zero hands each job straight to an idle thread.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Config` for the `DropOldest` policy with a capacity of
+    /// zero, as there is never a queued job to drop.
+    pub fn with_queue(threads: u32, capacity: usize, policy: QueuePolicy) -> Result<Self> {
+        if capacity == 0 && policy == QueuePolicy::DropOldest {
+            return Err(KvsError::Config(
+                "the drop-oldest policy needs a queue of at least one job".to_owned(),
+            ));
+        }
+        SharedQueueThreadPool::start(threads, channel::bounded(capacity), policy)
+    }
+
+    fn start(
+        threads: u32,
+        (tx, rx): (Sender<Job>, Receiver<Job>),
+        policy: QueuePolicy,
+    ) -> Result<Self> {
+        let pool = SharedQueueThreadPool {
+            tx: Arc::new(RwLock::new(Some(tx))),
+            rx: if policy == QueuePolicy::DropOldest {
+                Some(rx.clone())
+            } else {
+                None
+            },
+            policy,
+            threads: Arc::new(Threads::default()),
+        };
         for _ in 0..threads {
-            let rx = TaskReceiver(rx.clone());
-            thread::Builder::new().spawn(move || run_tasks(rx))?;
+            let rx = TaskReceiver {
+                rx: rx.clone(),
+                threads: Arc::clone(&pool.threads),
+            };
+            if let Err(e) = pool.threads.spawn(move || run_tasks(rx)) {
+                pool.join();
+                return Err(e.into());
+            }
         }
-        Ok(SharedQueueThreadPool { tx })
+        Ok(pool)
+    }
+
+    /// Queues the job, applying `policy` if the queue is full.
+    fn submit(&self, job: Job, policy: QueuePolicy) -> Result<()> {
+        let guard = self.tx.read().unwrap();
+        let tx = guard.as_ref().ok_or(KvsError::PoolShutDown)?;
+        let mut job = match tx.try_send(job) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Full(job)) => job,
+            Err(TrySendError::Disconnected(_)) => panic!("The thread pool has no thread."),
+        };
+        match policy {
+            QueuePolicy::Block => {
+                // A shutdown takes the sender under the write lock, which must not
+                // wait for room in the queue
+                let tx = tx.clone();
+                drop(guard);
+                tx.send(job).expect("The thread pool has no thread.")
+            }
+            QueuePolicy::Reject => return Err(KvsError::Busy),
+            QueuePolicy::CallerRuns => {
+                // The job may spawn into the pool, which must not wait for a shutdown
+                // waiting for this lock
+                drop(guard);
+                job()
+            }
+            QueuePolicy::DropOldest => {
+                let rx = self.rx.as_ref().unwrap();
+                loop {
+                    if rx.try_recv().is_ok() {
+                        debug!("Dropped the oldest job of the full thread pool queue.");
+                    }
+                    match tx.try_send(job) {
+                        Ok(()) => break,
+                        Err(e) => job = e.into_inner(),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn stop(&self, deadline: Option<Instant>) -> bool {
+        self.tx.write().unwrap().take();
+        self.threads.wait(deadline)
+    }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        SharedQueueThreadPool::start(threads, channel::unbounded(), QueuePolicy::Block)
     }
 
     /// Spawns a function into the thread pool.
     ///
+    /// When the queue is full, it waits for room under the `Reject` policy, as
+    /// spawning cannot fail.
+    ///
     /// # Panics
     ///
-    /// Panics if the thread pool has no thread.
+    /// Panics if the thread pool has no thread or is shut down.
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.tx
-            .send(Box::new(job))
-            .expect("The thread pool has no thread.");
+        let policy = match self.policy {
+            QueuePolicy::Reject => QueuePolicy::Block,
+            policy => policy,
+        };
+        match self.submit(Box::new(job), policy) {
+            Err(KvsError::PoolShutDown) => panic!("The thread pool is shut down."),
+            res => res.expect("only the Reject policy fails"),
+        }
+    }
+
+    /// Spawns a function into the thread pool, applying the queue policy if the
+    /// queue is full.
+    ///
+    /// Under the `CallerRuns` policy, a panic of the job unwinds the calling thread.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the thread pool has no thread.
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.submit(Box::new(job), self.policy)
+    }
+
+    /// Stops accepting jobs, then waits for the queued jobs to run and the threads
+    /// to exit, for at most `timeout`.
+    ///
+    /// The jobs of spawning threads blocked on a full queue are run too, within
+    /// the same timeout.
+    fn shutdown(&self, timeout: Duration) -> Result<()> {
+        if self.stop(Some(Instant::now() + timeout)) {
+            Ok(())
+        } else {
+            Err(KvsError::ShutdownTimeout(timeout))
+        }
+    }
+
+    fn join(&self) {
+        self.stop(None);
     }
 }
 
 #[derive(Clone)]
-struct TaskReceiver(Receiver<Box<dyn FnOnce() + Send + 'static>>);
+struct TaskReceiver {
+    rx: Receiver<Job>,
+    threads: Arc<Threads>,
+}
 
 impl Drop for TaskReceiver {
     fn drop(&mut self) {
         if thread::panicking() {
             let rx = self.clone();
-            if let Err(e) = thread::Builder::new().spawn(move || run_tasks(rx)) {
+            if let Err(e) = self.threads.spawn(move || run_tasks(rx)) {
                 error!("Failed to spawn a thread: {}", e);
             }
         }
@@ -61,11 +207,14 @@ impl Drop for TaskReceiver {
 
 fn run_tasks(rx: TaskReceiver) {
     loop {
-        match rx.0.recv() {
+        match rx.rx.recv() {
             Ok(task) => {
                 task();
             }
-            Err(_) => debug!("Thread exits because the thread pool is destroyed."),
+            Err(_) => {
+                debug!("Thread exits because the thread pool is shut down.");
+                break;
+            }
         }
     }
 }
@@ -0,0 +1,86 @@
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+/// Keeps track of the threads of a pool, to wait for them to exit.
+#[derive(Default)]
+pub(super) struct Threads {
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    running: Mutex<usize>,
+    exited: Condvar,
+}
+
+impl Threads {
+    /// Spawns a thread counted as running until `f` returns or panics.
+    pub(super) fn spawn<F>(self: &Arc<Self>, f: F) -> io::Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.started(1);
+        let threads = Arc::clone(self);
+        let res = thread::Builder::new().spawn(move || {
+            let _exit = ExitGuard(threads);
+            f()
+        });
+        match res {
+            Ok(handle) => {
+                let mut handles = self.handles.lock().unwrap();
+                handles.retain(|handle| !handle.is_finished());
+                handles.push(handle);
+                Ok(())
+            }
+            Err(e) => {
+                self.exited();
+                Err(e)
+            }
+        }
+    }
+
+    /// Counts threads spawned by someone else as running.
+    pub(super) fn started(&self, count: usize) {
+        *self.running.lock().unwrap() += count;
+    }
+
+    /// Counts a thread as exited.
+    pub(super) fn exited(&self) {
+        *self.running.lock().unwrap() -= 1;
+        self.exited.notify_all();
+    }
+
+    /// Waits for every thread to exit, until `deadline` if any, and joins the ones
+    /// spawned by `spawn`.
+    ///
+    /// Returns false if threads are still running at the deadline.
+    pub(super) fn wait(&self, deadline: Option<Instant>) -> bool {
+        let mut running = self.running.lock().unwrap();
+        while *running > 0 {
+            running = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.exited.wait_timeout(running, deadline - now).unwrap().0
+                }
+                None => self.exited.wait(running).unwrap(),
+            };
+        }
+        drop(running);
+
+        let handles: Vec<_> = self.handles.lock().unwrap().drain(..).collect();
+        for handle in handles {
+            // Panics were reported by the threads themselves
+            let _ = handle.join();
+        }
+        true
+    }
+}
+
+struct ExitGuard(Arc<Threads>);
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        self.0.exited();
+    }
+}
@@ -0,0 +1,192 @@
+use std::iter;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::threads::Threads;
+use super::ThreadPool;
+use crate::{KvsError, Result};
+use log::{debug, error};
+
+use crossbeam::deque::{Injector, Stealer, Worker};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// How long an idle thread sleeps before looking for work again, in case it missed
+/// a wake up.
+const IDLE_WAIT: Duration = Duration::from_millis(100);
+
+/// A thread pool where every thread has a deque of its own.
+///
+/// Jobs are spawned into a global queue. A thread takes a batch of them into its
+/// deque, and steals from the deques of the other threads once both are empty.
+///
+/// As with `SharedQueueThreadPool`, a thread whose job panics is replaced by a new
+/// one, which takes over its deque.
+#[derive(Clone)]
+pub struct WorkStealingThreadPool {
+    shared: Arc<Shared>,
+    threads: Arc<Threads>,
+}
+
+struct Shared {
+    injector: Injector<Job>,
+    stealers: Vec<Stealer<Job>>,
+    // Spawning holds the read lock, so no job is queued once shut down
+    closed: RwLock<bool>,
+    sleep: Mutex<()>,
+    wake_up: Condvar,
+}
+
+impl Shared {
+    fn notify_one(&self) {
+        let _sleep = self.sleep.lock().unwrap();
+        self.wake_up.notify_one();
+    }
+
+    /// Finds the next job, from the deque of the thread, then the global queue, then
+    /// the other deques.
+    fn find_task(&self, local: &Worker<Job>) -> Option<Job> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(|s| s.steal()).collect())
+            })
+            .find(|s| !s.is_retry())
+            .and_then(|s| s.success())
+        })
+    }
+
+    fn has_tasks(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|s| !s.is_empty())
+    }
+}
+
+impl WorkStealingThreadPool {
+    fn stop(&self, deadline: Option<Instant>) -> bool {
+        *self.shared.closed.write().unwrap() = true;
+        {
+            let _sleep = self.shared.sleep.lock().unwrap();
+            self.shared.wake_up.notify_all();
+        }
+        self.threads.wait(deadline)
+    }
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let locals: Vec<Worker<Job>> = (0..threads).map(|_| Worker::new_fifo()).collect();
+        let pool = WorkStealingThreadPool {
+            shared: Arc::new(Shared {
+                injector: Injector::new(),
+                stealers: locals.iter().map(|local| local.stealer()).collect(),
+                closed: RwLock::new(false),
+                sleep: Mutex::new(()),
+                wake_up: Condvar::new(),
+            }),
+            threads: Arc::new(Threads::default()),
+        };
+        for local in locals {
+            let runner = TaskRunner {
+                local: Some(local),
+                shared: Arc::clone(&pool.shared),
+                threads: Arc::clone(&pool.threads),
+            };
+            if let Err(e) = pool.threads.spawn(move || run_tasks(runner)) {
+                pool.join();
+                return Err(e.into());
+            }
+        }
+        Ok(pool)
+    }
+
+    /// Spawns a function into the thread pool.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the thread pool is shut down.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.try_spawn(job).is_err() {
+            panic!("The thread pool is shut down.");
+        }
+    }
+
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let closed = self.shared.closed.read().unwrap();
+        if *closed {
+            return Err(KvsError::PoolShutDown);
+        }
+        self.shared.injector.push(Box::new(job));
+        // Sleeping threads read the flag while holding the sleep lock
+        drop(closed);
+        self.shared.notify_one();
+        Ok(())
+    }
+
+    fn shutdown(&self, timeout: Duration) -> Result<()> {
+        if self.stop(Some(Instant::now() + timeout)) {
+            Ok(())
+        } else {
+            Err(KvsError::ShutdownTimeout(timeout))
+        }
+    }
+
+    fn join(&self) {
+        self.stop(None);
+    }
+}
+
+/// The state of a thread, handed over to its replacement if a job panics.
+struct TaskRunner {
+    local: Option<Worker<Job>>,
+    shared: Arc<Shared>,
+    threads: Arc<Threads>,
+}
+
+impl Drop for TaskRunner {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let runner = TaskRunner {
+                local: self.local.take(),
+                shared: Arc::clone(&self.shared),
+                threads: Arc::clone(&self.threads),
+            };
+            if let Err(e) = self.threads.spawn(move || run_tasks(runner)) {
+                error!("Failed to spawn a thread: {}", e);
+            }
+        }
+    }
+}
+
+fn run_tasks(runner: TaskRunner) {
+    let shared = &runner.shared;
+    let local = runner.local.as_ref().unwrap();
+    loop {
+        // Read before looking for a job, so no job is missed once shut down
+        let closed = *shared.closed.read().unwrap();
+        if let Some(task) = shared.find_task(local) {
+            if !local.is_empty() {
+                // Let another thread steal the rest of the batch
+                shared.notify_one();
+            }
+            task();
+            continue;
+        }
+        if closed {
+            debug!("Thread exits because the thread pool is shut down.");
+            return;
+        }
+
+        let sleep = shared.sleep.lock().unwrap();
+        if !shared.has_tasks() && !*shared.closed.read().unwrap() {
+            let _ = shared.wake_up.wait_timeout(sleep, IDLE_WAIT).unwrap();
+        }
+    }
+}
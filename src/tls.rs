@@ -0,0 +1,277 @@
+//! This module provides TLS for the connections of the protocol.
+//!
+//! A server serving TLS only accepts TLS connections, so its clients, its
+//! replicas and the other members of its cluster all connect with a
+//! `TlsConnector` trusting its certificate.
+
+use crate::{KvsError, Result};
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, PrivateKey, RootCertStore, ServerConfig,
+    ServerConnection, ServerName, StreamOwned,
+};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Server side TLS settings, the certificate presented to the clients.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+    /// Creates an acceptor presenting the certificate chain of `cert_file`, signed
+    /// by the private key of `key_file`, both PEM files.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Config` if a file cannot be read, or does not hold a
+    /// matching certificate and key.
+    pub fn new(cert_file: &Path, key_file: &Path) -> Result<Self> {
+        let certs = read_certs(cert_file)?;
+        let key = read_key(key_file)?;
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| KvsError::Config(format!("invalid TLS certificate: {}", e)))?;
+        Ok(TlsAcceptor {
+            config: Arc::new(config),
+        })
+    }
+}
+
+impl fmt::Debug for TlsAcceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsAcceptor").finish_non_exhaustive()
+    }
+}
+
+/// Client side TLS settings, the certificates trusted to verify the servers.
+///
+/// A server certificate is verified against the IP address connected to, unless a
+/// server name is given.
+#[derive(Clone)]
+pub struct TlsConnector {
+    config: Arc<ClientConfig>,
+    server_name: Option<ServerName>,
+}
+
+impl TlsConnector {
+    /// Creates a connector trusting the certificates of `ca_file`, a PEM file.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Config` if the file cannot be read or holds no valid
+    /// certificate.
+    pub fn new(ca_file: &Path) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(ca_file)? {
+            roots.add(&cert).map_err(|e| {
+                KvsError::Config(format!("invalid certificate in {:?}: {}", ca_file, e))
+            })?;
+        }
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(TlsConnector {
+            config: Arc::new(config),
+            server_name: None,
+        })
+    }
+
+    /// Verifies the server certificates against `name` rather than their address.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Config` if `name` is neither a DNS name nor an IP
+    /// address.
+    pub fn server_name(mut self, name: &str) -> Result<Self> {
+        let name = ServerName::try_from(name)
+            .map_err(|_| KvsError::Config(format!("invalid TLS server name `{}`", name)))?;
+        self.server_name = Some(name);
+        Ok(self)
+    }
+}
+
+impl fmt::Debug for TlsConnector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConnector")
+            .field("server_name", &self.server_name)
+            .finish_non_exhaustive()
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = open_pem(path)?;
+    let certs = rustls_pemfile::certs(&mut reader)
+        .map_err(|e| KvsError::Config(format!("cannot read {:?}: {}", path, e)))?;
+    if certs.is_empty() {
+        return Err(KvsError::Config(format!("no certificate in {:?}", path)));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = open_pem(path)?;
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| KvsError::Config(format!("cannot read {:?}: {}", path, e)))?;
+        match item {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => {}
+            None => return Err(KvsError::Config(format!("no private key in {:?}", path))),
+        }
+    }
+}
+
+fn open_pem(path: &Path) -> Result<BufReader<File>> {
+    let file =
+        File::open(path).map_err(|e| KvsError::Config(format!("cannot read {:?}: {}", path, e)))?;
+    Ok(BufReader::new(file))
+}
+
+trait Session: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Session for T {}
+
+/// A connection of the protocol, in the clear or over TLS.
+///
+/// Clones share the TLS session, which each read or write holds for its duration,
+/// so a connection is read and written by one thread at a time.
+pub(crate) struct Stream {
+    tcp: TcpStream,
+    session: Option<Arc<Mutex<dyn Session>>>,
+}
+
+impl Stream {
+    /// Wraps a connection accepted by a server, the handshake happening on the
+    /// first read or write.
+    pub(crate) fn accept(tcp: TcpStream, tls: Option<&TlsAcceptor>) -> Result<Stream> {
+        let session: Option<Arc<Mutex<dyn Session>>> = match tls {
+            Some(tls) => {
+                let conn = ServerConnection::new(Arc::clone(&tls.config)).map_err(tls_error)?;
+                Some(Arc::new(Mutex::new(StreamOwned::new(
+                    conn,
+                    tcp.try_clone()?,
+                ))))
+            }
+            None => None,
+        };
+        Ok(Stream { tcp, session })
+    }
+
+    /// Wraps a connection to a server, the handshake happening on the first read
+    /// or write.
+    pub(crate) fn connect(tcp: TcpStream, tls: Option<&TlsConnector>) -> Result<Stream> {
+        let session: Option<Arc<Mutex<dyn Session>>> = match tls {
+            Some(tls) => {
+                let name = match &tls.server_name {
+                    Some(name) => name.clone(),
+                    None => ServerName::IpAddress(tcp.peer_addr()?.ip()),
+                };
+                let conn =
+                    ClientConnection::new(Arc::clone(&tls.config), name).map_err(tls_error)?;
+                Some(Arc::new(Mutex::new(StreamOwned::new(
+                    conn,
+                    tcp.try_clone()?,
+                ))))
+            }
+            None => None,
+        };
+        Ok(Stream { tcp, session })
+    }
+
+    /// Returns the underlying socket, whose options apply to every clone.
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        &self.tcp
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        Ok(Stream {
+            tcp: self.tcp.try_clone()?,
+            session: self.session.clone(),
+        })
+    }
+
+    /// Returns true if the peer has neither sent data nor closed the connection.
+    ///
+    /// It does not block.
+    pub(crate) fn is_idle(&self) -> bool {
+        if self.tcp.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut buf = [0; 1];
+        let res = match &self.session {
+            None => self.tcp.peek(&mut buf),
+            // Reading also processes the TLS messages carrying no data, such as
+            // the session tickets sent after the handshake
+            Some(session) => lock(session).read(&mut buf),
+        };
+        let idle = matches!(res, Err(ref e) if e.kind() == io::ErrorKind::WouldBlock);
+        self.tcp.set_nonblocking(false).is_ok() && idle
+    }
+}
+
+fn lock<'a>(session: &'a Mutex<dyn Session + 'static>) -> MutexGuard<'a, dyn Session + 'static> {
+    // A panic while holding the session leaves it broken at worst, which the next
+    // read or write reports
+    session.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &self.session {
+            None => (&self.tcp).read(buf),
+            Some(session) => match lock(session).read(buf) {
+                // Peers close without a close_notify alert. A message truncated by
+                // an attacker still fails to parse.
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+                res => res,
+            },
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &self.session {
+            None => (&self.tcp).write(buf),
+            Some(session) => lock(session).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.session {
+            None => (&self.tcp).flush(),
+            Some(session) => lock(session).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
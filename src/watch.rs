@@ -0,0 +1,219 @@
+//! This module provides notifications of the writes to an engine.
+//!
+//! Every write gets a sequence number, greater than the numbers of the writes
+//! before it. Engines persisting their data also persist how far the numbers went,
+//! so they keep growing across restarts and a number is never given to two writes.
+//!
+//! The latest writes are kept in memory only, up to a number of them and of bytes.
+//! A watcher can resume right after the last change it saw as long as the engine
+//! stays open and the change is still kept: resuming from an older change, or one
+//! made before a restart, fails with `KvsError::SequenceExpired`, and the watcher
+//! must then read the keys it watches again.
+
+use crate::replication::LogOp;
+use crate::{KvsError, Result};
+use crossbeam::channel::{self, Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub use crossbeam::channel::RecvTimeoutError;
+
+/// Number of past changes a watcher can resume from.
+const HISTORY_SIZE: usize = 10_000;
+/// Bytes of keys and values of the past changes kept at most.
+const HISTORY_BYTES: usize = 16 * 1024 * 1024;
+/// A watcher this many changes behind is dropped, it can resume from its last change.
+const MAX_PENDING: usize = 10_000;
+/// Sequence numbers reserved in the sequence file at once.
+const SEQ_BLOCK: u64 = 1 << 16;
+
+/// A write to the engine.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    /// Sequence number of the write, increasing with every write.
+    pub seq: u64,
+    /// The write.
+    pub op: LogOp,
+}
+
+impl ChangeEvent {
+    /// Returns the key written.
+    pub fn key(&self) -> &str {
+        match &self.op {
+            LogOp::Set { key, .. } | LogOp::Remove { key } => key,
+        }
+    }
+}
+
+/// Receives the changes to the keys starting with a prefix.
+///
+/// The changes stop when the watcher falls too far behind or the engine is closed.
+pub struct Watcher {
+    // Shared with the history and the other watchers
+    receiver: Receiver<Arc<ChangeEvent>>,
+}
+
+impl Watcher {
+    /// Blocks until the next change.
+    pub fn recv(&self) -> Option<ChangeEvent> {
+        self.receiver.recv().ok().map(unshare)
+    }
+
+    /// Blocks until the next change, for at most `timeout`.
+    pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> std::result::Result<ChangeEvent, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout).map(unshare)
+    }
+}
+
+fn unshare(event: Arc<ChangeEvent>) -> ChangeEvent {
+    Arc::try_unwrap(event).unwrap_or_else(|event| (*event).clone())
+}
+
+impl Iterator for Watcher {
+    type Item = ChangeEvent;
+
+    fn next(&mut self) -> Option<ChangeEvent> {
+        self.recv()
+    }
+}
+
+/// Assigns sequence numbers to writes and hands them to the watchers.
+///
+/// Writes must be published in the order they are applied.
+pub(crate) struct ChangeFeed {
+    next_seq: u64,
+    // File holding `reserved`, the numbers below it may have been given
+    seq_file: Option<PathBuf>,
+    reserved: u64,
+    history: VecDeque<Arc<ChangeEvent>>,
+    // Bytes of the keys and values in `history`
+    history_bytes: usize,
+    watchers: Vec<(String, Sender<Arc<ChangeEvent>>)>,
+}
+
+impl ChangeFeed {
+    /// Creates a feed forgetting its sequence numbers when dropped. They start from
+    /// the clock, so they only grow across restarts if it does not step back.
+    pub(crate) fn new() -> Self {
+        ChangeFeed::starting_at(clock_micros(), None)
+    }
+
+    /// Opens a feed continuing after the sequence numbers reserved in `seq_file`,
+    /// or from the clock if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during reading the file, and returns
+    /// `KvsError::StringError` if it is corrupted.
+    pub(crate) fn open(seq_file: impl Into<PathBuf>) -> Result<Self> {
+        let seq_file = seq_file.into();
+        let next_seq = if seq_file.exists() {
+            let reserved = fs::read_to_string(&seq_file)?;
+            reserved.trim().parse().map_err(|_| {
+                KvsError::StringError(format!("corrupted sequence file {:?}", seq_file))
+            })?
+        } else {
+            clock_micros()
+        };
+        Ok(ChangeFeed::starting_at(next_seq, Some(seq_file)))
+    }
+
+    fn starting_at(next_seq: u64, seq_file: Option<PathBuf>) -> Self {
+        ChangeFeed {
+            next_seq,
+            seq_file,
+            reserved: next_seq,
+            history: VecDeque::new(),
+            history_bytes: 0,
+            watchers: Vec::new(),
+        }
+    }
+
+    /// Gives the next sequence number to a write and sends it to its watchers.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during reserving more sequence numbers, in which
+    /// case the write is not published.
+    pub(crate) fn publish(&mut self, op: LogOp) -> Result<()> {
+        if let Some(seq_file) = &self.seq_file {
+            if self.next_seq == self.reserved {
+                let reserved = self.next_seq + SEQ_BLOCK;
+                let mut tmp = seq_file.clone().into_os_string();
+                tmp.push(".tmp");
+                let mut file = File::create(&tmp)?;
+                write!(file, "{}", reserved)?;
+                file.sync_data()?;
+                fs::rename(&tmp, seq_file)?;
+                self.reserved = reserved;
+            }
+        }
+        let event = Arc::new(ChangeEvent {
+            seq: self.next_seq,
+            op,
+        });
+        self.next_seq += 1;
+
+        self.watchers.retain(|(prefix, sender)| {
+            if !event.key().starts_with(prefix.as_str()) {
+                return true;
+            }
+            sender.len() < MAX_PENDING && sender.send(Arc::clone(&event)).is_ok()
+        });
+
+        self.history_bytes += event_size(&event);
+        self.history.push_back(event);
+        while self.history.len() > HISTORY_SIZE || self.history_bytes > HISTORY_BYTES {
+            if let Some(event) = self.history.pop_front() {
+                self.history_bytes -= event_size(&event);
+            }
+        }
+        Ok(())
+    }
+
+    /// Watches the keys starting with `prefix`, from the change after `from` or from
+    /// the next write.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::SequenceExpired` if the changes after `from` are no
+    /// longer kept.
+    pub(crate) fn watch(&mut self, prefix: &str, from: Option<u64>) -> Result<Watcher> {
+        let (sender, receiver) = channel::unbounded();
+        if let Some(from) = from {
+            let first = self.history.front().map_or(self.next_seq, |e| e.seq);
+            if from + 1 < first || from >= self.next_seq {
+                return Err(KvsError::SequenceExpired(from));
+            }
+            for event in &self.history {
+                if event.seq > from && event.key().starts_with(prefix) {
+                    sender.send(Arc::clone(event)).unwrap();
+                }
+            }
+        }
+        self.watchers.push((prefix.to_owned(), sender));
+        Ok(Watcher { receiver })
+    }
+}
+
+fn event_size(event: &ChangeEvent) -> usize {
+    match &event.op {
+        LogOp::Set { key, value } => key.len() + value.len(),
+        LogOp::Remove { key } => key.len(),
+    }
+}
+
+fn clock_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
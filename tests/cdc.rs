@@ -0,0 +1,92 @@
+use kvs::cdc::LogTailer;
+use kvs::replication::{LogOp, LogPosition};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, KvsError, Result};
+use tempfile::TempDir;
+
+fn set(key: &str, value: &str) -> LogOp {
+    LogOp::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+fn ops(tailer: &mut LogTailer) -> Result<Vec<LogOp>> {
+    Ok(tailer.poll()?.into_iter().map(|entry| entry.op).collect())
+}
+
+#[test]
+fn tail_follows_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: Some(1000),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key0".to_owned(), "value".to_owned())?;
+
+    let mut tailer = LogTailer::open(temp_dir.path(), Some(LogPosition::default()))?;
+    assert_eq!(ops(&mut tailer)?, vec![set("key0", "value")]);
+
+    // Overwrites compact the log several times while the tailer keeps up
+    let mut seen = Vec::new();
+    for iter in 0..200 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+        if iter % 10 == 0 {
+            store.remove("key0".to_owned()).ok();
+        }
+        seen.extend(ops(&mut tailer)?);
+    }
+    let mut expected = Vec::new();
+    for iter in 0..200 {
+        expected.push(set("key1", &format!("{}", iter)));
+        if iter == 0 {
+            expected.push(LogOp::Remove {
+                key: "key0".to_owned(),
+            });
+        }
+    }
+    assert_eq!(seen, expected);
+    assert!(tailer.position().term > 3);
+
+    // A tailer resumes from its position
+    let pos = tailer.position();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let mut tailer = LogTailer::open(temp_dir.path(), Some(pos))?;
+    assert_eq!(ops(&mut tailer)?, vec![set("key2", "value2")]);
+    assert!(ops(&mut tailer)?.is_empty());
+
+    // A tailer left behind by compactions cannot resume
+    let pos = tailer.position();
+    for iter in 0..200 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    assert!(matches!(
+        LogTailer::open(temp_dir.path(), Some(pos)),
+        Err(KvsError::Compacted(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn tail_from_end() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut tailer = LogTailer::open(temp_dir.path(), None)?;
+    assert!(ops(&mut tailer)?.is_empty());
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(tailer.next().unwrap()?.op, set("key2", "value2"));
+
+    // A new writer term after a restart is followed
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(
+        ops(&mut tailer)?,
+        vec![LogOp::Remove {
+            key: "key1".to_owned()
+        }]
+    );
+    Ok(())
+}
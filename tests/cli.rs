@@ -1,7 +1,10 @@
 use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsEngine};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -212,12 +215,24 @@ fn cli_wrong_engine() {
     }
 }
 
-fn cli_access_server(engine: &str, addr: &str) {
+fn cli_access_server(engine: &str, pool: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
+    let server_args = [
+        "--engine",
+        engine,
+        "--addr",
+        addr,
+        "--pool",
+        pool,
+        "--threads",
+        "4",
+        "--data-dir",
+        "data",
+    ];
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(&server_args)
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
@@ -294,11 +309,15 @@ fn cli_access_server(engine: &str, addr: &str) {
     sender.send(()).unwrap();
     handle.join().unwrap();
 
+    // The engine marker lives with the data
+    assert!(temp_dir.path().join("data").join("engine").is_file());
+    assert!(!temp_dir.path().join("engine").exists());
+
     // Reopen and check value
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(&server_args)
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
@@ -328,10 +347,693 @@ fn cli_access_server(engine: &str, addr: &str) {
 
 #[test]
 fn cli_access_server_kvs_engine() {
-    cli_access_server("kvs", "127.0.0.1:4004");
+    cli_access_server("kvs", "rayon", "127.0.0.1:4004");
 }
 
 #[test]
 fn cli_access_server_sled_engine() {
-    cli_access_server("sled", "127.0.0.1:4005");
+    cli_access_server("sled", "rayon", "127.0.0.1:4005");
+}
+
+#[test]
+fn cli_access_server_kvs_engine_naive_pool() {
+    cli_access_server("kvs", "naive", "127.0.0.1:4011");
+}
+
+#[test]
+fn cli_access_server_kvs_engine_shared_pool() {
+    cli_access_server("kvs", "shared", "127.0.0.1:4012");
+}
+
+#[test]
+fn cli_access_server_sled_engine_naive_pool() {
+    cli_access_server("sled", "naive", "127.0.0.1:4013");
+}
+
+#[test]
+fn cli_access_server_sled_engine_shared_pool() {
+    cli_access_server("sled", "shared", "127.0.0.1:4014");
+}
+
+#[test]
+fn cli_access_server_kvs_engine_stealing_pool() {
+    cli_access_server("kvs", "stealing", "127.0.0.1:4020");
+}
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "rayon", "127.0.0.1:4021");
+}
+
+#[test]
+fn cli_wrong_engine_in_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(&["--engine", "sled", "--addr", "127.0.0.1:4015"])
+        .args(&["--data-dir", "data"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+
+    // Another data directory is free to use another engine
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4015"])
+        .args(&["--data-dir", "other"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4016"])
+        .args(&["--data-dir", "data"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_admin_stats() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let metrics_addr = "127.0.0.1:4007";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&[
+            "--engine",
+            "memory",
+            "--addr",
+            addr,
+            "--metrics-addr",
+            metrics_addr,
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("kvs_requests_total{op=\"set\"} 1"))
+        .stdout(contains("kvs_request_errors_total{op=\"remove\"} 1"));
+
+    let mut stream = TcpStream::connect(metrics_addr).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("kvs_requests_total{op=\"set\"} 1"));
+    assert!(response.contains("kvs_disk_bytes"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_slow_request_log_json() {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let addr = "127.0.0.1:4008";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&[
+            "--engine",
+            "memory",
+            "--addr",
+            addr,
+            "--log-format",
+            "json",
+            "--slow-threshold",
+            "0",
+        ])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "secret-value", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    for line in content.lines() {
+        let record: serde_json::Value = serde_json::from_str(line).expect("log line is not json");
+        assert!(record["message"].is_string());
+    }
+    assert!(content.contains("slow request"));
+    assert!(content.contains("key=\\\"key1\\\""));
+    assert!(content.contains("value=<redacted 12 bytes>"));
+    assert!(!content.contains("secret-value"));
+}
+
+#[test]
+fn cli_server_config_file() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4009";
+    let config_path = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config_path,
+        format!(
+            r#"
+addr = "{}"
+engine = "memory"
+data_dir = "data"
+
+[pool]
+kind = "shared"
+threads = 2
+
+[limits]
+max_value_size = 8
+"#,
+            addr
+        ),
+    )
+    .unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--config", config_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "too-long-value", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("exceeds the limit of 8 bytes"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    assert!(temp_dir.path().join("data").is_dir());
+}
+
+#[test]
+fn cli_invalid_server_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("kvs.toml");
+
+    fs::write(&config_path, "[pool]\nthreads = 0\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", config_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("pool.threads must be positive"));
+
+    fs::write(
+        &config_path,
+        "[pool]\nkind = \"shared\"\nqueue_size = 0\nqueue_policy = \"drop-oldest\"\n",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", config_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "pool.queue_size must be positive with drop-oldest",
+        ));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--pool",
+            "shared",
+            "--queue-size",
+            "8",
+            "--queue-policy",
+            "caller-runs",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("caller-runs is not supported"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--pool", "rayon", "--queue-policy", "reject"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "pool.queue_policy is only supported by the shared pool",
+        ));
+
+    fs::write(&config_path, "engine = \"sled\"\ncompression = \"lz4\"\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", config_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("compression is not supported by the sled engine"));
+
+    fs::write(&config_path, "unknown_field = 1\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", config_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown field"));
+
+    fs::write(&config_path, "addr = \"127.0.0.1:4010\"\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", config_path.to_str().unwrap()])
+        .args(&["--addr", "invalid-addr"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not a socket address"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--tls-cert", "cert.pem"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("tls.cert and tls.key must be set together"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--tls-cert", "cert.pem", "--tls-key", "key.pem"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("cannot read \"cert.pem\""));
+}
+
+// The server serves TLS to the clients trusting its certificate
+#[test]
+fn cli_tls() {
+    let temp_dir = TempDir::new().unwrap();
+    let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()]).unwrap();
+    fs::write(
+        temp_dir.path().join("cert.pem"),
+        cert.serialize_pem().unwrap(),
+    )
+    .unwrap();
+    fs::write(
+        temp_dir.path().join("key.pem"),
+        cert.serialize_private_key_pem(),
+    )
+    .unwrap();
+
+    let addr = "127.0.0.1:4023";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--tls-cert", "cert.pem", "--tls-key", "key.pem"])
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "set", "key1", "value1", "--addr", addr, "--tls-ca", "cert.pem",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--tls-ca", "cert.pem", "get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr, "--tls-ca", "cert.pem"])
+        .args(&["--tls-server-name", "kvs.example.com"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["stats", "--addr", addr, "--tls-ca", "cert.pem"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("kvs_"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// The memory engine saves its pairs to its snapshot when the server shuts down
+#[cfg(unix)]
+#[test]
+fn cli_memory_engine_snapshot() {
+    use nix::sys::signal::{kill, Signal};
+    use nix::unistd::Pid;
+
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4022";
+    let start = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "memory", "--snapshot", "snapshot.json"])
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+
+    let mut child = start();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM).unwrap();
+    assert!(child.wait().unwrap().success());
+    assert!(temp_dir.path().join("snapshot.json").exists());
+
+    let mut child = start();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // Without a snapshot, a cluster would keep applied entries the engine lost
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "memory", "--addr", addr, "--cluster-id", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("the memory engine needs a snapshot"));
+}
+
+#[test]
+fn cli_single_node_cluster() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4017";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", addr, "--cluster-id", "1"])
+        .args(&["--cluster-member", "1@127.0.0.1:4017"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["cluster", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("state: leader"))
+        .stdout(contains("  1 127.0.0.1:4017"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["remove-node", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr("cannot remove the last member\n");
+
+    assert!(temp_dir.path().join("raft").join("log").exists());
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_admin_tail() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.remove("key1".to_owned()).unwrap();
+    let position_file = temp_dir.path().join("tail-position");
+
+    // Prints the first `count` lines of `kvs-admin tail` from the saved position
+    let tail = |count: usize| -> Vec<String> {
+        let mut child = Command::cargo_bin("kvs-admin")
+            .unwrap()
+            .args(&["tail", "--from-start", "--position-file"])
+            .arg(&position_file)
+            .arg("--data-dir")
+            .arg(temp_dir.path())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let lines = stdout.lines().take(count).map(|l| l.unwrap()).collect();
+        // Let it save the position of the last line
+        thread::sleep(Duration::from_millis(200));
+        child.kill().expect("tail exited before killed");
+        child.wait().unwrap();
+        lines
+    };
+
+    let lines = tail(2);
+    assert!(lines[0].contains(r#""Set":{"key":"key1","value":"value1"}"#));
+    assert!(lines[1].contains(r#""Remove":{"key":"key1"}"#));
+    assert!(lines[1].contains(r#""pos":{"term":1,"#));
+
+    // Resumes after the last printed write
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    let lines = tail(1);
+    assert!(lines[0].contains(r#""Set":{"key":"key2","value":"value2"}"#));
+}
+
+#[test]
+fn cli_client_shell_batch() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4018";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["shell", "--file", "-", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("# load some keys\nset key1 value1\nset key2 a value with spaces\n\nget key2\nrm key1\nget key1\nscan key\n")
+        .assert()
+        .success()
+        .stdout("a value with spaces\nKey not found\nkey2\n");
+
+    let script = temp_dir.path().join("script");
+    fs::write(&script, "timing on\nget key2\nrm key1\nget key2\n").unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["shell", "--addr", addr, "--file"])
+        .arg(&script)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("a value with spaces\n("))
+        .stdout(contains(" ms)\n"))
+        .stderr("line 3: Key not found\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["shell", "--file", "-", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set key3\n")
+        .assert()
+        .failure()
+        .stderr("line 1: usage: set <key> <value>\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_client_load_and_dump() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4019";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let csv = temp_dir.path().join("pairs.csv");
+    fs::write(
+        &csv,
+        "key1,value1\nkey2,\"a, quoted\nvalue\"\nkey3\nkey4,value4\n",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["load", "--batch-size", "2", "--addr", addr])
+        .arg(&csv)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "line 4: expected a key and a value, found 1 fields\n",
+        ))
+        .stderr(contains("loaded 3 keys"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["load", "-", "--format", "jsonl", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("{\"key\": \"key5\", \"value\": \"value5\"}\n\n{\"key\": \"key1\", \"value\": \"value6\"}\n")
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["dump", "--format", "jsonl", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(concat!(
+            "{\"key\":\"key1\",\"value\":\"value6\"}\n",
+            "{\"key\":\"key2\",\"value\":\"a, quoted\\nvalue\"}\n",
+            "{\"key\":\"key4\",\"value\":\"value4\"}\n",
+            "{\"key\":\"key5\",\"value\":\"value5\"}\n",
+        ));
+
+    // TSV cannot hold the newline of key2
+    let tsv = temp_dir.path().join("pairs.tsv");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "dump",
+            "--prefix",
+            "key",
+            "--batch-size",
+            "3",
+            "--addr",
+            addr,
+        ])
+        .arg(&tsv)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("key `key2` holds a tab or a newline"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["dump", "--addr", addr])
+        .arg(&tsv)
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(&tsv).unwrap(),
+        "key1\tvalue6\nkey4\tvalue4\nkey5\tvalue5\n"
+    );
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
 }
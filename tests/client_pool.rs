@@ -0,0 +1,114 @@
+use kvs::thread_pool::*;
+use kvs::{
+    KvStore, KvsClient, KvsClientOptions, KvsClientPool, KvsClientPoolOptions, KvsServer, Result,
+};
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn start_server(addr: &str, dir: &TempDir) -> Result<KvsServer<KvStore, SharedQueueThreadPool>> {
+    let mut server = KvsServer::new(KvStore::open(dir.path())?, SharedQueueThreadPool::new(4)?);
+    server.run(addr)?;
+    Ok(server)
+}
+
+#[test]
+fn pool_shares_connections_between_threads() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4310";
+    let mut server = start_server(addr, &dir)?;
+
+    let options = KvsClientPoolOptions {
+        size: 2,
+        ..KvsClientPoolOptions::default()
+    };
+    let pool = KvsClientPool::new(addr, options)?;
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                for i in 0..20 {
+                    let key = format!("key{}-{}", thread_id, i);
+                    pool.set(key.clone(), format!("value{}", i)).unwrap();
+                    assert_eq!(pool.get(key).unwrap(), Some(format!("value{}", i)));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(pool.keys("key")?.len(), 160);
+
+    server.shutdown();
+    Ok(())
+}
+
+#[test]
+fn pool_retries_until_server_is_up() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4311";
+    let pool = KvsClientPool::new(addr, KvsClientPoolOptions::default())?;
+
+    let starter = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        start_server(addr, &dir).map(|server| (server, dir))
+    });
+    assert_eq!(pool.get("key1".to_owned())?, None);
+    let (mut server, _dir) = starter.join().unwrap()?;
+
+    let options = KvsClientPoolOptions {
+        max_retries: 0,
+        ..KvsClientPoolOptions::default()
+    };
+    let pool = KvsClientPool::new("127.0.0.1:4312", options)?;
+    assert!(pool.get("key1".to_owned()).is_err());
+
+    server.shutdown();
+    Ok(())
+}
+
+#[test]
+fn pool_replaces_closed_connections() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4313";
+    let pool = KvsClientPool::new(addr, KvsClientPoolOptions::default())?;
+
+    // The first server accepts one connection and closes it while it is idle
+    let listener = TcpListener::bind(addr)?;
+    let client = pool.client()?;
+    let (tcp, _) = listener.accept()?;
+    drop(client);
+    drop(tcp);
+    drop(listener);
+    thread::sleep(Duration::from_millis(100));
+
+    let mut server = start_server(addr, &dir)?;
+    let mut client = pool.client()?;
+    assert!(client.is_healthy());
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(client);
+    server.shutdown();
+    Ok(())
+}
+
+#[test]
+fn client_read_timeout() -> Result<()> {
+    let addr = "127.0.0.1:4314";
+    let listener = TcpListener::bind(addr)?;
+    let options = KvsClientOptions {
+        read_timeout: Some(Duration::from_millis(200)),
+        ..KvsClientOptions::default()
+    };
+    let mut client = KvsClient::connect_with(addr, options)?;
+    let _tcp = listener.accept()?;
+
+    let start = Instant::now();
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(!client.is_healthy());
+    Ok(())
+}
@@ -1,4 +1,9 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::replication::{LogPosition, LogRead};
+use kvs::{
+    metrics, CacheStats, Compression, Durability, IndexKind, KvStore, KvStoreOptions, KvsEngine,
+    ReadMode, Result,
+};
+use std::fs;
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
@@ -209,3 +214,393 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+fn compact_index_options() -> KvStoreOptions {
+    KvStoreOptions {
+        index: IndexKind::Compact,
+        compaction_threshold: Some(64 * 1024),
+        ..KvStoreOptions::default()
+    }
+}
+
+#[test]
+fn compact_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), compact_index_options())?;
+
+    // Enough keys to merge the writes into the compressed keys, and compact the log
+    for key_id in 0..40_000 {
+        store.set(format!("key{:05}", key_id), format!("{}", key_id))?;
+    }
+    for key_id in (0..40_000).step_by(3) {
+        store.remove(format!("key{:05}", key_id))?;
+    }
+    store.set("key00003".to_owned(), "again".to_owned())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key00003".to_owned())?, Some("again".to_owned()));
+        assert_eq!(store.get("key00006".to_owned())?, None);
+        assert_eq!(store.get("key39998".to_owned())?, Some("39998".to_owned()));
+        assert_eq!(store.get("key4".to_owned())?, None);
+        assert_eq!(
+            store.keys("key0001")?,
+            vec!["key00010", "key00011", "key00013", "key00014", "key00016", "key00017"]
+                .into_iter()
+                .chain(vec!["key00019"])
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(store.keys("")?.len(), 40_000 - 13_334 + 1);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), compact_index_options())?;
+    check(&store)?;
+    Ok(())
+}
+
+#[test]
+fn compact_index_uses_less_memory() -> Result<()> {
+    let skiplist_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compact_dir = TempDir::new().expect("unable to create temporary working directory");
+    let skiplist = KvStore::open(skiplist_dir.path())?;
+    let compact = KvStore::open_with(compact_dir.path(), compact_index_options())?;
+    for key_id in 0..50_000 {
+        skiplist.set(format!("user:{:08}", key_id), "value".to_owned())?;
+        compact.set(format!("user:{:08}", key_id), "value".to_owned())?;
+    }
+
+    assert!(
+        compact.index_memory() * 4 < skiplist.index_memory(),
+        "{} bytes for the compact index, {} for the skiplist",
+        compact.index_memory(),
+        skiplist.index_memory()
+    );
+    Ok(())
+}
+
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        cache_capacity: Some(64 * 1024),
+        compaction_threshold: Some(16 * 1024),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+    // Writes are never hidden by the cache
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Neither are the values moved by compactions
+    let value = "x".repeat(1000);
+    for iter in 0..50 {
+        for key_id in 0..10 {
+            let key = format!("key{}", key_id);
+            store.set(key.clone(), format!("{}{}", value, iter))?;
+            assert_eq!(store.get(key)?, Some(format!("{}{}", value, iter)));
+        }
+    }
+
+    // The cache stays within its capacity
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), value.clone())?;
+        store.get(format!("key{}", key_id))?;
+    }
+    let CacheStats { entries, bytes, .. } = store.cache_stats().unwrap();
+    assert!(bytes <= 64 * 1024, "{} bytes cached", bytes);
+    assert!(entries > 0 && entries < 1000, "{} values cached", entries);
+
+    // Values larger than a shard of the cache are read past it, not missed
+    let before = store.cache_stats().unwrap();
+    store.set("large".to_owned(), "x".repeat(8 * 1024))?;
+    store.get("large".to_owned())?;
+    store.get("large".to_owned())?;
+    let after = store.cache_stats().unwrap();
+    assert_eq!(after.bypassed - before.bypassed, 2);
+    assert_eq!((after.hits, after.misses), (before.hits, before.misses));
+    Ok(())
+}
+
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        read_mode: ReadMode::Mmap,
+        compaction_threshold: Some(16 * 1024),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "first".to_owned())?;
+    }
+    drop(store);
+
+    // The files of the previous run are mapped, the new one is read through handles
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key0".to_owned(), "second".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("second".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("first".to_owned()));
+
+    // Compactions retire the mapped files while clones read them
+    let reader = store.clone();
+    let handle = thread::spawn(move || -> Result<()> {
+        for _ in 0..20 {
+            for key_id in 1..100 {
+                assert_eq!(
+                    reader.get(format!("key{}", key_id))?,
+                    Some("first".to_owned())
+                );
+            }
+        }
+        Ok(())
+    });
+    for iter in 0..200 {
+        let value = format!("{:0>200}", iter);
+        store.set("key0".to_owned(), value.clone())?;
+        assert_eq!(store.get("key0".to_owned())?, Some(value));
+    }
+    handle.join().unwrap()?;
+    assert_eq!(store.keys("key")?.len(), 100);
+    Ok(())
+}
+
+#[test]
+fn bloom_filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        index: IndexKind::Compact,
+        bloom_fp_rate: Some(0.01),
+        compaction_threshold: Some(32 * 1024),
+        ..KvStoreOptions::default()
+    };
+    let avoided = || metrics::global().bloom_avoided_reads.get();
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..5 {
+        for key_id in 0..2000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    let persisted = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("bloom".as_ref()))
+        .count();
+    assert_eq!(persisted, 1);
+
+    let check = |store: &KvStore| -> Result<()> {
+        let before = avoided();
+        for key_id in 0..1000 {
+            assert_eq!(store.get(format!("absent{}", key_id))?, None);
+        }
+        assert!(
+            avoided() - before >= 950,
+            "{} reads avoided",
+            avoided() - before
+        );
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key1999".to_owned())?, Some("4".to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    // The filters of the compacted terms are read back, the others rebuilt
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    check(&store)?;
+    Ok(())
+}
+
+#[test]
+fn compressed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compression: Some(Compression::Lz4),
+        compression_threshold: 64,
+        compaction_threshold: None,
+        ..KvStoreOptions::default()
+    };
+    let document = |id: usize| {
+        format!(
+            r#"{{"id": {}, "tags": ["alpha", "beta"], "description": "{}"}}"#,
+            id,
+            "a verbose description ".repeat(20)
+        )
+    };
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for id in 0..100 {
+        store.set(format!("doc{}", id), document(id))?;
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+    let stats = store.compression_stats();
+    assert!(stats.ratio() > 2.0, "{:?}", stats);
+
+    let log = fs::read_to_string(temp_dir.path().join("1.log"))?;
+    assert!(!log.contains("a verbose description"));
+    assert!(log.contains(r#""value":"value""#));
+
+    let check = |store: &KvStore| -> Result<()> {
+        for id in 0..100 {
+            assert_eq!(store.get(format!("doc{}", id))?, Some(document(id)));
+        }
+        assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    check(&store)?;
+    assert_eq!(store.compression_stats(), stats);
+    drop(store);
+
+    // Values are rewritten with zstd by the compaction, and smaller
+    let options = KvStoreOptions {
+        recompression: Some(Compression::Zstd),
+        compaction_threshold: Some(0),
+        ..options
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("doc0".to_owned(), document(0))?;
+    check(&store)?;
+    let recompressed = store.compression_stats();
+    assert!(recompressed.stored_bytes < stats.stored_bytes);
+    assert_eq!(recompressed.raw_bytes, stats.raw_bytes);
+    Ok(())
+}
+
+// A position past the end of its log file, e.g. saved before the log was truncated,
+// cannot be resumed from
+#[test]
+fn read_log_past_end() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let (pos, _) = store.snapshot()?;
+    match store.read_log(pos, 10)? {
+        LogRead::Entries { entries, next, .. } => {
+            assert!(entries.is_empty());
+            assert_eq!(next, pos);
+        }
+        LogRead::Compacted => panic!("the end of the log is a valid position"),
+    }
+
+    let past_end = LogPosition {
+        term: pos.term,
+        offset: pos.offset + 1000,
+    };
+    assert!(matches!(store.read_log(past_end, 10)?, LogRead::Compacted));
+
+    Ok(())
+}
+
+// Snapshots and log reads happen outside the writer lock, while writes compact the
+// log away under it
+#[test]
+fn snapshot_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: Some(0),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "0".to_owned())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 1..=50 {
+                for i in 0..100 {
+                    store.set(format!("key{}", i), round.to_string())?;
+                }
+            }
+            Ok(())
+        })
+    };
+    while !writer.is_finished() {
+        let (pos, pairs) = store.snapshot()?;
+        assert_eq!(pairs.len(), 100);
+        // The log read from the snapshot is complete, or compacted away meanwhile
+        if let LogRead::Entries { entries, next, .. } = store.read_log(pos, 1000)? {
+            assert!(entries.iter().all(|entry| entry.pos < next));
+        }
+    }
+    writer.join().unwrap()?;
+
+    let (_, pairs) = store.snapshot()?;
+    assert!(pairs.iter().all(|(_, value)| value == "50"));
+    Ok(())
+}
+
+// Compacted logs are synced before the stale ones are deleted
+#[test]
+fn compaction_with_sync_durability() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        durability: Durability::Sync,
+        compaction_threshold: Some(1024),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..20 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..20 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+    Ok(())
+}
+
+#[test]
+fn disk_bytes_by_store() -> Result<()> {
+    let first_dir = TempDir::new().expect("unable to create temporary working directory");
+    let second_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_bytes = |dir: &TempDir| -> u64 {
+        WalkDir::new(dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+    let reported = |dir: &TempDir| metrics::global().disk_bytes.get(dir.path());
+
+    let first = KvStore::open(first_dir.path())?;
+    let second = KvStore::open(second_dir.path())?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    for key_id in 0..100 {
+        second.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    second.remove("key0".to_owned())?;
+    assert_eq!(reported(&first_dir), Some(log_bytes(&first_dir) as i64));
+    assert_eq!(reported(&second_dir), Some(log_bytes(&second_dir) as i64));
+
+    // A closed store is no longer reported
+    drop(second);
+    assert_eq!(reported(&second_dir), None);
+    drop(first);
+    let first = KvStore::open(first_dir.path())?;
+    assert_eq!(reported(&first_dir), Some(log_bytes(&first_dir) as i64));
+    drop(first);
+    Ok(())
+}
@@ -0,0 +1,194 @@
+use kvs::{KvsEngine, LsmKvsEngine, LsmOptions, Result};
+use std::fs;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use tempfile::TempDir;
+
+fn small_options() -> LsmOptions {
+    LsmOptions {
+        memtable_size: 16 * 1024,
+        block_size: 256,
+        ..LsmOptions::default()
+    }
+}
+
+#[test]
+fn get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.remove("key2".to_owned()).is_err());
+    assert!(store.remove("key3".to_owned()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // The write-ahead log is replayed
+    drop(store);
+    let store = LsmKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.keys("")?, vec!["key1".to_owned()]);
+
+    Ok(())
+}
+
+// Flushed and merged SSTables keep the last write of every key
+#[test]
+fn flush_and_merge() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::open_with(temp_dir.path(), small_options())?;
+
+    for iter in 0..20 {
+        for key_id in 0..500 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for key_id in 0..250 {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    let check = |store: &LsmKvsEngine| -> Result<()> {
+        for key_id in 0..500 {
+            let expected = if key_id < 250 {
+                None
+            } else {
+                Some("19".to_owned())
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        assert_eq!(store.keys("")?.len(), 250);
+        Ok(())
+    };
+    check(&store)?;
+
+    // Closing the engine waits for the merges of the full tiers
+    drop(store);
+    let store = LsmKvsEngine::open_with(temp_dir.path(), small_options())?;
+    let tables = store.tables();
+    assert!(tables.len() > 1, "no merge in {:?}", tables);
+    assert!(tables.iter().all(|&n| n < small_options().tier_size));
+    check(&store)?;
+
+    drop(store);
+    let store = LsmKvsEngine::open_with(temp_dir.path(), small_options())?;
+    assert_eq!(store.tables(), tables);
+    check(&store)
+}
+
+// Memtables are flushed to the first tier while a merge runs
+#[test]
+fn writes_during_merge() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = LsmOptions {
+        memtable_size: 4 * 1024,
+        tier_size: 2,
+        ..small_options()
+    };
+    let store = LsmKvsEngine::open_with(temp_dir.path(), options.clone())?;
+
+    let value = "v".repeat(100);
+    let mut written = 0;
+    while store.tables().first().copied().unwrap_or(0) <= options.tier_size {
+        assert!(written < 200_000, "no flush while merging");
+        store.set(format!("key{:06}", written), value.clone())?;
+        written += 1;
+    }
+
+    drop(store);
+    let store = LsmKvsEngine::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.keys("key")?.len(), written);
+    Ok(())
+}
+
+#[test]
+fn ordered_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::open_with(temp_dir.path(), small_options())?;
+
+    // Spread the keys over the memtable and several SSTables
+    for key_id in (0..2000).rev() {
+        store.set(format!("key{:04}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0100".to_owned())?;
+    store.set("key0101".to_owned(), "new".to_owned())?;
+
+    let pairs = store.scan("key0099".to_owned().."key0103".to_owned())?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("key0099".to_owned(), "value99".to_owned()),
+            ("key0101".to_owned(), "new".to_owned()),
+            ("key0102".to_owned(), "value102".to_owned()),
+        ]
+    );
+
+    let keys = store.keys("key1")?;
+    assert_eq!(keys.len(), 1000);
+    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(store.scan(..)?.len(), 1999);
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_set_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::open_with(temp_dir.path(), small_options())?;
+    let barrier = Arc::new(Barrier::new(8));
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        let barrier = Arc::clone(&barrier);
+        let handle = thread::spawn(move || {
+            barrier.wait();
+            for i in 0..500 {
+                let key = format!("key{}_{}", thread_id, i);
+                store.set(key.clone(), format!("value{}", i)).unwrap();
+                assert_eq!(store.get(key).unwrap(), Some(format!("value{}", i)));
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    drop(store);
+    let store = LsmKvsEngine::open_with(temp_dir.path(), small_options())?;
+    assert_eq!(store.keys("")?.len(), 4000);
+
+    Ok(())
+}
+
+// A write-ahead log left behind by a flush is not replayed over newer writes
+#[test]
+fn flushed_wal_not_replayed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::open_with(temp_dir.path(), small_options())?;
+    store.set("key".to_owned(), "old".to_owned())?;
+    for iter in 0..2 {
+        for key_id in 0..500 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.set("key".to_owned(), "new".to_owned())?;
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), "2".to_owned())?;
+    }
+    drop(store);
+
+    // The first log, as if it could not be removed after its flush
+    fs::write(
+        temp_dir.path().join("1.wal"),
+        r#"{"Set":{"key":"key","value":"old"}}"#,
+    )
+    .unwrap();
+    let store = LsmKvsEngine::open_with(temp_dir.path(), small_options())?;
+    assert_eq!(store.get("key".to_owned())?, Some("new".to_owned()));
+    assert!(!temp_dir.path().join("1.wal").exists());
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use kvs::replication::LogOp;
+use kvs::{KvsEngine, MemoryKvsEngine, Result};
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn get_set_remove() -> Result<()> {
+    let store = MemoryKvsEngine::new();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("other".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.remove("key2".to_owned()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.keys("key")?, vec!["key1".to_owned()]);
+
+    Ok(())
+}
+
+// The pairs are saved when the last clone is dropped, and loaded again
+#[test]
+fn snapshot_on_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("snapshot");
+    let store = MemoryKvsEngine::with_snapshot(&path)?;
+    let clone = store.clone();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    clone.set("key2".to_owned(), "value2".to_owned())?;
+
+    drop(store);
+    assert!(!path.exists());
+    drop(clone);
+    assert!(path.exists());
+
+    let store = MemoryKvsEngine::with_snapshot(&path)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.keys("")?.len(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn watch_writes() -> Result<()> {
+    let store = MemoryKvsEngine::new();
+    let watcher = store.watch("key", None)?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let event = watcher.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(
+        event.op,
+        LogOp::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned()
+        }
+    );
+    let event = watcher.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(
+        event.op,
+        LogOp::Remove {
+            key: "key1".to_owned()
+        }
+    );
+
+    Ok(())
+}
@@ -0,0 +1,236 @@
+use kvs::raft::{Network, NodeState, RaftConfig};
+use kvs::thread_pool::*;
+use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, Result};
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Polls `cond` until it holds or the timeout expires.
+fn wait_for<F: FnMut() -> bool>(mut cond: F) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(10) {
+        if cond() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+struct Node {
+    server: KvsServer<KvStore, SharedQueueThreadPool>,
+    engine: KvStore,
+    addr: String,
+    _dir: TempDir,
+}
+
+/// Nodes of a cluster running in this process, talking over loopback.
+struct Cluster {
+    nodes: BTreeMap<u64, Node>,
+    network: Network,
+    snapshot_threshold: usize,
+}
+
+impl Cluster {
+    /// Starts `size` nodes listening on consecutive ports from `base_port`.
+    fn start(base_port: u16, size: u64, snapshot_threshold: usize) -> Result<Cluster> {
+        let mut cluster = Cluster {
+            nodes: BTreeMap::new(),
+            network: Network::new(),
+            snapshot_threshold,
+        };
+        let members: BTreeMap<u64, String> = (1..=size)
+            .map(|id| (id, format!("127.0.0.1:{}", base_port + id as u16 - 1)))
+            .collect();
+        for (&id, addr) in &members {
+            cluster.start_node(id, addr, members.clone())?;
+        }
+        Ok(cluster)
+    }
+
+    /// Starts node `id`, an empty `members` makes it wait to be added.
+    fn start_node(&mut self, id: u64, addr: &str, members: BTreeMap<u64, String>) -> Result<()> {
+        let dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = KvStore::open(dir.path())?;
+        let mut config = RaftConfig::new(id, dir.path().join("raft"));
+        config.members = members;
+        config.election_timeout = Duration::from_millis(150);
+        config.heartbeat_interval = Duration::from_millis(30);
+        config.snapshot_threshold = self.snapshot_threshold;
+        config.network = self.network.clone();
+
+        let mut server =
+            KvsServer::new(engine.clone(), SharedQueueThreadPool::new(4)?).cluster(config);
+        server.run(addr)?;
+        self.nodes.insert(
+            id,
+            Node {
+                server,
+                engine,
+                addr: addr.to_owned(),
+                _dir: dir,
+            },
+        );
+        Ok(())
+    }
+
+    fn client(&self, id: u64) -> KvsClient {
+        KvsClient::connect(&self.nodes[&id].addr).unwrap()
+    }
+
+    /// Waits until one of `candidates` is the leader and returns its id.
+    fn leader(&self, candidates: &[u64]) -> u64 {
+        let mut leader = None;
+        assert!(wait_for(|| {
+            leader = candidates.iter().cloned().find(|&id| {
+                let status = self.client(id).cluster_status().unwrap();
+                status.state == NodeState::Leader
+            });
+            leader.is_some()
+        }));
+        leader.unwrap()
+    }
+
+    fn get(&self, id: u64, key: &str) -> Option<String> {
+        self.nodes[&id].engine.get(key.to_owned()).unwrap()
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for node in self.nodes.values_mut() {
+            node.server.shutdown();
+        }
+    }
+}
+
+#[test]
+fn cluster_replicates_writes_and_redirects() -> Result<()> {
+    let cluster = Cluster::start(4200, 3, 1000)?;
+    let leader = cluster.leader(&[1, 2, 3]);
+    let follower = if leader == 1 { 2 } else { 1 };
+
+    // Followers send the client on to the leader
+    let mut client = cluster.client(follower);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    for id in 1..=3 {
+        assert!(wait_for(
+            || cluster.get(id, "key1") == Some("value1".to_owned())
+        ));
+    }
+
+    let mut client = cluster.client(follower);
+    client.remove("key1".to_owned())?;
+    for id in 1..=3 {
+        assert!(wait_for(|| cluster.get(id, "key1").is_none()));
+    }
+    assert!(client.remove("key1".to_owned()).is_err());
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn cluster_survives_leader_partition() -> Result<()> {
+    let cluster = Cluster::start(4203, 3, 1000)?;
+    let old_leader = cluster.leader(&[1, 2, 3]);
+    cluster
+        .client(old_leader)
+        .set("key1".to_owned(), "value1".to_owned())?;
+
+    cluster.network.isolate(old_leader);
+    let others: Vec<u64> = (1..=3).filter(|&id| id != old_leader).collect();
+
+    // The isolated leader cannot commit without a majority
+    let res = cluster
+        .client(old_leader)
+        .set("lost".to_owned(), "value".to_owned());
+    assert!(res.is_err());
+
+    let new_leader = cluster.leader(&others);
+    assert_ne!(new_leader, old_leader);
+    let mut client = cluster.client(others[0]);
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // The old leader catches up and drops its uncommitted write
+    cluster.network.heal();
+    assert!(wait_for(
+        || cluster.get(old_leader, "key2") == Some("value2".to_owned())
+    ));
+    assert_eq!(cluster.get(old_leader, "key1"), Some("value1".to_owned()));
+    let mut client = cluster.client(old_leader);
+    assert_eq!(client.get("lost".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn cluster_membership_changes() -> Result<()> {
+    let mut cluster = Cluster::start(4206, 3, 1000)?;
+    cluster
+        .client(1)
+        .set("key1".to_owned(), "value1".to_owned())?;
+
+    // A node with no members joins once added
+    cluster.start_node(4, "127.0.0.1:4209", BTreeMap::new())?;
+    cluster.client(1).add_node(4, "127.0.0.1:4209".to_owned())?;
+    assert!(wait_for(
+        || cluster.get(4, "key1") == Some("value1".to_owned())
+    ));
+    assert!(wait_for(|| {
+        let status = cluster.client(4).cluster_status().unwrap();
+        status.members.len() == 4
+    }));
+    assert!(cluster
+        .client(1)
+        .add_node(4, "127.0.0.1:4209".to_owned())
+        .is_err());
+
+    // Removing the leader hands the cluster over to the others
+    let old_leader = cluster.leader(&[1, 2, 3, 4]);
+    cluster.client(old_leader).remove_node(old_leader)?;
+    let others: Vec<u64> = (1..=4).filter(|&id| id != old_leader).collect();
+    let new_leader = cluster.leader(&others);
+    assert_ne!(new_leader, old_leader);
+
+    cluster
+        .client(others[0])
+        .set("key2".to_owned(), "value2".to_owned())?;
+    for &id in &others {
+        assert!(wait_for(
+            || cluster.get(id, "key2") == Some("value2".to_owned())
+        ));
+    }
+    let status = cluster.client(new_leader).cluster_status()?;
+    assert_eq!(status.members.keys().cloned().collect::<Vec<_>>(), others);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(cluster.get(old_leader, "key2"), None);
+    Ok(())
+}
+
+#[test]
+fn cluster_catches_up_from_snapshot() -> Result<()> {
+    let cluster = Cluster::start(4210, 3, 20)?;
+    let leader = cluster.leader(&[1, 2, 3]);
+    let lagging = if leader == 1 { 2 } else { 1 };
+
+    cluster.network.isolate(lagging);
+    let mut client = cluster.client(leader);
+    for key_id in 0..100 {
+        client.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    client.remove("key0".to_owned())?;
+    assert!(client.cluster_status()?.snapshot_index > 0);
+
+    cluster.network.heal();
+    for key_id in 1..100 {
+        assert!(wait_for(
+            || cluster.get(lagging, &format!("key{}", key_id)) == Some(format!("value{}", key_id))
+        ));
+    }
+    assert_eq!(cluster.get(lagging, "key0"), None);
+    let status = cluster.client(lagging).cluster_status()?;
+    assert!(status.snapshot_index > 0);
+    Ok(())
+}
@@ -0,0 +1,189 @@
+use kvs::thread_pool::*;
+use kvs::{
+    KvStore, KvStoreOptions, KvsClient, KvsClientOptions, KvsEngine, KvsError, KvsServer, Result,
+};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Polls `cond` until it holds or the timeout expires.
+fn wait_for<F: FnMut() -> bool>(mut cond: F) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(10) {
+        if cond() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+fn get(addr: &str, key: &str) -> Option<String> {
+    KvsClient::connect(addr)
+        .unwrap()
+        .get(key.to_owned())
+        .unwrap()
+}
+
+#[test]
+fn replica_follows_primary() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary_addr = "127.0.0.1:4101";
+    let replica_addr = "127.0.0.1:4102";
+
+    let mut primary = KvsServer::new(
+        KvStore::open(primary_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    );
+    primary.run(primary_addr)?;
+
+    let mut client = KvsClient::connect(primary_addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut replica = KvsServer::new(
+        KvStore::open(replica_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    )
+    .replica_of(
+        primary_addr.to_owned(),
+        replica_dir.path().join("replication"),
+    );
+    replica.run(replica_addr)?;
+
+    assert!(wait_for(
+        || get(replica_addr, "key2") == Some("value2".to_owned())
+    ));
+    assert_eq!(get(replica_addr, "key1"), Some("value1".to_owned()));
+
+    // Later writes are streamed as they happen
+    client.set("key1".to_owned(), "value3".to_owned())?;
+    client.remove("key2".to_owned())?;
+    assert!(wait_for(|| get(replica_addr, "key2").is_none()));
+    assert_eq!(get(replica_addr, "key1"), Some("value3".to_owned()));
+
+    // The replica is read-only until promoted
+    let mut replica_client = KvsClient::connect(replica_addr)?;
+    assert!(matches!(
+        replica_client.set("key3".to_owned(), "value4".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    replica_client.promote()?;
+    replica_client.set("key3".to_owned(), "value4".to_owned())?;
+    assert_eq!(
+        replica_client.get("key3".to_owned())?,
+        Some("value4".to_owned())
+    );
+    assert!(replica_client.promote().is_err());
+
+    // The promoted replica no longer follows the primary
+    client.set("key1".to_owned(), "value5".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(get(replica_addr, "key1"), Some("value3".to_owned()));
+
+    replica.shutdown();
+    primary.shutdown();
+    Ok(())
+}
+
+#[test]
+fn replica_catches_up_from_snapshot() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary_addr = "127.0.0.1:4103";
+    let replica_addr = "127.0.0.1:4104";
+
+    let options = KvStoreOptions {
+        compaction_threshold: Some(1024),
+        ..KvStoreOptions::default()
+    };
+    let primary_store = KvStore::open_with(primary_dir.path(), options)?;
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            primary_store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    let mut primary = KvsServer::new(primary_store, SharedQueueThreadPool::new(4)?);
+    primary.run(primary_addr)?;
+
+    // A key the primary does not have is dropped by the snapshot
+    let replica_store = KvStore::open(replica_dir.path())?;
+    replica_store.set("stale".to_owned(), "value".to_owned())?;
+    let mut replica = KvsServer::new(replica_store, SharedQueueThreadPool::new(4)?).replica_of(
+        primary_addr.to_owned(),
+        replica_dir.path().join("replication"),
+    );
+    replica.run(replica_addr)?;
+
+    assert!(wait_for(|| get(replica_addr, "stale").is_none()));
+    for key_id in 0..100 {
+        assert_eq!(
+            get(replica_addr, &format!("key{}", key_id)),
+            Some("19".to_owned())
+        );
+    }
+
+    // A restarted replica resumes from its saved position
+    replica.shutdown();
+    let mut client = KvsClient::connect(primary_addr)?;
+    client.set("key0".to_owned(), "20".to_owned())?;
+
+    let replica_addr = "127.0.0.1:4105";
+    let mut replica = KvsServer::new(
+        KvStore::open(replica_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    )
+    .replica_of(
+        primary_addr.to_owned(),
+        replica_dir.path().join("replication"),
+    );
+    replica.run(replica_addr)?;
+    assert!(wait_for(
+        || get(replica_addr, "key0") == Some("20".to_owned())
+    ));
+
+    replica.shutdown();
+    primary.shutdown();
+    Ok(())
+}
+
+// A follower does not hold a worker of the primary's pool
+#[test]
+fn replica_leaves_pool_to_clients() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary_addr = "127.0.0.1:4106";
+    let replica_addr = "127.0.0.1:4107";
+
+    let mut primary = KvsServer::new(
+        KvStore::open(primary_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    );
+    primary.run(primary_addr)?;
+    let mut replica = KvsServer::new(
+        KvStore::open(replica_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    )
+    .replica_of(
+        primary_addr.to_owned(),
+        replica_dir.path().join("replication"),
+    );
+    replica.run(replica_addr)?;
+    thread::sleep(Duration::from_millis(200));
+
+    let options = KvsClientOptions {
+        read_timeout: Some(Duration::from_secs(5)),
+        ..KvsClientOptions::default()
+    };
+    let mut client = KvsClient::connect_with(primary_addr, options)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(wait_for(
+        || get(replica_addr, "key1") == Some("value1".to_owned())
+    ));
+
+    drop(client);
+    replica.shutdown();
+    primary.shutdown();
+    Ok(())
+}
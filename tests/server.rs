@@ -0,0 +1,136 @@
+use kvs::thread_pool::*;
+use kvs::{KvStore, KvsClient, KvsEngine, KvsError, KvsServer, MemoryKvsEngine, Result};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn errors_keep_their_kind_over_the_wire() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4320";
+    let mut server = KvsServer::new(KvStore::open(dir.path())?, SharedQueueThreadPool::new(2)?)
+        .max_value_size(8);
+    server.run(addr)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        client.set("key1".to_owned(), "a long value".to_owned()),
+        Err(KvsError::TooLarge("value", 12, 8))
+    ));
+    assert!(matches!(
+        client.cluster_status(),
+        Err(KvsError::NotClustered)
+    ));
+
+    // The connection is still usable after errors
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    server.shutdown();
+    Ok(())
+}
+
+#[test]
+fn full_pool_answers_busy() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4330";
+    let pool = SharedQueueThreadPool::with_queue(1, 0, QueuePolicy::Reject)?;
+    let mut server = KvsServer::new(KvStore::open(dir.path())?, pool);
+    server.run(addr)?;
+
+    // The first client holds the only thread
+    let mut first = KvsClient::connect(addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut second = KvsClient::connect(addr)?;
+    let res = second.get("key1".to_owned());
+    assert!(matches!(res, Err(KvsError::Busy)), "{:?}", res);
+
+    drop(first);
+    thread::sleep(Duration::from_millis(100));
+    let mut third = KvsClient::connect(addr)?;
+    assert_eq!(third.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(third);
+    server.shutdown();
+    Ok(())
+}
+
+/// An engine panicking on every `get`, and listing no keys.
+#[derive(Clone)]
+struct PanickingEngine(KvStore);
+
+impl KvsEngine for PanickingEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&self, _key: String) -> Result<Option<String>> {
+        panic!("get is broken");
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+}
+
+#[test]
+fn panicking_request_answers_internal_error() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4331";
+    let engine = PanickingEngine(KvStore::open(dir.path())?);
+    let mut server = KvsServer::new(engine, SharedQueueThreadPool::new(1)?);
+    server.run(addr)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    match client.get("key1".to_owned()) {
+        Err(KvsError::Internal) => {}
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    // The only thread of the pool survives the panic
+    let mut client = KvsClient::connect(addr)?;
+    client.remove("key1".to_owned())?;
+    // Nor does the engine list its keys
+    match client.keys("key") {
+        Err(KvsError::Unsupported(op)) => assert_eq!(op, "Listing keys"),
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    drop(client);
+    server.shutdown();
+    Ok(())
+}
+
+// Batches far larger than the requests sent ahead of their responses
+#[test]
+fn large_batches() -> Result<()> {
+    let addr = "127.0.0.1:4340";
+    let mut server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2)?);
+    server.run(addr)?;
+
+    let value = "v".repeat(1024);
+    let keys: Vec<_> = (0..1000).map(|id| format!("key{}", id)).collect();
+    let pairs = keys
+        .iter()
+        .map(|key| (key.clone(), value.clone()))
+        .collect();
+    let mut client = KvsClient::connect(addr)?;
+    for res in client.set_batch(pairs)? {
+        res?;
+    }
+    let values = client.get_batch(keys)?;
+    assert_eq!(values.len(), 1000);
+    for res in values {
+        assert_eq!(res?, Some(value.clone()));
+    }
+
+    drop(client);
+    server.shutdown();
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use kvs::sharding::{HashRing, DEFAULT_VIRTUAL_NODES};
+use kvs::thread_pool::*;
+use kvs::{KvStore, KvsClient, KvsError, KvsServer, Result, ShardedKvsClient};
+use std::collections::HashMap;
+use tempfile::TempDir;
+
+struct Shards {
+    servers: Vec<KvsServer<KvStore, SharedQueueThreadPool>>,
+    addrs: Vec<String>,
+    _dirs: Vec<TempDir>,
+}
+
+impl Shards {
+    fn start(ports: &[u16]) -> Result<Shards> {
+        let mut shards = Shards {
+            servers: Vec::new(),
+            addrs: Vec::new(),
+            _dirs: Vec::new(),
+        };
+        for port in ports {
+            let dir = TempDir::new().expect("unable to create temporary working directory");
+            let addr = format!("127.0.0.1:{}", port);
+            let mut server =
+                KvsServer::new(KvStore::open(dir.path())?, SharedQueueThreadPool::new(2)?);
+            server.run(&addr)?;
+            shards.servers.push(server);
+            shards.addrs.push(addr);
+            shards._dirs.push(dir);
+        }
+        Ok(shards)
+    }
+}
+
+impl Drop for Shards {
+    fn drop(&mut self) {
+        for server in &mut self.servers {
+            server.shutdown();
+        }
+    }
+}
+
+// Each key must be stored on the server the ring routes it to, and only there.
+fn assert_placed(ring: &HashRing, keys: &[String]) -> Result<()> {
+    let mut stored = HashMap::new();
+    for addr in ring.nodes() {
+        for key in KvsClient::connect(addr)?.keys("")? {
+            assert!(stored.insert(key, addr.clone()).is_none());
+        }
+    }
+    assert_eq!(stored.len(), keys.len());
+    for key in keys {
+        assert_eq!(stored[key], ring.node_for(key));
+    }
+    Ok(())
+}
+
+#[test]
+fn ring_moves_keys_only_to_new_node() -> Result<()> {
+    let nodes: Vec<String> = (0..3).map(|i| format!("node{}", i)).collect();
+    let mut ring = HashRing::new(nodes, DEFAULT_VIRTUAL_NODES)?;
+    let keys: Vec<String> = (0..3000).map(|i| format!("key{}", i)).collect();
+
+    let mut counts = HashMap::new();
+    for key in &keys {
+        *counts.entry(ring.node_for(key).to_owned()).or_insert(0) += 1;
+    }
+    for count in counts.values() {
+        assert!(*count > 600, "unbalanced ring: {:?}", counts);
+    }
+
+    let before: Vec<String> = keys
+        .iter()
+        .map(|key| ring.node_for(key).to_owned())
+        .collect();
+    ring.add("node3".to_owned());
+    let mut moved = 0;
+    for (key, old) in keys.iter().zip(before) {
+        let new = ring.node_for(key);
+        if new != old {
+            assert_eq!(new, "node3");
+            moved += 1;
+        }
+    }
+    assert!(moved > 400 && moved < 1200, "moved {} keys", moved);
+    Ok(())
+}
+
+#[test]
+fn ring_needs_points() {
+    assert!(matches!(
+        HashRing::new(Vec::new(), DEFAULT_VIRTUAL_NODES),
+        Err(KvsError::Config(_))
+    ));
+    assert!(matches!(
+        HashRing::new(vec!["node0".to_owned()], 0),
+        Err(KvsError::Config(_))
+    ));
+}
+
+#[test]
+fn sharded_client_spreads_keys() -> Result<()> {
+    let shards = Shards::start(&[4300, 4301, 4302])?;
+    let mut client = ShardedKvsClient::connect(shards.addrs.clone())?;
+
+    let keys: Vec<String> = (0..100).map(|i| format!("key{}", i)).collect();
+    let pairs = keys
+        .iter()
+        .map(|key| (key.clone(), format!("value of {}", key)))
+        .collect();
+    client.set_many(pairs)?;
+    assert_placed(client.ring(), &keys)?;
+    for addr in &shards.addrs {
+        assert!(!KvsClient::connect(addr)?.keys("")?.is_empty());
+    }
+
+    let mut query = vec!["key7".to_owned(), "missing".to_owned(), "key42".to_owned()];
+    assert_eq!(
+        client.get_many(query.clone())?,
+        vec![
+            Some("value of key7".to_owned()),
+            None,
+            Some("value of key42".to_owned())
+        ]
+    );
+    assert_eq!(client.keys("key9")?.len(), 11);
+
+    query.remove(1);
+    client.remove_many(query)?;
+    client.remove("key8".to_owned())?;
+    assert_eq!(client.get("key7".to_owned())?, None);
+    assert_eq!(client.get("key8".to_owned())?, None);
+    assert_eq!(client.keys("")?.len(), 97);
+    assert!(client.remove("key8".to_owned()).is_err());
+
+    // A missing key does not keep the keys after it from being removed
+    let mut query = vec!["missing".to_owned()];
+    query.extend((10..30).map(|i| format!("key{}", i)));
+    assert!(matches!(
+        client.remove_many(query),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(client.keys("")?.len(), 77);
+    Ok(())
+}
+
+#[test]
+fn add_server_migrates_keys() -> Result<()> {
+    let shards = Shards::start(&[4303, 4304, 4305, 4306])?;
+    let (old, new) = shards.addrs.split_at(3);
+    let mut client = ShardedKvsClient::connect(old.to_vec())?;
+
+    let keys: Vec<String> = (0..200).map(|i| format!("key{}", i)).collect();
+    for key in &keys {
+        client.set(key.clone(), format!("value of {}", key))?;
+    }
+
+    let moved = client.add_server(new[0].clone())?;
+    assert!(moved > 0);
+    assert_eq!(KvsClient::connect(&new[0])?.keys("")?.len(), moved);
+    assert_placed(client.ring(), &keys)?;
+    assert!(client.add_server(new[0].clone()).is_err());
+
+    // A client created with the new server list sees every key
+    let mut client = ShardedKvsClient::connect(shards.addrs.clone())?;
+    let values = client.get_many(keys.clone())?;
+    for (key, value) in keys.iter().zip(values) {
+        assert_eq!(value, Some(format!("value of {}", key)));
+    }
+    Ok(())
+}
@@ -1,9 +1,13 @@
+use std::cell::RefCell;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvsError, Result};
 
+use crossbeam::channel::{self, Sender};
 use crossbeam_utils::sync::WaitGroup;
 
 fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
@@ -46,6 +50,76 @@ fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
     spawn_counter(pool)
 }
 
+fn spawn_with_handle_reports_panic<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    let handle = pool.spawn_with_handle(|| 6 * 7)?;
+    assert_eq!(handle.join().unwrap(), 42);
+
+    let handle = pool.spawn_with_handle(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!("broken job {}", 1);
+    })?;
+    let panic = handle.join().map(|_: ()| ()).unwrap_err();
+    assert_eq!(panic.message(), "broken job 1");
+
+    let handle = pool.spawn_with_handle(|| "still running")?;
+    assert_eq!(handle.join().unwrap(), "still running");
+    pool.join();
+    Ok(())
+}
+
+thread_local! {
+    static EXIT_NOTICE: RefCell<Option<ExitNotice>> = RefCell::new(None);
+}
+
+/// Reports the exit of the thread storing it.
+struct ExitNotice(Sender<()>);
+
+impl Drop for ExitNotice {
+    fn drop(&mut self) {
+        let _ = self.0.send(());
+    }
+}
+
+fn shutdown_joins_threads<P: ThreadPool>() -> Result<()> {
+    const THREADS: usize = 4;
+    const TASK_NUM: usize = 100;
+
+    let pool = P::new(THREADS as u32)?;
+    let barrier = Arc::new(Barrier::new(THREADS));
+    let (exit_tx, exit_rx) = channel::unbounded();
+    for _ in 0..THREADS {
+        let barrier = Arc::clone(&barrier);
+        let exit_tx = exit_tx.clone();
+        pool.spawn(move || {
+            // Every thread takes one of these tasks
+            barrier.wait();
+            EXIT_NOTICE.with(|notice| *notice.borrow_mut() = Some(ExitNotice(exit_tx)));
+        })
+    }
+    drop(exit_tx);
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(1));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    // Shutting down a clone stops the whole pool, after the queued tasks ran
+    pool.clone().shutdown(Duration::from_secs(10))?;
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+    for _ in 0..THREADS {
+        exit_rx
+            .recv_timeout(Duration::from_secs(1))
+            .expect("a thread is still running");
+    }
+    assert!(matches!(pool.try_spawn(|| {}), Err(KvsError::PoolShutDown)));
+    Ok(())
+}
+
 #[test]
 fn naive_thread_pool_spawn_counter() -> Result<()> {
     let pool = NaiveThreadPool::new(4)?;
@@ -64,7 +138,267 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+#[test]
+fn work_stealing_thread_pool_spawn_counter() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn elastic_thread_pool_spawn_counter() -> Result<()> {
+    let pool = ElasticThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn work_stealing_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn elastic_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<ElasticThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_shutdown() -> Result<()> {
+    shutdown_joins_threads::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown() -> Result<()> {
+    shutdown_joins_threads::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_shutdown() -> Result<()> {
+    shutdown_joins_threads::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_shutdown() -> Result<()> {
+    shutdown_joins_threads::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn elastic_thread_pool_shutdown() -> Result<()> {
+    shutdown_joins_threads::<ElasticThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown_timeout() -> Result<()> {
+    let (pool, release) = blocked_pool(QueuePolicy::Block)?;
+    assert!(matches!(
+        pool.shutdown(Duration::from_millis(50)),
+        Err(KvsError::ShutdownTimeout(_))
+    ));
+    drop(release);
+    pool.join();
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown_timeout_with_blocked_spawner() -> Result<()> {
+    let (pool, release) = blocked_pool(QueuePolicy::Block)?;
+    pool.spawn(|| {});
+    let spawner = {
+        let pool = pool.clone();
+        thread::spawn(move || pool.spawn(|| {}))
+    };
+    thread::sleep(Duration::from_millis(50));
+
+    // The spawner waiting for room does not hold the shutdown back
+    let start = Instant::now();
+    assert!(matches!(
+        pool.shutdown(Duration::from_millis(50)),
+        Err(KvsError::ShutdownTimeout(_))
+    ));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    drop(release);
+    spawner.join().unwrap();
+    pool.join();
+    Ok(())
+}
+
+/// Returns a pool with a single thread, kept busy until `release` is dropped, and a
+/// queue of one job.
+fn blocked_pool(policy: QueuePolicy) -> Result<(SharedQueueThreadPool, Sender<()>)> {
+    let pool = SharedQueueThreadPool::with_queue(1, 1, policy)?;
+    let (started_tx, started_rx) = channel::bounded(0);
+    let (release, wait) = channel::bounded::<()>(0);
+    pool.spawn(move || {
+        started_tx.send(()).unwrap();
+        let _ = wait.recv();
+    });
+    started_rx.recv().unwrap();
+    Ok((pool, release))
+}
+
+#[test]
+fn shared_queue_thread_pool_reject_policy() -> Result<()> {
+    let (pool, release) = blocked_pool(QueuePolicy::Reject)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+
+    let queued = Arc::clone(&counter);
+    pool.try_spawn(move || {
+        queued.fetch_add(1, Ordering::SeqCst);
+    })?;
+    assert!(matches!(pool.try_spawn(|| {}), Err(KvsError::Busy)));
+
+    drop(release);
+    spawn_counter(pool)?;
+    assert_eq!(counter.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_caller_runs_policy() -> Result<()> {
+    let (pool, release) = blocked_pool(QueuePolicy::CallerRuns)?;
+    pool.try_spawn(|| {})?;
+
+    let caller = thread::current().id();
+    let (ran_tx, ran_rx) = channel::bounded(1);
+    pool.try_spawn(move || ran_tx.send(thread::current().id()).unwrap())?;
+    assert_eq!(ran_rx.try_recv().unwrap(), caller);
+
+    drop(release);
+    spawn_counter(pool)
+}
+
+#[test]
+fn shared_queue_thread_pool_drop_oldest_policy() -> Result<()> {
+    let (pool, release) = blocked_pool(QueuePolicy::DropOldest)?;
+    let (ran_tx, ran_rx) = channel::unbounded();
+
+    for i in 0..3 {
+        let ran_tx = ran_tx.clone();
+        pool.try_spawn(move || ran_tx.send(i).unwrap())?;
+    }
+    drop(ran_tx);
+    drop(release);
+    assert_eq!(ran_rx.iter().collect::<Vec<_>>(), vec![2]);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_drop_oldest_needs_queue() {
+    assert!(matches!(
+        SharedQueueThreadPool::with_queue(1, 0, QueuePolicy::DropOldest),
+        Err(KvsError::Config(_))
+    ));
+}
+
+#[test]
+fn elastic_thread_pool_grows_and_shrinks() -> Result<()> {
+    let pool = ElasticThreadPool::with_options(ElasticOptions {
+        min_threads: 1,
+        max_threads: 3,
+        idle_timeout: Duration::from_millis(100),
+    })?;
+    let (started_tx, started_rx) = channel::unbounded();
+    let (release, wait) = channel::bounded::<()>(0);
+    for _ in 0..4 {
+        let started_tx = started_tx.clone();
+        let wait = wait.clone();
+        pool.spawn(move || {
+            started_tx.send(()).unwrap();
+            let _ = wait.recv();
+        });
+    }
+    for _ in 0..3 {
+        started_rx.recv().unwrap();
+    }
+    let stats = PoolStats {
+        active: 3,
+        idle: 0,
+        queued: 1,
+    };
+    assert_eq!(pool.stats(), stats);
+
+    drop(release);
+    started_rx.recv().unwrap();
+    thread::sleep(Duration::from_millis(500));
+    let stats = PoolStats {
+        active: 0,
+        idle: 1,
+        queued: 0,
+    };
+    assert_eq!(pool.stats(), stats);
+    pool.join();
+    Ok(())
+}
+
+// Threads timing out together never shrink the pool below its minimum
+#[test]
+fn elastic_thread_pool_keeps_min_threads() -> Result<()> {
+    let pool = ElasticThreadPool::with_options(ElasticOptions {
+        min_threads: 2,
+        max_threads: 8,
+        idle_timeout: Duration::from_millis(50),
+    })?;
+    for _ in 0..10 {
+        let (started_tx, started_rx) = channel::unbounded();
+        let (release, wait) = channel::bounded::<()>(0);
+        for _ in 0..8 {
+            let started_tx = started_tx.clone();
+            let wait = wait.clone();
+            pool.spawn(move || {
+                started_tx.send(()).unwrap();
+                let _ = wait.recv();
+            });
+        }
+        for _ in 0..8 {
+            started_rx.recv().unwrap();
+        }
+        drop(release);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(pool.stats().idle, 2);
+    }
+    pool.join();
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle_reports_panic::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle_reports_panic::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle_reports_panic::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle_reports_panic::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn elastic_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle_reports_panic::<ElasticThreadPool>()
+}
+
+#[test]
+fn dropped_job_handle_reports_panic() -> Result<()> {
+    let (pool, release) = blocked_pool(QueuePolicy::DropOldest)?;
+    let dropped = pool.spawn_with_handle(|| ())?;
+    let kept = pool.spawn_with_handle(|| ())?;
+    drop(release);
+    assert_eq!(
+        dropped.join().unwrap_err().message(),
+        "job dropped without running"
+    );
+    assert!(kept.join().is_ok());
+    pool.join();
+    Ok(())
+}
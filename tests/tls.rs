@@ -0,0 +1,229 @@
+use kvs::raft::RaftConfig;
+use kvs::thread_pool::*;
+use kvs::tls::{TlsAcceptor, TlsConnector};
+use kvs::{
+    KvStore, KvsClient, KvsClientOptions, KvsEngine, KvsError, KvsServer, MemoryKvsEngine, Result,
+};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Writes a self-signed certificate for 127.0.0.1 and its key to `dir`, returning
+/// their paths.
+fn self_signed(dir: &Path) -> (PathBuf, PathBuf) {
+    let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()]).unwrap();
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+    (cert_path, key_path)
+}
+
+fn tls_options(ca: &Path) -> Result<KvsClientOptions> {
+    Ok(KvsClientOptions {
+        tls: Some(TlsConnector::new(ca)?),
+        ..KvsClientOptions::default()
+    })
+}
+
+fn tls_server(
+    addr: &str,
+    cert: &Path,
+    key: &Path,
+) -> Result<KvsServer<MemoryKvsEngine, SharedQueueThreadPool>> {
+    let mut server = KvsServer::new(MemoryKvsEngine::new(), SharedQueueThreadPool::new(2)?)
+        .tls(TlsAcceptor::new(cert, key)?);
+    server.run(addr)?;
+    Ok(server)
+}
+
+#[test]
+fn tls_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (cert, key) = self_signed(temp_dir.path());
+    let addr = "127.0.0.1:4350";
+    let mut server = tls_server(addr, &cert, &key)?;
+
+    let mut client = KvsClient::connect_with(addr, tls_options(&cert)?)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    let resps = client.set_batch(vec![("key2".to_owned(), "value2".to_owned())])?;
+    assert!(resps.into_iter().all(|res| res.is_ok()));
+    assert_eq!(client.keys("key")?, vec!["key1", "key2"]);
+    // The messages of the handshake are not data left on the connection
+    assert!(client.is_healthy());
+
+    let mut watch = KvsClient::connect_with(addr, tls_options(&cert)?)?.watch("key", None)?;
+    client.remove("key1".to_owned())?;
+    let event = watch.next().unwrap()?;
+    assert_eq!(
+        event.op,
+        kvs::replication::LogOp::Remove {
+            key: "key1".to_owned()
+        }
+    );
+
+    drop(client);
+    server.shutdown();
+    Ok(())
+}
+
+#[test]
+fn tls_rejects_other_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (cert, key) = self_signed(temp_dir.path());
+    let addr = "127.0.0.1:4351";
+    let mut server = tls_server(addr, &cert, &key)?;
+
+    // A client in the clear gets no answer
+    let options = KvsClientOptions {
+        read_timeout: Some(Duration::from_secs(1)),
+        ..KvsClientOptions::default()
+    };
+    let mut client = KvsClient::connect_with(addr, options)?;
+    assert!(client.get("key1".to_owned()).is_err());
+
+    // A client trusting another certificate does not accept the server
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (other_cert, _) = self_signed(other_dir.path());
+    let mut client = KvsClient::connect_with(addr, tls_options(&other_cert)?)?;
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvsError::Io(_))
+    ));
+
+    // Nor does one expecting another name
+    let options = KvsClientOptions {
+        tls: Some(TlsConnector::new(&cert)?.server_name("kvs.example.com")?),
+        ..KvsClientOptions::default()
+    };
+    let mut client = KvsClient::connect_with(addr, options)?;
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvsError::Io(_))
+    ));
+
+    let mut client = KvsClient::connect_with(addr, tls_options(&cert)?)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    server.shutdown();
+    Ok(())
+}
+
+#[test]
+fn tls_invalid_files() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (cert, key) = self_signed(temp_dir.path());
+    let missing = temp_dir.path().join("missing.pem");
+
+    assert!(matches!(
+        TlsAcceptor::new(&cert, &missing),
+        Err(KvsError::Config(_))
+    ));
+    // The certificate file holds no key, nor the key file a certificate
+    assert!(matches!(
+        TlsAcceptor::new(&cert, &cert),
+        Err(KvsError::Config(_))
+    ));
+    assert!(matches!(TlsConnector::new(&key), Err(KvsError::Config(_))));
+    assert!(matches!(
+        TlsConnector::new(&missing),
+        Err(KvsError::Config(_))
+    ));
+}
+
+#[test]
+fn tls_replica_follows_primary() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (cert, key) = self_signed(temp_dir.path());
+    let primary_addr = "127.0.0.1:4352";
+    let replica_addr = "127.0.0.1:4353";
+
+    let mut primary = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .tls(TlsAcceptor::new(&cert, &key)?);
+    primary.run(primary_addr)?;
+    let mut client = KvsClient::connect_with(primary_addr, tls_options(&cert)?)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut replica = KvsServer::new(
+        KvStore::open(replica_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .replica_of(
+        primary_addr.to_owned(),
+        replica_dir.path().join("replication"),
+    )
+    .peer_tls(TlsConnector::new(&cert)?);
+    replica.run(replica_addr)?;
+
+    let start = Instant::now();
+    let mut replica_client = KvsClient::connect(replica_addr)?;
+    while replica_client.get("key1".to_owned())?.is_none() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "replica is not following"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    drop(replica_client);
+    replica.shutdown();
+    primary.shutdown();
+    Ok(())
+}
+
+#[test]
+fn tls_cluster_replicates_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (cert, key) = self_signed(temp_dir.path());
+    let members: BTreeMap<u64, String> = (1..=3)
+        .map(|id| (id, format!("127.0.0.1:{}", 4353 + id)))
+        .collect();
+
+    let mut nodes = Vec::new();
+    for (&id, addr) in &members {
+        let dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = KvStore::open(dir.path())?;
+        let mut config = RaftConfig::new(id, dir.path().join("raft"));
+        config.members = members.clone();
+        let mut server = KvsServer::new(engine.clone(), SharedQueueThreadPool::new(4)?)
+            .cluster(config)
+            .tls(TlsAcceptor::new(&cert, &key)?)
+            .peer_tls(TlsConnector::new(&cert)?);
+        server.run(addr)?;
+        nodes.push((server, engine, dir));
+    }
+
+    // Redirected to the leader, over TLS as well
+    let start = Instant::now();
+    let mut client = KvsClient::connect_with(&members[&1], tls_options(&cert)?)?;
+    while let Err(e) = client.set("key1".to_owned(), "value1".to_owned()) {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "no leader: {}",
+            e
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+    for (_, engine, _) in &nodes {
+        while engine.get("key1".to_owned())?.is_none() {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "write not replicated"
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    for (server, _, _) in &mut nodes {
+        server.shutdown();
+    }
+    Ok(())
+}
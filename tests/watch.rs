@@ -0,0 +1,127 @@
+use kvs::replication::LogOp;
+use kvs::thread_pool::*;
+use kvs::{KvStore, KvsClient, KvsEngine, KvsError, KvsServer, Result};
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn set(key: &str, value: &str) -> LogOp {
+    LogOp::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+#[test]
+fn watch_engine_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("other".to_owned(), "value0".to_owned())?;
+
+    let watcher = store.watch("config/", None)?;
+    store.set("config/a".to_owned(), "value1".to_owned())?;
+    store.set("other".to_owned(), "value2".to_owned())?;
+    store.remove("config/a".to_owned())?;
+
+    let first = watcher.recv().unwrap();
+    assert_eq!(first.op, set("config/a", "value1"));
+    let second = watcher.recv().unwrap();
+    assert_eq!(
+        second.op,
+        LogOp::Remove {
+            key: "config/a".to_owned()
+        }
+    );
+    assert_eq!(second.seq, first.seq + 2);
+    assert!(watcher.recv_timeout(Duration::from_millis(50)).is_err());
+
+    // Resuming replays the writes following the sequence number
+    let mut resumed = store.watch("", Some(first.seq))?;
+    assert_eq!(resumed.next().unwrap().op, set("other", "value2"));
+    assert_eq!(resumed.next().unwrap().seq, second.seq);
+
+    assert!(matches!(
+        store.watch("", Some(first.seq - 10)),
+        Err(KvsError::SequenceExpired(_))
+    ));
+    Ok(())
+}
+
+// Large values push the oldest changes out of the history
+#[test]
+fn watch_history_bounded_by_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let watcher = store.watch("", None)?;
+    let value = "v".repeat(1024 * 1024);
+    for i in 0..20 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    let first = watcher.recv().unwrap();
+    let seqs: Vec<u64> = (1..20).map(|_| watcher.recv().unwrap().seq).collect();
+
+    assert!(matches!(
+        store.watch("", Some(first.seq)),
+        Err(KvsError::SequenceExpired(_))
+    ));
+    let resumed = store.watch("", Some(seqs[17]))?;
+    assert_eq!(resumed.recv().unwrap().seq, seqs[18]);
+    Ok(())
+}
+
+// Sequence numbers keep growing across restarts, and resuming from a change made
+// before one fails instead of missing writes
+#[test]
+fn watch_across_restarts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let watcher = store.watch("", None)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let before = watcher.recv().unwrap();
+    drop(watcher);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        store.watch("", Some(before.seq)),
+        Err(KvsError::SequenceExpired(_))
+    ));
+    let watcher = store.watch("", None)?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(watcher.recv().unwrap().seq > before.seq);
+    Ok(())
+}
+
+#[test]
+fn watch_over_the_wire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4321";
+    let mut server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    server.run(addr)?;
+
+    // Watches do not hold a worker of the pool, the client keeps the other one
+    let mut watch = KvsClient::connect(addr)?.watch("key", None)?;
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("other".to_owned(), "value".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+
+    let first = watch.next().unwrap()?;
+    assert_eq!(first.op, set("key1", "value1"));
+    drop(watch);
+
+    // A new connection resumes after the last change seen
+    let mut watch = KvsClient::connect(addr)?.watch("key", Some(first.seq))?;
+    assert_eq!(watch.next().unwrap()?.op, set("key2", "value2"));
+
+    let res = KvsClient::connect(addr)?.watch("key", Some(1));
+    match res {
+        Err(e) => assert!(matches!(e, KvsError::SequenceExpired(1))),
+        Ok(_) => panic!("resumed from an expired sequence number"),
+    }
+
+    server.shutdown();
+    Ok(())
+}